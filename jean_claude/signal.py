@@ -104,6 +104,22 @@ def _get_signal_cli_path() -> Path:
     )
 
 
+def _error_message(result: subprocess.CompletedProcess) -> str:
+    """The message of a failed signal-cli run.
+
+    Failures print `{"error": {"code": ..., "message": ...}}` on stdout; older
+    binaries printed the message on stderr instead.
+    """
+    try:
+        error = json.loads(result.stdout)["error"]
+        return f"{error['message']} ({error['code']})"
+    except (json.JSONDecodeError, KeyError, TypeError):
+        pass
+    if result.stderr.strip():
+        return result.stderr.strip()
+    return f"Exit code {result.returncode}"
+
+
 def _run_signal_cli(*args: str, capture: bool = True) -> dict | list | None:
     """Run the signal-cli binary and return parsed JSON output.
 
@@ -135,10 +151,7 @@ def _run_signal_cli(*args: str, capture: bool = True) -> dict | list | None:
     result = subprocess.run(cmd, capture_output=True, text=True)
 
     if result.returncode != 0:
-        error_msg = (
-            result.stderr.strip() if result.stderr else f"Exit code {result.returncode}"
-        )
-        raise JeanClaudeError(f"Signal error: {error_msg}")
+        raise JeanClaudeError(f"Signal error: {_error_message(result)}")
 
     # Parse JSON from stdout
     stdout = result.stdout.strip()
@@ -179,10 +192,7 @@ def _run_signal_cli_with_stdin(*args: str, stdin_data: str) -> dict | list | Non
     result = subprocess.run(cmd, input=stdin_data, capture_output=True, text=True)
 
     if result.returncode != 0:
-        error_msg = (
-            result.stderr.strip() if result.stderr else f"Exit code {result.returncode}"
-        )
-        raise JeanClaudeError(f"Signal error: {error_msg}")
+        raise JeanClaudeError(f"Signal error: {_error_message(result)}")
 
     # Parse JSON from stdout
     stdout = result.stdout.strip()
@@ -209,23 +219,154 @@ def cli():
     """
 
 
+def _link(
+    command: str,
+    device_name: str | None,
+    server: str | None,
+    qr_output: str | None,
+    url_only: bool,
+    timeout: int | None,
+) -> None:
+    args = [command]
+    if device_name:
+        args.extend(["--device-name", device_name])
+    if server:
+        args.extend(["--server", server])
+    if qr_output:
+        args.extend(["--qr-output", qr_output])
+    if url_only:
+        args.append("--url-only")
+    if timeout is not None:
+        args.extend(["--timeout", str(timeout)])
+    _run_signal_cli(*args, capture=False)
+
+
 @cli.command()
 @click.option(
-    "-d", "--device-name", default="jean-claude", help="Device name shown in Signal"
+    "-d", "--device-name", help="Device name shown in Signal [default: jean-claude]"
+)
+@click.option(
+    "--server",
+    type=click.Choice(["production", "staging"]),
+    help="Signal servers to use [default: production]",
 )
-def link(device_name: str):
+@click.option("--qr-output", help="Save the QR code PNG here, or - for base64 in JSON")
+@click.option("--url-only", is_flag=True, help="Print only the provisioning URL")
+@click.option("--timeout", type=int, help="Seconds to wait for the scan")
+def link(
+    device_name: str | None,
+    server: str | None,
+    qr_output: str | None,
+    url_only: bool,
+    timeout: int | None,
+):
     """Link as a secondary device by scanning QR code.
 
     Opens a QR code in the terminal. Scan with Signal on your phone:
-    Settings > Linked Devices > Link New Device.
+    Settings > Linked Devices > Link New Device. On a headless server,
+    --url-only or --qr-output prints the provisioning URL as JSON instead.
     """
-    _run_signal_cli("link", "--device-name", device_name, capture=False)
+    _link("link", device_name, server, qr_output, url_only, timeout)
 
 
 @cli.command()
-def status():
-    """Show Signal connection status."""
-    result = _run_signal_cli("status")
+@click.option(
+    "-d", "--device-name", help="Device name shown in Signal [default: jean-claude]"
+)
+@click.option(
+    "--server",
+    type=click.Choice(["production", "staging"]),
+    help="Signal servers to use [default: production]",
+)
+@click.option("--qr-output", help="Save the QR code PNG here, or - for base64 in JSON")
+@click.option("--url-only", is_flag=True, help="Print only the provisioning URL")
+@click.option("--timeout", type=int, help="Seconds to wait for the scan")
+def relink(
+    device_name: str | None,
+    server: str | None,
+    qr_output: str | None,
+    url_only: bool,
+    timeout: int | None,
+):
+    """Link this device again, keeping its message history and read state.
+
+    For when Signal no longer accepts this device (NOT_LINKED after it was
+    linked). Takes the same options as `link`. Scanning with another account's
+    phone fails and leaves the store as it was.
+    """
+    _link("relink", device_name, server, qr_output, url_only, timeout)
+
+
+@cli.command()
+@click.argument("phone_number")
+@click.option("--voice", is_flag=True, help="Get the code by voice call, not SMS")
+@click.option("--captcha", help="Captcha link from signalcaptchas.org, if required")
+@click.option(
+    "--server",
+    type=click.Choice(["production", "staging"]),
+    help="Signal servers to use [default: production]",
+)
+def register(
+    phone_number: str, voice: bool, captcha: str | None, server: str | None
+):
+    """Register a dedicated number as a primary Signal device.
+
+    For running jean-claude on its own number without a phone. Signal sends
+    a verification code by SMS (or voice call); finish with `verify-code`
+    within 10 minutes. The first code given ends the session, right or wrong.
+    If Signal asks for a captcha, solve one at
+    https://signalcaptchas.org/registration/generate.html and pass the
+    resulting link with --captcha.
+    """
+    args = ["register", phone_number]
+    if voice:
+        args.append("--voice")
+    if captcha:
+        args.extend(["--captcha", captcha])
+    if server:
+        args.extend(["--server", server])
+    result = _run_signal_cli(*args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command("verify-code")
+@click.argument("code")
+def verify_code(code: str):
+    """Finish a `register` with the verification code Signal sent."""
+    result = _run_signal_cli("verify-code", code)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+@click.option(
+    "--keep-data", is_flag=True, help="Keep the local database and caches"
+)
+def unlink(keep_data: bool):
+    """Unlink this device from Signal and delete its local data.
+
+    Removes jean-claude from Settings > Linked Devices on your phone. Run
+    `link` again to reconnect.
+    """
+    args = ["unlink"]
+    if keep_data:
+        args.append("--keep-data")
+    result = _run_signal_cli(*args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+@click.option(
+    "--deep", is_flag=True, help="Also check the servers and credentials, with latency"
+)
+def status(deep: bool):
+    """Show Signal connection status and what's stored locally."""
+    args = ["status"]
+    if deep:
+        args.append("--deep")
+    result = _run_signal_cli(*args)
     if result:
         click.echo(json.dumps(result, indent=2))
 
@@ -239,70 +380,600 @@ def whoami():
 
 
 @cli.command()
-@click.option("-n", "--max-results", default=50, help="Maximum chats to return")
-def chats(max_results: int):
+def ping():
+    """Check that Signal is reachable and this device is still linked.
+
+    Much cheaper than receive, for health checks. Reports how long connecting
+    and one round trip took; fails with NETWORK or NOT_LINKED otherwise.
+    """
+    result = _run_signal_cli("ping")
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+@click.option("--fix", is_flag=True, help="Repair what can be repaired")
+def doctor(fix: bool):
+    """Check the Signal databases and downloaded media for problems.
+
+    Runs integrity and foreign key checks, compares schema versions, and finds
+    reads of messages that never arrived and view-once media with no message.
+    With --fix, upgrades stale schemas, deletes orphaned rows and removes
+    unreferenced media. Corrupt databases need a backup restored instead.
+    """
+    args = ["doctor"]
+    if fix:
+        args.append("--fix")
+    result = _run_signal_cli(*args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+@click.option("--retention-days", type=int, help="Delete messages older than this")
+@click.option(
+    "--max-messages-per-chat", type=int, help="Keep at most this many per chat"
+)
+def prune(retention_days: int | None, max_messages_per_chat: int | None):
+    """Delete old Signal messages and leftovers to keep the databases small.
+
+    Deletes messages older than --retention-days and each chat's oldest beyond
+    --max-messages-per-chat (defaults from config.toml), expired disappearing
+    messages, stale read syncs, view-once media with no message and old event
+    log entries.
+    """
+    args = ["prune"]
+    if retention_days is not None:
+        args.extend(["--retention-days", str(retention_days)])
+    if max_messages_per_chat is not None:
+        args.extend(["--max-messages-per-chat", str(max_messages_per_chat)])
+    result = _run_signal_cli(*args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+@click.option("--new-passphrase-file", help="File holding the new passphrase")
+@click.option(
+    "--generate", is_flag=True, help="Generate a passphrase and keep it in the keychain"
+)
+@click.option(
+    "--store",
+    type=click.Choice(["keyring", "file"]),
+    help="Where to keep the new passphrase as a secret",
+)
+@click.option("--decrypt", is_flag=True, help="Remove the encryption instead")
+def rekey(
+    new_passphrase_file: str | None, generate: bool, store: str | None, decrypt: bool
+):
+    """Encrypt the Signal databases with a passphrase, change it, or remove it.
+
+    The current passphrase comes from JC_SIGNAL_PASSPHRASE_FILE or the
+    signal-cli secret `signal-db-passphrase`, which is updated if it's set.
+    --generate keeps a random passphrase only in that secret, in the OS
+    keychain. Stop `serve` first.
+    """
+    if not new_passphrase_file and not generate and not decrypt:
+        raise click.UsageError("Pass --new-passphrase-file, --generate or --decrypt")
+    args = ["rekey"]
+    if new_passphrase_file:
+        args.extend(["--new-passphrase-file", new_passphrase_file])
+    if generate:
+        args.append("--generate")
+    if store:
+        args.extend(["--store", store])
+    if decrypt:
+        args.append("--decrypt")
+    result = _run_signal_cli(*args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+def version():
+    """Show signal-cli's version, library versions and supported features."""
+    result = _run_signal_cli("version", "--json")
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+@click.option(
+    "-n", "--max-results", type=int, help="Maximum chats to return [default: 50]"
+)
+@click.option(
+    "--with-avatars", is_flag=True, help="Download avatars and include file paths"
+)
+@click.option(
+    "--with-unread-counts", is_flag=True, help="Include each chat's unread count"
+)
+def chats(max_results: int | None, with_avatars: bool, with_unread_counts: bool):
     """List Signal chats (contacts and groups).
 
     Shows contacts and groups with names and IDs.
     """
-    result = _run_signal_cli("chats", "--max-results", str(max_results))
+    args = ["chats"]
+    if max_results is not None:
+        args.extend(["--max-results", str(max_results)])
+    if with_avatars:
+        args.append("--with-avatars")
+    if with_unread_counts:
+        args.append("--with-unread-counts")
+    result = _run_signal_cli(*args)
     if result and isinstance(result, list):
         click.echo(json.dumps(result, indent=2))
 
 
 @cli.command()
-@click.argument("recipient")
-def send(recipient: str):
-    """Send a Signal message.
+@click.option(
+    "-n", "--max-results", type=int, help="Maximum conversations [default: 50]"
+)
+def conversations(max_results: int | None):
+    """List chats by most recent message.
+
+    Each chat has a preview of its latest message, its unread count and
+    whether it's muted, archived or pinned (local flags, set with the chat
+    commands). Pinned chats come first.
+    """
+    args = ["conversations"]
+    if max_results is not None:
+        args.extend(["--max-results", str(max_results)])
+    result = _run_signal_cli(*args)
+    if result and isinstance(result, list):
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.group()
+def chat():
+    """Mute, archive or pin chats (stored locally).
+
+    \b
+    Examples:
+        jean-claude signal chat mute "abc123-def456-..."
+        jean-claude signal chat pin "uuid1" "grouphex"
+    """
+    pass
+
+
+def _chat_flag(action: str, chat_ids: tuple[str, ...]):
+    result = _run_signal_cli("chat", action, *chat_ids)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@chat.command("mute")
+@click.argument("chat_ids", nargs=-1, required=True)
+def chat_mute(chat_ids: tuple[str, ...]):
+    """Mute chats, for an agent to skip."""
+    _chat_flag("mute", chat_ids)
+
+
+@chat.command("unmute")
+@click.argument("chat_ids", nargs=-1, required=True)
+def chat_unmute(chat_ids: tuple[str, ...]):
+    """Unmute chats."""
+    _chat_flag("unmute", chat_ids)
+
+
+@chat.command("archive")
+@click.argument("chat_ids", nargs=-1, required=True)
+def chat_archive(chat_ids: tuple[str, ...]):
+    """Archive chats."""
+    _chat_flag("archive", chat_ids)
+
+
+@chat.command("unarchive")
+@click.argument("chat_ids", nargs=-1, required=True)
+def chat_unarchive(chat_ids: tuple[str, ...]):
+    """Unarchive chats."""
+    _chat_flag("unarchive", chat_ids)
+
+
+@chat.command("pin")
+@click.argument("chat_ids", nargs=-1, required=True)
+def chat_pin(chat_ids: tuple[str, ...]):
+    """Pin chats to the top of conversations."""
+    _chat_flag("pin", chat_ids)
+
+
+@chat.command("unpin")
+@click.argument("chat_ids", nargs=-1, required=True)
+def chat_unpin(chat_ids: tuple[str, ...]):
+    """Unpin chats."""
+    _chat_flag("unpin", chat_ids)
+
 
-    RECIPIENT: UUID of the contact to send to.
+@cli.command()
+@click.argument("recipients", nargs=-1)
+@click.option(
+    "--batch",
+    is_flag=True,
+    help="Read a JSON array of {recipient, text, attachments} from stdin",
+)
+@click.option(
+    "--batch-interval",
+    type=int,
+    help="Milliseconds between batch messages (default 1000)",
+)
+@click.option(
+    "--queue", is_flag=True, help="Put it in the outbox to send later (see flush)"
+)
+@click.option(
+    "--dry-run", is_flag=True, help="Check the message and show it without sending"
+)
+@click.option(
+    "--mention",
+    "mentions",
+    multiple=True,
+    metavar="UUID@START:LEN",
+    help="Mention a group member over a span of the text (repeatable)",
+)
+@click.option(
+    "--markdown",
+    is_flag=True,
+    help="Turn *bold*, _italic_, ~strike~ and ```mono``` into text styles",
+)
+@click.option(
+    "--split",
+    is_flag=True,
+    help="Send text over 2000 characters as several numbered messages",
+)
+@click.option(
+    "--link-preview",
+    is_flag=True,
+    help="Fetch the first https link's title and image and send a preview",
+)
+@click.option(
+    "--voice-note",
+    type=click.Path(exists=True, dir_okay=False),
+    help="Send this AAC recording as a playable voice note (no stdin)",
+)
+@click.option(
+    "--contact",
+    metavar="CONTACT|FILE",
+    help="Share a contact (UUID, name or .vcf file) as a card (no stdin)",
+)
+def send(
+    recipients: tuple[str, ...],
+    batch: bool,
+    batch_interval: int | None,
+    queue: bool,
+    dry_run: bool,
+    mentions: tuple[str, ...],
+    markdown: bool,
+    split: bool,
+    link_preview: bool,
+    voice_note: str | None,
+    contact: str | None,
+):
+    """Send a Signal message.
 
-    Message body is read from stdin.
+    RECIPIENTS: UUIDs or names of the contacts to send to, or a group's chat
+    ID. With several, each gets their own copy and the output has a result per
+    recipient. In a group, `@Name` for a member mentions them.
+
+    Message body is read from stdin. With --batch, stdin is instead a JSON
+    array of messages, each with a recipient, text and optional attachment
+    paths, sent in order with a result per message. With --queue, the message
+    waits in the outbox until `flush` or a running `serve` sends it. With
+    --dry-run, recipients are resolved and the message checked, and what would
+    be sent is printed instead of sending it. With --markdown, *bold*, _italic_,
+    ~strikethrough~ and ```monospace``` are sent as Signal text styles. Signal
+    caps a message at 2000 characters; with --split, longer text is sent as
+    several messages, split between paragraphs or sentences and numbered (n/m).
+    With --link-preview, the first https link gets a preview with the page's
+    title, description and image, as the Signal apps show. With --voice-note,
+    an AAC recording is sent as a voice note the recipient can play in the
+    chat, and stdin isn't read. With --contact, a contact card (from a contact
+    or a .vcf file) is sent instead of text.
 
     \b
     Examples:
         echo "Hello!" | jean-claude signal send "abc123-uuid"
+        echo "Meeting moved" | jean-claude signal send "Alice" "Bob"
+        jean-claude signal send --batch < messages.json
+        echo "Landed" | jean-claude signal send --queue "Alice"
+        echo "@Alice can you check?" | jean-claude signal send "group-chat-id"
+        jean-claude signal send --voice-note memo.aac "Alice"
+        jean-claude signal send --contact "Carol" "Alice"
     """
-    body = read_body_stdin()
-    result = _run_signal_cli_with_stdin("send", recipient, stdin_data=body)
+    if batch:
+        if recipients:
+            raise click.UsageError("--batch takes its recipients from stdin")
+        args = ["--batch"]
+        if batch_interval is not None:
+            args += ["--batch-interval", str(batch_interval)]
+    elif recipients:
+        args = list(recipients)
+    else:
+        raise click.UsageError("Give at least one recipient, or --batch")
+    if queue:
+        args.append("--queue")
+    if dry_run:
+        args.append("--dry-run")
+    for mention in mentions:
+        args += ["--mention", mention]
+    if markdown:
+        args.append("--markdown")
+    if split:
+        args.append("--split")
+    if link_preview:
+        args.append("--link-preview")
+    if voice_note:
+        args += ["--voice-note", voice_note]
+    if contact:
+        args += ["--contact", contact]
+    if voice_note or contact:
+        result = _run_signal_cli("send", *args)
+    else:
+        body = read_body_stdin()
+        result = _run_signal_cli_with_stdin("send", *args, stdin_data=body)
     if result:
         click.echo(json.dumps(result, indent=2))
 
 
 @cli.command()
-def receive():
+@click.option("--webhook", help="Also POST each item to this URL, HMAC-signed")
+@click.option(
+    "--on-message", help="Shell command run per message; its output is the reply"
+)
+@click.option(
+    "--ignore-content",
+    help="Comma-separated kinds to drop: stories, typing, receipts, calls",
+)
+@click.option(
+    "--include-stories", is_flag=True, help="Return stories and download their media"
+)
+@click.option(
+    "--wait", is_flag=True, help="Wait for another receiving instance, not fail"
+)
+@click.option("--store-only", is_flag=True, help="Print only counts, not the messages")
+def receive(
+    webhook: str | None,
+    on_message: str | None,
+    ignore_content: str | None,
+    include_stories: bool,
+    wait: bool,
+    store_only: bool,
+):
     """Receive pending messages.
 
-    Downloads and displays any pending messages from Signal.
+    Downloads and displays any pending messages from Signal. With --webhook,
+    the signing key comes from JC_SIGNAL_WEBHOOK_SECRET or the signal-cli
+    secret `signal-webhook-secret`. With --on-message, the command gets each
+    message's JSON on stdin, and anything it prints is sent back to that chat.
+    Per-chat rules in the data dir's policy.toml can deny, hold for approval,
+    auto-reply or escalate instead. --ignore-content drops whole kinds of
+    content before they're saved or returned. Stories are dropped unless
+    --include-stories is given. If another instance is already receiving, this
+    fails with BUSY unless --wait is given. --store-only, for keeping the local
+    store fresh from cron, prints only how many messages and events arrived.
+    """
+    args = ["--webhook", webhook] if webhook else []
+    if on_message:
+        args += ["--on-message", on_message]
+    if ignore_content:
+        args += ["--ignore-content", ignore_content]
+    if include_stories:
+        args.append("--include-stories")
+    if wait:
+        args.append("--wait")
+    if store_only:
+        args.append("--store-only")
+    result = _run_signal_cli("receive", *args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command("sync-contacts")
+@click.option("--timeout", type=int, help="Seconds to wait for the phone [default: 60]")
+def sync_contacts(timeout: int | None):
+    """Ask the phone for its contacts and wait until they're synced.
+
+    Prints how many contacts were added or changed. Messages that arrive while
+    waiting are saved and show up in `events`.
     """
-    result = _run_signal_cli("receive")
+    args = ["--timeout", str(timeout)] if timeout is not None else []
+    result = _run_signal_cli("sync-contacts", *args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command("await-reply")
+@click.argument("chat_id")
+@click.option("--timeout", type=int, help="Give up after this many seconds")
+@click.option("--matching", help="Only count a message whose text matches this regex")
+def await_reply(chat_id: str, timeout: int | None, matching: str | None):
+    """Wait for the next incoming message in a chat, and print it.
+
+    Receives every few seconds until a message from someone else arrives in
+    CHAT_ID, then prints it as the event log has it (timestamps in unix
+    seconds). Other messages received meanwhile are saved and show up in
+    `events`, but no --on-message hooks run for them. Without --timeout it
+    waits indefinitely.
+    """
+    args = [chat_id]
+    if timeout is not None:
+        args += ["--timeout", str(timeout)]
+    if matching:
+        args += ["--matching", matching]
+    result = _run_signal_cli("await-reply", *args)
     if result:
         click.echo(json.dumps(result, indent=2))
 
 
 @cli.command()
 @click.argument("chat_id")
-@click.option("-n", "--max-results", default=50, help="Maximum messages to return")
-def messages(chat_id: str, max_results: int):
+@click.option(
+    "-n", "--max-results", type=int, help="Maximum messages to return [default: 50]"
+)
+@click.option(
+    "--order-by",
+    type=click.Choice(["sent", "received"]),
+    default="sent",
+    help="Order by sender's timestamp or by local arrival time",
+)
+@click.option(
+    "--verify-ordering", is_flag=True, help="Fail on ties or clock-skewed messages"
+)
+@click.option(
+    "--as-of", help="Show the chat as it was then (e.g. 2h, 3d, or a unix timestamp)"
+)
+@click.option("--since", help="Only messages from then on (ISO 8601, 3d, unix time)")
+@click.option("--until", help="Only messages up to then (same forms as --since)")
+@click.option("--before-id", help="Only messages older than this ID (next page)")
+@click.option("--after-id", help="Only messages newer than this ID")
+@click.option("--ascending", is_flag=True, help="Oldest first")
+@click.option("--unread-only", is_flag=True, help="Only unread incoming messages")
+def messages(
+    chat_id: str,
+    max_results: int | None,
+    order_by: str,
+    verify_ordering: bool,
+    as_of: str | None,
+    since: str | None,
+    until: str | None,
+    before_id: str | None,
+    after_id: str | None,
+    ascending: bool,
+    unread_only: bool,
+):
     """Read stored messages from a chat.
 
     CHAT_ID: UUID of the contact or hex group ID.
 
-    Messages are stored locally after running 'receive'.
+    Messages are stored locally after running 'receive'. Output is newest
+    first; ties are broken by sender, then sender device. To page back through
+    a long chat, pass the last ID of each page as --before-id.
 
     \b
     Examples:
         jean-claude signal messages "abc123-def456-..."
         jean-claude signal messages "abc123-def456-..." -n 20
+        jean-claude signal messages "abc123-def456-..." --order-by received
+        jean-claude signal messages "abc123-def456-..." --as-of 1735000000
+        jean-claude signal messages "abc123-def456-..." --since 2025-01-01
+        jean-claude signal messages "abc123-def456-..." --before-id 1735000000123_abc...
+    """
+    args = ["messages", chat_id, "--order-by", order_by]
+    if max_results is not None:
+        args.extend(["-n", str(max_results)])
+    if verify_ordering:
+        args.append("--verify-ordering")
+    if as_of:
+        args.extend(["--as-of", as_of])
+    if since:
+        args.extend(["--since", since])
+    if until:
+        args.extend(["--until", until])
+    if before_id:
+        args.extend(["--before-id", before_id])
+    if after_id:
+        args.extend(["--after-id", after_id])
+    if ascending:
+        args.append("--ascending")
+    if unread_only:
+        args.append("--unread-only")
+    result = _run_signal_cli(*args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+@click.argument("chat_id")
+@click.option(
+    "--format",
+    "fmt",
+    type=click.Choice(["json", "csv", "html"]),
+    default="json",
+    help="Output file format",
+)
+@click.option("-o", "--output", required=True, help="File to write")
+@click.option(
+    "--with-attachments", is_flag=True, help="Also download attachments beside it"
+)
+def export(chat_id: str, fmt: str, output: str, with_attachments: bool):
+    """Write a chat's full stored history to a file.
+
+    CHAT_ID: UUID of a contact or hex group ID.
+
+    Messages are oldest first. With --with-attachments, attachments are saved
+    in <name>_attachments/ next to the file and linked by relative path.
+
+    \b
+    Examples:
+        jean-claude signal export "abc123-uuid" -o alice.json
+        jean-claude signal export "abc123-uuid" --format html -o a.html \\
+            --with-attachments
+    """
+    args = ["export", chat_id, "--format", fmt, "--output", output]
+    if with_attachments:
+        args.append("--with-attachments")
+    result = _run_signal_cli(*args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+@click.argument("query")
+@click.option("--chat", "chat_id", help="Only search this chat")
+@click.option("--sender", help="Only messages from this sender UUID")
+@click.option("--since", help="Only messages after this (e.g. 30d, 12h, unix time)")
+@click.option("--until", help="Only messages before this (same forms as --since)")
+@click.option(
+    "-n", "--max-results", type=int, help="Maximum results to return [default: 50]"
+)
+@click.option("--reindex", is_flag=True, help="Rebuild the index from stored messages")
+def search(
+    query: str,
+    chat_id: str | None,
+    sender: str | None,
+    since: str | None,
+    until: str | None,
+    max_results: int | None,
+    reindex: bool,
+):
+    """Search stored messages across all chats.
+
+    QUERY: SQLite FTS5 syntax: words, "quoted phrases", prefix*, AND/OR/NOT.
+
+    Results are best match first, with the chat's name and a snippet
+    showing the match in [brackets].
+
+    \b
+    Examples:
+        jean-claude signal search "dinner"
+        jean-claude signal search '"see you" OR cya*' --since 7d
+        jean-claude signal search invoice --chat "abc123-def456-..."
     """
-    result = _run_signal_cli("messages", chat_id, "-n", str(max_results))
+    args = ["search", query]
+    if chat_id:
+        args.extend(["--chat", chat_id])
+    if sender:
+        args.extend(["--sender", sender])
+    if since:
+        args.extend(["--since", since])
+    if until:
+        args.extend(["--until", until])
+    if max_results is not None:
+        args.extend(["-n", str(max_results)])
+    if reindex:
+        args.append("--reindex")
+    result = _run_signal_cli(*args)
     if result:
         click.echo(json.dumps(result, indent=2))
 
 
 @cli.command("mark-read")
-@click.argument("chat_ids", nargs=-1, required=True)
-def mark_read(chat_ids: tuple[str, ...]):
+@click.argument("chat_ids", nargs=-1)
+@click.option(
+    "--message",
+    "messages",
+    multiple=True,
+    metavar="CHAT_ID:MESSAGE_ID",
+    help="Mark just this message read; repeat for several",
+)
+def mark_read(chat_ids: tuple[str, ...], messages: tuple[str, ...]):
     """Mark messages in chats as read (local only).
 
     CHAT_IDS: One or more UUIDs of contacts or hex group IDs.
@@ -311,7 +982,278 @@ def mark_read(chat_ids: tuple[str, ...]):
     Examples:
         jean-claude signal mark-read "abc123-def456-..."
         jean-claude signal mark-read "uuid1" "uuid2" "grouphex"
+        jean-claude signal mark-read --message "grouphex:1705312200000_uuid"
+    """
+    if not chat_ids and not messages:
+        raise click.UsageError("Give chat IDs or --message")
+    args = ["mark-read", *chat_ids]
+    for message in messages:
+        args.extend(["--message", message])
+    result = _run_signal_cli(*args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command("mark-unread")
+@click.argument("chat_id")
+@click.option("--after", metavar="MESSAGE_ID", help="Only messages sent after this one")
+def mark_unread(chat_id: str, after: str | None):
+    """Mark messages in a chat as unread again (local only).
+
+    Use this to requeue messages when handling them failed partway.
+
+    CHAT_ID: UUID of a contact or hex group ID.
+
+    \b
+    Examples:
+        jean-claude signal mark-unread "abc123-def456-..."
+        jean-claude signal mark-unread "grouphex" --after "1705312200000_uuid"
+    """
+    args = ["mark-unread", chat_id]
+    if after:
+        args.extend(["--after", after])
+    result = _run_signal_cli(*args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.group()
+def expire():
+    """Control disappearing messages."""
+    pass
+
+
+@expire.command("set")
+@click.argument("chat_id")
+@click.argument("seconds", type=int)
+def expire_set(chat_id: str, seconds: int):
+    """Set a 1:1 chat's disappearing-messages timer (0 turns it off).
+
+    CHAT_ID: UUID of the contact. Groups' timers are changed from the phone.
+
+    \b
+    Examples:
+        jean-claude signal expire set "abc123-def456-..." 86400
+    """
+    result = _run_signal_cli("expire", "set", chat_id, str(seconds))
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+# Draft command group
+@cli.group()
+def draft():
+    """Stage a reply per chat before sending it (stored locally)."""
+    pass
+
+
+@draft.command("set")
+@click.argument("chat_id")
+def draft_set(chat_id: str):
+    """Save the draft for a chat, with text from stdin.
+
+    \b
+    Examples:
+        echo "On my way" | jean-claude signal draft set "abc123-uuid"
+    """
+    body = read_body_stdin()
+    result = _run_signal_cli_with_stdin("draft", "set", chat_id, stdin_data=body)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@draft.command("get")
+@click.argument("chat_id")
+def draft_get(chat_id: str):
+    """Show the draft for a chat (text is null if there is none)."""
+    result = _run_signal_cli("draft", "get", chat_id)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@draft.command("clear")
+@click.argument("chat_id")
+def draft_clear(chat_id: str):
+    """Discard the draft for a chat."""
+    result = _run_signal_cli("draft", "clear", chat_id)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+@click.option("--all", "all_", is_flag=True, help="Retry every pending message now")
+def flush(all_: bool):
+    """Send queued messages that are due (see send --queue).
+
+    Messages that fail for lack of network or rate limiting are retried
+    later, waiting twice as long each time; other failures give up.
+    """
+    result = _run_signal_cli("flush", *(["--all"] if all_ else []))
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.group()
+def outbox():
+    """Show or remove messages queued with send --queue."""
+    pass
+
+
+@outbox.command("list")
+def outbox_list():
+    """List queued messages, including ones given up on with their error."""
+    result = _run_signal_cli("outbox", "list")
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@outbox.command("remove")
+@click.argument("ids", nargs=-1, required=True, type=int)
+def outbox_remove(ids: tuple[int, ...]):
+    """Remove queued messages without sending them."""
+    result = _run_signal_cli("outbox", "remove", *map(str, ids))
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.group()
+def sticker():
+    """List installed sticker packs or send a sticker."""
+    pass
+
+
+@sticker.command("list")
+def sticker_list():
+    """List the sticker packs installed on the phone, with sticker IDs and emoji."""
+    result = _run_signal_cli("sticker", "list")
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@sticker.command("send")
+@click.argument("chat_id")
+@click.argument("pack_id")
+@click.argument("sticker_id", type=int)
+def sticker_send(chat_id: str, pack_id: str, sticker_id: int):
+    """Send a sticker from an installed pack.
+
+    CHAT_ID: Contact UUID or group chat ID. PACK_ID and STICKER_ID come from
+    `sticker list`.
+
+    \b
+    Example:
+        jean-claude signal sticker send "abc123-uuid" "9acc9e8a..." 3
+    """
+    result = _run_signal_cli("sticker", "send", chat_id, pack_id, str(sticker_id))
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+def mcp():
+    """Serve Signal as MCP tools over stdio.
+
+    Exposes list_chats, receive_messages, get_messages, send_message and
+    mark_read to an MCP client (e.g. in an agent's MCP server config).
+
+    \b
+    Example config entry:
+        {"command": "jean-claude", "args": ["signal", "mcp"]}
+    """
+    _run_signal_cli("mcp", capture=False)
+
+
+@cli.command()
+@click.option(
+    "--listen", default="127.0.0.1:8686", show_default=True, help="Address to bind"
+)
+@click.option("--webhook", help="POST each item received to this URL, HMAC-signed")
+@click.option(
+    "--on-message", help="Shell command run per message; its output is the reply"
+)
+def serve(listen: str, webhook: str | None, on_message: str | None):
+    """Serve a localhost REST API for other local components.
+
+    Requires a bearer token from JC_SIGNAL_API_TOKEN or the signal-cli
+    secret `signal-api-token`. Runs until interrupted. Messages it receives
+    get --webhook, --on-message and policy.toml handling as with `receive`.
+    """
+    args = ["--webhook", webhook] if webhook else []
+    if on_message:
+        args += ["--on-message", on_message]
+    _run_signal_cli("serve", "--listen", listen, *args, capture=False)
+
+
+# Backup command group
+@cli.group()
+def backup():
+    """Move this device's state to another machine as one encrypted file.
+
+    The passphrase comes from SIGNAL_CLI_BACKUP_PASSPHRASE, or else the
+    signal-cli secret `signal-backup-passphrase`.
     """
-    result = _run_signal_cli("mark-read", *chat_ids)
+    pass
+
+
+@backup.command("create")
+@click.argument("path")
+def backup_create(path: str):
+    """Write the store, local databases, config files and avatars to PATH."""
+    result = _run_signal_cli("backup", "create", path)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+@backup.command("restore")
+@click.argument("path")
+@click.option("--force", is_flag=True, help="Replace an existing store")
+def backup_restore(path: str, force: bool):
+    """Replace this machine's Signal state with the backup at PATH.
+
+    The device stays linked; stop using the machine the backup came from.
+    """
+    args = ["backup", "restore", path]
+    if force:
+        args.append("--force")
+    result = _run_signal_cli(*args)
+    if result:
+        click.echo(json.dumps(result, indent=2))
+
+
+# Db command group
+@cli.group()
+def db():
+    """Manage the Signal store and local databases."""
+    pass
+
+
+@db.command("upgrade")
+def db_upgrade():
+    """Migrate every database to this build's schema.
+
+    Run after installing a new build. Each database is backed up into the
+    data dir's backups/ first and restored if a migration fails or a table
+    loses rows. Stop `serve` first.
+    """
+    result = _run_signal_cli("db", "upgrade")
     if result:
         click.echo(json.dumps(result, indent=2))
+
+
+@cli.command()
+@click.option("--to", required=True, help="Standby directory, or host:path for rsync")
+@click.option("--interval", type=int, help="Seconds between copies [default: 60]")
+@click.option("--once", is_flag=True, help="Copy once and exit")
+def replicate(to: str, interval: int | None, once: bool):
+    """Keep a standby copy of the Signal store and local databases.
+
+    Copies whatever changed every --interval seconds until interrupted. If
+    this host fails, copy the standby's files into the new host's Signal
+    data dir to carry on without re-linking.
+    """
+    args = ["replicate", "--to", to]
+    if interval is not None:
+        args.extend(["--interval", str(interval)])
+    if once:
+        args.append("--once")
+    _run_signal_cli(*args, capture=False)
@@ -0,0 +1,59 @@
+//! Embeds the git commit and the resolved Signal library versions, for
+//! `version`.
+
+use std::path::Path;
+use std::process::Command;
+
+/// A locked package's version, with the tag or commit for git sources
+/// (e.g. `0.8.0-dev (66b56a7)` or `v0.86.6`).
+fn locked_version(lock: &str, name: &str) -> Option<String> {
+    let entry = lock.split("[[package]]").find(|entry| {
+        entry
+            .lines()
+            .any(|line| line == format!("name = \"{}\"", name))
+    })?;
+    let field = |key: &str| {
+        entry.lines().find_map(|line| {
+            line.strip_prefix(key)?
+                .strip_prefix(" = \"")?
+                .strip_suffix('"')
+                .map(str::to_string)
+        })
+    };
+    let version = field("version")?;
+    let Some(source) = field("source").filter(|source| source.starts_with("git+")) else {
+        return Some(version);
+    };
+    if let Some((_, tag)) = source.split_once("?tag=") {
+        return Some(tag.split('#').next().unwrap_or(tag).to_string());
+    }
+    let commit = source.rsplit('#').next().unwrap_or_default();
+    Some(format!("{} ({})", version, &commit[..commit.len().min(7)]))
+}
+
+fn main() {
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=JC_GIT_HASH={}", git_hash);
+
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let lock_path = Path::new(&manifest_dir).join("Cargo.lock");
+    let lock = std::fs::read_to_string(&lock_path).unwrap_or_default();
+    for (name, var) in [
+        ("presage", "JC_PRESAGE_VERSION"),
+        ("libsignal-service", "JC_LIBSIGNAL_SERVICE_VERSION"),
+        ("libsignal-protocol", "JC_LIBSIGNAL_VERSION"),
+    ] {
+        let version = locked_version(&lock, name).unwrap_or_else(|| "unknown".to_string());
+        println!("cargo:rustc-env={}={}", var, version);
+    }
+
+    println!("cargo:rerun-if-changed={}", lock_path.display());
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+}
@@ -0,0 +1,82 @@
+//! When each message actually reached us.
+//!
+//! Signal messages only carry the sender's clock (the sent timestamp), and
+//! presage doesn't keep the envelope's server timestamp. To order by arrival,
+//! and to spot senders whose clocks are off, `receive` records the local time
+//! each message was pulled off the queue.
+
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{local, Result};
+
+/// How far a sent timestamp may run ahead of arrival before we call it skew.
+pub const CLOCK_SKEW_TOLERANCE_MS: u64 = 2 * 60 * 1000;
+
+pub fn open_arrivals_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// Record arrival times (milliseconds) for `(sender, sent_ts)` pairs.
+/// The first recorded arrival wins if a message is seen twice.
+pub fn record(conn: &mut Connection, arrivals: &[(String, u64, u64)]) -> Result<()> {
+    let tx = conn.transaction()?;
+    for (sender, sent_ts, received_at) in arrivals {
+        tx.execute(
+            "INSERT OR IGNORE INTO arrivals (sender, sent_ts, received_at) VALUES (?1, ?2, ?3)",
+            rusqlite::params![sender, *sent_ts as i64, *received_at as i64],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Arrival time in milliseconds, if this message came in through `receive`.
+pub fn received_at(conn: &Connection, sender: &str, sent_ts: u64) -> Result<Option<u64>> {
+    Ok(conn
+        .query_row(
+            "SELECT received_at FROM arrivals WHERE sender = ?1 AND sent_ts = ?2",
+            rusqlite::params![sender, sent_ts as i64],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?
+        .map(|at| at as u64))
+}
+
+/// Whether the sender's clock was ahead of ours when the message arrived.
+pub fn is_clock_skewed(sent_ts: u64, received_at: u64) -> bool {
+    sent_ts > received_at + CLOCK_SKEW_TOLERANCE_MS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skew_is_a_sent_time_past_the_tolerance() {
+        let arrived = 1_700_000_000_000;
+        assert!(!is_clock_skewed(arrived, arrived));
+        // Sent "before" it arrived is normal
+        assert!(!is_clock_skewed(arrived - 60 * 60 * 1000, arrived));
+        assert!(!is_clock_skewed(arrived + CLOCK_SKEW_TOLERANCE_MS, arrived));
+        assert!(is_clock_skewed(
+            arrived + CLOCK_SKEW_TOLERANCE_MS + 1,
+            arrived
+        ));
+        assert!(!is_clock_skewed(0, 0));
+    }
+
+    #[test]
+    fn the_first_arrival_is_kept() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        local::migrate(&mut conn).unwrap();
+        record(&mut conn, &[("a".to_string(), 100, 200)]).unwrap();
+        record(
+            &mut conn,
+            &[("a".to_string(), 100, 900), ("b".to_string(), 100, 300)],
+        )
+        .unwrap();
+        assert_eq!(received_at(&conn, "a", 100).unwrap(), Some(200));
+        assert_eq!(received_at(&conn, "b", 100).unwrap(), Some(300));
+        assert_eq!(received_at(&conn, "a", 101).unwrap(), None);
+    }
+}
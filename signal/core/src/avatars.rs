@@ -0,0 +1,67 @@
+//! Downloading contact and group avatars.
+
+use std::path::{Path, PathBuf};
+
+use presage::libsignal_service::prelude::ProfileKey;
+use presage::proto::GroupContextV2;
+use presage::store::{ContentsStore, Thread};
+
+use crate::{parse_thread, Result, SignalManager};
+
+/// Pick a file extension from an image's magic bytes
+fn image_extension(data: &[u8]) -> &'static str {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => "png",
+        [0xFF, 0xD8, ..] => "jpg",
+        [b'G', b'I', b'F', ..] => "gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "webp",
+        _ => "bin",
+    }
+}
+
+/// Download a chat's avatar into `<data_dir>/avatars/`.
+///
+/// Returns None when the chat has no avatar (or, for contacts, when we don't
+/// have their profile key yet and so can't decrypt it).
+pub async fn download_avatar(
+    manager: &mut SignalManager,
+    chat_id: &str,
+    dir: &Path,
+) -> Result<Option<PathBuf>> {
+    let data = match parse_thread(chat_id)? {
+        Thread::Contact(uuid) => {
+            let Some(contact) = manager.store().contact_by_id(&uuid).await? else {
+                return Ok(None);
+            };
+            match <[u8; 32]>::try_from(contact.profile_key) {
+                Ok(key) => {
+                    manager
+                        .retrieve_profile_avatar_by_uuid(uuid, ProfileKey::create(key))
+                        .await?
+                }
+                // Contact sync may still have given us an avatar
+                Err(_) => contact.avatar.map(|a| a.reader.to_vec()),
+            }
+        }
+        Thread::Group(master_key) => {
+            let Some(group) = manager.store().group(master_key).await? else {
+                return Ok(None);
+            };
+            let context = GroupContextV2 {
+                master_key: Some(master_key.to_vec()),
+                revision: Some(group.revision),
+                ..Default::default()
+            };
+            manager.retrieve_group_avatar(context).await?
+        }
+    };
+
+    let Some(data) = data.filter(|d| !d.is_empty()) else {
+        return Ok(None);
+    };
+
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.{}", chat_id, image_extension(&data)));
+    std::fs::write(&path, &data)?;
+    Ok(Some(path))
+}
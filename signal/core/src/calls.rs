@@ -0,0 +1,138 @@
+//! Call notifications.
+//!
+//! Calls can't be answered here, but someone trying to call is worth knowing
+//! about. A 1:1 call arrives as `CallMessage`s: an offer while it rings, then
+//! hangups. If my phone answered or declined it, the caller says so with a
+//! hangup, and the phone syncs a `CallEvent` saying whether it was picked up.
+//! A plain hangup right after the offer means the call was missed, but one
+//! arriving alone may end a call answered earlier, so then only the phone's
+//! sync counts. Each call seen during a `receive` becomes one `call` event,
+//! rolled up like reactions: `missed` if it ended unanswered, `offer` if it
+//! was still ringing, and nothing if it was picked up. Group calls are
+//! announced by a message in the group (see [`EventOutput::from_data_message`]).
+
+use std::collections::BTreeMap;
+
+use presage::libsignal_service::prelude::Uuid;
+use presage::proto::sync_message::CallEvent;
+use presage::proto::CallMessage;
+
+use crate::model::EventOutput;
+use crate::timestamp::Timestamp;
+
+// Offer types
+const OFFER_VIDEO: i32 = 1;
+
+// Hangup types; the others mean one of my devices answered, declined or was
+// busy
+const HANGUP_NORMAL: i32 = 0;
+
+// Call event types, directions and events
+const CALL_AUDIO: i32 = 1;
+const CALL_VIDEO: i32 = 2;
+const DIRECTION_INCOMING: i32 = 1;
+const EVENT_ACCEPTED: i32 = 1;
+const EVENT_NOT_ACCEPTED: i32 = 2;
+
+#[derive(Default)]
+struct Call {
+    /// "audio" or "video", once known
+    media: Option<&'static str>,
+    /// When the call was first seen, in milliseconds
+    timestamp: u64,
+    /// Whether the offer was seen
+    offered: bool,
+    answered: bool,
+    missed: bool,
+}
+
+/// 1:1 calls seen during a `receive`, by (caller ACI, call ID)
+#[derive(Default)]
+pub struct Calls {
+    calls: BTreeMap<(String, u64), Call>,
+}
+
+impl Calls {
+    fn call(&mut self, caller: &str, id: u64, timestamp: u64) -> &mut Call {
+        self.calls
+            .entry((caller.to_string(), id))
+            .or_insert_with(|| Call {
+                timestamp,
+                ..Default::default()
+            })
+    }
+
+    /// Note a call message from `caller`, sent at `timestamp`.
+    pub fn observe(&mut self, caller: &str, timestamp: u64, message: &CallMessage) {
+        if let Some(offer) = &message.offer {
+            let media = if offer.r#type == Some(OFFER_VIDEO) {
+                "video"
+            } else {
+                "audio"
+            };
+            let call = self.call(caller, offer.id.unwrap_or(0), timestamp);
+            call.media = Some(media);
+            call.offered = true;
+        }
+        if let Some(hangup) = &message.hangup {
+            let call = self.call(caller, hangup.id.unwrap_or(0), timestamp);
+            if hangup.r#type.unwrap_or(HANGUP_NORMAL) == HANGUP_NORMAL {
+                call.missed |= call.offered && !call.answered;
+            } else {
+                call.answered = true;
+                call.missed = false;
+            }
+        }
+    }
+
+    /// Note a call my phone synced: it says whether a 1:1 call was picked up.
+    pub fn observe_sync(&mut self, event: &CallEvent) {
+        let incoming = event.direction == Some(DIRECTION_INCOMING);
+        let media = match event.r#type {
+            Some(CALL_AUDIO) => "audio",
+            Some(CALL_VIDEO) => "video",
+            _ => return,
+        };
+        let caller = event
+            .conversation_id
+            .as_deref()
+            .and_then(|id| Uuid::from_slice(id).ok());
+        let (true, Some(caller), Some(id)) = (incoming, caller, event.id) else {
+            return;
+        };
+        let call = self.call(&caller.to_string(), id, event.timestamp.unwrap_or(0));
+        call.media.get_or_insert(media);
+        match event.event {
+            Some(EVENT_ACCEPTED) => {
+                call.answered = true;
+                call.missed = false;
+            }
+            Some(EVENT_NOT_ACCEPTED) => call.missed = !call.answered,
+            _ => {}
+        }
+    }
+
+    /// A `call` event for each call that rang or was missed, and wasn't picked
+    /// up.
+    pub fn events(self) -> Vec<EventOutput> {
+        self.calls
+            .into_iter()
+            .filter_map(|((caller, _), call)| {
+                let kind = match call {
+                    Call { answered: true, .. } => return None,
+                    Call { missed: true, .. } => "missed",
+                    Call { offered: true, .. } => "offer",
+                    _ => return None,
+                };
+                Some(EventOutput::Call {
+                    chat_id: caller.clone(),
+                    sender: caller,
+                    timestamp: Timestamp::from_millis(call.timestamp),
+                    direction: "incoming",
+                    kind,
+                    media: call.media,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,90 @@
+//! Listing contacts and groups.
+
+use std::path::Path;
+
+use presage::store::ContentsStore;
+use tracing::{instrument, warn};
+
+use crate::avatars::download_avatar;
+use crate::model::ChatOutput;
+use crate::{conversations, read_sync, Result, SignalManager};
+
+/// Contacts followed by groups, truncated to `max_results`.
+///
+/// With `avatar_dir`, each chat's avatar is downloaded there and its path
+/// included; a failed download is logged and skipped. With `unread_counts`,
+/// each chat's count of unread incoming messages is included. Each chat has
+/// its local muted, archived and pinned flags.
+#[instrument(skip_all)]
+pub async fn list_chats(
+    manager: &mut SignalManager,
+    max_results: usize,
+    avatar_dir: Option<&Path>,
+    unread_counts: bool,
+) -> Result<Vec<ChatOutput>> {
+    let store = manager.store();
+
+    let mut chats = Vec::new();
+
+    // Add contacts
+    for contact in store.contacts().await?.flatten() {
+        chats.push(ChatOutput {
+            id: contact.uuid.to_string(),
+            name: contact.name.clone(),
+            is_group: false,
+            phone: contact.phone_number.map(|p| p.format().to_string()),
+            avatar: None,
+            unread_count: None,
+            is_muted: false,
+            is_archived: false,
+            is_pinned: false,
+        });
+    }
+
+    // Add groups
+    for (master_key, group) in store.groups().await?.flatten() {
+        chats.push(ChatOutput {
+            id: hex::encode(master_key),
+            name: group.title.clone(),
+            is_group: true,
+            phone: None,
+            avatar: None,
+            unread_count: None,
+            is_muted: false,
+            is_archived: false,
+            is_pinned: false,
+        });
+    }
+
+    // Limit results
+    chats.truncate(max_results);
+
+    let flags_db = conversations::open_conversations_db()?;
+    for chat in &mut chats {
+        let flags = conversations::flags(&flags_db, &chat.id)?;
+        chat.is_muted = flags.muted;
+        chat.is_archived = flags.archived;
+        chat.is_pinned = flags.pinned;
+    }
+
+    if unread_counts {
+        let my_uuid = manager.whoami().await?.aci;
+        let read_db = read_sync::open_read_sync_db()?;
+        for chat in &mut chats {
+            let count = read_sync::unread_count(manager, &read_db, &chat.id, my_uuid).await?;
+            chat.unread_count = Some(count);
+        }
+    }
+
+    if let Some(avatar_dir) = avatar_dir {
+        for chat in &mut chats {
+            // A missing avatar shouldn't hide the rest of the chat list
+            match download_avatar(manager, &chat.id, avatar_dir).await {
+                Ok(path) => chat.avatar = path.map(|p| p.display().to_string()),
+                Err(e) => warn!("Failed to download avatar for {}: {}", chat.id, e),
+            }
+        }
+    }
+
+    Ok(chats)
+}
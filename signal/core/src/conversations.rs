@@ -0,0 +1,146 @@
+//! The conversation list: every chat with its latest message and unread
+//! count, most recent first, like the app's main screen.
+//!
+//! The app's muted, archived and pinned flags live in Signal's storage
+//! service, which presage doesn't sync, so the flags here are local and set
+//! with `chat mute`, `chat archive` and `chat pin`. Pinned chats come first.
+//!
+//! Version 1 added `pinned`.
+
+use presage::libsignal_service::content::ContentBody;
+use presage::libsignal_service::prelude::Uuid;
+use presage::store::ContentsStore;
+use rusqlite::{Connection, OptionalExtension};
+use tracing::instrument;
+
+use crate::chats::list_chats;
+use crate::model::{
+    message_id, message_preview, ConversationOutput, EventOutput, LastMessageOutput,
+};
+use crate::timestamp::Timestamp;
+use crate::{local, parse_thread, read_sync, Result, SignalManager};
+
+pub fn open_conversations_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// A chat's local flags
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChatFlags {
+    pub muted: bool,
+    pub archived: bool,
+    pub pinned: bool,
+}
+
+pub fn set_muted(conn: &Connection, chat_id: &str, muted: bool) -> Result<()> {
+    conn.execute(
+        "INSERT INTO chat_flags (chat_id, muted) VALUES (?1, ?2)
+         ON CONFLICT(chat_id) DO UPDATE SET muted = excluded.muted",
+        rusqlite::params![chat_id, muted],
+    )?;
+    Ok(())
+}
+
+pub fn set_archived(conn: &Connection, chat_id: &str, archived: bool) -> Result<()> {
+    conn.execute(
+        "INSERT INTO chat_flags (chat_id, archived) VALUES (?1, ?2)
+         ON CONFLICT(chat_id) DO UPDATE SET archived = excluded.archived",
+        rusqlite::params![chat_id, archived],
+    )?;
+    Ok(())
+}
+
+pub fn set_pinned(conn: &Connection, chat_id: &str, pinned: bool) -> Result<()> {
+    conn.execute(
+        "INSERT INTO chat_flags (chat_id, pinned) VALUES (?1, ?2)
+         ON CONFLICT(chat_id) DO UPDATE SET pinned = excluded.pinned",
+        rusqlite::params![chat_id, pinned],
+    )?;
+    Ok(())
+}
+
+pub fn flags(conn: &Connection, chat_id: &str) -> Result<ChatFlags> {
+    Ok(conn
+        .query_row(
+            "SELECT muted, archived, pinned FROM chat_flags WHERE chat_id = ?1",
+            [chat_id],
+            |row| {
+                Ok(ChatFlags {
+                    muted: row.get(0)?,
+                    archived: row.get(1)?,
+                    pinned: row.get(2)?,
+                })
+            },
+        )
+        .optional()?
+        .unwrap_or_default())
+}
+
+/// The newest message in a chat with something to show, skipping events.
+async fn last_message(
+    manager: &SignalManager,
+    chat_id: &str,
+    my_uuid: Uuid,
+) -> Result<Option<LastMessageOutput>> {
+    let thread = parse_thread(chat_id)?;
+    for content in manager.store().messages(&thread, ..).await?.rev().flatten() {
+        let ContentBody::DataMessage(dm) = &content.body else {
+            continue;
+        };
+        let sender_uuid = content.metadata.sender.raw_uuid();
+        let sender = sender_uuid.to_string();
+        if EventOutput::from_data_message(chat_id, &sender, dm).is_some() {
+            continue;
+        }
+        let Some(text) = message_preview(&content.body) else {
+            continue;
+        };
+        let ts = dm.timestamp.unwrap_or(0);
+        return Ok(Some(LastMessageOutput {
+            id: message_id(ts, &sender),
+            sender,
+            timestamp: Timestamp::from_millis(ts),
+            text,
+            is_outgoing: sender_uuid == my_uuid,
+        }));
+    }
+    Ok(None)
+}
+
+/// Every chat with its latest message, unread count and flags, pinned chats
+/// first and then most recent first; chats with no messages come last.
+/// Truncated to `max_results`.
+#[instrument(skip_all)]
+pub async fn list_conversations(
+    manager: &mut SignalManager,
+    max_results: usize,
+) -> Result<Vec<ConversationOutput>> {
+    let my_uuid = manager.whoami().await?.aci;
+    let read_db = read_sync::open_read_sync_db()?;
+
+    let mut conversations = Vec::new();
+    for chat in list_chats(manager, usize::MAX, None, false).await? {
+        let last_message = last_message(manager, &chat.id, my_uuid).await?;
+        let unread_count = read_sync::unread_count(manager, &read_db, &chat.id, my_uuid).await?;
+        conversations.push(ConversationOutput {
+            id: chat.id,
+            name: chat.name,
+            is_group: chat.is_group,
+            last_message,
+            unread_count,
+            is_muted: chat.is_muted,
+            is_archived: chat.is_archived,
+            is_pinned: chat.is_pinned,
+        });
+    }
+
+    conversations.sort_by(|a, b| {
+        let time = |c: &ConversationOutput| c.last_message.as_ref().map(|m| m.timestamp);
+        b.is_pinned
+            .cmp(&a.is_pinned)
+            .then_with(|| time(b).cmp(&time(a)))
+    });
+    conversations.truncate(max_results);
+
+    Ok(conversations)
+}
@@ -0,0 +1,86 @@
+//! Remote deletes ("delete for everyone").
+//!
+//! A sender deletes a message for everyone with a data message naming it by
+//! sent timestamp. The stored message is replaced with a tombstone, a data
+//! message with nothing in it, and dropped from the search index; the delete
+//! is recorded here, so `messages` can show the tombstone as `deleted` and
+//! when. Only the message's sender can delete it.
+
+use presage::libsignal_service::content::{Content, ContentBody};
+use presage::proto::DataMessage;
+use presage::store::ContentsStore;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{local, parse_thread, search, Result, SignalManager};
+
+pub fn open_deletes_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// The sent timestamp of the message a data message deletes, if it's a delete
+pub fn target(dm: &DataMessage) -> Option<u64> {
+    dm.delete.as_ref()?.target_sent_timestamp
+}
+
+/// When a message was deleted by its sender, in milliseconds, if it was
+pub fn deleted_at(
+    conn: &Connection,
+    chat_id: &str,
+    sender: &str,
+    timestamp: u64,
+) -> Result<Option<u64>> {
+    Ok(conn
+        .query_row(
+            "SELECT deleted_at FROM deleted
+             WHERE chat_id = ?1 AND sender = ?2 AND timestamp = ?3",
+            rusqlite::params![chat_id, sender, timestamp as i64],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?
+        .map(|ms| ms as u64))
+}
+
+/// Delete `sender`'s message sent at `target` in a chat, as asked by a delete
+/// they sent at `deleted_at`. Returns whether a stored message was replaced;
+/// the delete is recorded either way.
+pub async fn apply(
+    manager: &SignalManager,
+    conn: &Connection,
+    search_db: Option<&Connection>,
+    chat_id: &str,
+    sender: &str,
+    target: u64,
+    deleted_at: u64,
+) -> Result<bool> {
+    conn.execute(
+        "INSERT OR IGNORE INTO deleted (chat_id, sender, timestamp, deleted_at)
+         VALUES (?1, ?2, ?3, ?4)",
+        rusqlite::params![chat_id, sender, target as i64, deleted_at as i64],
+    )?;
+    if let Some(search_db) = search_db {
+        search::remove(search_db, chat_id, sender, target)?;
+    }
+
+    let thread = parse_thread(chat_id)?;
+    let Some(content) = manager.store().message(&thread, target).await? else {
+        return Ok(false);
+    };
+    let ContentBody::DataMessage(dm) = &content.body else {
+        return Ok(false);
+    };
+    if content.metadata.sender.raw_uuid().to_string() != sender {
+        return Ok(false);
+    }
+    // Only what places the message in its chat is kept
+    let tombstone = DataMessage {
+        timestamp: dm.timestamp,
+        group_v2: dm.group_v2.clone(),
+        expire_timer: dm.expire_timer,
+        ..Default::default()
+    };
+    manager
+        .store()
+        .save_message(&thread, Content::from_body(tombstone, content.metadata))
+        .await?;
+    Ok(true)
+}
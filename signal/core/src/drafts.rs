@@ -0,0 +1,44 @@
+//! Per-chat message drafts.
+//!
+//! Signal keeps drafts on each device and never sends them over the wire, so
+//! there's nothing to sync: drafts here are local to this data dir, one per
+//! chat, for staging a reply before `send`.
+
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{local, Result};
+
+pub fn open_drafts_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// Save a chat's draft, replacing any previous one.
+pub fn set(conn: &Connection, chat_id: &str, text: &str) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    conn.execute(
+        "INSERT OR REPLACE INTO drafts (chat_id, text, updated_at) VALUES (?1, ?2, ?3)",
+        rusqlite::params![chat_id, text, now],
+    )?;
+    Ok(())
+}
+
+/// A chat's draft and when it was last saved (unix seconds).
+pub fn get(conn: &Connection, chat_id: &str) -> Result<Option<(String, i64)>> {
+    Ok(conn
+        .query_row(
+            "SELECT text, updated_at FROM drafts WHERE chat_id = ?1",
+            [chat_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?)
+}
+
+/// Discard a chat's draft. Returns whether there was one.
+pub fn clear(conn: &Connection, chat_id: &str) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM drafts WHERE chat_id = ?1", [chat_id])? > 0)
+}
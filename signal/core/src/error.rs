@@ -0,0 +1,110 @@
+//! Errors returned by the library, grouped by what a caller can do about them.
+
+use presage::libsignal_service::prelude::Uuid;
+use presage::libsignal_service::push_service::ServiceError;
+use presage_store_sqlite::SqliteStoreError;
+
+use crate::outbound::Outbound;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// No linked or registered account in the data dir
+    #[error("Not linked to Signal. Run 'signal-cli link' first.")]
+    NotLinked,
+
+    /// The server no longer recognizes this device, e.g. after it was removed
+    /// from the phone's linked devices
+    #[error("This device is no longer linked to Signal. Run 'signal-cli relink' to link it again, keeping its history.")]
+    Unlinked,
+
+    /// A `link` was interrupted before finishing; running it again cleans up
+    #[error("A previous 'signal-cli link' was interrupted. Run it again to start over.")]
+    LinkInterrupted,
+
+    /// The Signal servers couldn't be reached or rejected the request
+    #[error("Network error: {0}")]
+    Network(#[source] BoxError),
+
+    /// Another process is receiving for this account (see [`crate::lock`])
+    #[error("Another signal-cli instance is running. Use --wait to wait for it to finish.")]
+    Busy,
+
+    /// The databases are encrypted and the passphrase is missing or wrong
+    #[error("The databases are encrypted and the passphrase is missing or wrong. Pass --passphrase-file, or set the signal-db-passphrase secret.")]
+    Encrypted,
+
+    /// The server is throttling this account; retry later
+    #[error("Rate limited by the Signal server, try again later")]
+    RateLimited,
+
+    /// The contact's safety number changed and the identity policy blocks
+    /// them until it's trusted again
+    #[error("Identity key for {0} changed. Verify the safety number, then run 'signal-cli identity trust {0}'")]
+    UntrustedIdentity(Uuid),
+
+    /// A chat ID, UUID or contact name that doesn't resolve to one recipient
+    #[error("{0}")]
+    InvalidRecipient(String),
+
+    /// The presage store or one of the local databases failed
+    #[error("Storage error: {0}")]
+    Storage(#[source] BoxError),
+
+    /// `--read-only` is set and the action would send something
+    #[error("Refusing to {0}: signal-cli is in read-only mode")]
+    ReadOnly(Outbound),
+
+    /// A request that can't be carried out as given
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl Error {
+    pub fn network(e: impl Into<BoxError>) -> Self {
+        Error::Network(e.into())
+    }
+
+    pub fn storage(e: impl Into<BoxError>) -> Self {
+        Error::Storage(e.into())
+    }
+}
+
+impl From<presage::Error<SqliteStoreError>> for Error {
+    fn from(e: presage::Error<SqliteStoreError>) -> Self {
+        match e {
+            presage::Error::NotYetRegisteredError => Error::NotLinked,
+            presage::Error::ServiceError(ServiceError::Unauthorized) => Error::Unlinked,
+            presage::Error::ServiceError(ServiceError::RateLimitExceeded) => Error::RateLimited,
+            presage::Error::Store(e) => Error::storage(e),
+            e => Error::network(e),
+        }
+    }
+}
+
+impl From<SqliteStoreError> for Error {
+    fn from(e: SqliteStoreError) -> Self {
+        Error::storage(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::storage(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::storage(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::storage(e)
+    }
+}
@@ -0,0 +1,223 @@
+//! Durable event log with independent consumer cursors.
+//!
+//! `receive` drains Signal's server-side queue, so only one process ever sees
+//! a given message. To let several consumers (a logger, a bot, a UI) each see
+//! the full stream, everything `receive` emits is appended here, and each
+//! named consumer reads from its own ack position via `events <consumer>`.
+//!
+//! The log doesn't grow forever: each `receive` [`trim`]s the events every
+//! consumer has acked once they're a week old, and `prune` also drops those
+//! past its retention period, acked or not.
+
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::timestamp::{with_time_format, TimeFormat};
+use crate::{local, Result};
+
+/// A logged event as returned to consumers.
+#[derive(Serialize)]
+pub struct EventRecord {
+    pub seq: i64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub created_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<String>,
+    pub data: serde_json::Value,
+}
+
+/// How long an event every consumer has acked stays in the log, for `serve`
+/// clients resuming with `Last-Event-ID`
+pub const ACKED_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+pub fn open_events_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// Append events in a single transaction.
+///
+/// Each item is `(kind, chat_id, payload)`. Payload timestamps are always
+/// stored as unix seconds, whatever `--time-format` the writer had, so the
+/// log reads the same for every consumer.
+pub fn append<T: Serialize>(
+    conn: &mut Connection,
+    items: &[(&str, Option<&str>, &T)],
+) -> Result<usize> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    let tx = conn.transaction()?;
+    for (kind, chat_id, payload) in items {
+        tx.execute(
+            "INSERT INTO events (kind, created_at, chat_id, payload) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![
+                kind,
+                now,
+                chat_id,
+                with_time_format(TimeFormat::Unix, || serde_json::to_string(payload))?
+            ],
+        )?;
+    }
+    tx.commit()?;
+
+    Ok(items.len())
+}
+
+fn acked_seq(conn: &Connection, consumer: &str) -> Result<i64> {
+    Ok(conn
+        .query_row(
+            "SELECT acked_seq FROM consumers WHERE name = ?1",
+            [consumer],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0))
+}
+
+/// Events after the consumer's ack position, oldest first.
+pub fn pending(conn: &Connection, consumer: &str, limit: usize) -> Result<Vec<EventRecord>> {
+    after(conn, acked_seq(conn, consumer)?, limit)
+}
+
+/// Sequence number of the newest event, or 0 if the log is empty.
+pub fn last_seq(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("SELECT COALESCE(MAX(seq), 0) FROM events", [], |row| row.get(0))?)
+}
+
+/// Events after `seq`, oldest first.
+pub fn after(conn: &Connection, seq: i64, limit: usize) -> Result<Vec<EventRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT seq, kind, created_at, chat_id, payload FROM events
+         WHERE seq > ?1 ORDER BY seq LIMIT ?2",
+    )?;
+    let rows = stmt.query_map(rusqlite::params![seq, limit as i64], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, Option<String>>(3)?,
+            row.get::<_, String>(4)?,
+        ))
+    })?;
+
+    let mut events = Vec::new();
+    for row in rows {
+        let (seq, kind, created_at, chat_id, payload) = row?;
+        events.push(EventRecord {
+            seq,
+            kind,
+            created_at,
+            chat_id,
+            data: serde_json::from_str(&payload)?,
+        });
+    }
+    Ok(events)
+}
+
+/// Advance a consumer's ack position. Never moves it backwards.
+pub fn ack(conn: &Connection, consumer: &str, seq: i64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO consumers (name, acked_seq) VALUES (?1, ?2)
+         ON CONFLICT(name) DO UPDATE SET acked_seq = MAX(acked_seq, excluded.acked_seq)",
+        rusqlite::params![consumer, seq],
+    )?;
+    Ok(())
+}
+
+/// Delete the events nobody needs any more: those every consumer has acked,
+/// once they're [`ACKED_RETENTION_SECS`] old, and with `before` (unix seconds)
+/// every one logged before it, acked or not. Events after `keep_after` stay
+/// regardless, as a receive checkpoint still points at them. Returns how many
+/// were deleted.
+pub fn trim(conn: &Connection, before: Option<i64>, keep_after: Option<i64>) -> Result<usize> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    Ok(conn.execute(
+        "DELETE FROM events WHERE seq <= ?1 AND (
+             (seq <= (SELECT COALESCE(MIN(acked_seq), ?1) FROM consumers) AND created_at < ?2)
+             OR created_at < ?3
+         )",
+        rusqlite::params![
+            keep_after.unwrap_or(i64::MAX),
+            now - ACKED_RETENTION_SECS,
+            before.unwrap_or(i64::MIN)
+        ],
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log with events 1 to 4, logged 30, 20, 10 and 0 days ago
+    fn log() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::local::migrate(&mut conn).unwrap();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        for days in [30, 20, 10, 0] {
+            conn.execute(
+                "INSERT INTO events (kind, created_at, payload) VALUES ('message', ?1, '{}')",
+                [now - days * 24 * 60 * 60],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    fn seqs(conn: &Connection) -> Vec<i64> {
+        after(conn, 0, 10)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.seq)
+            .collect()
+    }
+
+    #[test]
+    fn trims_old_events_without_consumers() {
+        let conn = log();
+        assert_eq!(trim(&conn, None, None).unwrap(), 3);
+        assert_eq!(seqs(&conn), [4]);
+    }
+
+    #[test]
+    fn keeps_what_a_consumer_hasnt_acked() {
+        let conn = log();
+        ack(&conn, "bot", 3).unwrap();
+        ack(&conn, "logger", 1).unwrap();
+        assert_eq!(trim(&conn, None, None).unwrap(), 1);
+        assert_eq!(seqs(&conn), [2, 3, 4]);
+    }
+
+    #[test]
+    fn cutoff_trims_unacked_events() {
+        let conn = log();
+        ack(&conn, "logger", 1).unwrap();
+        let fifteen_days_ago = log_time(&conn, 2) + 5 * 24 * 60 * 60;
+        assert_eq!(trim(&conn, Some(fifteen_days_ago), None).unwrap(), 2);
+        assert_eq!(seqs(&conn), [3, 4]);
+    }
+
+    #[test]
+    fn keeps_events_after_a_checkpoint() {
+        let conn = log();
+        assert_eq!(trim(&conn, Some(i64::MAX), Some(1)).unwrap(), 1);
+        assert_eq!(seqs(&conn), [2, 3, 4]);
+    }
+
+    fn log_time(conn: &Connection, seq: i64) -> i64 {
+        conn.query_row(
+            "SELECT created_at FROM events WHERE seq = ?1",
+            [seq],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+}
@@ -0,0 +1,203 @@
+//! Disappearing messages.
+//!
+//! A chat's timer is changed by a data message flagged as a timer update, and
+//! every message sent while it's on carries the timer (`expire_timer`, in
+//! seconds). presage stores messages but never deletes them, so messages with
+//! a timer are recorded here as they're stored, and [`prune`] deletes them
+//! from the store and the search index once their time is up. As in the app,
+//! my own messages count down from when they were sent and other people's only
+//! once they're read; the countdown is measured from the sent time either way.
+//!
+//! Each chat's timer is kept here too, so `send` can carry it: a message
+//! without the timer would turn it off for the recipient.
+
+use std::time::UNIX_EPOCH;
+
+use presage::libsignal_service::content::ContentBody;
+use presage::libsignal_service::prelude::Uuid;
+use presage::libsignal_service::protocol::ServiceId;
+use presage::proto::{data_message, DataMessage};
+use presage::store::{ContentsStore, Thread};
+use rusqlite::{Connection, OptionalExtension};
+use tracing::{instrument, warn};
+
+use crate::{
+    local, outbound, parse_thread, read_sync, search, thread_chat_id, Error, Result, SignalManager,
+};
+
+pub fn open_expiry_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// Whether a data message changes its chat's timer rather than being a message
+pub fn is_timer_update(dm: &DataMessage) -> bool {
+    dm.flags.unwrap_or(0) & data_message::Flags::ExpirationTimerUpdate as u32 != 0
+}
+
+/// Record a chat's timer, unless a newer version is already recorded.
+fn set_timer(conn: &Connection, chat_id: &str, seconds: u32, version: u32) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    conn.execute(
+        "INSERT INTO timers (chat_id, seconds, version, updated_at) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (chat_id) DO UPDATE SET
+             seconds = excluded.seconds,
+             version = excluded.version,
+             updated_at = excluded.updated_at
+         WHERE excluded.version >= version",
+        rusqlite::params![chat_id, seconds, version, now],
+    )?;
+    Ok(())
+}
+
+/// A chat's (seconds, version) timer, if it has been seen to change. Zero
+/// seconds means off.
+pub fn timer(conn: &Connection, chat_id: &str) -> Result<Option<(u32, u32)>> {
+    Ok(conn
+        .query_row(
+            "SELECT seconds, version FROM timers WHERE chat_id = ?1",
+            [chat_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?)
+}
+
+/// Note a stored data message: a timer update changes the chat's timer, and a
+/// message with a timer is tracked until it expires.
+pub fn observe(conn: &Connection, chat_id: &str, sender: &str, dm: &DataMessage) -> Result<()> {
+    let seconds = dm.expire_timer.unwrap_or(0);
+    if is_timer_update(dm) {
+        return set_timer(conn, chat_id, seconds, dm.expire_timer_version.unwrap_or(0));
+    }
+    if let (Some(ts), true) = (dm.timestamp, seconds > 0) {
+        conn.execute(
+            "INSERT OR IGNORE INTO expiring (chat_id, sender, timestamp, seconds)
+             VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![chat_id, sender, ts as i64, seconds],
+        )?;
+    }
+    Ok(())
+}
+
+/// [`observe`] a message this device just sent, as the store saved it.
+pub async fn observe_stored(
+    manager: &SignalManager,
+    conn: &Connection,
+    thread: &Thread,
+    timestamp: u64,
+) -> Result<()> {
+    if let Some(content) = manager.store().message(thread, timestamp).await? {
+        if let ContentBody::DataMessage(dm) = &content.body {
+            let sender = content.metadata.sender.raw_uuid().to_string();
+            observe(conn, &thread_chat_id(thread), &sender, dm)?;
+        }
+    }
+    Ok(())
+}
+
+/// The timer to put on a message sent to `uuid`: the last one seen in the
+/// chat, or failing that the one from the phone's contact sync.
+pub async fn outgoing_timer(
+    manager: &SignalManager,
+    conn: &Connection,
+    uuid: Uuid,
+) -> Result<Option<(u32, u32)>> {
+    if let Some(timer) = timer(conn, &uuid.to_string())? {
+        return Ok(Some(timer));
+    }
+    Ok(manager
+        .store()
+        .contact_by_id(&uuid)
+        .await?
+        .filter(|contact| contact.expire_timer > 0)
+        .map(|contact| (contact.expire_timer, contact.expire_timer_version)))
+}
+
+/// Change a 1:1 chat's timer (0 turns it off) by telling the contact.
+/// Returns the update's sent timestamp in milliseconds.
+///
+/// A group's timer is part of the group's state, changed with a group update
+/// that presage can't make, so groups are refused.
+#[instrument(skip_all)]
+pub async fn set(manager: &mut SignalManager, chat_id: &str, seconds: u32) -> Result<u64> {
+    let Thread::Contact(uuid) = parse_thread(chat_id)? else {
+        return Err(Error::Invalid(
+            "A group's disappearing-messages timer can only be changed from the phone".to_string(),
+        ));
+    };
+    let conn = open_expiry_db()?;
+    let version = outgoing_timer(manager, &conn, uuid)
+        .await?
+        .map_or(1, |(_, version)| version + 1);
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let update = DataMessage {
+        flags: Some(data_message::Flags::ExpirationTimerUpdate as u32),
+        expire_timer: Some(seconds),
+        expire_timer_version: Some(version),
+        timestamp: Some(timestamp),
+        ..Default::default()
+    };
+    outbound::send_message(
+        manager,
+        ServiceId::Aci(uuid.into()),
+        ContentBody::DataMessage(update),
+        timestamp,
+    )
+    .await?;
+    set_timer(&conn, &uuid.to_string(), seconds, version)?;
+    Ok(timestamp)
+}
+
+/// Delete messages whose time is up from the store and the search index.
+/// Returns how many were deleted.
+#[instrument(skip_all)]
+pub async fn prune(manager: &SignalManager) -> Result<usize> {
+    let conn = open_expiry_db()?;
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let due: Vec<(String, String, u64)> = conn
+        .prepare(
+            "SELECT chat_id, sender, timestamp FROM expiring
+             WHERE timestamp / 1000 + seconds <= ?1",
+        )?
+        .query_map([now], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get::<_, i64>(2)? as u64))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    if due.is_empty() {
+        return Ok(0);
+    }
+
+    let my_aci = manager.whoami().await?.aci.to_string();
+    let read_db = read_sync::open_read_sync_db()?;
+    let search_db = search::open_search_db()?;
+    let mut store = manager.store().clone();
+    let mut pruned = 0;
+    for (chat_id, sender, ts) in due {
+        // Unread messages wait for their countdown to start
+        if sender != my_aci && !read_sync::is_read(&read_db, &chat_id, &sender, ts) {
+            continue;
+        }
+        let thread = parse_thread(&chat_id)?;
+        match store.delete_message(&thread, ts).await {
+            Ok(_) => pruned += 1,
+            Err(e) => {
+                warn!("Failed to delete expired message in {}: {}", chat_id, e);
+                continue;
+            }
+        }
+        search::remove(&search_db, &thread_chat_id(&thread), &sender, ts)?;
+        conn.execute(
+            "DELETE FROM expiring WHERE chat_id = ?1 AND sender = ?2 AND timestamp = ?3",
+            rusqlite::params![chat_id, sender, ts as i64],
+        )?;
+    }
+    Ok(pruned)
+}
@@ -0,0 +1,242 @@
+//! Reading a chat's stored message history.
+
+use presage::libsignal_service::content::ContentBody;
+use presage::store::ContentsStore;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::model::{
+    message_id, parse_message_id, reply_to, EventOutput, MessageOrder, MessageOutput, ReceivedItem,
+};
+use crate::timestamp::Timestamp;
+use crate::{
+    arrivals, deletes, mentions, parse_thread, read_sync, roster, shared_contacts, stickers,
+    stories, view_once, Error, Result, SignalManager,
+};
+
+/// Which part of a thread [`thread_messages`] returns, for paging through
+/// long histories.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Page {
+    /// Unix milliseconds, inclusive, on the `order_by` clock
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Only messages after this one in newest-first order (older ones)
+    pub before_id: Option<String>,
+    /// Only messages before this one in newest-first order (newer ones)
+    pub after_id: Option<String>,
+    /// Oldest first, so `max_results` keeps the oldest messages in range
+    pub ascending: bool,
+    /// Only incoming messages that haven't been read
+    pub unread_only: bool,
+}
+
+/// (primary time, sent time, sender, device): a message's position in the
+/// thread
+type SortKey = (u64, u64, String, u32);
+
+/// The sort key of the message with this ID. An old-style ID (a bare sent
+/// time) can match several senders' messages; `oldest` picks the oldest such
+/// message, else the newest.
+fn cursor(keyed: &[(SortKey, ReceivedItem)], id: &str, oldest: bool) -> Result<SortKey> {
+    let (ts, sender) = parse_message_id(id)?;
+    // Sorted newest first
+    let mut matching = keyed
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| key.1 == ts && sender.is_none_or(|sender| key.2 == sender));
+    let key = if oldest {
+        matching.next_back()
+    } else {
+        matching.next()
+    };
+    key.cloned()
+        .ok_or_else(|| Error::Invalid(format!("No message with ID {} in this chat", id)))
+}
+
+/// Stored messages and events for a chat, newest first.
+///
+/// See [`MessageOrder`] for the ordering guarantees. With `verify_ordering`,
+/// fails instead of returning an order with ties or clock-skewed timestamps.
+///
+/// With `as_of` (unix milliseconds), returns the thread as it stood then:
+/// messages that arrived later are left out. A message we never received
+/// (one we sent) counts from its sent time. Read state is still current.
+///
+/// `page` narrows the result to a time range or to one side of a message, so
+/// a client can walk the thread a page at a time by passing the last ID it
+/// saw as `before_id` (or `after_id` with `ascending`).
+#[instrument(skip_all)]
+pub async fn thread_messages(
+    manager: &SignalManager,
+    chat_id: &str,
+    max_results: usize,
+    order_by: MessageOrder,
+    verify_ordering: bool,
+    as_of: Option<u64>,
+    page: &Page,
+) -> Result<Vec<ReceivedItem>> {
+    let store = manager.store();
+    let my_uuid = manager.whoami().await?.aci;
+
+    // Open read sync database for read state
+    let read_db = read_sync::open_read_sync_db()?;
+    let arrivals_db = arrivals::open_arrivals_db()?;
+    let deletes_db = deletes::open_deletes_db()?;
+
+    let thread = parse_thread(chat_id)?;
+
+    // Sort everything before truncating: the store's order is by sent time only
+    let contents: Vec<_> = store.messages(&thread, ..).await?.flatten().collect();
+    let mut keyed: Vec<(SortKey, ReceivedItem)> = Vec::new();
+    let mut skewed = Vec::new();
+
+    // Read state for the whole thread at once
+    let messages: Vec<(String, u64)> = contents
+        .iter()
+        .filter_map(|content| match &content.body {
+            ContentBody::DataMessage(dm) => Some((
+                content.metadata.sender.raw_uuid().to_string(),
+                dm.timestamp.unwrap_or(0),
+            )),
+            _ => None,
+        })
+        .collect();
+    let read_statuses = read_sync::read_statuses(&read_db, chat_id, &messages)?;
+
+    for content in contents {
+        if let ContentBody::DataMessage(dm) = &content.body {
+            let ts = dm.timestamp.unwrap_or(0);
+            let sender_uuid = content.metadata.sender.raw_uuid();
+            let sender_aci = sender_uuid.to_string();
+            let device = u32::from(content.metadata.sender_device);
+
+            let received_at = arrivals::received_at(&arrivals_db, &sender_aci, ts)?;
+            if as_of.is_some_and(|as_of| received_at.unwrap_or(ts) > as_of) {
+                continue;
+            }
+            let clock_skew = received_at.is_some_and(|at| arrivals::is_clock_skewed(ts, at));
+            if clock_skew {
+                skewed.push(message_id(ts, &sender_aci));
+            }
+            let primary = match order_by {
+                MessageOrder::Sent => ts,
+                MessageOrder::Received => received_at.unwrap_or(ts),
+            };
+            let key = (primary, ts, sender_aci.clone(), device);
+
+            // Group updates show up as events when received, not kept here
+            if roster::is_group_update(dm) {
+                continue;
+            }
+            let is_outgoing = sender_uuid == my_uuid;
+            if let Some(event) = EventOutput::from_data_message(chat_id, &sender_aci, dm) {
+                let event = if is_outgoing {
+                    event.sent_by_me()
+                } else {
+                    event
+                };
+                keyed.push((key, ReceivedItem::Event(event)));
+                continue;
+            }
+
+            let is_read = read_statuses[&(sender_aci.clone(), ts)];
+            let deleted_at = deletes::deleted_at(&deletes_db, chat_id, &sender_aci, ts)?;
+            let (text, mentions, styles) = mentions::render(manager, dm).await;
+            let sticker = match &dm.sticker {
+                Some(sticker) => Some(stickers::render(manager, sticker, false).await),
+                None => None,
+            };
+
+            keyed.push((
+                key,
+                ReceivedItem::Message(Box::new(MessageOutput {
+                    id: message_id(ts, &sender_aci),
+                    chat_id: chat_id.to_string(),
+                    sender: sender_aci,
+                    sender_name: None,
+                    timestamp: Timestamp::from_millis(ts),
+                    received_at: received_at.map(Timestamp::from_millis),
+                    clock_skew,
+                    sender_device: device,
+                    sealed_sender: content.metadata.unidentified_sender,
+                    server_guid: content.metadata.server_guid.map(|guid| guid.to_string()),
+                    text,
+                    mentions,
+                    styles,
+                    sticker,
+                    view_once: view_once::is_view_once(dm),
+                    media: Vec::new(),
+                    contacts: dm.contact.iter().map(shared_contacts::render).collect(),
+                    story_id: stories::story_id(dm),
+                    reply_to: reply_to(dm),
+                    deleted: deleted_at.is_some(),
+                    deleted_at: deleted_at.map(Timestamp::from_millis),
+                    is_outgoing,
+                    is_read,
+                    expires_in: dm.expire_timer.filter(|&secs| secs > 0),
+                })),
+            ));
+        }
+    }
+
+    keyed.sort_by(|a, b| b.0.cmp(&a.0));
+
+    if verify_ordering {
+        let ties: Vec<String> = keyed
+            .windows(2)
+            .filter(|w| w[0].0 == w[1].0)
+            .map(|w| format!("{} from {}", w[0].0 .1, w[0].0 .2))
+            .collect();
+        let mut problems = Vec::new();
+        if !ties.is_empty() {
+            problems.push(format!("indistinguishable messages: {}", ties.join(", ")));
+        }
+        if order_by == MessageOrder::Sent && !skewed.is_empty() {
+            problems.push(format!(
+                "clock-skewed messages (try --order-by received): {}",
+                skewed.join(", ")
+            ));
+        }
+        if !problems.is_empty() {
+            return Err(Error::Invalid(format!(
+                "Ordering is not stable: {}",
+                problems.join("; ")
+            )));
+        }
+    }
+
+    let before = page
+        .before_id
+        .as_deref()
+        .map(|id| cursor(&keyed, id, true))
+        .transpose()?;
+    let after = page
+        .after_id
+        .as_deref()
+        .map(|id| cursor(&keyed, id, false))
+        .transpose()?;
+    keyed.retain(|(key, _)| {
+        page.since.is_none_or(|since| key.0 >= since)
+            && page.until.is_none_or(|until| key.0 <= until)
+            && before.as_ref().is_none_or(|before| key < before)
+            && after.as_ref().is_none_or(|after| key > after)
+    });
+    if page.unread_only {
+        keyed.retain(|(_, item)| match item {
+            ReceivedItem::Message(message) => !message.is_read && !message.is_outgoing,
+            ReceivedItem::Event(_) | ReceivedItem::Logged(_) => false,
+        });
+    }
+    if page.ascending {
+        keyed.reverse();
+    }
+
+    let messages: Vec<ReceivedItem> = keyed
+        .into_iter()
+        .take(max_results)
+        .map(|(_, item)| item)
+        .collect();
+
+    Ok(messages)
+}
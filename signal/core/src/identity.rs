@@ -0,0 +1,186 @@
+//! Identity key change tracking.
+//!
+//! presage's `OnNewIdentity::Trust` silently accepts a contact's new identity
+//! key, and `Reject` drops their messages before we ever see them. Neither
+//! lets us tell the user that a safety number changed. So presage stays on
+//! `Trust`, and this module records the last key we saw for each contact,
+//! flags rotations, and lets the configured policy decide what happens next.
+
+use presage::libsignal_service::prelude::Uuid;
+use presage::libsignal_service::protocol::{DeviceId, IdentityKeyStore, ProtocolAddress};
+use presage::store::Store;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::model::EventOutput;
+use crate::{local, Error, Result, SignalManager};
+
+/// What to do when a contact's identity key changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityPolicy {
+    /// Accept the new key silently
+    #[default]
+    Trust,
+    /// Accept the new key, but report an identity_changed event
+    Warn,
+    /// Report the change and refuse to exchange messages until re-trusted
+    Block,
+}
+
+/// Outcome of comparing a contact's current key with the recorded one.
+pub enum Observation {
+    /// First time we've seen a key for this contact (trusted on first use)
+    New,
+    /// Same key as before
+    Unchanged,
+    /// The key rotated since we last saw it
+    Changed { old_key: String },
+}
+
+#[derive(Serialize)]
+pub struct IdentityRecord {
+    pub uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub fingerprint: String,
+    pub trusted: bool,
+    pub first_seen: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changed_at: Option<i64>,
+}
+
+pub fn open_identity_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// Record the key currently in the protocol store for `uuid`.
+///
+/// A rotated key stays trusted only under [`IdentityPolicy::Trust`]; otherwise
+/// it must be approved with [`trust`].
+pub fn observe(
+    conn: &Connection,
+    uuid: &str,
+    identity_key: &str,
+    policy: IdentityPolicy,
+) -> Result<Observation> {
+    let existing: Option<(String, bool)> = conn
+        .query_row(
+            "SELECT identity_key, trusted FROM identities WHERE uuid = ?1",
+            [uuid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+
+    match existing {
+        None => {
+            conn.execute(
+                "INSERT INTO identities (uuid, identity_key, trusted, first_seen) VALUES (?1, ?2, 1, ?3)",
+                rusqlite::params![uuid, identity_key, now()],
+            )?;
+            Ok(Observation::New)
+        }
+        Some((old_key, _)) if old_key == identity_key => Ok(Observation::Unchanged),
+        Some((old_key, _)) => {
+            conn.execute(
+                "UPDATE identities SET identity_key = ?2, trusted = ?3, changed_at = ?4 WHERE uuid = ?1",
+                rusqlite::params![uuid, identity_key, policy == IdentityPolicy::Trust, now()],
+            )?;
+            Ok(Observation::Changed { old_key })
+        }
+    }
+}
+
+/// Whether the recorded key for `uuid` is trusted. Unknown contacts are.
+pub fn is_trusted(conn: &Connection, uuid: &str) -> Result<bool> {
+    Ok(conn
+        .query_row(
+            "SELECT trusted FROM identities WHERE uuid = ?1",
+            [uuid],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(true))
+}
+
+/// Approve the currently recorded key. Returns false if we have no record.
+pub fn trust(conn: &Connection, uuid: &str) -> Result<bool> {
+    let updated = conn.execute("UPDATE identities SET trusted = 1 WHERE uuid = ?1", [uuid])?;
+    Ok(updated > 0)
+}
+
+pub fn list(conn: &Connection) -> Result<Vec<IdentityRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT uuid, identity_key, trusted, first_seen, changed_at
+         FROM identities ORDER BY trusted, changed_at DESC",
+    )?;
+    let records = stmt
+        .query_map([], |row| {
+            Ok(IdentityRecord {
+                uuid: row.get(0)?,
+                name: None,
+                fingerprint: row.get(1)?,
+                trusted: row.get(2)?,
+                first_seen: row.get(3)?,
+                changed_at: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(records)
+}
+
+/// Hex fingerprint of a contact's identity key, from the protocol store
+pub async fn current_identity_key(
+    manager: &SignalManager,
+    uuid: Uuid,
+) -> Result<Option<String>> {
+    let address = ProtocolAddress::new(uuid.to_string(), DeviceId::from(1));
+    let key = manager
+        .store()
+        .aci_protocol_store()
+        .get_identity(&address)
+        .await
+        .map_err(Error::storage)?;
+    Ok(key.map(|k| hex::encode(k.serialize())))
+}
+
+/// Compare a contact's identity key with the recorded one under `policy`.
+///
+/// Returns whether messages may be exchanged with them, plus an
+/// `identity_changed` event if the key rotated and the policy wants it reported.
+pub async fn check_identity(
+    manager: &SignalManager,
+    conn: &Connection,
+    uuid: Uuid,
+    policy: IdentityPolicy,
+) -> Result<(bool, Option<EventOutput>)> {
+    let Some(key) = current_identity_key(manager, uuid).await? else {
+        return Ok((true, None));
+    };
+    let uuid_str = uuid.to_string();
+    let observation = observe(conn, &uuid_str, &key, policy)?;
+    let allowed = policy != IdentityPolicy::Block || is_trusted(conn, &uuid_str)?;
+
+    let event = match observation {
+        Observation::Changed { old_key } if policy != IdentityPolicy::Trust => {
+            Some(EventOutput::IdentityChanged {
+                chat_id: uuid_str.clone(),
+                uuid: uuid_str,
+                old_fingerprint: old_key,
+                new_fingerprint: key,
+                policy,
+                blocked: !allowed,
+            })
+        }
+        _ => None,
+    };
+    Ok((allowed, event))
+}
@@ -0,0 +1,272 @@
+//! Signal client logic behind `signal-cli`: linking, storage, sending and
+//! receiving, and the local databases layered on top of presage.
+//!
+//! Results are plain data (see [`model`]); printing them is left to callers.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use directories::ProjectDirs;
+use presage::libsignal_service::prelude::Uuid;
+use presage::manager::Registered;
+use presage::model::identity::OnNewIdentity;
+use presage::store::{ContentsStore, Thread};
+use presage::Manager;
+use presage_store_sqlite::SqliteStore;
+use tracing::{debug, instrument};
+
+pub use error::{Error, Result};
+
+pub mod arrivals;
+pub mod avatars;
+pub mod calls;
+pub mod chats;
+pub mod conversations;
+pub mod deletes;
+pub mod drafts;
+pub mod error;
+pub mod events;
+pub mod expiry;
+pub mod history;
+pub mod identity;
+pub mod link;
+pub mod lists;
+pub mod local;
+pub mod lock;
+pub mod mentions;
+pub mod model;
+pub mod outbound;
+pub mod outbox;
+pub mod read_sync;
+pub mod receive;
+pub mod retention;
+pub mod roster;
+pub mod search;
+pub mod send;
+pub mod shared_contacts;
+pub mod status;
+pub mod stickers;
+pub mod stories;
+pub mod styles;
+pub mod timestamp;
+pub mod usage;
+pub mod view_once;
+pub mod voice_notes;
+
+/// A linked or registered account, ready to use
+pub type SignalManager = Manager<SqliteStore, Registered>;
+
+/// Account selected with `--account`, set once at startup
+static ACCOUNT: OnceLock<String> = OnceLock::new();
+
+pub fn set_account(name: String) -> Result<()> {
+    if RESERVED_DIRS.contains(&name.as_str()) {
+        return Err(Error::Invalid(format!(
+            "Invalid account name '{}': the default account keeps a dir by that name",
+            name
+        )));
+    }
+    if !is_account_name(&name) {
+        return Err(Error::Invalid(format!(
+            "Invalid account name '{}': use letters, digits, '-' and '_'",
+            name
+        )));
+    }
+    ACCOUNT
+        .set(name)
+        .map_err(|_| Error::Invalid("Account already set".to_string()))
+}
+
+/// Data dir for the current account: `signal/` for the default account (the
+/// layout predating accounts), `signal/<name>/` for named ones.
+pub fn get_data_dir() -> Result<PathBuf> {
+    let dirs = ProjectDirs::from("", "", "jean-claude")
+        .ok_or_else(|| Error::storage("Failed to determine data directory"))?;
+    let mut data_dir = dirs.data_dir().join("signal");
+    if let Some(account) = ACCOUNT.get() {
+        data_dir = data_dir.join(account);
+    }
+    std::fs::create_dir_all(&data_dir)?;
+    Ok(data_dir)
+}
+
+/// Dirs the default account keeps in its data dir, so no account is named
+/// after one
+const RESERVED_DIRS: &[&str] = &[
+    "avatars",
+    "backup-staging",
+    "backups",
+    "replica",
+    "run",
+    "stickers",
+    "stories",
+    "view-once",
+];
+
+fn is_account_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether `dir`, inside the default account's data dir, could be a named
+/// account's: any dir with an account's name, whether or not it has a store
+/// yet (it may only have its config, or be mid-link).
+pub fn is_account_dir(dir: &std::path::Path) -> bool {
+    let name = dir.file_name().and_then(|name| name.to_str());
+    dir.is_dir() && name.is_some_and(|name| !RESERVED_DIRS.contains(&name) && is_account_name(name))
+}
+
+pub fn get_db_path() -> Result<String> {
+    let path = get_data_dir()?.join("signal.db");
+    Ok(path.display().to_string())
+}
+
+/// Passphrase the store and local databases are encrypted with, set once at
+/// startup. Without one they're plaintext.
+static PASSPHRASE: OnceLock<String> = OnceLock::new();
+
+pub fn set_passphrase(passphrase: String) {
+    let _ = PASSPHRASE.set(passphrase);
+}
+
+pub fn passphrase() -> Option<&'static str> {
+    PASSPHRASE.get().map(String::as_str)
+}
+
+/// Whether a database file is encrypted. A plaintext one starts with SQLite's
+/// header; an empty one hasn't been written yet.
+pub fn is_encrypted(path: &Path) -> Result<bool> {
+    let mut header = [0u8; 16];
+    let read = std::fs::File::open(path)?.read_exact(&mut header);
+    Ok(read.is_ok() && &header != b"SQLite format 3\0")
+}
+
+/// Key a connection with the passphrase, if there is one, and check that it
+/// opens the database. Must come before anything else on the connection.
+pub fn unlock(conn: &rusqlite::Connection) -> Result<()> {
+    if let Some(passphrase) = passphrase() {
+        conn.pragma_update(None, "key", passphrase)?;
+    }
+    match conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(())) {
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == rusqlite::ErrorCode::NotADatabase =>
+        {
+            Err(Error::Encrypted)
+        }
+        result => Ok(result?),
+    }
+}
+
+/// How long a local database write waits for another process's to finish
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Open a local database in the data dir, unlocked with the passphrase if
+/// there is one. Write-ahead logging lets readers carry on during a write,
+/// and a write waits up to [`BUSY_TIMEOUT`] for another instead of failing
+/// with "database is locked".
+pub fn open_db(name: &str) -> Result<rusqlite::Connection> {
+    let conn = rusqlite::Connection::open(get_data_dir()?.join(name))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    unlock(&conn)?;
+    // Answers with the mode now in effect
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+    Ok(conn)
+}
+
+#[instrument(skip_all)]
+pub async fn open_store() -> Result<SqliteStore> {
+    let db_path = get_db_path()?;
+    debug!("Opening store at {}", db_path);
+
+    // sqlx would only say the file isn't a database
+    if Path::new(&db_path).exists() {
+        unlock(&rusqlite::Connection::open(&db_path)?)?;
+    }
+    SqliteStore::open_with_passphrase(&db_path, passphrase(), OnNewIdentity::Trust)
+        .await
+        .map_err(Error::storage)
+}
+
+#[instrument(skip_all)]
+pub async fn load_registered_manager() -> Result<SignalManager> {
+    let store = open_store().await?;
+    Manager::load_registered(store).await.map_err(|e| {
+        // Partial state from an unfinished link makes loading fail; say so
+        if matches!(link::pending(), Ok(Some(_))) {
+            Error::LinkInterrupted
+        } else {
+            e.into()
+        }
+    })
+}
+
+/// Parse a chat ID: UUID for contacts, 64-character hex master key for groups
+pub fn parse_thread(chat_id: &str) -> Result<Thread> {
+    if let Ok(uuid) = chat_id.parse::<Uuid>() {
+        Ok(Thread::Contact(uuid))
+    } else if let Ok(master_key) = hex::decode(chat_id) {
+        let key: [u8; 32] = master_key
+            .try_into()
+            .map_err(|_| {
+                Error::InvalidRecipient("Group master key must be 32 bytes".to_string())
+            })?;
+        Ok(Thread::Group(key))
+    } else {
+        Err(Error::InvalidRecipient(
+            "Invalid chat_id: must be a UUID or 64-character hex string".to_string(),
+        ))
+    }
+}
+
+/// Chat ID for a thread, the inverse of [`parse_thread`]
+pub fn thread_chat_id(thread: &Thread) -> String {
+    match thread {
+        Thread::Contact(uuid) => uuid.to_string(),
+        Thread::Group(master_key) => hex::encode(master_key),
+    }
+}
+
+/// Resolve recipient to UUID - accepts UUID directly or contact name
+pub async fn resolve_recipient(manager: &SignalManager, recipient: &str) -> Result<Uuid> {
+    // Try parsing as UUID first
+    if let Ok(uuid) = recipient.parse::<Uuid>() {
+        return Ok(uuid);
+    }
+
+    // Search contacts by name (case-insensitive substring match)
+    let search_lower = recipient.to_lowercase();
+    let mut matches: Vec<_> = manager
+        .store()
+        .contacts()
+        .await?
+        .filter_map(|c| c.ok())
+        .filter(|c| c.name.to_lowercase().contains(&search_lower))
+        .collect();
+
+    match matches.len() {
+        0 => Err(Error::InvalidRecipient(format!(
+            "No contact found matching '{}'. Use a UUID or exact contact name.",
+            recipient
+        ))),
+        1 => Ok(matches.remove(0).uuid),
+        _ => {
+            let mut msg = format!(
+                "Multiple contacts match '{}'. Use a UUID or more specific name:\n",
+                recipient
+            );
+            for contact in &matches {
+                let phone = contact
+                    .phone_number
+                    .as_ref()
+                    .map(|p| p.format().to_string())
+                    .unwrap_or_default();
+                msg.push_str(&format!("  - {} ({}) {}\n", contact.name, contact.uuid, phone));
+            }
+            Err(Error::InvalidRecipient(msg))
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Detecting and recovering from an interrupted `link`.
+//!
+//! Provisioning keys only live in the `link` process. If it's killed partway,
+//! or the phone never confirms, the store can be left holding some of the new
+//! device's state but no usable registration, and every other command then
+//! fails without saying why. So `link` writes a marker before provisioning and
+//! removes it once the device is linked; a marker left behind means the last
+//! attempt didn't finish.
+//!
+//! The server can also stop accepting a device that linked fine, such as one
+//! removed on the phone or unused for about a month. Receiving, sending and
+//! `status` record the first rejection (see [`note_unlinked`]), so `status`
+//! can report it without a request of its own, until a `relink` clears it.
+
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use presage::store::StateStore;
+use presage::Manager;
+use presage_store_sqlite::SqliteStore;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{get_data_dir, Error, Result, SignalManager};
+
+/// The marker for a `link` that hasn't finished.
+#[derive(Serialize, Deserialize)]
+pub struct PendingLink {
+    pub device_name: String,
+    /// When linking started (unix seconds)
+    pub started_at: i64,
+}
+
+/// What [`recover`] found.
+pub enum Recovery {
+    /// No interrupted link
+    None,
+    /// The link finished but was interrupted before clearing its marker
+    Completed(SignalManager),
+    /// The partial link was cleared from the store, so linking can start over
+    Cleared(PendingLink),
+}
+
+/// The record of the server rejecting this device.
+#[derive(Serialize, Deserialize)]
+pub struct Unlinked {
+    /// When a rejected request first showed it (unix milliseconds)
+    pub detected_at: u64,
+}
+
+fn marker_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("link-pending.json"))
+}
+
+fn unlinked_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("unlinked.json"))
+}
+
+/// The interrupted link, if there is one.
+pub fn pending() -> Result<Option<PendingLink>> {
+    let path = marker_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = std::fs::read_to_string(&path)?;
+    Ok(Some(serde_json::from_str(&data)?))
+}
+
+/// Record that linking is starting.
+pub fn begin(device_name: &str) -> Result<()> {
+    let marker = PendingLink {
+        device_name: device_name.to_string(),
+        started_at: std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64,
+    };
+    std::fs::write(marker_path()?, serde_json::to_vec(&marker)?)?;
+    Ok(())
+}
+
+fn remove_marker(path: PathBuf) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::storage(e)),
+        _ => Ok(()),
+    }
+}
+
+/// Record that linking stopped with the store as it was before.
+pub fn cancel() -> Result<()> {
+    remove_marker(marker_path()?)
+}
+
+/// Record that linking finished.
+pub fn finish() -> Result<()> {
+    remove_marker(marker_path()?)?;
+    clear_unlinked()
+}
+
+/// Record that the server rejected this device's credentials; the first
+/// rejection counts.
+pub fn record_unlinked() -> Result<()> {
+    let path = unlinked_path()?;
+    if path.exists() {
+        return Ok(());
+    }
+    let record = Unlinked {
+        detected_at: std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+    };
+    std::fs::write(path, serde_json::to_vec(&record)?)?;
+    Ok(())
+}
+
+/// Pass `result` through, recording it if the server rejected this device,
+/// so `status` reports that from then on.
+pub fn note_unlinked<T>(result: Result<T>) -> Result<T> {
+    if let Err(Error::Unlinked) = &result {
+        if let Err(e) = record_unlinked() {
+            warn!("Failed to record that this device was unlinked: {}", e);
+        }
+    }
+    result
+}
+
+/// Whether the server has rejected this device since it last linked.
+pub fn unlinked() -> Result<Option<Unlinked>> {
+    let path = unlinked_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = std::fs::read_to_string(&path)?;
+    Ok(Some(serde_json::from_str(&data)?))
+}
+
+/// Forget a rejection, once the server accepts the device again.
+pub fn clear_unlinked() -> Result<()> {
+    remove_marker(unlinked_path()?)
+}
+
+/// Resolve an interrupted link: keep it if the registration was saved (the
+/// server had already accepted the device), otherwise clear what was left.
+pub async fn recover(store: &mut SqliteStore) -> Result<Recovery> {
+    let Some(pending) = pending()? else {
+        return Ok(Recovery::None);
+    };
+
+    if let Ok(manager) = Manager::load_registered(store.clone()).await {
+        finish()?;
+        return Ok(Recovery::Completed(manager));
+    }
+
+    debug!("Clearing partial link started at {}", pending.started_at);
+    store.clear_registration().await?;
+    finish()?;
+    Ok(Recovery::Cleared(pending))
+}
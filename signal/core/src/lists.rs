@@ -0,0 +1,105 @@
+//! Local distribution lists for broadcast sends.
+//!
+//! The app's custom story lists live in Signal's storage service, which
+//! presage doesn't sync, so lists here are local: a name and a set of contact
+//! UUIDs. `send --list <name>` delivers the message to each member as an
+//! individual 1:1 message.
+
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{local, Error, Result};
+
+pub fn open_lists_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+fn exists(conn: &Connection, name: &str) -> Result<bool> {
+    Ok(conn
+        .query_row("SELECT 1 FROM lists WHERE name = ?1", [name], |_| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+fn ensure_exists(conn: &Connection, name: &str) -> Result<()> {
+    if !exists(conn, name)? {
+        return Err(Error::Invalid(format!(
+            "No list named '{}'. Create it with 'lists create'.",
+            name
+        )));
+    }
+    Ok(())
+}
+
+pub fn create(conn: &Connection, name: &str) -> Result<()> {
+    if exists(conn, name)? {
+        return Err(Error::Invalid(format!("List '{}' already exists", name)));
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    conn.execute(
+        "INSERT INTO lists (name, created_at) VALUES (?1, ?2)",
+        rusqlite::params![name, now],
+    )?;
+    Ok(())
+}
+
+/// Delete a list and its memberships. Returns whether it existed.
+pub fn delete(conn: &Connection, name: &str) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM lists WHERE name = ?1", [name])? > 0)
+}
+
+/// Add members, ignoring ones already present. Returns how many were new.
+pub fn add(conn: &mut Connection, name: &str, uuids: &[String]) -> Result<usize> {
+    ensure_exists(conn, name)?;
+    let tx = conn.transaction()?;
+    let mut added = 0;
+    for uuid in uuids {
+        added += tx.execute(
+            "INSERT OR IGNORE INTO list_members (list_name, uuid) VALUES (?1, ?2)",
+            rusqlite::params![name, uuid],
+        )?;
+    }
+    tx.commit()?;
+    Ok(added)
+}
+
+/// Remove members. Returns how many were actually on the list.
+pub fn remove(conn: &mut Connection, name: &str, uuids: &[String]) -> Result<usize> {
+    ensure_exists(conn, name)?;
+    let tx = conn.transaction()?;
+    let mut removed = 0;
+    for uuid in uuids {
+        removed += tx.execute(
+            "DELETE FROM list_members WHERE list_name = ?1 AND uuid = ?2",
+            rusqlite::params![name, uuid],
+        )?;
+    }
+    tx.commit()?;
+    Ok(removed)
+}
+
+/// Member UUIDs of a list, sorted.
+pub fn members(conn: &Connection, name: &str) -> Result<Vec<String>> {
+    ensure_exists(conn, name)?;
+    let mut stmt =
+        conn.prepare("SELECT uuid FROM list_members WHERE list_name = ?1 ORDER BY uuid")?;
+    let members = stmt
+        .query_map([name], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(members)
+}
+
+/// All lists with their member counts, by name.
+pub fn all(conn: &Connection) -> Result<Vec<(String, usize)>> {
+    let mut stmt = conn.prepare(
+        "SELECT l.name, COUNT(m.uuid) FROM lists l
+         LEFT JOIN list_members m ON m.list_name = l.name
+         GROUP BY l.name ORDER BY l.name",
+    )?;
+    let lists = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(lists)
+}
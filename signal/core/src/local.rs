@@ -0,0 +1,407 @@
+//! The local metadata database, `local.db`.
+//!
+//! presage-store-sqlite doesn't expose its connection for custom tables, so
+//! state the store has no room for (read syncs, usage, the outbox, the event
+//! log, drafts and the rest) is kept here instead. The schema changes
+//! through [`MIGRATIONS`], applied in order when the database is opened and
+//! each recorded in `schema_version`; new tables and columns belong in
+//! another migration, never in the module that uses them.
+//!
+//! `local.db` started out as `read_sync.db`; an existing one is adopted by
+//! renaming it, since its tables are the first migration's. The other
+//! features each had a file of their own ([`ADOPTED`]), which is copied in
+//! and removed the first time `local.db` is opened.
+
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{Connection, TransactionBehavior};
+
+use crate::{get_data_dir, open_db, passphrase, Result};
+
+/// Each schema version's changes, version 1 first. Never edit one that has
+/// shipped; add another.
+const MIGRATIONS: &[&str] = &[
+    // 1: read syncs and read watermarks
+    "CREATE TABLE IF NOT EXISTS read_sync (
+        sender_aci TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        read_at INTEGER NOT NULL,
+        PRIMARY KEY (sender_aci, timestamp)
+    );
+    CREATE TABLE IF NOT EXISTS read_watermarks (
+        chat_id TEXT PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );",
+    // 2: reads by sent time alone, and by when they were recorded
+    "CREATE INDEX IF NOT EXISTS read_sync_timestamp ON read_sync (timestamp);
+    CREATE INDEX IF NOT EXISTS read_sync_read_at ON read_sync (read_at);",
+    // 3: every feature's tables, which had a file each until now, and the
+    // files copied in so far
+    "CREATE TABLE IF NOT EXISTS arrivals (
+        sender TEXT NOT NULL,
+        sent_ts INTEGER NOT NULL,
+        received_at INTEGER NOT NULL,
+        PRIMARY KEY (sender, sent_ts)
+    );
+    CREATE TABLE IF NOT EXISTS chat_flags (
+        chat_id TEXT PRIMARY KEY,
+        muted INTEGER NOT NULL DEFAULT 0,
+        archived INTEGER NOT NULL DEFAULT 0,
+        pinned INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS deleted (
+        chat_id TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        deleted_at INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, sender, timestamp)
+    );
+    CREATE TABLE IF NOT EXISTS drafts (
+        chat_id TEXT PRIMARY KEY,
+        text TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS events (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        kind TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        chat_id TEXT,
+        payload TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS consumers (
+        name TEXT PRIMARY KEY,
+        acked_seq INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS timers (
+        chat_id TEXT PRIMARY KEY,
+        seconds INTEGER NOT NULL,
+        version INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS expiring (
+        chat_id TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        seconds INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, sender, timestamp)
+    );
+    CREATE TABLE IF NOT EXISTS identities (
+        uuid TEXT PRIMARY KEY,
+        identity_key TEXT NOT NULL,
+        trusted INTEGER NOT NULL,
+        first_seen INTEGER NOT NULL,
+        changed_at INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS lists (
+        name TEXT PRIMARY KEY,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS list_members (
+        list_name TEXT NOT NULL REFERENCES lists (name) ON DELETE CASCADE,
+        uuid TEXT NOT NULL,
+        PRIMARY KEY (list_name, uuid)
+    );
+    CREATE TABLE IF NOT EXISTS outbox (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        recipient TEXT NOT NULL,
+        text TEXT,
+        status TEXT NOT NULL DEFAULT 'pending',
+        queued_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT,
+        styles TEXT,
+        view_once INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS attachments (
+        item_id INTEGER NOT NULL REFERENCES outbox (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        content_type TEXT NOT NULL,
+        file_name TEXT,
+        data BLOB NOT NULL,
+        voice_note INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (item_id, position)
+    );
+    CREATE TABLE IF NOT EXISTS contacts (
+        uuid TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        phone TEXT
+    );
+    CREATE TABLE IF NOT EXISTS groups (
+        id TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        is_member INTEGER NOT NULL,
+        members TEXT,
+        description TEXT,
+        timer INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        chat_id TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        UNIQUE (chat_id, sender, timestamp)
+    );
+    CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts
+        USING fts5(text, content = 'messages', content_rowid = 'id');
+    CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+        INSERT INTO messages_fts (rowid, text) VALUES (new.id, new.text);
+    END;
+    CREATE TABLE IF NOT EXISTS usage (
+        chat_id TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS auto_replies (
+        chat_id TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, timestamp)
+    );
+    CREATE TABLE IF NOT EXISTS opened (
+        sender_aci TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        opened_at INTEGER NOT NULL,
+        PRIMARY KEY (sender_aci, timestamp)
+    );
+    CREATE TABLE IF NOT EXISTS api_tokens (
+        name TEXT PRIMARY KEY,
+        token_hash TEXT NOT NULL UNIQUE,
+        chats TEXT,
+        operations TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS adopted (
+        name TEXT PRIMARY KEY,
+        adopted_at INTEGER NOT NULL
+    );",
+];
+
+/// The files that came before `local.db`, each with the tables it had, in an
+/// order that copies a foreign key's parent before its children.
+pub const ADOPTED: &[(&str, &[&str])] = &[
+    ("api_tokens.db", &["api_tokens"]),
+    ("arrivals.db", &["arrivals"]),
+    ("conversations.db", &["chat_flags"]),
+    ("deletes.db", &["deleted"]),
+    ("drafts.db", &["drafts"]),
+    ("events.db", &["events", "consumers"]),
+    ("expiry.db", &["timers", "expiring"]),
+    ("identities.db", &["identities"]),
+    ("lists.db", &["lists", "list_members"]),
+    ("outbox.db", &["outbox", "attachments"]),
+    ("roster.db", &["contacts", "groups", "meta"]),
+    ("search.db", &["messages"]),
+    ("usage.db", &["usage", "auto_replies"]),
+    ("view_once.db", &["opened"]),
+];
+
+/// Move `read_sync.db` and its WAL to `local.db`, if there's no `local.db`
+/// yet.
+fn adopt_read_sync_db(data_dir: &Path) -> Result<()> {
+    let old = data_dir.join("read_sync.db");
+    if !old.exists() || data_dir.join("local.db").exists() {
+        return Ok(());
+    }
+    for suffix in ["-wal", "-shm", ""] {
+        let from = data_dir.join(format!("read_sync.db{}", suffix));
+        if from.exists() {
+            std::fs::rename(&from, data_dir.join(format!("local.db{}", suffix)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the tables of the file attached as `old` into `local.db`, unless an
+/// earlier open already did (and removing the file failed, or another
+/// process hasn't got to it). Columns an older build's file lacks take their
+/// defaults; search's index is rebuilt by its insert trigger.
+fn copy_adopted(conn: &mut Connection, name: &str, tables: &[&str]) -> Result<()> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let copied: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM adopted WHERE name = ?1",
+        [name],
+        |row| row.get(0),
+    )?;
+    if !copied {
+        for table in tables {
+            let columns: Vec<String> = tx
+                .prepare(
+                    "SELECT name FROM pragma_table_info(?1, 'old')
+                     WHERE name IN (SELECT name FROM pragma_table_info(?1, 'main'))",
+                )?
+                .query_map([table], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            // A table added after the file was last opened
+            if columns.is_empty() {
+                continue;
+            }
+            let columns = columns.join(", ");
+            tx.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO main.{table} ({columns}) SELECT {columns} FROM old.{table}"
+                ),
+                [],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO adopted (name, adopted_at) VALUES (?1, ?2)",
+            rusqlite::params![name, now()],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Copy a file from before `local.db` into it, then remove the file and its
+/// WAL.
+fn adopt(conn: &mut Connection, data_dir: &Path, name: &str, tables: &[&str]) -> Result<()> {
+    let path = data_dir.join(name);
+    conn.execute(
+        "ATTACH DATABASE ?1 AS old KEY ?2",
+        rusqlite::params![path.to_string_lossy(), passphrase().unwrap_or_default()],
+    )?;
+    let copied = copy_adopted(conn, name, tables);
+    conn.execute("DETACH DATABASE old", [])?;
+    copied?;
+    for suffix in ["", "-wal", "-shm"] {
+        match std::fs::remove_file(data_dir.join(format!("{}{}", name, suffix))) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Apply the migrations `conn` hasn't had, each in its own transaction.
+/// [`open_local_db`] does this; it's public so tests can set up an
+/// in-memory database.
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            applied_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    let current: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )?;
+    for (version, sql) in (1..).zip(MIGRATIONS).skip(current as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, applied_at) VALUES (?1, ?2)",
+            rusqlite::params![version, now()],
+        )?;
+        // Mirrored where `version` and `db upgrade` look for every database's
+        tx.execute_batch(&format!("PRAGMA user_version = {}", version))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+pub fn open_local_db() -> Result<Connection> {
+    let data_dir = get_data_dir()?;
+    adopt_read_sync_db(&data_dir)?;
+    let mut conn = open_db("local.db")?;
+    // Lists' members and queued attachments go with what they belong to
+    conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    migrate(&mut conn)?;
+    for (name, tables) in ADOPTED {
+        if data_dir.join(name).exists() {
+            adopt(&mut conn, &data_dir, name, tables)?;
+        }
+    }
+    Ok(conn)
+}
+
+/// The schema version this build migrates `local.db` to
+pub fn schema_version() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(conn: &Connection) -> (i64, i64) {
+        let versions = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        let user_version = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        (versions, user_version)
+    }
+
+    #[test]
+    fn migrates_from_nothing_and_again_changes_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(applied(&conn), (schema_version(), schema_version()));
+        for (_, tables) in ADOPTED {
+            for table in *tables {
+                conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |_| Ok(()))
+                    .unwrap();
+            }
+        }
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(applied(&conn), (schema_version(), schema_version()));
+    }
+
+    #[test]
+    fn copies_an_older_file_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        // An outbox from before `styles` and `view_once`, and before queued
+        // attachments
+        conn.execute_batch(
+            "ATTACH DATABASE ':memory:' AS old;
+            CREATE TABLE old.outbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                recipient TEXT NOT NULL,
+                text TEXT,
+                status TEXT NOT NULL DEFAULT 'pending',
+                queued_at INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                last_error TEXT
+            );
+            INSERT INTO old.outbox (recipient, text, queued_at, next_attempt_at)
+                VALUES ('a', 'hi', 1, 1);",
+        )
+        .unwrap();
+
+        let outbox = |conn: &Connection| -> Vec<(i64, String, bool)> {
+            conn.prepare("SELECT id, text, view_once FROM main.outbox")
+                .unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap()
+        };
+        copy_adopted(&mut conn, "outbox.db", &["outbox", "attachments"]).unwrap();
+        assert_eq!(outbox(&conn), [(1, "hi".to_string(), false)]);
+
+        conn.execute("DELETE FROM main.outbox", []).unwrap();
+        copy_adopted(&mut conn, "outbox.db", &["outbox", "attachments"]).unwrap();
+        assert!(outbox(&conn).is_empty());
+    }
+}
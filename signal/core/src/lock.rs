@@ -0,0 +1,91 @@
+//! Locks in the data dir: the store lock, and the instance lock.
+//!
+//! Receiving and sending both advance the sessions in the message store, and
+//! two processes doing either at once (a cron `receive` racing a `send`) can
+//! interleave their writes. So each takes an advisory lock on `store.lock`
+//! for as long as it talks to the server, and a second process waits its turn
+//! instead of failing. The lock is per process: nested sections, such as the
+//! receipts a `receive` sends, reuse the one already held.
+//!
+//! Receiving, including the drain before every send, also takes the instance
+//! lock, `instance.lock`, and keeps it until the process exits. Two processes
+//! draining the same queue would split its messages between them, so a second
+//! one fails with [`Error::Busy`] rather than waiting, unless told to wait
+//! (see [`set_wait`]). A `serve` daemon holds it for as long as it runs.
+
+use std::fs::File;
+use std::sync::{Mutex, OnceLock};
+
+use tracing::debug;
+
+use crate::{get_data_dir, Error, Result};
+
+/// The locked file and how many sections in this process hold it
+static HELD: Mutex<(Option<File>, usize)> = Mutex::new((None, 0));
+
+/// The instance lock, once this process has it
+static INSTANCE: Mutex<Option<File>> = Mutex::new(None);
+
+static WAIT: OnceLock<bool> = OnceLock::new();
+
+/// Wait for another instance to finish receiving instead of failing. Only
+/// the first call takes effect.
+pub fn set_wait(wait: bool) {
+    let _ = WAIT.set(wait);
+}
+
+fn open_lock_file(name: &str) -> Result<File> {
+    Ok(File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(get_data_dir()?.join(name))?)
+}
+
+/// The store lock, released when the last guard in the process is dropped.
+pub struct StoreLock(());
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+        held.1 -= 1;
+        if held.1 == 0 {
+            // Closing the file releases the lock
+            held.0 = None;
+        }
+    }
+}
+
+/// Take the store lock, waiting while another process holds it.
+pub fn lock_store() -> Result<StoreLock> {
+    let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+    if held.1 == 0 {
+        let file = open_lock_file("store.lock")?;
+        if file.try_lock().is_err() {
+            debug!("Waiting for another process to release the store");
+            file.lock()?;
+        }
+        held.0 = Some(file);
+    }
+    held.1 += 1;
+    Ok(StoreLock(()))
+}
+
+/// Take the instance lock for the rest of the process, unless it already has
+/// it. Fails if another process holds it, or waits with [`set_wait`].
+pub fn lock_instance() -> Result<()> {
+    let mut instance = INSTANCE.lock().unwrap_or_else(|e| e.into_inner());
+    if instance.is_some() {
+        return Ok(());
+    }
+    let file = open_lock_file("instance.lock")?;
+    if file.try_lock().is_err() {
+        if !WAIT.get().copied().unwrap_or(false) {
+            return Err(Error::Busy);
+        }
+        debug!("Waiting for another instance to finish receiving");
+        file.lock()?;
+    }
+    *instance = Some(file);
+    Ok(())
+}
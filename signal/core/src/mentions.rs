@@ -0,0 +1,345 @@
+//! @-mentions in group messages.
+//!
+//! A mention is a body range over a placeholder character (U+FFFC) carrying
+//! the mentioned member's ACI; each client shows it as `@` and its own name
+//! for them, and pings them. Range offsets count UTF-16 code units, as in the
+//! other clients. Offsets given here (`--mention <uuid>@<start>:<len>`) count
+//! characters of the message as sent, which is friendlier to type.
+
+use std::str::FromStr;
+
+use presage::libsignal_service::prelude::Uuid;
+use presage::proto::body_range::AssociatedValue;
+use presage::proto::{BodyRange, DataMessage};
+use presage::store::ContentsStore;
+use serde::{Deserialize, Serialize};
+
+use crate::model::MentionOutput;
+use crate::styles::{self, StyleRange};
+use crate::{Error, Result, SignalManager};
+
+/// Stands in for a mention in the message body
+pub const PLACEHOLDER: char = '\u{fffc}';
+
+/// A member to mention and the span of text (in characters) the mention
+/// replaces
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mention {
+    pub uuid: Uuid,
+    pub start: usize,
+    pub length: usize,
+}
+
+impl FromStr for Mention {
+    type Err = Error;
+
+    /// Parse `<uuid>@<start>:<len>`
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid =
+            || Error::Invalid(format!("Invalid mention '{}': expected UUID@START:LEN", s));
+        let (uuid, span) = s.split_once('@').ok_or_else(invalid)?;
+        let (start, length) = span.split_once(':').ok_or_else(invalid)?;
+        Ok(Mention {
+            uuid: uuid.parse().map_err(|_| invalid())?,
+            start: start.parse().map_err(|_| invalid())?,
+            length: length.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Mentions for the `@Name` tokens in `text` that name one of `members`, by
+/// full or first name, ignoring case. The longest matching name wins; a name
+/// shared by several members is refused rather than guessed.
+pub async fn find(manager: &SignalManager, members: &[Uuid], text: &str) -> Result<Vec<Mention>> {
+    let mut names: Vec<(Uuid, String)> = Vec::new();
+    for &uuid in members {
+        let Some(contact) = manager.store().contact_by_id(&uuid).await? else {
+            continue;
+        };
+        let name = contact.name.trim().to_lowercase();
+        if name.is_empty() {
+            continue;
+        }
+        if let Some((first, _)) = name.split_once(' ') {
+            names.push((uuid, first.to_string()));
+        }
+        names.push((uuid, name));
+    }
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut mentions = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let at_word_start = i == 0 || !chars[i - 1].is_alphanumeric();
+        if chars[i] != '@' || !at_word_start {
+            i += 1;
+            continue;
+        }
+        let rest = &chars[i + 1..];
+        let mut best: Vec<(Uuid, usize)> = Vec::new();
+        for (uuid, name) in &names {
+            let len = name.chars().count();
+            let matches = rest.len() >= len
+                && rest[..len].iter().collect::<String>().to_lowercase() == *name
+                && rest.get(len).is_none_or(|c| !c.is_alphanumeric());
+            if !matches {
+                continue;
+            }
+            match best.first() {
+                Some(&(_, best_len)) if best_len > len => {}
+                Some(&(_, best_len)) if best_len == len => {
+                    if !best.iter().any(|(other, _)| other == uuid) {
+                        best.push((*uuid, len));
+                    }
+                }
+                _ => best = vec![(*uuid, len)],
+            }
+        }
+        match best.as_slice() {
+            [] => i += 1,
+            [(uuid, len)] => {
+                mentions.push(Mention {
+                    uuid: *uuid,
+                    start: i,
+                    length: len + 1,
+                });
+                i += len + 1;
+            }
+            [(_, len), ..] => {
+                let token: String = chars[i..=i + len].iter().collect();
+                return Err(Error::Invalid(format!(
+                    "'{}' matches several members of the group; mention them with --mention",
+                    token
+                )));
+            }
+        }
+    }
+    Ok(mentions)
+}
+
+/// Replace each mention's span of `text` with the placeholder, returning the
+/// new body and its body ranges: the mentions', and `styles` moved to match.
+pub fn apply(
+    text: &str,
+    mentions: &[Mention],
+    styles: &[StyleRange],
+) -> Result<(String, Vec<BodyRange>)> {
+    let mut mentions = mentions.to_vec();
+    mentions.sort_by_key(|m| m.start);
+    let chars: Vec<char> = text.chars().collect();
+    let mut body = String::new();
+    let mut ranges = Vec::new();
+    // Where each char of `text` lands in the body, in chars
+    let mut offsets = Vec::with_capacity(chars.len() + 1);
+    let mut count = 0;
+    let mut next = 0;
+    for mention in &mentions {
+        let end = mention.start + mention.length;
+        if mention.length == 0 || mention.start < next || end > chars.len() {
+            return Err(Error::Invalid(format!(
+                "Mention of {} at {}:{} is empty, overlaps another or runs past the message",
+                mention.uuid, mention.start, mention.length
+            )));
+        }
+        for &c in &chars[next..mention.start] {
+            offsets.push(count);
+            body.push(c);
+            count += 1;
+        }
+        offsets.extend(std::iter::repeat_n(count, mention.length));
+        ranges.push(BodyRange {
+            start: Some(body.encode_utf16().count() as u32),
+            length: Some(1),
+            associated_value: Some(AssociatedValue::MentionAci(mention.uuid.to_string())),
+        });
+        body.push(PLACEHOLDER);
+        count += 1;
+        next = end;
+    }
+    for &c in &chars[next..] {
+        offsets.push(count);
+        body.push(c);
+        count += 1;
+    }
+    offsets.push(count);
+
+    let last = offsets.len() - 1;
+    let styles: Vec<StyleRange> = styles
+        .iter()
+        .map(|range| {
+            let start = offsets[range.start.min(last)];
+            let end = offsets[(range.start + range.length).min(last)];
+            StyleRange {
+                style: range.style,
+                start,
+                length: end - start,
+            }
+        })
+        .collect();
+    ranges.extend(styles::body_ranges(&body, &styles));
+    Ok((body, ranges))
+}
+
+/// A received message's text with each mention written as `@Name` (the
+/// mentioned UUID when they aren't a contact), who was mentioned, and its
+/// styles over that text.
+pub async fn render(
+    manager: &SignalManager,
+    dm: &DataMessage,
+) -> (String, Vec<MentionOutput>, Vec<StyleRange>) {
+    let body = dm.body.clone().unwrap_or_default();
+    if dm.body_ranges.is_empty() {
+        return (body, Vec::new(), Vec::new());
+    }
+    let mut ranges: Vec<(usize, usize, &str)> = dm
+        .body_ranges
+        .iter()
+        .filter_map(|range| match &range.associated_value {
+            Some(AssociatedValue::MentionAci(aci)) => {
+                Some((range.start? as usize, range.length? as usize, aci.as_str()))
+            }
+            _ => None,
+        })
+        .collect();
+    ranges.sort_by_key(|&(start, _, _)| start);
+
+    let mut mentions = Vec::new();
+    let mut text = String::new();
+    // Char of `text` at each UTF-16 offset of the body, for the styles
+    let mut offsets = Vec::new();
+    let mut count = 0;
+    let mut ranges = ranges.into_iter().peekable();
+    // Position in UTF-16 code units, and where the current mention ends
+    let mut pos = 0;
+    let mut skip_until = 0;
+    for c in body.chars() {
+        let before = count;
+        while let Some(&(start, length, aci)) = ranges.peek() {
+            if start > pos {
+                break;
+            }
+            ranges.next();
+            if start < skip_until {
+                continue;
+            }
+            let name = match aci.parse::<Uuid>() {
+                Ok(uuid) => manager
+                    .store()
+                    .contact_by_id(&uuid)
+                    .await
+                    .ok()
+                    .flatten()
+                    .map(|contact| contact.name)
+                    .filter(|name| !name.is_empty()),
+                Err(_) => None,
+            };
+            let shown = name.as_deref().unwrap_or(aci);
+            text.push('@');
+            text.push_str(shown);
+            count += 1 + shown.chars().count();
+            mentions.push(MentionOutput {
+                uuid: aci.to_string(),
+                name,
+            });
+            skip_until = start + length;
+        }
+        offsets.extend(std::iter::repeat_n(before, c.len_utf16()));
+        if pos >= skip_until {
+            text.push(c);
+            count += 1;
+        }
+        pos += c.len_utf16();
+    }
+    offsets.push(count);
+    let styles = styles::from_body_ranges(&dm.body_ranges, &offsets);
+    (text, mentions, styles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::styles::TextStyle;
+    use presage::proto::body_range::Style;
+
+    const ALICE: Uuid = Uuid::from_u128(1);
+    const BOB: Uuid = Uuid::from_u128(2);
+
+    fn mention(uuid: Uuid, start: usize, length: usize) -> Mention {
+        Mention {
+            uuid,
+            start,
+            length,
+        }
+    }
+
+    /// (start, length, value) of each range
+    fn spans(ranges: &[BodyRange]) -> Vec<(u32, u32, AssociatedValue)> {
+        ranges
+            .iter()
+            .map(|range| {
+                (
+                    range.start.unwrap(),
+                    range.length.unwrap(),
+                    range.associated_value.clone().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_uuid_start_and_length() {
+        assert_eq!(
+            format!("{}@8:6", ALICE).parse::<Mention>().unwrap(),
+            mention(ALICE, 8, 6)
+        );
+        for invalid in ["", "8:6", "not-a-uuid@8:6", &format!("{}@8", ALICE)] {
+            assert!(invalid.parse::<Mention>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn replaces_each_mention_and_counts_utf16_before_it() {
+        // The emoji is two UTF-16 units, 'é' one
+        let text = "héllo 😀 @Alice and @Bob!";
+        let styles = [
+            // "and", between the mentions
+            StyleRange {
+                style: TextStyle::Bold,
+                start: 15,
+                length: 3,
+            },
+            // All of "@Alice", which becomes one placeholder
+            StyleRange {
+                style: TextStyle::Italic,
+                start: 8,
+                length: 6,
+            },
+        ];
+        // Given out of order
+        let (body, ranges) =
+            apply(text, &[mention(BOB, 19, 4), mention(ALICE, 8, 6)], &styles).unwrap();
+        assert_eq!(body, "héllo 😀 \u{fffc} and \u{fffc}!");
+        let style = |style: Style| AssociatedValue::Style(style as i32);
+        assert_eq!(
+            spans(&ranges),
+            [
+                (9, 1, AssociatedValue::MentionAci(ALICE.to_string())),
+                (15, 1, AssociatedValue::MentionAci(BOB.to_string())),
+                (11, 3, style(Style::Bold)),
+                (9, 1, style(Style::Italic)),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_mentions_that_overlap_or_run_past_the_text() {
+        let text = "@Alice @Bob";
+        for mentions in [
+            vec![mention(ALICE, 0, 0)],
+            vec![mention(ALICE, 0, 6), mention(BOB, 5, 4)],
+            vec![mention(BOB, 7, 5)],
+        ] {
+            assert!(apply(text, &mentions, &[]).is_err(), "{:?}", mentions);
+        }
+    }
+}
@@ -0,0 +1,631 @@
+//! Output types shared by every command, serialized as the CLI's JSON.
+
+use std::path::PathBuf;
+
+use presage::libsignal_service::content::ContentBody;
+use presage::libsignal_service::prelude::Uuid;
+use presage::proto::DataMessage;
+use serde::{Deserialize, Serialize};
+
+use crate::identity::IdentityPolicy;
+use crate::styles::StyleRange;
+use crate::timestamp::Timestamp;
+use crate::{Error, Result};
+
+#[derive(Serialize)]
+pub struct ChatOutput {
+    pub id: String,
+    pub name: String,
+    pub is_group: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    /// Incoming messages not yet read; only with `chats --with-unread-counts`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unread_count: Option<usize>,
+    /// Local flags, set with `chat mute`, `chat archive` and `chat pin`
+    pub is_muted: bool,
+    pub is_archived: bool,
+    pub is_pinned: bool,
+}
+
+/// A chat in `conversations` output
+#[derive(Serialize)]
+pub struct ConversationOutput {
+    pub id: String,
+    pub name: String,
+    pub is_group: bool,
+    /// Absent for chats with no messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_message: Option<LastMessageOutput>,
+    pub unread_count: usize,
+    /// Local flags, set with `chat mute`, `chat archive` and `chat pin`
+    pub is_muted: bool,
+    pub is_archived: bool,
+    pub is_pinned: bool,
+}
+
+#[derive(Serialize)]
+pub struct LastMessageOutput {
+    pub id: String,
+    pub sender: String,
+    pub timestamp: Timestamp,
+    /// Preview: the first 100 characters, `[attachment]`, `[view-once media]`,
+    /// `[sticker]` or `[contact]`
+    pub text: String,
+    pub is_outgoing: bool,
+}
+
+#[derive(Serialize)]
+pub struct MessageOutput {
+    pub id: String,
+    pub chat_id: String,
+    pub sender: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
+    pub timestamp: Timestamp,
+    /// When `receive` pulled it off the queue (absent for outgoing/synced messages)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<Timestamp>,
+    /// The sender's clock was ahead of ours, so `timestamp` can't be trusted for ordering
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub clock_skew: bool,
+    /// Device the sender sent from (1 is their primary phone)
+    pub sender_device: u32,
+    /// Delivered with sealed sender, so the server didn't see who sent it
+    pub sealed_sender: bool,
+    /// Server-assigned message ID, when the server provided one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_guid: Option<String>,
+    /// Mentions are written as `@Name`
+    pub text: String,
+    /// Group members mentioned in the text
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<MentionOutput>,
+    /// Bold, italic and other styles over spans of the text
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub styles: Vec<StyleRange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sticker: Option<StickerOutput>,
+    /// A photo or video meant to be seen once
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub view_once: bool,
+    /// View-once media saved in the quarantine directory; only the `receive`
+    /// that first sees the message saves it
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub media: Vec<PathBuf>,
+    /// Contact cards shared in the message
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub contacts: Vec<SharedContactOutput>,
+    /// ID of the story this replies to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub story_id: Option<String>,
+    /// ID of the message this quotes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// The sender deleted it for everyone; only this tombstone is left
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    /// When it was deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<Timestamp>,
+    pub is_outgoing: bool,
+    pub is_read: bool,
+    /// Disappearing-message timer in seconds; the message is deleted this
+    /// long after it's sent, once read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct MentionOutput {
+    pub uuid: String,
+    /// Contact name, when they're a contact
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// A received sticker
+#[derive(Serialize)]
+pub struct StickerOutput {
+    /// Hex pack ID
+    pub pack_id: String,
+    pub sticker_id: u32,
+    /// Emoji the sticker stands for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emoji: Option<String>,
+    /// The sticker's image, once downloaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+/// A contact card shared in a message
+#[derive(Serialize)]
+pub struct SharedContactOutput {
+    /// Full name, or the nickname when the card has no name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub phones: Vec<ContactDetailOutput>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub emails: Vec<ContactDetailOutput>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub addresses: Vec<ContactAddressOutput>,
+}
+
+/// A phone number or email address on a shared contact
+#[derive(Serialize)]
+pub struct ContactDetailOutput {
+    pub value: String,
+    /// "home", "mobile" (phones only), "work" or "custom" (see `label`)
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// A postal address on a shared contact
+#[derive(Serialize)]
+pub struct ContactAddressOutput {
+    /// "home", "work" or "custom" (see `label`)
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub street: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pobox: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub neighborhood: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postcode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+}
+
+/// An item in `receive` or `messages` output: either a message or a typed event.
+///
+/// Messages keep their plain schema; events carry a `type` field so consumers
+/// can tell them apart.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ReceivedItem {
+    Message(Box<MessageOutput>),
+    Event(EventOutput),
+    /// Either, read back from the event log
+    Logged(LoggedItem),
+}
+
+impl ReceivedItem {
+    /// Name used for this item in the event log
+    pub fn kind(&self) -> &str {
+        match self {
+            ReceivedItem::Message(_) => "message",
+            ReceivedItem::Event(event) => event.kind(),
+            ReceivedItem::Logged(item) => &item.kind,
+        }
+    }
+
+    pub fn chat_id(&self) -> &str {
+        match self {
+            ReceivedItem::Message(m) => &m.chat_id,
+            ReceivedItem::Event(event) => event.chat_id(),
+            ReceivedItem::Logged(item) => &item.chat_id,
+        }
+    }
+}
+
+/// A [`ReceivedItem`] as the event log has it, with timestamps in unix
+/// seconds.
+#[derive(Serialize)]
+#[serde(transparent)]
+pub struct LoggedItem {
+    #[serde(skip)]
+    pub kind: String,
+    #[serde(skip)]
+    pub chat_id: String,
+    pub data: serde_json::Value,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventOutput {
+    /// Reactions to one of my messages, rolled up per emoji
+    ReactionSummary {
+        chat_id: String,
+        /// ID of the message that was reacted to
+        target_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target_text: Option<String>,
+        emoji: String,
+        count: usize,
+        reactors: Vec<String>,
+        /// Time of the most recent reaction
+        timestamp: Timestamp,
+    },
+
+    /// A contact's identity key (safety number) changed
+    IdentityChanged {
+        chat_id: String,
+        uuid: String,
+        old_fingerprint: String,
+        new_fingerprint: String,
+        policy: IdentityPolicy,
+        /// Whether their messages are being dropped until re-trusted
+        blocked: bool,
+    },
+
+    /// A MobileCoin payment, or a request to activate payments
+    PaymentNotification {
+        id: String,
+        chat_id: String,
+        sender: String,
+        timestamp: Timestamp,
+        /// "payment", "activation_request" or "activated"
+        action: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+        /// Whether a transaction receipt was attached (amounts aren't decoded)
+        has_receipt: bool,
+    },
+
+    /// A donation badge gifted to me
+    GiftBadge {
+        id: String,
+        chat_id: String,
+        sender: String,
+        timestamp: Timestamp,
+    },
+
+    /// The sender deleted one of their messages for everyone
+    MessageDeleted {
+        id: String,
+        chat_id: String,
+        sender: String,
+        timestamp: Timestamp,
+        /// ID of the deleted message
+        target_id: String,
+    },
+
+    /// The chat's disappearing-messages timer changed
+    ExpireTimerChanged {
+        id: String,
+        chat_id: String,
+        sender: String,
+        timestamp: Timestamp,
+        /// The new timer; 0 means messages no longer disappear
+        seconds: u32,
+    },
+
+    /// A contact appeared in the store, e.g. from a contact sync
+    ContactAdded {
+        chat_id: String,
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        phone: Option<String>,
+    },
+
+    /// A contact's name or number changed
+    ContactUpdated {
+        chat_id: String,
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        phone: Option<String>,
+        /// "name" and/or "phone"
+        changed: Vec<&'static str>,
+    },
+
+    /// This account became a member of a group
+    GroupJoined { chat_id: String, title: String },
+
+    /// This account is no longer a member of a group
+    GroupLeft { chat_id: String, title: String },
+
+    /// Something changed in a group this account is a member of
+    GroupChange {
+        chat_id: String,
+        /// The group's title now
+        title: String,
+        /// "member_added", "member_removed", "name_changed",
+        /// "description_changed" or "timer_changed"
+        change: &'static str,
+        /// Who sent the update, when it arrived as a message
+        #[serde(skip_serializing_if = "Option::is_none")]
+        by: Option<String>,
+        /// ACIs of the members added or removed
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        members: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        old_title: Option<String>,
+        /// The new description; absent when it was removed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+        /// The new disappearing-messages timer; 0 means off
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seconds: Option<u32>,
+    },
+
+    /// Someone called, or started a group call
+    Call {
+        chat_id: String,
+        /// Who called
+        sender: String,
+        timestamp: Timestamp,
+        /// "incoming", or "outgoing" for group calls I started
+        direction: &'static str,
+        /// "offer" (ringing), "missed" or "group_started"
+        kind: &'static str,
+        /// "audio" or "video"; unknown for group calls
+        #[serde(skip_serializing_if = "Option::is_none")]
+        media: Option<&'static str>,
+    },
+
+    /// A story posted by a contact, or to a group (only with
+    /// `--include-stories`)
+    Story {
+        id: String,
+        chat_id: String,
+        sender: String,
+        timestamp: Timestamp,
+        /// A text story's text, or a photo or video's caption
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+        /// The photo or video, once downloaded
+        #[serde(skip_serializing_if = "Option::is_none")]
+        media: Option<PathBuf>,
+        allows_replies: bool,
+    },
+
+    /// Someone started or stopped typing; only the latest state per sender
+    /// and chat in a `receive` is kept
+    Typing {
+        chat_id: String,
+        sender: String,
+        /// False when they stopped
+        started: bool,
+        timestamp: Timestamp,
+    },
+}
+
+impl EventOutput {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EventOutput::ReactionSummary { .. } => "reaction_summary",
+            EventOutput::IdentityChanged { .. } => "identity_changed",
+            EventOutput::PaymentNotification { .. } => "payment_notification",
+            EventOutput::GiftBadge { .. } => "gift_badge",
+            EventOutput::MessageDeleted { .. } => "message_deleted",
+            EventOutput::ExpireTimerChanged { .. } => "expire_timer_changed",
+            EventOutput::ContactAdded { .. } => "contact_added",
+            EventOutput::ContactUpdated { .. } => "contact_updated",
+            EventOutput::GroupJoined { .. } => "group_joined",
+            EventOutput::GroupLeft { .. } => "group_left",
+            EventOutput::GroupChange { .. } => "group_change",
+            EventOutput::Call { .. } => "call",
+            EventOutput::Story { .. } => "story",
+            EventOutput::Typing { .. } => "typing",
+        }
+    }
+
+    pub fn chat_id(&self) -> &str {
+        match self {
+            EventOutput::ReactionSummary { chat_id, .. }
+            | EventOutput::IdentityChanged { chat_id, .. }
+            | EventOutput::PaymentNotification { chat_id, .. }
+            | EventOutput::GiftBadge { chat_id, .. }
+            | EventOutput::MessageDeleted { chat_id, .. }
+            | EventOutput::ExpireTimerChanged { chat_id, .. }
+            | EventOutput::ContactAdded { chat_id, .. }
+            | EventOutput::ContactUpdated { chat_id, .. }
+            | EventOutput::GroupJoined { chat_id, .. }
+            | EventOutput::GroupLeft { chat_id, .. }
+            | EventOutput::GroupChange { chat_id, .. }
+            | EventOutput::Call { chat_id, .. }
+            | EventOutput::Story { chat_id, .. }
+            | EventOutput::Typing { chat_id, .. } => chat_id,
+        }
+    }
+
+    /// The event as it is for its sender, when that's me: a group call I
+    /// started is outgoing
+    pub fn sent_by_me(mut self) -> Self {
+        if let EventOutput::Call { direction, .. } = &mut self {
+            *direction = "outgoing";
+        }
+        self
+    }
+
+    /// Typed event for data messages that carry no text of their own
+    /// (payments, gift badges, deletes, timer changes, group calls), so they
+    /// don't show up as empty messages
+    pub fn from_data_message(chat_id: &str, sender: &str, dm: &DataMessage) -> Option<Self> {
+        use presage::proto::data_message::payment::{self, notification};
+
+        let ts = dm.timestamp.unwrap_or(0);
+        let id = message_id(ts, sender);
+        let chat_id = chat_id.to_string();
+        let sender = sender.to_string();
+        let timestamp = Timestamp::from_millis(ts);
+
+        if let Some(item) = dm.payment.as_ref().and_then(|p| p.item.as_ref()) {
+            let (action, note, has_receipt) = match item {
+                payment::Item::Notification(n) => (
+                    "payment",
+                    n.note.clone().filter(|note| !note.is_empty()),
+                    matches!(
+                        &n.transaction,
+                        Some(notification::Transaction::MobileCoin(mc)) if mc.receipt.is_some()
+                    ),
+                ),
+                payment::Item::Activation(a) => {
+                    // Activation type 0 is a request, 1 confirms activation
+                    let action = if a.r#type.unwrap_or(0) == 0 {
+                        "activation_request"
+                    } else {
+                        "activated"
+                    };
+                    (action, None, false)
+                }
+            };
+            return Some(EventOutput::PaymentNotification {
+                id,
+                chat_id,
+                sender,
+                timestamp,
+                action,
+                note,
+                has_receipt,
+            });
+        }
+
+        if dm.gift_badge.is_some() {
+            return Some(EventOutput::GiftBadge {
+                id,
+                chat_id,
+                sender,
+                timestamp,
+            });
+        }
+
+        if let Some(target) = crate::deletes::target(dm) {
+            return Some(EventOutput::MessageDeleted {
+                target_id: message_id(target, &sender),
+                id,
+                chat_id,
+                sender,
+                timestamp,
+            });
+        }
+
+        if crate::expiry::is_timer_update(dm) {
+            return Some(EventOutput::ExpireTimerChanged {
+                id,
+                chat_id,
+                sender,
+                timestamp,
+                seconds: dm.expire_timer.unwrap_or(0),
+            });
+        }
+
+        // Sent when someone starts or joins a group call
+        if dm.group_call_update.is_some() {
+            return Some(EventOutput::Call {
+                chat_id,
+                sender,
+                timestamp,
+                direction: "incoming",
+                kind: "group_started",
+                media: None,
+            });
+        }
+
+        None
+    }
+}
+
+/// Sort order for `messages`. Output is always newest first.
+///
+/// Ties are broken by sender ACI, then sender device, so the order is stable
+/// across runs. With `received`, messages we never received (our own, or ones
+/// from before arrivals were tracked) fall back to their sent time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum MessageOrder {
+    Sent,
+    Received,
+}
+
+/// A message's ID: `<sent time in ms>_<sender ACI>`.
+///
+/// Signal identifies a message by its author and sent time (reactions,
+/// quotes and remote deletes point at it that way), so the pair is unique in
+/// a chat, unlike the sent time alone, and is the same on every device and
+/// every run. The sent time comes first so IDs still sort roughly by time.
+pub fn message_id(timestamp: u64, sender: &str) -> String {
+    format!("{}_{}", timestamp, sender)
+}
+
+/// ID of the message a data message quotes, if it quotes one
+pub fn reply_to(dm: &DataMessage) -> Option<String> {
+    let quote = dm.quote.as_ref()?;
+    Some(message_id(quote.id?, quote.author_aci.as_deref()?))
+}
+
+/// Split a message ID into its sent time and sender. A bare timestamp, the
+/// ID format before senders were part of it, gives no sender.
+pub fn parse_message_id(id: &str) -> Result<(u64, Option<&str>)> {
+    let invalid = || Error::Invalid(format!("Invalid message ID: {}", id));
+    let (timestamp, sender) = match id.split_once('_') {
+        Some((timestamp, sender)) => {
+            Uuid::parse_str(sender).map_err(|_| invalid())?;
+            (timestamp, Some(sender))
+        }
+        None => (id, None),
+    };
+    Ok((timestamp.parse().map_err(|_| invalid())?, sender))
+}
+
+/// Short human-readable description of a stored message, for summaries
+pub fn message_preview(body: &ContentBody) -> Option<String> {
+    let ContentBody::DataMessage(dm) = body else {
+        return None;
+    };
+    match dm.body.as_deref() {
+        Some(text) if !text.is_empty() => Some(text.chars().take(100).collect()),
+        _ if crate::view_once::is_view_once(dm) => Some("[view-once media]".to_string()),
+        _ if !dm.attachments.is_empty() => Some("[attachment]".to_string()),
+        _ if dm.sticker.is_some() => Some("[sticker]".to_string()),
+        _ if !dm.contact.is_empty() => Some("[contact]".to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENDER: &str = "11111111-2222-3333-4444-555555555555";
+
+    #[test]
+    fn message_ids_round_trip() {
+        let id = message_id(1700000000000, SENDER);
+        assert_eq!(id, format!("1700000000000_{}", SENDER));
+        assert_eq!(
+            parse_message_id(&id).unwrap(),
+            (1700000000000, Some(SENDER))
+        );
+    }
+
+    #[test]
+    fn bare_timestamps_have_no_sender() {
+        assert_eq!(
+            parse_message_id("1700000000000").unwrap(),
+            (1700000000000, None)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_message_ids() {
+        for id in [
+            "",
+            "_",
+            "abc",
+            "-1",
+            "1700000000000_",
+            "1700000000000_not-a-uuid",
+            &format!("_{}", SENDER),
+            &format!("{}_1700000000000", SENDER),
+        ] {
+            assert!(parse_message_id(id).is_err(), "{:?}", id);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Single choke point for everything that sends to Signal.
+//!
+//! In read-only (observer) mode the bridge only archives and notifies. Rather
+//! than trusting each command to check a flag, all outbound traffic (messages,
+//! receipts, typing indicators, attachment uploads) goes through this module,
+//! which refuses it while read-only is set, as do requests that change the
+//! account on the server, such as unlinking a device. Sends hold the store
+//! lock (see [`crate::lock`]).
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use presage::libsignal_service::content::ContentBody;
+use presage::libsignal_service::protocol::ServiceId;
+use presage::libsignal_service::sender::AttachmentSpec;
+use presage::proto::AttachmentPointer;
+use tracing::instrument;
+
+use crate::lock::lock_store;
+use crate::{link, Error, Result, SignalManager};
+
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Kinds of outbound traffic, for error messages.
+#[derive(Clone, Copy, Debug)]
+pub enum Outbound {
+    Message,
+    Receipt,
+    Typing,
+    Attachment,
+    ContactRequest,
+    Unlink,
+}
+
+impl fmt::Display for Outbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outbound::Message => "send a message",
+            Outbound::Receipt => "send a receipt",
+            Outbound::Typing => "send a typing indicator",
+            Outbound::Attachment => "upload an attachment",
+            Outbound::ContactRequest => "request contacts from the primary device",
+            Outbound::Unlink => "unlink a device",
+        })
+    }
+}
+
+impl From<&ContentBody> for Outbound {
+    fn from(body: &ContentBody) -> Self {
+        match body {
+            ContentBody::ReceiptMessage(_) => Outbound::Receipt,
+            ContentBody::TypingMessage(_) => Outbound::Typing,
+            _ => Outbound::Message,
+        }
+    }
+}
+
+pub fn set_read_only(read_only: bool) {
+    READ_ONLY.store(read_only, Ordering::SeqCst);
+}
+
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::SeqCst)
+}
+
+/// Fail if `action` isn't allowed in the current mode.
+pub fn ensure_allowed(action: Outbound) -> Result<()> {
+    if is_read_only() {
+        return Err(Error::ReadOnly(action));
+    }
+    Ok(())
+}
+
+/// Send any content to a single recipient.
+#[instrument(skip_all, fields(recipient = %recipient.raw_uuid()))]
+pub async fn send_message(
+    manager: &mut SignalManager,
+    recipient: ServiceId,
+    body: ContentBody,
+    timestamp: u64,
+) -> Result<()> {
+    ensure_allowed(Outbound::from(&body))?;
+    let _lock = lock_store()?;
+    let result = manager.send_message(recipient, body, timestamp).await;
+    link::note_unlinked(result.map_err(Error::from))
+}
+
+/// Send any content to every member of a group.
+#[instrument(skip_all)]
+pub async fn send_to_group(
+    manager: &mut SignalManager,
+    master_key: &[u8; 32],
+    body: ContentBody,
+    timestamp: u64,
+) -> Result<()> {
+    ensure_allowed(Outbound::from(&body))?;
+    let _lock = lock_store()?;
+    let result = manager
+        .send_message_to_group(master_key, body, timestamp)
+        .await;
+    link::note_unlinked(result.map_err(Error::from))
+}
+
+/// Ask the primary device to send its contacts.
+#[instrument(skip_all)]
+pub async fn request_contacts(manager: &mut SignalManager) -> Result<()> {
+    ensure_allowed(Outbound::ContactRequest)?;
+    let _lock = lock_store()?;
+    link::note_unlinked(manager.request_contacts().await.map_err(Error::from))
+}
+
+/// Unlink a device from the account: this one, or as the primary, another.
+#[instrument(skip_all, fields(device_id))]
+pub async fn unlink_device(manager: &SignalManager, device_id: u32) -> Result<()> {
+    ensure_allowed(Outbound::Unlink)?;
+    manager.unlink_secondary(device_id as i64).await?;
+    Ok(())
+}
+
+/// Upload attachments to the CDN, failing if any single upload fails
+#[instrument(skip_all, fields(count = attachments.len()))]
+pub async fn upload_attachments(
+    manager: &SignalManager,
+    attachments: Vec<(AttachmentSpec, Vec<u8>)>,
+) -> Result<Vec<AttachmentPointer>> {
+    ensure_allowed(Outbound::Attachment)?;
+    manager
+        .upload_attachments(attachments)
+        .await?
+        .into_iter()
+        .map(|r| r.map_err(Error::network))
+        .collect()
+}
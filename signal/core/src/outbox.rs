@@ -0,0 +1,344 @@
+//! Messages queued to send later.
+//!
+//! `send --queue` stores a message here instead of sending it, so it isn't
+//! lost while the network is down. [`flush`] sends what's due: a message that
+//! fails for a reason that may pass (no network, rate limiting) waits twice as
+//! long before each new attempt, up to [`MAX_BACKOFF_SECS`], and is given up
+//! on after [`MAX_ATTEMPTS`]; any other failure gives up at once. Sent
+//! messages are removed, and given-up ones are kept with their error until
+//! removed, so a failure is never silent.
+
+use std::time::UNIX_EPOCH;
+
+use presage::libsignal_service::prelude::Uuid;
+use presage::libsignal_service::sender::AttachmentSpec;
+use rusqlite::Connection;
+use serde::Serialize;
+use tracing::instrument;
+
+use crate::identity::IdentityPolicy;
+use crate::send::{self, Outgoing};
+use crate::timestamp::Timestamp;
+use crate::{local, outbound, Error, Result, SignalManager};
+
+/// Wait before the first retry; doubled for each one after
+const BASE_BACKOFF_SECS: u64 = 30;
+
+/// Longest wait between attempts
+pub const MAX_BACKOFF_SECS: u64 = 3600;
+
+/// Attempts before a message is given up on
+pub const MAX_ATTEMPTS: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboxStatus {
+    /// Waiting for its next attempt
+    Pending,
+    /// Given up on; see `last_error`
+    Failed,
+    /// Delivered by this flush and removed from the outbox
+    Sent,
+}
+
+impl OutboxStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            OutboxStatus::Pending => "pending",
+            OutboxStatus::Failed => "failed",
+            OutboxStatus::Sent => "sent",
+        }
+    }
+}
+
+/// A queued message, as `outbox list` shows it.
+#[derive(Serialize)]
+pub struct OutboxItem {
+    pub id: i64,
+    /// Recipient's UUID
+    pub recipient: String,
+    pub text: Option<String>,
+    /// Number of attachments
+    pub attachments: usize,
+    pub status: OutboxStatus,
+    pub queued_at: Timestamp,
+    pub attempts: u32,
+    /// When the next attempt is due; absent once given up on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// One message's outcome in a [`flush`].
+#[derive(Serialize)]
+pub struct FlushResult {
+    pub id: i64,
+    pub recipient: String,
+    pub status: OutboxStatus,
+    /// Sent time, for a message that went
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FlushReport {
+    pub sent: usize,
+    /// Messages still waiting after this flush, including ones not yet due
+    pub pending: usize,
+    /// Messages given up on by this flush
+    pub failed: usize,
+    pub results: Vec<FlushResult>,
+}
+
+pub fn open_outbox_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Wait after `attempts` failed attempts
+fn backoff_millis(attempts: u32) -> u64 {
+    let secs = BASE_BACKOFF_SECS.saturating_mul(1 << attempts.saturating_sub(1).min(16));
+    secs.min(MAX_BACKOFF_SECS) * 1000
+}
+
+/// Whether a failed send may succeed if tried again later
+fn is_retryable(e: &Error) -> bool {
+    matches!(e, Error::Network(_) | Error::RateLimited)
+}
+
+/// Queue a message for each recipient, due at once. Returns the new IDs.
+pub fn enqueue(
+    conn: &mut Connection,
+    recipients: &[Uuid],
+    outgoing: &Outgoing,
+) -> Result<Vec<i64>> {
+    let now = now_millis() as i64;
+    let styles = if outgoing.styles.is_empty() {
+        None
+    } else {
+        Some(serde_json::to_string(&outgoing.styles)?)
+    };
+    let tx = conn.transaction()?;
+    let mut ids = Vec::new();
+    for uuid in recipients {
+        tx.execute(
+            "INSERT INTO outbox (recipient, text, styles, view_once, queued_at, next_attempt_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+            rusqlite::params![
+                uuid.to_string(),
+                outgoing.text,
+                styles,
+                outgoing.view_once,
+                now
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        for (position, (spec, data)) in outgoing.attachments.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO attachments
+                    (item_id, position, content_type, file_name, data, voice_note)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                rusqlite::params![
+                    id,
+                    position,
+                    spec.content_type,
+                    spec.file_name,
+                    data,
+                    spec.voice_note == Some(true)
+                ],
+            )?;
+        }
+        ids.push(id);
+    }
+    tx.commit()?;
+    Ok(ids)
+}
+
+/// Queued messages, pending and given up on, oldest first.
+pub fn list(conn: &Connection) -> Result<Vec<OutboxItem>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, recipient, text, status, queued_at, attempts, next_attempt_at, last_error,
+                (SELECT COUNT(*) FROM attachments WHERE item_id = outbox.id)
+         FROM outbox ORDER BY id",
+    )?;
+    let items = stmt
+        .query_map([], |row| {
+            let status = if row.get::<_, String>(3)? == "failed" {
+                OutboxStatus::Failed
+            } else {
+                OutboxStatus::Pending
+            };
+            Ok(OutboxItem {
+                id: row.get(0)?,
+                recipient: row.get(1)?,
+                text: row.get(2)?,
+                status,
+                queued_at: Timestamp::from_millis(row.get::<_, i64>(4)? as u64),
+                attempts: row.get(5)?,
+                next_attempt_at: (status == OutboxStatus::Pending)
+                    .then(|| {
+                        row.get::<_, i64>(6)
+                            .map(|ms| Timestamp::from_millis(ms as u64))
+                    })
+                    .transpose()?,
+                last_error: row.get(7)?,
+                attachments: row.get::<_, i64>(8)? as usize,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(items)
+}
+
+/// Remove queued messages, sent or not. Returns how many were removed.
+pub fn remove(conn: &Connection, ids: &[i64]) -> Result<usize> {
+    let mut removed = 0;
+    for id in ids {
+        removed += conn.execute("DELETE FROM outbox WHERE id = ?1", [id])?;
+    }
+    Ok(removed)
+}
+
+/// A queued message, rebuilt for sending
+fn load(conn: &Connection, id: i64) -> Result<(Uuid, Outgoing)> {
+    let (recipient, text, styles, view_once): (String, Option<String>, Option<String>, bool) = conn
+        .query_row(
+            "SELECT recipient, text, styles, view_once FROM outbox WHERE id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+    let recipient = recipient
+        .parse()
+        .map_err(|_| Error::Invalid(format!("Invalid UUID in outbox: {}", recipient)))?;
+    let attachments = conn
+        .prepare(
+            "SELECT content_type, file_name, data, voice_note FROM attachments
+             WHERE item_id = ?1 ORDER BY position",
+        )?
+        .query_map([id], |row| {
+            let data: Vec<u8> = row.get(2)?;
+            let spec = AttachmentSpec {
+                content_type: row.get(0)?,
+                length: data.len(),
+                file_name: row.get(1)?,
+                voice_note: row.get::<_, bool>(3)?.then_some(true),
+                ..Default::default()
+            };
+            Ok((spec, data))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    let styles = match styles {
+        Some(json) => serde_json::from_str(&json)?,
+        None => Vec::new(),
+    };
+    Ok((
+        recipient,
+        Outgoing {
+            text,
+            attachments,
+            styles,
+            preview: None,
+            sticker: None,
+            view_once,
+            contact: None,
+        },
+    ))
+}
+
+/// Try to send the queued messages that are due, or every pending one with
+/// `all`, oldest first.
+#[instrument(skip_all)]
+pub async fn flush(
+    manager: &mut SignalManager,
+    policy: IdentityPolicy,
+    all: bool,
+) -> Result<FlushReport> {
+    let conn = open_outbox_db()?;
+    let now = now_millis();
+    let due: Vec<(i64, String, u32)> = conn
+        .prepare(
+            "SELECT id, recipient, attempts FROM outbox
+             WHERE status = 'pending' AND (?1 OR next_attempt_at <= ?2)
+             ORDER BY id",
+        )?
+        .query_map(rusqlite::params![all, now as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    if !due.is_empty() {
+        outbound::ensure_allowed(outbound::Outbound::Message)?;
+    }
+
+    let mut results = Vec::new();
+    for (id, recipient, attempts) in due {
+        let result = match load(&conn, id) {
+            Ok((uuid, outgoing)) => send::send(manager, vec![uuid], outgoing, policy, false).await,
+            Err(e) => Err(e),
+        };
+        let attempts = attempts + 1;
+        let mut flushed = FlushResult {
+            id,
+            recipient,
+            status: OutboxStatus::Sent,
+            timestamp: None,
+            next_attempt_at: None,
+            error: None,
+        };
+        match result {
+            Ok(report) => {
+                conn.execute("DELETE FROM outbox WHERE id = ?1", [id])?;
+                flushed.timestamp = Some(Timestamp::from_millis(report.timestamp));
+            }
+            Err(e) => {
+                let error = e.to_string();
+                if is_retryable(&e) && attempts < MAX_ATTEMPTS {
+                    let next = now_millis() + backoff_millis(attempts);
+                    flushed.status = OutboxStatus::Pending;
+                    flushed.next_attempt_at = Some(Timestamp::from_millis(next));
+                    conn.execute(
+                        "UPDATE outbox SET attempts = ?2, next_attempt_at = ?3, last_error = ?4
+                         WHERE id = ?1",
+                        rusqlite::params![id, attempts, next as i64, error],
+                    )?;
+                } else {
+                    flushed.status = OutboxStatus::Failed;
+                    conn.execute(
+                        "UPDATE outbox SET status = ?2, attempts = ?3, last_error = ?4
+                         WHERE id = ?1",
+                        rusqlite::params![id, OutboxStatus::Failed.as_str(), attempts, error],
+                    )?;
+                }
+                flushed.error = Some(error);
+            }
+        }
+        results.push(flushed);
+    }
+
+    let pending: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM outbox WHERE status = 'pending'",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(FlushReport {
+        sent: results
+            .iter()
+            .filter(|r| r.status == OutboxStatus::Sent)
+            .count(),
+        pending: pending as usize,
+        failed: results
+            .iter()
+            .filter(|r| r.status == OutboxStatus::Failed)
+            .count(),
+        results,
+    })
+}
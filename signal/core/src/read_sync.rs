@@ -0,0 +1,632 @@
+//! Track read sync messages from other devices.
+//!
+//! Kept in the local metadata database (see [`crate::local`]). This tracks
+//! when messages were read on other devices (phone), allowing us to show
+//! accurate is_read status.
+//!
+//! Besides individual reads, each chat has a read watermark: the sent
+//! timestamp up to which everything in it counts as read. `mark-read` moves it
+//! to the chat's newest message, and a read sync from the phone moves it to
+//! the synced message, since reading a chat reads everything before it. A
+//! message that arrives late with an older timestamp is therefore already
+//! read, in groups as much as in 1:1 chats.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::UNIX_EPOCH;
+
+use presage::libsignal_service::content::{Content, ContentBody};
+use presage::libsignal_service::prelude::Uuid;
+use presage::libsignal_service::protocol::ServiceId;
+use presage::proto::{receipt_message, sync_message, ReceiptMessage};
+use presage::store::{ContentsStore, Thread};
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+
+use crate::model::{parse_message_id, EventOutput};
+use crate::{local, outbound, parse_thread, thread_chat_id, Error, Result, SignalManager};
+
+/// Whether `mark-read` tells senders their messages were read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum ReadReceiptPolicy {
+    /// Only record reads locally
+    #[default]
+    Off,
+    /// Also send read receipts to the senders
+    Send,
+}
+
+/// Reads recorded longer ago than this are of messages the server no longer
+/// holds, if they haven't arrived yet
+pub const ORPHANED_READ_DAYS: i64 = 45;
+
+/// Open the database read syncs are kept in, `local.db`.
+pub fn open_read_sync_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// Move a chat's read watermark forward to `timestamp`; it never moves back.
+fn advance_watermark(conn: &Connection, chat_id: &str, timestamp: u64) -> rusqlite::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    conn.execute(
+        "INSERT INTO read_watermarks (chat_id, timestamp, updated_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT (chat_id) DO UPDATE SET
+             timestamp = excluded.timestamp,
+             updated_at = excluded.updated_at
+         WHERE excluded.timestamp > timestamp",
+        rusqlite::params![chat_id, timestamp as i64, now],
+    )?;
+
+    Ok(())
+}
+
+/// Record that a message was read (from SyncMessage.Read)
+fn mark_as_read(conn: &Connection, sender_aci: &str, timestamp: u64) -> rusqlite::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    conn.execute(
+        "INSERT OR IGNORE INTO read_sync (sender_aci, timestamp, read_at) VALUES (?1, ?2, ?3)",
+        rusqlite::params![sender_aci, timestamp as i64, now],
+    )?;
+
+    Ok(())
+}
+
+/// Check if a message in a chat has been read, either by itself or by being
+/// at or below the chat's watermark.
+/// Returns false on database errors (safe default: show as unread).
+pub fn is_read(conn: &Connection, chat_id: &str, sender_aci: &str, timestamp: u64) -> bool {
+    conn.query_row(
+        "SELECT 1 WHERE EXISTS (
+             SELECT 1 FROM read_sync WHERE sender_aci = ?2 AND timestamp = ?3
+         ) OR EXISTS (
+             SELECT 1 FROM read_watermarks WHERE chat_id = ?1 AND timestamp >= ?3
+         )",
+        rusqlite::params![chat_id, sender_aci, timestamp as i64],
+        |_| Ok(()),
+    )
+    .is_ok()
+}
+
+/// Whether each of a chat's messages, given as (sender ACI, sent timestamp),
+/// has been read, as [`is_read`] says, in two queries rather than one per
+/// message.
+pub fn read_statuses(
+    conn: &Connection,
+    chat_id: &str,
+    messages: &[(String, u64)],
+) -> Result<HashMap<(String, u64), bool>> {
+    let watermark = watermark(conn, chat_id)?;
+    let above: Vec<u64> = messages
+        .iter()
+        .map(|(_, ts)| *ts)
+        .filter(|ts| watermark.is_none_or(|w| *ts > w))
+        .collect();
+    let mut read = HashSet::new();
+    if let (Some(&first), Some(&last)) = (above.iter().min(), above.iter().max()) {
+        read = conn
+            .prepare(
+                "SELECT sender_aci, timestamp FROM read_sync WHERE timestamp BETWEEN ?1 AND ?2",
+            )?
+            .query_map([first as i64, last as i64], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+    }
+    Ok(messages
+        .iter()
+        .map(|message| {
+            let is_read = watermark.is_some_and(|w| message.1 <= w) || read.contains(message);
+            (message.clone(), is_read)
+        })
+        .collect())
+}
+
+/// A chat's read watermark: everything sent at or before it counts as read.
+pub fn watermark(conn: &Connection, chat_id: &str) -> Result<Option<u64>> {
+    let timestamp: Option<i64> = conn
+        .query_row(
+            "SELECT timestamp FROM read_watermarks WHERE chat_id = ?1",
+            [chat_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(timestamp.map(|ts| ts as u64))
+}
+
+/// How many messages in a chat from other people haven't been read. Only
+/// messages after the chat's watermark are read from the store.
+pub async fn unread_count(
+    manager: &SignalManager,
+    conn: &Connection,
+    chat_id: &str,
+    my_uuid: Uuid,
+) -> Result<usize> {
+    let thread = parse_thread(chat_id)?;
+    let after = watermark(conn, chat_id)?.map_or(0, |ts| ts + 1);
+    let mut incoming = Vec::new();
+    for content in manager.store().messages(&thread, after..).await?.flatten() {
+        let ContentBody::DataMessage(dm) = &content.body else {
+            continue;
+        };
+        let sender_uuid = content.metadata.sender.raw_uuid();
+        if sender_uuid == my_uuid {
+            continue;
+        }
+        let sender_aci = sender_uuid.to_string();
+        // Reactions, payments, group updates etc. are events, not messages to read
+        if EventOutput::from_data_message(chat_id, &sender_aci, dm).is_some()
+            || crate::roster::is_group_update(dm)
+        {
+            continue;
+        }
+        incoming.push((sender_aci, dm.timestamp.unwrap_or(0)));
+    }
+    let statuses = read_statuses(conn, chat_id, &incoming)?;
+    Ok(statuses.values().filter(|is_read| !**is_read).count())
+}
+
+/// The chat each stored message, given as (sender ACI, sent timestamp),
+/// belongs to: the sender's 1:1 chat, or failing that the group containing
+/// it. Messages not in the store are left out. Each group is read once for
+/// all the messages, over the span of their timestamps, rather than every
+/// group being searched for each message.
+async fn find_threads(
+    manager: &SignalManager,
+    messages: &[(String, u64)],
+) -> Result<HashMap<(String, u64), Thread>> {
+    let store = manager.store();
+    let mut threads = HashMap::new();
+    let mut in_groups = HashMap::new();
+    for message in messages {
+        let Ok(sender) = message.0.parse::<Uuid>() else {
+            continue;
+        };
+        let contact = Thread::Contact(sender);
+        if store.message(&contact, message.1).await?.is_some() {
+            threads.insert(message.clone(), contact);
+        } else {
+            in_groups.insert((sender, message.1), message);
+        }
+    }
+
+    let timestamps = in_groups.keys().map(|(_, timestamp)| *timestamp);
+    let (Some(first), Some(last)) = (timestamps.clone().min(), timestamps.max()) else {
+        return Ok(threads);
+    };
+    for (master_key, _) in store.groups().await?.flatten() {
+        let group = Thread::Group(master_key);
+        for content in store.messages(&group, first..=last).await?.flatten() {
+            let metadata = &content.metadata;
+            let sent = (metadata.sender.raw_uuid(), metadata.timestamp);
+            if let Some(message) = in_groups.remove(&sent) {
+                threads.insert(message.clone(), group.clone());
+            }
+        }
+        if in_groups.is_empty() {
+            break;
+        }
+    }
+    Ok(threads)
+}
+
+/// Process SyncMessage read entries in a single transaction, moving each
+/// read message's chat watermark up to it.
+///
+/// Reads of messages we don't have yet are still recorded individually; if
+/// the message arrives later, that record is what marks it read.
+pub async fn process_sync_reads(
+    manager: &SignalManager,
+    conn: &mut Connection,
+    reads: &[sync_message::Read],
+) -> Result<usize> {
+    let synced: Vec<(String, u64)> = reads
+        .iter()
+        .filter_map(|read| Some((read.sender_aci.clone()?, read.timestamp?)))
+        .collect();
+    // Look up chats first: the transaction can't be held across awaits
+    let threads = find_threads(manager, &synced).await?;
+
+    let tx = conn.transaction()?;
+    for read in &synced {
+        mark_as_read(&tx, &read.0, read.1)?;
+        if let Some(thread) = threads.get(read) {
+            advance_watermark(&tx, &thread_chat_id(thread), read.1)?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(synced.len())
+}
+
+/// Individual reads recorded before `before` (unix seconds) of messages that
+/// never arrived, as (sender ACI, sent timestamp). Reads are kept for
+/// messages still on their way, but none will arrive once the server has
+/// dropped them.
+pub async fn orphaned_reads(
+    manager: &SignalManager,
+    conn: &Connection,
+    before: i64,
+) -> Result<Vec<(String, u64)>> {
+    let reads: Vec<(String, u64)> = conn
+        .prepare("SELECT sender_aci, timestamp FROM read_sync WHERE read_at < ?1")?
+        .query_map([before], |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    let threads = find_threads(manager, &reads).await?;
+    Ok(reads
+        .into_iter()
+        .filter(|read| !threads.contains_key(read))
+        .collect())
+}
+
+/// Delete individual reads with one prepared statement; callers wrap it in
+/// a transaction.
+fn delete_reads(conn: &Connection, reads: &[(String, u64)]) -> rusqlite::Result<()> {
+    let mut delete =
+        conn.prepare("DELETE FROM read_sync WHERE sender_aci = ?1 AND timestamp = ?2")?;
+    for (sender_aci, timestamp) in reads {
+        delete.execute(rusqlite::params![sender_aci, *timestamp as i64])?;
+    }
+    Ok(())
+}
+
+/// Forget individual reads, each given as (sender ACI, sent timestamp), in
+/// one transaction.
+pub fn forget_reads(conn: &mut Connection, reads: &[(String, u64)]) -> Result<()> {
+    let tx = conn.transaction()?;
+    delete_reads(&tx, reads)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Forget individual reads of messages sent before `before` (milliseconds),
+/// such as ones pruned from the store. Returns how many there were.
+pub fn forget_reads_before(conn: &Connection, before: u64) -> Result<usize> {
+    Ok(conn.execute(
+        "DELETE FROM read_sync WHERE timestamp < ?1",
+        [before as i64],
+    )?)
+}
+
+/// Record messages, each given as (sender ACI, sent timestamp), as read, in
+/// one transaction. Returns how many there were.
+pub fn mark_reads(conn: &mut Connection, reads: &[(String, u64)]) -> Result<i64> {
+    let tx = conn.transaction()?;
+    for (sender_aci, ts) in reads {
+        mark_as_read(&tx, sender_aci, *ts)?;
+    }
+    tx.commit()?;
+    Ok(reads.len() as i64)
+}
+
+/// Counts from [`mark_read`].
+pub struct MarkReadResult {
+    pub chats_marked: usize,
+    pub messages_marked: i64,
+}
+
+/// Mark every incoming message in the given chats as read locally, and send
+/// read receipts to the senders if `receipts` says so. Invalid chat IDs are
+/// skipped with a warning.
+#[instrument(skip_all)]
+pub async fn mark_read(
+    manager: &mut SignalManager,
+    chat_ids: &[String],
+    receipts: ReadReceiptPolicy,
+) -> Result<MarkReadResult> {
+    let store = manager.store();
+    let my_uuid = manager.whoami().await?.aci;
+
+    let mut read_db = open_read_sync_db()?;
+    let mut total_messages = 0i64;
+    let mut chats_marked = 0usize;
+    // Sender -> timestamps newly marked read, for read receipts
+    let mut newly_read: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+
+    for chat_id in chat_ids {
+        // Parse chat_id as UUID (contact) or hex (group)
+        let thread = if let Ok(uuid) = chat_id.parse::<Uuid>() {
+            Thread::Contact(uuid)
+        } else if let Ok(master_key) = hex::decode(chat_id) {
+            let key: [u8; 32] = master_key
+                .try_into()
+                .map_err(|_| {
+                    Error::InvalidRecipient("Group master key must be 32 bytes".to_string())
+                })?;
+            Thread::Group(key)
+        } else {
+            warn!("Invalid chat_id: {}", chat_id);
+            continue;
+        };
+
+        // Get all incoming messages from this chat and mark them read
+        // Collect (sender_aci, timestamp) pairs - groups have multiple senders
+        let chat_id = thread_chat_id(&thread);
+        let messages_iter = store.messages(&thread, ..).await?;
+        let mut incoming: Vec<(String, u64)> = Vec::new();
+        let mut newest = None;
+
+        for content in messages_iter.flatten() {
+            if let ContentBody::DataMessage(dm) = &content.body {
+                let Some(ts) = dm.timestamp else {
+                    continue;
+                };
+                newest = newest.max(Some(ts));
+                let sender_uuid = content.metadata.sender.raw_uuid();
+                if sender_uuid != my_uuid {
+                    incoming.push((sender_uuid.to_string(), ts));
+                }
+            }
+        }
+
+        // Mark each message with its actual sender
+        let statuses = read_statuses(&read_db, &chat_id, &incoming)?;
+        let to_mark: Vec<(String, u64)> = incoming
+            .into_iter()
+            .filter(|message| !statuses[message])
+            .collect();
+        mark_reads(&mut read_db, &to_mark)?;
+        // Anything older that turns up later is read too
+        if let Some(newest) = newest {
+            advance_watermark(&read_db, &chat_id, newest)?;
+        }
+        total_messages += to_mark.len() as i64;
+        chats_marked += 1;
+
+        for (sender_aci, ts) in to_mark {
+            newly_read.entry(sender_aci).or_default().push(ts);
+        }
+    }
+
+    if receipts == ReadReceiptPolicy::Send {
+        send_read_receipts(manager, newly_read).await;
+    }
+
+    Ok(MarkReadResult {
+        chats_marked,
+        messages_marked: total_messages,
+    })
+}
+
+/// Tell senders their messages were read. Best-effort: the local read state
+/// is already saved.
+async fn send_read_receipts(manager: &mut SignalManager, newly_read: BTreeMap<String, Vec<u64>>) {
+    if newly_read.is_empty() {
+        return;
+    }
+    if let Err(e) = outbound::ensure_allowed(outbound::Outbound::Receipt) {
+        warn!("Not sending read receipts: {}", e);
+        return;
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    for (sender_aci, timestamps) in newly_read {
+        let Ok(uuid) = sender_aci.parse::<Uuid>() else {
+            continue;
+        };
+        let receipt = ReceiptMessage {
+            r#type: Some(receipt_message::Type::Read as i32),
+            timestamp: timestamps,
+        };
+        if let Err(e) = outbound::send_message(
+            manager,
+            ServiceId::Aci(uuid.into()),
+            ContentBody::ReceiptMessage(receipt),
+            now,
+        )
+        .await
+        {
+            warn!("Failed to send read receipt to {}: {}", sender_aci, e);
+        }
+    }
+}
+
+/// A chat's message by its ID, with the ID's sent timestamp.
+async fn find_message(
+    manager: &SignalManager,
+    thread: &Thread,
+    id: &str,
+) -> Result<(u64, Content)> {
+    let (ts, sender) = parse_message_id(id)?;
+    let content = manager
+        .store()
+        .message(thread, ts)
+        .await?
+        .filter(|content| {
+            let stored = content.metadata.sender.raw_uuid().to_string();
+            sender.is_none_or(|sender| sender == stored)
+        })
+        .ok_or_else(|| {
+            Error::Invalid(format!(
+                "No message with ID {} in chat {}",
+                id,
+                thread_chat_id(thread)
+            ))
+        })?;
+    Ok((ts, content))
+}
+
+/// Mark single messages read, each given as `(chat_id, message_id)`, and
+/// send read receipts if `receipts` says so.
+///
+/// Unlike [`mark_read`], the chats' watermarks don't move: earlier messages
+/// stay unread, so a client that handles messages out of order can record
+/// exactly which ones it has dealt with. My own messages count as read
+/// already.
+#[instrument(skip_all)]
+pub async fn mark_messages_read(
+    manager: &mut SignalManager,
+    messages: &[(String, String)],
+    receipts: ReadReceiptPolicy,
+) -> Result<MarkReadResult> {
+    let my_uuid = manager.whoami().await?.aci;
+    let mut read_db = open_read_sync_db()?;
+    let mut newly_read: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+
+    // Incoming messages by chat, each once
+    let mut incoming: BTreeMap<String, BTreeSet<(String, u64)>> = BTreeMap::new();
+    for (chat_id, id) in messages {
+        let thread = parse_thread(chat_id)?;
+        let (ts, content) = find_message(manager, &thread, id).await?;
+        let sender_uuid = content.metadata.sender.raw_uuid();
+        if sender_uuid == my_uuid {
+            continue;
+        }
+        incoming
+            .entry(thread_chat_id(&thread))
+            .or_default()
+            .insert((sender_uuid.to_string(), ts));
+    }
+    let mut to_mark = Vec::new();
+    for (chat_id, messages) in incoming {
+        let messages: Vec<(String, u64)> = messages.into_iter().collect();
+        let statuses = read_statuses(&read_db, &chat_id, &messages)?;
+        to_mark.extend(messages.into_iter().filter(|message| !statuses[message]));
+    }
+    let messages_marked = mark_reads(&mut read_db, &to_mark)?;
+    for (sender_aci, ts) in to_mark {
+        newly_read.entry(sender_aci).or_default().push(ts);
+    }
+
+    if receipts == ReadReceiptPolicy::Send {
+        send_read_receipts(manager, newly_read).await;
+    }
+
+    Ok(MarkReadResult {
+        chats_marked: 0,
+        messages_marked,
+    })
+}
+
+/// Make a chat's messages unread again: all of them, or with `after` only
+/// those sent after that message. Individual reads are forgotten and the
+/// watermark moves back, so a handler that failed partway can pick the
+/// messages up again. Returns how many messages were read before.
+#[instrument(skip_all)]
+pub async fn mark_unread(
+    manager: &SignalManager,
+    chat_id: &str,
+    after: Option<&str>,
+) -> Result<i64> {
+    let thread = parse_thread(chat_id)?;
+    let chat_id = thread_chat_id(&thread);
+    let after = match after {
+        Some(id) => Some(find_message(manager, &thread, id).await?.0),
+        None => None,
+    };
+    let my_uuid = manager.whoami().await?.aci;
+    let mut conn = open_read_sync_db()?;
+
+    let mut to_unmark: Vec<(String, u64)> = Vec::new();
+    let from = after.map_or(0, |ts| ts + 1);
+    for content in manager.store().messages(&thread, from..).await?.flatten() {
+        let ContentBody::DataMessage(dm) = &content.body else {
+            continue;
+        };
+        let Some(ts) = dm.timestamp else {
+            continue;
+        };
+        let sender_uuid = content.metadata.sender.raw_uuid();
+        if sender_uuid == my_uuid {
+            continue;
+        }
+        to_unmark.push((sender_uuid.to_string(), ts));
+    }
+    let statuses = read_statuses(&conn, &chat_id, &to_unmark)?;
+    let messages_marked = to_unmark
+        .iter()
+        .filter(|message| statuses[*message])
+        .count() as i64;
+
+    let tx = conn.transaction()?;
+    delete_reads(&tx, &to_unmark)?;
+    match after {
+        Some(ts) => {
+            tx.execute(
+                "UPDATE read_watermarks SET timestamp = ?2 WHERE chat_id = ?1 AND timestamp > ?2",
+                rusqlite::params![chat_id, ts as i64],
+            )?;
+        }
+        None => {
+            tx.execute("DELETE FROM read_watermarks WHERE chat_id = ?1", [&chat_id])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(messages_marked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT: &str = "chat";
+
+    fn db() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        local::migrate(&mut conn).unwrap();
+        conn
+    }
+
+    fn message(sender: &str, timestamp: u64) -> (String, u64) {
+        (sender.to_string(), timestamp)
+    }
+
+    #[test]
+    fn read_statuses_match_is_read() {
+        let mut conn = db();
+        advance_watermark(&conn, CHAT, 200).unwrap();
+        mark_reads(&mut conn, &[message("a", 300), message("b", 500)]).unwrap();
+        // Another chat's watermark doesn't count
+        advance_watermark(&conn, "other", 1000).unwrap();
+
+        let messages = [
+            // At or below the watermark
+            message("a", 100),
+            message("b", 200),
+            // Read individually
+            message("a", 300),
+            message("b", 500),
+            // Unread, including a read of the same time by someone else
+            message("b", 300),
+            message("a", 400),
+        ];
+        let statuses = read_statuses(&conn, CHAT, &messages).unwrap();
+        for (message, expected) in messages.iter().zip([true, true, true, true, false, false]) {
+            assert_eq!(statuses[message], expected, "{:?}", message);
+            assert_eq!(is_read(&conn, CHAT, &message.0, message.1), expected);
+        }
+    }
+
+    #[test]
+    fn read_statuses_without_a_watermark() {
+        let mut conn = db();
+        mark_reads(&mut conn, &[message("a", 300)]).unwrap();
+        let statuses = read_statuses(&conn, CHAT, &[message("a", 300), message("a", 100)]).unwrap();
+        assert!(statuses[&message("a", 300)]);
+        assert!(!statuses[&message("a", 100)]);
+        assert!(read_statuses(&conn, CHAT, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_watermark_only_moves_forward() {
+        let conn = db();
+        advance_watermark(&conn, CHAT, 200).unwrap();
+        advance_watermark(&conn, CHAT, 100).unwrap();
+        assert_eq!(watermark(&conn, CHAT).unwrap(), Some(200));
+        assert_eq!(watermark(&conn, "other").unwrap(), None);
+    }
+}
@@ -0,0 +1,855 @@
+//! Draining the server queue.
+//!
+//! The server forgets a message once it's handed over, so each is saved, and
+//! logged to the event stream, before the next one is read. A run that dies
+//! partway leaves its checkpoint, `receive-checkpoint` in the data dir,
+//! holding where in the event log it started; the next run returns what that
+//! one logged along with its own.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
+
+use futures::{pin_mut, StreamExt};
+use presage::libsignal_service::content::{Content, ContentBody};
+use presage::libsignal_service::prelude::{GroupMasterKey, GroupSecretParams, Uuid};
+use presage::model::messages::Received;
+use presage::proto::{sync_message, DataMessage, SyncMessage, TypingMessage};
+use presage::store::{ContentsStore, Thread};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info_span, instrument, warn, Instrument};
+
+use crate::calls::Calls;
+use crate::identity::{self, check_identity, IdentityPolicy};
+use crate::model::{
+    message_id, message_preview, reply_to, EventOutput, LoggedItem, MessageOutput, ReceivedItem,
+};
+use crate::timestamp::Timestamp;
+use crate::{
+    arrivals, deletes, events, expiry, get_data_dir, link, lock, mentions, outbound, parse_thread,
+    read_sync, roster, search, shared_contacts, status, stickers, stories, thread_chat_id,
+    view_once, Error, Result, SignalManager,
+};
+
+/// Result of draining the server queue.
+pub struct ReceiveResult {
+    /// Messages and events in arrival order, with reaction summaries last
+    pub items: Vec<ReceivedItem>,
+    /// Messages marked read by sync messages from other devices
+    pub read_sync_count: usize,
+}
+
+/// Result of [`sync_contacts`].
+pub struct ContactSyncResult {
+    /// Contacts the sync added or changed (name or phone number)
+    pub contacts_updated: usize,
+    pub contacts_total: usize,
+    /// Everything else that arrived while waiting, handled as `receive` does
+    pub received: ReceiveResult,
+}
+
+/// Categories of incoming content that `receive` can be told to drop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    /// Stories, and replies and reactions to them (mine from other devices too)
+    Stories,
+    /// Typing indicators
+    Typing,
+    /// Delivery, read and viewed receipts from other people
+    Receipts,
+    /// Call offers, answers and hangups, and call events synced from my other
+    /// devices
+    Calls,
+}
+
+impl ContentKind {
+    /// The category a piece of content falls in, if any
+    pub fn of(body: &ContentBody) -> Option<Self> {
+        match body {
+            ContentBody::StoryMessage(_) => Some(ContentKind::Stories),
+            ContentBody::DataMessage(dm) if dm.story_context.is_some() => {
+                Some(ContentKind::Stories)
+            }
+            ContentBody::TypingMessage(_) => Some(ContentKind::Typing),
+            ContentBody::ReceiptMessage(_) => Some(ContentKind::Receipts),
+            ContentBody::CallMessage(_) => Some(ContentKind::Calls),
+            ContentBody::DataMessage(dm) if dm.group_call_update.is_some() => {
+                Some(ContentKind::Calls)
+            }
+            ContentBody::SynchronizeMessage(sm) => {
+                if sm.call_event.is_some() {
+                    return Some(ContentKind::Calls);
+                }
+                let sent = sm.sent.as_ref()?;
+                let story_reply = sent
+                    .message
+                    .as_ref()
+                    .is_some_and(|dm| dm.story_context.is_some());
+                (sent.story_message.is_some() || story_reply).then_some(ContentKind::Stories)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `TypingMessage` action for starting to type; the other is stopping
+const TYPING_STARTED: i32 = 0;
+
+static IGNORED: OnceLock<Vec<ContentKind>> = OnceLock::new();
+
+/// Drop these kinds of content in every later `receive`, before they're
+/// saved, indexed or logged. Only the first call takes effect.
+pub fn set_ignored(kinds: Vec<ContentKind>) {
+    let _ = IGNORED.set(kinds);
+}
+
+fn is_ignored(body: &ContentBody) -> bool {
+    let Some(kind) = ContentKind::of(body) else {
+        return false;
+    };
+    IGNORED.get().is_some_and(|ignored| ignored.contains(&kind))
+}
+
+/// The group a message was sent in, if any
+fn group_thread(dm: &DataMessage) -> Option<Thread> {
+    let master_key = dm.group_v2.as_ref()?.master_key.as_deref()?;
+    match <[u8; 32]>::try_from(master_key) {
+        Ok(key) => Some(Thread::Group(key)),
+        Err(_) => {
+            warn!("Ignoring group context with a {}-byte master key", master_key.len());
+            None
+        }
+    }
+}
+
+/// Chat IDs of the stored groups, by group ID. Chat IDs are made from the
+/// master key, but typing indicators only carry the ID derived from it.
+async fn group_chat_ids(manager: &SignalManager) -> Result<HashMap<Vec<u8>, String>> {
+    let mut ids = HashMap::new();
+    for (master_key, _) in manager.store().groups().await?.flatten() {
+        let params = GroupSecretParams::derive_from_master_key(GroupMasterKey::new(master_key));
+        ids.insert(
+            params.get_group_identifier().to_vec(),
+            thread_chat_id(&Thread::Group(master_key)),
+        );
+    }
+    Ok(ids)
+}
+
+/// The chat a typing indicator from `sender_aci` is for, looking up the
+/// groups' IDs the first time one is in a group
+async fn typing_chat_id(
+    manager: &SignalManager,
+    group_ids: &mut Option<HashMap<Vec<u8>, String>>,
+    sender_aci: &str,
+    typing: &TypingMessage,
+) -> Option<String> {
+    let Some(group_id) = &typing.group_id else {
+        return Some(sender_aci.to_string());
+    };
+    if group_ids.is_none() {
+        match group_chat_ids(manager).await {
+            Ok(ids) => *group_ids = Some(ids),
+            Err(e) => {
+                warn!("Failed to look up groups for typing indicators: {}", e);
+                return None;
+            }
+        }
+    }
+    group_ids.as_ref()?.get(group_id).cloned()
+}
+
+/// The thread a message I sent belongs in: its group, or the 1:1 chat with
+/// the recipient.
+fn sent_thread(sent: &sync_message::Sent, dm: &DataMessage) -> Option<Thread> {
+    if let Some(thread) = group_thread(dm) {
+        return Some(thread);
+    }
+    // PNI destinations ("PNI:<uuid>") have no contact thread to file under
+    let destination = sent.destination_service_id.as_deref()?;
+    destination.parse::<Uuid>().ok().map(Thread::Contact)
+}
+
+/// Save a `SyncMessage.Sent` transcript as an outgoing message in the thread
+/// it was sent to, so `messages` shows both halves of a conversation.
+///
+/// It's stored as a plain data message from me, the same shape as messages
+/// sent by this tool. Reactions and transcripts without a usable destination
+/// are skipped.
+async fn save_sent_transcript(
+    manager: &mut SignalManager,
+    search_db: Option<&rusqlite::Connection>,
+    expiry_db: Option<&rusqlite::Connection>,
+    deletes_db: Option<&rusqlite::Connection>,
+    content: &Content,
+    sm: &SyncMessage,
+) -> Option<ReceivedItem> {
+    let sent = sm.sent.as_ref()?;
+    let dm = sent.message.as_ref()?;
+    if dm.reaction.is_some() {
+        return None;
+    }
+    let Some(thread) = sent_thread(sent, dm) else {
+        warn!(
+            "Skipping sent transcript with no usable destination: {:?}",
+            sent.destination_service_id
+        );
+        return None;
+    };
+    let chat_id = thread_chat_id(&thread);
+    let ts = dm.timestamp.or(sent.timestamp).unwrap_or(0);
+
+    let my_aci = content.metadata.sender.raw_uuid().to_string();
+    let transcript = Content::from_body(dm.clone(), content.metadata.clone());
+    index(search_db, &chat_id, &transcript);
+    track_expiry(expiry_db, &chat_id, &my_aci, dm);
+    if let Err(e) = manager
+        .store()
+        .save_message(&thread, transcript)
+        .instrument(info_span!("persist", ts))
+        .await
+    {
+        warn!("Failed to save sent transcript: {}", e);
+    }
+    if let Some(target) = deletes::target(dm) {
+        delete(
+            manager, deletes_db, search_db, &chat_id, &my_aci, target, ts,
+        )
+        .await;
+    }
+
+    if roster::is_group_update(dm) {
+        return None;
+    }
+    if let Some(event) = EventOutput::from_data_message(&chat_id, &my_aci, dm) {
+        return Some(ReceivedItem::Event(event.sent_by_me()));
+    }
+    let (text, mentions, styles) = mentions::render(manager, dm).await;
+    let sticker = match &dm.sticker {
+        Some(sticker) => Some(stickers::render(manager, sticker, true).await),
+        None => None,
+    };
+    Some(ReceivedItem::Message(Box::new(MessageOutput {
+        id: message_id(ts, &my_aci),
+        chat_id,
+        sender: my_aci,
+        sender_name: None,
+        timestamp: Timestamp::from_millis(ts),
+        received_at: None,
+        clock_skew: false,
+        sender_device: u32::from(content.metadata.sender_device),
+        sealed_sender: content.metadata.unidentified_sender,
+        server_guid: content.metadata.server_guid.map(|guid| guid.to_string()),
+        text,
+        mentions,
+        styles,
+        sticker,
+        view_once: view_once::is_view_once(dm),
+        media: Vec::new(),
+        contacts: dm.contact.iter().map(shared_contacts::render).collect(),
+        story_id: stories::story_id(dm),
+        reply_to: reply_to(dm),
+        deleted: false,
+        deleted_at: None,
+        is_outgoing: true,
+        is_read: true,
+        expires_in: dm.expire_timer.filter(|&secs| secs > 0),
+    })))
+}
+
+/// Add a message to the search index; a failure only costs search results
+fn index(search_db: Option<&rusqlite::Connection>, chat_id: &str, content: &Content) {
+    if let Some(conn) = search_db {
+        if let Err(e) = search::add_content(conn, chat_id, content) {
+            warn!("Failed to index message: {}", e);
+        }
+    }
+}
+
+/// Replace a message its sender deleted for everyone with a tombstone; a
+/// failure leaves it as it was
+async fn delete(
+    manager: &SignalManager,
+    deletes_db: Option<&rusqlite::Connection>,
+    search_db: Option<&rusqlite::Connection>,
+    chat_id: &str,
+    sender: &str,
+    target: u64,
+    deleted_at: u64,
+) {
+    if let Some(conn) = deletes_db {
+        let result = deletes::apply(
+            manager, conn, search_db, chat_id, sender, target, deleted_at,
+        )
+        .await;
+        if let Err(e) = result {
+            warn!("Failed to delete {}: {}", message_id(target, sender), e);
+        }
+    }
+}
+
+/// Note a message's disappearing timer; a failure only means it won't be
+/// pruned
+fn track_expiry(
+    expiry_db: Option<&rusqlite::Connection>,
+    chat_id: &str,
+    sender: &str,
+    dm: &DataMessage,
+) {
+    if let Some(conn) = expiry_db {
+        if let Err(e) = expiry::observe(conn, chat_id, sender, dm) {
+            warn!("Failed to track disappearing message: {}", e);
+        }
+    }
+}
+
+/// Note when a message arrived; a failure only costs its `received_at`
+fn record_arrival(arrivals_db: Option<&mut Connection>, sender: &str, ts: u64, received_at: u64) {
+    if let Some(conn) = arrivals_db {
+        if let Err(e) = arrivals::record(conn, &[(sender.to_string(), ts, received_at)]) {
+            warn!("Failed to record arrival time: {}", e);
+        }
+    }
+}
+
+/// Append the items after `logged` to the event log, and move it past them; a
+/// failure only costs other consumers those items
+fn log(events_db: Option<&mut Connection>, items: &[ReceivedItem], logged: &mut usize) {
+    let Some(conn) = events_db else {
+        return;
+    };
+    if *logged < items.len() {
+        let batch: Vec<_> = items[*logged..]
+            .iter()
+            .map(|item| (item.kind(), Some(item.chat_id()), item))
+            .collect();
+        if let Err(e) = events::append(conn, &batch) {
+            warn!("Failed to log received messages to event stream: {}", e);
+        }
+        *logged = items.len();
+    }
+}
+
+fn checkpoint_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("receive-checkpoint"))
+}
+
+/// The event-log position the next `receive` resumes after, if an
+/// interrupted one (or a send) left items for it
+pub fn checkpoint() -> Result<Option<i64>> {
+    match std::fs::read_to_string(checkpoint_path()?) {
+        Ok(text) => Ok(text.trim().parse::<i64>().ok()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// What an interrupted run logged, if one was, and otherwise a checkpoint for
+/// this run. An interrupted run's checkpoint is kept, so it still covers
+/// both if this one is interrupted too.
+fn resume(events_db: &Connection) -> Result<Vec<ReceivedItem>> {
+    let Some(seq) = checkpoint()? else {
+        std::fs::write(checkpoint_path()?, events::last_seq(events_db)?.to_string())?;
+        return Ok(Vec::new());
+    };
+    Ok(events::after(events_db, seq, i64::MAX as usize)?
+        .into_iter()
+        .map(|record| {
+            ReceivedItem::Logged(LoggedItem {
+                kind: record.kind,
+                chat_id: record.chat_id.unwrap_or_default(),
+                data: record.data,
+            })
+        })
+        .collect())
+}
+
+fn clear_checkpoint() -> Result<()> {
+    match std::fs::remove_file(checkpoint_path()?) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Drain pending messages from the server.
+///
+/// Every message is saved to the store (including ones I sent from other
+/// devices), arrival times are recorded, and all returned items are appended
+/// to the event log, each as it's processed. Content of an ignored kind (see
+/// [`set_ignored`]) is dropped first. Items a run that was interrupted logged
+/// come first, as [`ReceivedItem::Logged`].
+#[instrument(skip_all)]
+pub async fn receive(manager: &mut SignalManager, policy: IdentityPolicy) -> Result<ReceiveResult> {
+    Ok(drain(manager, policy, false, false).await?.0)
+}
+
+/// Each contact's (name, phone number), by UUID
+async fn contact_details(
+    manager: &SignalManager,
+) -> Result<HashMap<Uuid, (String, Option<String>)>> {
+    Ok(manager
+        .store()
+        .contacts()
+        .await?
+        .flatten()
+        .map(|contact| {
+            let phone = contact.phone_number.map(|p| p.format().to_string());
+            (contact.uuid, (contact.name, phone))
+        })
+        .collect())
+}
+
+/// Ask the primary device for its contacts and wait until the sync has been
+/// processed.
+///
+/// The reply comes through the message queue, so this drains it as `receive`
+/// does, saving and logging whatever else arrives first. It waits for as long
+/// as the reply takes; callers wanting a limit should time it out.
+#[instrument(skip_all)]
+pub async fn sync_contacts(
+    manager: &mut SignalManager,
+    policy: IdentityPolicy,
+) -> Result<ContactSyncResult> {
+    let before = contact_details(manager).await?;
+    outbound::request_contacts(manager).await?;
+    let (received, contacts_synced) = drain(manager, policy, true, false).await?;
+    if !contacts_synced {
+        return Err(Error::network(
+            "Connection closed before the contact sync arrived",
+        ));
+    }
+    let after = contact_details(manager).await?;
+
+    let contacts_updated = after
+        .iter()
+        .filter(|(uuid, details)| before.get(uuid) != Some(details))
+        .count();
+    Ok(ContactSyncResult {
+        contacts_updated,
+        contacts_total: after.len(),
+        received,
+    })
+}
+
+/// Drain the queue before a send, so sessions are current.
+///
+/// What arrives is handled as [`receive`] handles it: saved, and appended to
+/// the event log. The checkpoint is kept, so the next `receive` returns it
+/// (as [`ReceivedItem::Logged`]) instead of it being lost to the send.
+#[instrument(skip_all)]
+pub(crate) async fn drain_before_send(
+    manager: &mut SignalManager,
+    policy: IdentityPolicy,
+) -> Result<()> {
+    drain(manager, policy, false, true).await?;
+    Ok(())
+}
+
+/// Drain the queue until it's empty, and with `until_contacts` also until a
+/// contact sync has been processed. Also returns whether one was. With
+/// `keep_checkpoint`, the items aren't the caller's to return, so the next
+/// run picks them up.
+async fn drain(
+    manager: &mut SignalManager,
+    policy: IdentityPolicy,
+    until_contacts: bool,
+    keep_checkpoint: bool,
+) -> Result<(ReceiveResult, bool)> {
+    lock::lock_instance()?;
+    let _lock = lock::lock_store()?;
+    // Open read sync database
+    let mut read_db = read_sync::open_read_sync_db()?;
+    let identity_db = identity::open_identity_db()?;
+    let search_db = search::open_search_db()
+        .inspect_err(|e| warn!("Failed to open search index: {}", e))
+        .ok();
+    let expiry_db = expiry::open_expiry_db()
+        .inspect_err(|e| warn!("Failed to open expiry database: {}", e))
+        .ok();
+    let view_once_db = view_once::open_view_once_db()?;
+    let deletes_db = deletes::open_deletes_db()
+        .inspect_err(|e| warn!("Failed to open deletes database: {}", e))
+        .ok();
+    let mut arrivals_db = arrivals::open_arrivals_db()
+        .inspect_err(|e| warn!("Failed to open arrivals database: {}", e))
+        .ok();
+    let mut events_db = events::open_events_db()
+        .inspect_err(|e| warn!("Failed to open event log: {}", e))
+        .ok();
+    // Sender -> whether their messages may be shown; checked once per run
+    let mut identity_allowed: HashMap<Uuid, bool> = HashMap::new();
+
+    let my_uuid = manager.whoami().await?.aci;
+
+    let mut received_messages = match &events_db {
+        Some(conn) => resume(conn).unwrap_or_else(|e| {
+            warn!("Failed to check for an interrupted receive: {}", e);
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    // Those are in the log already
+    let mut logged = received_messages.len();
+    let mut read_sync_count = 0;
+    let mut queue_empty = false;
+    let mut contacts_synced = false;
+    // (chat_id, target timestamp, emoji) -> (reactors, latest reaction timestamp)
+    let mut reactions: BTreeMap<(String, u64, String), (Vec<String>, u64)> = BTreeMap::new();
+    // (sender, sent timestamp) of view-once messages opened here
+    let mut views_opened: Vec<(String, u64)> = Vec::new();
+    let mut calls = Calls::default();
+    // (chat_id, sender) -> (whether they're typing, when that was sent)
+    let mut typing: BTreeMap<(String, String), (bool, u64)> = BTreeMap::new();
+    let mut group_ids = None;
+    // Group chat_id -> sender of its last update message
+    let mut group_updates: HashMap<String, String> = HashMap::new();
+
+    let messages = manager
+        .receive_messages()
+        .instrument(info_span!("receive.connect"))
+        .await;
+    let messages = link::note_unlinked(messages.map_err(Error::from))?;
+    pin_mut!(messages);
+
+    loop {
+        // Before the next read, so a crash loses at most the message in hand
+        log(events_db.as_mut(), &received_messages, &mut logged);
+        // Each item is a network read plus decryption
+        let Some(content) = messages
+            .next()
+            .instrument(info_span!("receive.next"))
+            .await
+        else {
+            break;
+        };
+        match content {
+            Received::QueueEmpty => {
+                queue_empty = true;
+                if !until_contacts || contacts_synced {
+                    debug!("Queue empty, done syncing");
+                    break;
+                }
+                debug!("Queue empty, waiting for contacts sync");
+            }
+            Received::Contacts => {
+                debug!("Received contacts sync");
+                contacts_synced = true;
+                if until_contacts && queue_empty {
+                    break;
+                }
+            }
+            Received::Content(c) if is_ignored(&c.body) => {
+                debug!("Ignoring {:?}", ContentKind::of(&c.body));
+            }
+            Received::Content(c) => {
+                match &c.body {
+                    ContentBody::DataMessage(
+                        dm @ DataMessage {
+                            reaction: Some(reaction),
+                            timestamp,
+                            ..
+                        },
+                    ) => {
+                        // Reactions aren't messages. Ones to my messages are rolled up
+                        // into summaries below; the rest would just be empty noise.
+                        let targets_me = reaction.target_author_aci.as_deref()
+                            == Some(my_uuid.to_string().as_str());
+                        if let (true, Some(emoji), Some(target_ts), false) = (
+                            targets_me,
+                            reaction.emoji.clone(),
+                            reaction.target_sent_timestamp,
+                            reaction.remove.unwrap_or(false),
+                        ) {
+                            let sender_aci = c.metadata.sender.raw_uuid().to_string();
+                            let chat_id = match group_thread(dm) {
+                                Some(group) => thread_chat_id(&group),
+                                None => sender_aci.clone(),
+                            };
+                            let entry = reactions
+                                .entry((chat_id, target_ts, emoji))
+                                .or_default();
+                            if !entry.0.contains(&sender_aci) {
+                                entry.0.push(sender_aci);
+                            }
+                            entry.1 = entry.1.max(timestamp.unwrap_or(0));
+                        }
+                    }
+                    ContentBody::DataMessage(dm) => {
+                        let ts = dm.timestamp.unwrap_or(0);
+                        let sender_uuid = c.metadata.sender.raw_uuid();
+                        let sender_aci = sender_uuid.to_string();
+                        let received_at = std::time::SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_millis() as u64;
+                        record_arrival(arrivals_db.as_mut(), &sender_aci, ts, received_at);
+
+                        let allowed = match identity_allowed.get(&sender_uuid) {
+                            Some(&allowed) => allowed,
+                            None => {
+                                let (allowed, event) =
+                                    check_identity(manager, &identity_db, sender_uuid, policy)
+                                        .await
+                                        .unwrap_or_else(|e| {
+                                            warn!("Failed to check identity of {}: {}", sender_aci, e);
+                                            (true, None)
+                                        });
+                                if let Some(event) = event {
+                                    received_messages.push(ReceivedItem::Event(event));
+                                }
+                                identity_allowed.insert(sender_uuid, allowed);
+                                allowed
+                            }
+                        };
+
+                        // Under the block policy, untrusted senders' messages are
+                        // dropped unsaved, so nothing reading the store shows them
+                        if !allowed {
+                            continue;
+                        }
+
+                        // Save message to store for later retrieval, in its group if
+                        // it has one
+                        let thread = group_thread(dm).unwrap_or(Thread::Contact(sender_uuid));
+                        let chat_id = thread_chat_id(&thread);
+                        index(search_db.as_ref(), &chat_id, &c);
+                        track_expiry(expiry_db.as_ref(), &chat_id, &sender_aci, dm);
+                        if let Err(e) = manager
+                            .store()
+                            .save_message(&thread, (*c).clone())
+                            .instrument(info_span!("persist", ts))
+                            .await
+                        {
+                            warn!("Failed to save message: {}", e);
+                        }
+
+                        // What changed shows up as events once the roster is compared
+                        if roster::is_group_update(dm) {
+                            group_updates.insert(chat_id, sender_aci);
+                            continue;
+                        }
+                        if let Some(target) = deletes::target(dm) {
+                            delete(
+                                manager,
+                                deletes_db.as_ref(),
+                                search_db.as_ref(),
+                                &chat_id,
+                                &sender_aci,
+                                target,
+                                ts,
+                            )
+                            .await;
+                        }
+                        if let Some(event) =
+                            EventOutput::from_data_message(&chat_id, &sender_aci, dm)
+                        {
+                            received_messages.push(ReceivedItem::Event(event));
+                            continue;
+                        }
+
+                        // Check if this message was already read (from a previous sync)
+                        let is_read = read_sync::is_read(&read_db, &chat_id, &sender_aci, ts);
+                        let (text, mentions, styles) = mentions::render(manager, dm).await;
+                        let sticker = match &dm.sticker {
+                            Some(sticker) => Some(stickers::render(manager, sticker, true).await),
+                            None => None,
+                        };
+                        let view_once = view_once::is_view_once(dm);
+                        let media = if view_once {
+                            match view_once::open(manager, &view_once_db, &sender_aci, dm).await {
+                                Ok(Some(media)) => {
+                                    views_opened.push((sender_aci.clone(), ts));
+                                    media
+                                }
+                                Ok(None) => Vec::new(),
+                                Err(e) => {
+                                    warn!("Failed to open view-once message: {}", e);
+                                    Vec::new()
+                                }
+                            }
+                        } else {
+                            Vec::new()
+                        };
+
+                        received_messages.push(ReceivedItem::Message(Box::new(MessageOutput {
+                            id: message_id(ts, &sender_aci),
+                            chat_id,
+                            sender: sender_aci,
+                            sender_name: None,
+                            timestamp: Timestamp::from_millis(ts),
+                            received_at: Some(Timestamp::from_millis(received_at)),
+                            clock_skew: arrivals::is_clock_skewed(ts, received_at),
+                            sender_device: u32::from(c.metadata.sender_device),
+                            sealed_sender: c.metadata.unidentified_sender,
+                            server_guid: c.metadata.server_guid.map(|guid| guid.to_string()),
+                            text,
+                            mentions,
+                            styles,
+                            sticker,
+                            view_once,
+                            media,
+                            contacts: dm.contact.iter().map(shared_contacts::render).collect(),
+                            story_id: stories::story_id(dm),
+                            reply_to: reply_to(dm),
+                            deleted: false,
+                            deleted_at: None,
+                            is_outgoing: false,
+                            is_read,
+                            expires_in: dm.expire_timer.filter(|&secs| secs > 0),
+                        })));
+                    }
+                    ContentBody::SynchronizeMessage(sm) => {
+                        // Messages I sent from another device (e.g. my phone)
+                        if let Some(group) = sm
+                            .sent
+                            .as_ref()
+                            .and_then(|sent| sent.message.as_ref())
+                            .filter(|dm| roster::is_group_update(dm))
+                            .and_then(group_thread)
+                        {
+                            group_updates.insert(thread_chat_id(&group), my_uuid.to_string());
+                        }
+                        if let Some(item) = save_sent_transcript(
+                            manager,
+                            search_db.as_ref(),
+                            expiry_db.as_ref(),
+                            deletes_db.as_ref(),
+                            &c,
+                            sm,
+                        )
+                        .await
+                        {
+                            received_messages.push(item);
+                        }
+
+                        // Process read sync entries from other devices
+                        if !sm.read.is_empty() {
+                            match read_sync::process_sync_reads(manager, &mut read_db, &sm.read).await {
+                                Ok(count) => {
+                                    read_sync_count += count;
+                                    debug!("Processed {} read sync entries", count);
+                                }
+                                Err(e) => {
+                                    warn!("Failed to save read sync: {}", e);
+                                }
+                            }
+                        }
+
+                        // Whether my phone picked up a call
+                        if let Some(event) = &sm.call_event {
+                            calls.observe_sync(event);
+                        }
+
+                        // View-once media opened on another device isn't downloaded here
+                        if let Some(open) = &sm.view_once_open {
+                            if let Err(e) = view_once::process_sync_open(&view_once_db, open) {
+                                warn!("Failed to save view-once open: {}", e);
+                            }
+                        }
+                    }
+                    ContentBody::StoryMessage(story) if stories::included() => {
+                        let sender_aci = c.metadata.sender.raw_uuid().to_string();
+                        let event =
+                            stories::render(manager, &sender_aci, c.metadata.timestamp, story)
+                                .await;
+                        received_messages.push(ReceivedItem::Event(event));
+                    }
+                    ContentBody::TypingMessage(message) => {
+                        let sender_uuid = c.metadata.sender.raw_uuid();
+                        let sender_aci = sender_uuid.to_string();
+                        let chat_id =
+                            typing_chat_id(manager, &mut group_ids, &sender_aci, message).await;
+                        if let (Some(chat_id), false) = (chat_id, sender_uuid == my_uuid) {
+                            // Only the latest state matters
+                            let started =
+                                message.action.unwrap_or(TYPING_STARTED) == TYPING_STARTED;
+                            let ts = message.timestamp.unwrap_or(c.metadata.timestamp);
+                            typing.insert((chat_id, sender_aci), (started, ts));
+                        }
+                    }
+                    ContentBody::CallMessage(call) => {
+                        let caller = c.metadata.sender.raw_uuid();
+                        if caller != my_uuid {
+                            calls.observe(&caller.to_string(), c.metadata.timestamp, call);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    for ((chat_id, target_ts, emoji), (reactors, latest)) in reactions {
+        let target_text = match parse_thread(&chat_id) {
+            Ok(thread) => match manager.store().message(&thread, target_ts).await {
+                Ok(Some(content)) => message_preview(&content.body),
+                _ => None,
+            },
+            Err(_) => None,
+        };
+        received_messages.push(ReceivedItem::Event(EventOutput::ReactionSummary {
+            chat_id,
+            target_id: message_id(target_ts, &my_uuid.to_string()),
+            target_text,
+            emoji,
+            count: reactors.len(),
+            reactors,
+            timestamp: Timestamp::from_millis(latest),
+        }));
+    }
+
+    received_messages.extend(calls.events().into_iter().map(ReceivedItem::Event));
+    for ((chat_id, sender), (started, ts)) in typing {
+        received_messages.push(ReceivedItem::Event(EventOutput::Typing {
+            chat_id,
+            sender,
+            started,
+            timestamp: Timestamp::from_millis(ts),
+        }));
+    }
+
+    view_once::sync_opens(manager, my_uuid, views_opened).await;
+
+    // Contact syncs and group updates change the store as they're processed
+    match roster::open_roster_db() {
+        Ok(mut conn) => match roster::changes(manager, &mut conn, &group_updates).await {
+            Ok(events) => received_messages.extend(events.into_iter().map(ReceivedItem::Event)),
+            Err(e) => warn!("Failed to check for contact and group changes: {}", e),
+        },
+        Err(e) => warn!("Failed to open roster: {}", e),
+    }
+
+    // Reads synced above may have started some messages' countdowns
+    if let Err(e) = expiry::prune(manager).await {
+        warn!("Failed to prune disappearing messages: {}", e);
+    }
+
+    // Log to the event stream so every consumer sees these, not just this caller
+    log(events_db.as_mut(), &received_messages, &mut logged);
+    // Everything logged is returned, so the next run starts afresh
+    if !keep_checkpoint {
+        if let Err(e) = clear_checkpoint() {
+            warn!("Failed to remove the receive checkpoint: {}", e);
+        }
+        if let Some(conn) = &events_db {
+            if let Err(e) = events::trim(conn, None, None) {
+                warn!("Failed to trim the event log: {}", e);
+            }
+        }
+    }
+    if let Err(e) = status::record_receive() {
+        warn!("Failed to record the receive time: {}", e);
+    }
+
+    Ok((
+        ReceiveResult {
+            items: received_messages,
+            read_sync_count,
+        },
+        contacts_synced,
+    ))
+}
@@ -0,0 +1,128 @@
+//! Message retention.
+//!
+//! presage keeps every message it stores, so left alone the store only grows.
+//! [`prune`] deletes a chat's messages sent before a cutoff, and its oldest
+//! past a per-chat cap, from the store and the search index. Disappearing
+//! messages are deleted on their own schedule (see [`crate::expiry`]).
+
+use presage::store::{ContentsStore, Thread};
+use tracing::{instrument, warn};
+
+use crate::{search, thread_chat_id, Result, SignalManager};
+
+/// Which messages to keep. Each limit that's `None` keeps everything.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retention {
+    /// Delete messages sent before this, in milliseconds
+    pub before: Option<u64>,
+    /// Delete each chat's oldest messages beyond this many
+    pub max_messages_per_chat: Option<usize>,
+}
+
+impl Retention {
+    /// Whether a message sent at `ts` (milliseconds), the `index`th newest
+    /// in its chat counting from 0, goes
+    fn deletes(&self, index: usize, ts: u64) -> bool {
+        let expired = self.before.is_some_and(|before| ts < before);
+        let over_cap = self.max_messages_per_chat.is_some_and(|max| index >= max);
+        expired || over_cap
+    }
+}
+
+/// Delete the messages `retention` doesn't keep from the store and the search
+/// index. Returns how many were deleted.
+#[instrument(skip_all)]
+pub async fn prune(manager: &SignalManager, retention: Retention) -> Result<usize> {
+    if retention.before.is_none() && retention.max_messages_per_chat.is_none() {
+        return Ok(0);
+    }
+    let mut store = manager.store().clone();
+    let mut threads: Vec<Thread> = store
+        .contacts()
+        .await?
+        .flatten()
+        .map(|contact| Thread::Contact(contact.uuid))
+        .collect();
+    threads.extend(
+        store
+            .groups()
+            .await?
+            .flatten()
+            .map(|(master_key, _)| Thread::Group(master_key)),
+    );
+
+    let search_db = search::open_search_db()?;
+    let mut pruned = 0;
+    for thread in &threads {
+        let chat_id = thread_chat_id(thread);
+        // Newest first, so the cap counts from the most recent
+        let messages: Vec<(u64, String)> = store
+            .messages(thread, ..)
+            .await?
+            .rev()
+            .flatten()
+            .map(|content| {
+                let sender = content.metadata.sender.raw_uuid().to_string();
+                (content.metadata.timestamp, sender)
+            })
+            .collect();
+        for (index, (ts, sender)) in messages.into_iter().enumerate() {
+            if !retention.deletes(index, ts) {
+                continue;
+            }
+            match store.delete_message(thread, ts).await {
+                Ok(_) => pruned += 1,
+                Err(e) => {
+                    warn!("Failed to delete old message in {}: {}", chat_id, e);
+                    continue;
+                }
+            }
+            search::remove(&search_db, &chat_id, &sender, ts)?;
+        }
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_keeps_everything() {
+        let retention = Retention::default();
+        assert!(!retention.deletes(10_000, 0));
+    }
+
+    #[test]
+    fn cutoff_deletes_older_messages() {
+        let retention = Retention {
+            before: Some(1_000),
+            max_messages_per_chat: None,
+        };
+        assert!(retention.deletes(0, 999));
+        assert!(!retention.deletes(0, 1_000));
+        assert!(!retention.deletes(5_000, 2_000));
+    }
+
+    #[test]
+    fn cap_deletes_oldest_past_it() {
+        let retention = Retention {
+            before: None,
+            max_messages_per_chat: Some(2),
+        };
+        assert!(!retention.deletes(0, 0));
+        assert!(!retention.deletes(1, 0));
+        assert!(retention.deletes(2, u64::MAX));
+    }
+
+    #[test]
+    fn either_limit_deletes() {
+        let retention = Retention {
+            before: Some(1_000),
+            max_messages_per_chat: Some(2),
+        };
+        assert!(retention.deletes(0, 500));
+        assert!(retention.deletes(3, 2_000));
+        assert!(!retention.deletes(1, 2_000));
+    }
+}
@@ -0,0 +1,272 @@
+//! Contact and group changes, as events.
+//!
+//! The contacts and groups in the store change as sync data arrives: a
+//! contact sync from the phone, or a group update in a message. After each
+//! `receive`, the store is compared with the last known roster, kept here,
+//! and the differences become `contact_added`, `contact_updated`,
+//! `group_joined`, `group_left` and `group_change` events. The first run only
+//! records the roster, so existing chats don't all show up as new.
+//!
+//! A group update message carries the change encrypted, and presage applies
+//! it to the store rather than passing it on, so the update itself isn't
+//! shown as a message; only who sent it is kept, for the `group_change`
+//! events it caused.
+
+use std::collections::{BTreeSet, HashMap};
+
+use presage::libsignal_service::prelude::Uuid;
+use presage::proto::DataMessage;
+use presage::store::ContentsStore;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::model::EventOutput;
+use crate::{local, Result, SignalManager};
+
+pub fn open_roster_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// Whether a data message only announces a group change, which shows up as
+/// `group_change` events instead.
+pub fn is_group_update(dm: &DataMessage) -> bool {
+    dm.group_v2
+        .as_ref()
+        .is_some_and(|group| group.group_change.is_some())
+        && dm.body.as_deref().unwrap_or_default().is_empty()
+        && dm.attachments.is_empty()
+        && dm.sticker.is_none()
+        && dm.contact.is_empty()
+}
+
+/// A group as recorded in the roster. The details are `None` for groups
+/// recorded before they were, so nothing is reported for them until the next
+/// receive.
+struct GroupState {
+    title: String,
+    is_member: bool,
+    /// Member ACIs
+    members: Option<BTreeSet<String>>,
+    /// Empty for none
+    description: Option<String>,
+    /// Disappearing-messages timer in seconds, 0 for off
+    timer: Option<u32>,
+}
+
+/// What changed in a group I'm still a member of, other than my own
+/// membership, as `group_change` events
+fn group_changes(
+    chat_id: &str,
+    me: &str,
+    old: &GroupState,
+    new: &GroupState,
+    by: Option<&String>,
+) -> Vec<EventOutput> {
+    let event = |change, members, old_title, description, seconds| EventOutput::GroupChange {
+        chat_id: chat_id.to_string(),
+        title: new.title.clone(),
+        change,
+        by: by.cloned(),
+        members,
+        old_title,
+        description,
+        seconds,
+    };
+    let mut events = Vec::new();
+    if let (Some(old_members), Some(new_members)) = (&old.members, &new.members) {
+        let others = |a: &BTreeSet<String>, b: &BTreeSet<String>| -> Vec<String> {
+            a.difference(b).filter(|m| *m != me).cloned().collect()
+        };
+        for (change, members) in [
+            ("member_added", others(new_members, old_members)),
+            ("member_removed", others(old_members, new_members)),
+        ] {
+            if !members.is_empty() {
+                events.push(event(change, members, None, None, None));
+            }
+        }
+    }
+    if old.title != new.title {
+        let old_title = Some(old.title.clone());
+        events.push(event("name_changed", Vec::new(), old_title, None, None));
+    }
+    if let (Some(old_description), Some(description)) = (&old.description, &new.description) {
+        if old_description != description {
+            let description = Some(description.clone()).filter(|d| !d.is_empty());
+            events.push(event(
+                "description_changed",
+                Vec::new(),
+                None,
+                description,
+                None,
+            ));
+        }
+    }
+    if let (Some(old_timer), Some(timer)) = (old.timer, new.timer) {
+        if old_timer != timer {
+            events.push(event("timer_changed", Vec::new(), None, None, Some(timer)));
+        }
+    }
+    events
+}
+
+fn has_baseline(conn: &Connection) -> Result<bool> {
+    Ok(conn
+        .query_row("SELECT 1 FROM meta WHERE key = 'baseline'", [], |_| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+/// Compare the store's contacts and groups with the recorded roster, record
+/// the new one, and return what changed. `updated_by` maps the chat IDs of
+/// groups that had update messages to who sent the last one.
+pub async fn changes(
+    manager: &SignalManager,
+    conn: &mut Connection,
+    updated_by: &HashMap<String, String>,
+) -> Result<Vec<EventOutput>> {
+    let store = manager.store();
+    let my_uuid = manager.whoami().await?.aci;
+
+    let mut contacts: Vec<(String, String, Option<String>)> = Vec::new();
+    for contact in store.contacts().await?.flatten() {
+        contacts.push((
+            contact.uuid.to_string(),
+            contact.name,
+            contact.phone_number.map(|p| p.format().to_string()),
+        ));
+    }
+    let mut groups: Vec<(String, GroupState)> = Vec::new();
+    for (master_key, group) in store.groups().await?.flatten() {
+        let members: BTreeSet<String> = group
+            .members
+            .iter()
+            .map(|m| Uuid::from(m.aci).to_string())
+            .collect();
+        groups.push((
+            hex::encode(master_key),
+            GroupState {
+                title: group.title,
+                is_member: members.contains(&my_uuid.to_string()),
+                members: Some(members),
+                description: Some(group.description.unwrap_or_default()),
+                timer: Some(group.disappearing_messages_timer.map_or(0, |t| t.duration)),
+            },
+        ));
+    }
+
+    let known_contacts: HashMap<String, (String, Option<String>)> = conn
+        .prepare("SELECT uuid, name, phone FROM contacts")?
+        .query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?
+        .collect::<rusqlite::Result<_>>()?;
+    let known_groups: HashMap<String, GroupState> = conn
+        .prepare("SELECT id, title, is_member, members, description, timer FROM groups")?
+        .query_map([], |row| {
+            let members: Option<String> = row.get(3)?;
+            Ok((
+                row.get(0)?,
+                GroupState {
+                    title: row.get(1)?,
+                    is_member: row.get(2)?,
+                    members: members.and_then(|m| serde_json::from_str(&m).ok()),
+                    description: row.get(4)?,
+                    timer: row.get(5)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut events = Vec::new();
+    for (uuid, name, phone) in &contacts {
+        match known_contacts.get(uuid) {
+            None => events.push(EventOutput::ContactAdded {
+                chat_id: uuid.clone(),
+                name: name.clone(),
+                phone: phone.clone(),
+            }),
+            Some((old_name, old_phone)) => {
+                let mut changed = Vec::new();
+                if old_name != name {
+                    changed.push("name");
+                }
+                if old_phone != phone {
+                    changed.push("phone");
+                }
+                if !changed.is_empty() {
+                    events.push(EventOutput::ContactUpdated {
+                        chat_id: uuid.clone(),
+                        name: name.clone(),
+                        phone: phone.clone(),
+                        changed,
+                    });
+                }
+            }
+        }
+    }
+    let me = my_uuid.to_string();
+    for (id, group) in &groups {
+        let known = known_groups.get(id);
+        let was_member = known.is_some_and(|known| known.is_member);
+        if group.is_member && !was_member {
+            events.push(EventOutput::GroupJoined {
+                chat_id: id.clone(),
+                title: group.title.clone(),
+            });
+        } else if was_member && !group.is_member {
+            events.push(EventOutput::GroupLeft {
+                chat_id: id.clone(),
+                title: group.title.clone(),
+            });
+        } else if let (Some(known), true) = (known, group.is_member) {
+            events.extend(group_changes(id, &me, known, group, updated_by.get(id)));
+        }
+    }
+    // A group gone from the store entirely has been left too
+    for (id, known) in &known_groups {
+        if known.is_member && !groups.iter().any(|(current, _)| current == id) {
+            events.push(EventOutput::GroupLeft {
+                chat_id: id.clone(),
+                title: known.title.clone(),
+            });
+        }
+    }
+
+    let baseline = has_baseline(conn)?;
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM contacts", [])?;
+    for (uuid, name, phone) in &contacts {
+        tx.execute(
+            "INSERT INTO contacts (uuid, name, phone) VALUES (?1, ?2, ?3)",
+            rusqlite::params![uuid, name, phone],
+        )?;
+    }
+    tx.execute("DELETE FROM groups", [])?;
+    for (id, group) in &groups {
+        let members = group
+            .members
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        tx.execute(
+            "INSERT INTO groups (id, title, is_member, members, description, timer)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![
+                id,
+                group.title,
+                group.is_member,
+                members,
+                group.description,
+                group.timer
+            ],
+        )?;
+    }
+    tx.execute(
+        "INSERT OR IGNORE INTO meta (key, value) VALUES ('baseline', '1')",
+        [],
+    )?;
+    tx.commit()?;
+
+    if !baseline {
+        return Ok(Vec::new());
+    }
+    Ok(events)
+}
@@ -0,0 +1,243 @@
+//! Full-text search over stored message bodies.
+//!
+//! presage's store can only be read a thread at a time, so bodies are copied
+//! into an SQLite FTS5 index in `local.db`. `receive` and `send` add messages
+//! as they're stored; [`reindex`] walks the whole store to fill in anything
+//! older, and runs automatically the first time the index is searched.
+
+use std::collections::HashMap;
+
+use presage::libsignal_service::content::{Content, ContentBody};
+use presage::store::{ContentsStore, Thread};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::model::message_id;
+use crate::timestamp::Timestamp;
+use crate::{local, parse_thread, thread_chat_id, Error, Result, SignalManager};
+
+/// Restrictions on which messages [`search`] returns. Times are unix
+/// milliseconds, inclusive.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SearchFilters {
+    pub chat_id: Option<String>,
+    pub sender: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    pub id: String,
+    pub chat_id: String,
+    /// Contact or group name, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_name: Option<String>,
+    pub is_group: bool,
+    pub sender: String,
+    pub timestamp: Timestamp,
+    pub text: String,
+    /// The matching part of the text, with matches in [brackets]
+    pub snippet: String,
+}
+
+pub fn open_search_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// Add a message to the index. Empty bodies and messages already indexed are
+/// skipped. Returns whether it was added.
+pub fn add(
+    conn: &Connection,
+    chat_id: &str,
+    sender: &str,
+    timestamp: u64,
+    text: &str,
+) -> Result<bool> {
+    if text.trim().is_empty() {
+        return Ok(false);
+    }
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO messages (chat_id, sender, timestamp, text) VALUES (?1, ?2, ?3, ?4)",
+        rusqlite::params![chat_id, sender, timestamp as i64, text],
+    )?;
+    Ok(added > 0)
+}
+
+/// Add a stored message to the index if it's a data message with a body.
+pub fn add_content(conn: &Connection, chat_id: &str, content: &Content) -> Result<bool> {
+    let ContentBody::DataMessage(dm) = &content.body else {
+        return Ok(false);
+    };
+    let (Some(ts), Some(text)) = (dm.timestamp, dm.body.as_deref()) else {
+        return Ok(false);
+    };
+    let sender = content.metadata.sender.raw_uuid().to_string();
+    add(conn, chat_id, &sender, ts, text)
+}
+
+/// Drop a message from the index, e.g. once it has disappeared.
+pub fn remove(conn: &Connection, chat_id: &str, sender: &str, timestamp: u64) -> Result<()> {
+    let params = rusqlite::params![chat_id, sender, timestamp as i64];
+    // The FTS table doesn't keep its own copy of the text, so it has to be
+    // told what to remove before the row goes
+    conn.execute(
+        "INSERT INTO messages_fts (messages_fts, rowid, text)
+         SELECT 'delete', id, text FROM messages
+         WHERE chat_id = ?1 AND sender = ?2 AND timestamp = ?3",
+        params,
+    )?;
+    conn.execute(
+        "DELETE FROM messages WHERE chat_id = ?1 AND sender = ?2 AND timestamp = ?3",
+        params,
+    )?;
+    Ok(())
+}
+
+/// Index a message this device just sent, as the store saved it.
+pub async fn add_stored(manager: &SignalManager, thread: &Thread, timestamp: u64) -> Result<()> {
+    if let Some(content) = manager.store().message(thread, timestamp).await? {
+        add_content(&open_search_db()?, &thread_chat_id(thread), &content)?;
+    }
+    Ok(())
+}
+
+pub fn is_empty(conn: &Connection) -> Result<bool> {
+    Ok(
+        conn.query_row("SELECT NOT EXISTS (SELECT 1 FROM messages)", [], |row| {
+            row.get(0)
+        })?,
+    )
+}
+
+/// Index every stored message in every contact and group thread. Returns how
+/// many weren't indexed before.
+#[instrument(skip_all)]
+pub async fn reindex(manager: &SignalManager, conn: &mut Connection) -> Result<usize> {
+    let store = manager.store();
+    let mut threads: Vec<Thread> = store
+        .contacts()
+        .await?
+        .flatten()
+        .map(|contact| Thread::Contact(contact.uuid))
+        .collect();
+    threads.extend(
+        store
+            .groups()
+            .await?
+            .flatten()
+            .map(|(master_key, _)| Thread::Group(master_key)),
+    );
+
+    let tx = conn.transaction()?;
+    let mut added = 0;
+    for thread in &threads {
+        let chat_id = thread_chat_id(thread);
+        for content in store.messages(thread, ..).await?.flatten() {
+            if add_content(&tx, &chat_id, &content)? {
+                added += 1;
+            }
+        }
+    }
+    tx.commit()?;
+
+    Ok(added)
+}
+
+/// SQLITE_ERROR from running the query means FTS5 couldn't parse it (e.g.
+/// "fts5: syntax error near ..." or "unterminated string"); that's the
+/// caller's mistake, not a storage failure
+fn query_error(e: rusqlite::Error) -> Error {
+    match &e {
+        rusqlite::Error::SqliteFailure(err, Some(message))
+            if err.code == rusqlite::ErrorCode::Unknown =>
+        {
+            Error::Invalid(format!("Invalid search query: {}", message))
+        }
+        _ => e.into(),
+    }
+}
+
+/// Messages matching an FTS5 `query` (words, "phrases", prefix*, AND/OR/NOT),
+/// best match first. An empty index is filled from the store first.
+#[instrument(skip_all)]
+pub async fn search(
+    manager: &SignalManager,
+    conn: &mut Connection,
+    query: &str,
+    filters: &SearchFilters,
+    max_results: usize,
+) -> Result<Vec<SearchHit>> {
+    if is_empty(conn)? {
+        reindex(manager, conn).await?;
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT m.chat_id, m.sender, m.timestamp, m.text,
+                snippet(messages_fts, 0, '[', ']', '…', 12)
+         FROM messages_fts JOIN messages m ON m.id = messages_fts.rowid
+         WHERE messages_fts MATCH ?1
+           AND (?2 IS NULL OR m.chat_id = ?2)
+           AND (?3 IS NULL OR m.sender = ?3)
+           AND (?4 IS NULL OR m.timestamp >= ?4)
+           AND (?5 IS NULL OR m.timestamp <= ?5)
+         ORDER BY rank, m.timestamp DESC
+         LIMIT ?6",
+    )?;
+    let rows = stmt
+        .query_map(
+            rusqlite::params![
+                query,
+                filters.chat_id,
+                filters.sender,
+                filters.since.map(|ts| ts as i64),
+                filters.until.map(|ts| ts as i64),
+                max_results as i64,
+            ],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            },
+        )
+        .map_err(query_error)?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(query_error)?;
+
+    // Chat ID -> (name, is_group), looked up once per chat
+    let mut chats: HashMap<String, (Option<String>, bool)> = HashMap::new();
+    let mut hits = Vec::new();
+    for (chat_id, sender, ts, text, snippet) in rows {
+        if !chats.contains_key(&chat_id) {
+            let chat = match parse_thread(&chat_id)? {
+                Thread::Contact(uuid) => {
+                    let contact = manager.store().contact_by_id(&uuid).await?;
+                    (contact.map(|c| c.name).filter(|n| !n.is_empty()), false)
+                }
+                Thread::Group(master_key) => {
+                    let group = manager.store().group(master_key).await?;
+                    (group.map(|g| g.title), true)
+                }
+            };
+            chats.insert(chat_id.clone(), chat);
+        }
+        let (chat_name, is_group) = chats[&chat_id].clone();
+        hits.push(SearchHit {
+            id: message_id(ts as u64, &sender),
+            chat_id,
+            chat_name,
+            is_group,
+            sender,
+            timestamp: Timestamp::from_millis(ts as u64),
+            text,
+            snippet,
+        });
+    }
+
+    Ok(hits)
+}
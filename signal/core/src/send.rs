@@ -0,0 +1,679 @@
+//! Sending a message to one or more recipients, or to a group.
+
+use std::time::UNIX_EPOCH;
+
+use presage::libsignal_service::content::ContentBody;
+use presage::libsignal_service::prelude::Uuid;
+use presage::libsignal_service::protocol::ServiceId;
+use presage::libsignal_service::sender::AttachmentSpec;
+use presage::proto::data_message::{Contact, Sticker};
+use presage::proto::{DataMessage, GroupContextV2, Preview};
+use presage::store::{ContentsStore, Thread};
+use tracing::{instrument, warn};
+
+use crate::identity::{self, check_identity, IdentityPolicy};
+use crate::mentions::{self, Mention};
+use crate::styles::{self, StyleRange};
+use crate::{
+    expiry, outbound, receive, search, thread_chat_id, usage, Error, Result, SignalManager,
+};
+
+/// Largest attachment the Signal servers accept
+pub const MAX_ATTACHMENT_BYTES: usize = 100 * 1024 * 1024;
+
+/// Longest text Signal sends as a plain message, in characters
+pub const MAX_TEXT_CHARS: usize = 2000;
+
+/// Room kept at the end of each part of a split message for its `(n/m)`
+const PART_MARKER_CHARS: usize = 16;
+
+/// What to send: text, attachments, or both, or a sticker.
+pub struct Outgoing {
+    pub text: Option<String>,
+    pub attachments: Vec<(AttachmentSpec, Vec<u8>)>,
+    /// Styles over spans of `text` (see [`styles::parse_markdown`])
+    pub styles: Vec<StyleRange>,
+    /// Preview of a link in `text`, shown under the message
+    pub preview: Option<LinkPreview>,
+    /// A sticker from an installed pack (see [`stickers::outgoing`])
+    pub sticker: Option<OutgoingSticker>,
+    /// Send the attachment as a view-once photo or video, which the recipient
+    /// can open only once
+    pub view_once: bool,
+    /// A contact card (see [`crate::shared_contacts`])
+    pub contact: Option<Contact>,
+}
+
+/// A link's title, description and image, as fetched by the sender; Signal
+/// clients don't fetch previews of received links themselves.
+pub struct LinkPreview {
+    /// Must appear in the message text
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<(AttachmentSpec, Vec<u8>)>,
+}
+
+/// A sticker to send, with its image for uploading.
+pub struct OutgoingSticker {
+    pub pack_id: Vec<u8>,
+    pub pack_key: Vec<u8>,
+    pub sticker_id: u32,
+    pub emoji: Option<String>,
+    pub image: (AttachmentSpec, Vec<u8>),
+}
+
+/// Outcome of [`send`].
+pub struct SendReport {
+    /// Sent timestamp in milliseconds, shared by every copy
+    pub timestamp: u64,
+    /// Number of recipients the message was delivered to
+    pub delivered: usize,
+    /// Recipients that were skipped or failed
+    pub failed: Vec<String>,
+    /// Why each recipient in `failed` was skipped or failed
+    pub errors: Vec<(Uuid, String)>,
+    /// Recipients whose safety number changed since it was last seen
+    pub identity_changed: Vec<Uuid>,
+}
+
+/// Check that a message can be sent as it is, before anything goes out.
+pub fn validate(outgoing: &Outgoing) -> Result<()> {
+    let has_text = outgoing
+        .text
+        .as_deref()
+        .is_some_and(|t| !t.trim().is_empty());
+    if !has_text
+        && outgoing.attachments.is_empty()
+        && outgoing.sticker.is_none()
+        && outgoing.contact.is_none()
+    {
+        return Err(Error::Invalid(
+            "Message has no text or attachments".to_string(),
+        ));
+    }
+    let length = outgoing.text.as_deref().map_or(0, |t| t.chars().count());
+    if length > MAX_TEXT_CHARS {
+        return Err(Error::Invalid(format!(
+            "Message is {} characters; Signal's limit is {} (split it with --split)",
+            length, MAX_TEXT_CHARS
+        )));
+    }
+    if let Some(range) = outgoing
+        .styles
+        .iter()
+        .find(|range| range.length == 0 || range.start + range.length > length)
+    {
+        return Err(Error::Invalid(format!(
+            "Style {:?} at {}:{} is empty or runs past the text",
+            range.style, range.start, range.length
+        )));
+    }
+    if let Some(preview) = &outgoing.preview {
+        if !outgoing
+            .text
+            .as_deref()
+            .is_some_and(|t| t.contains(&preview.url))
+        {
+            return Err(Error::Invalid(format!(
+                "Link preview for {} needs the link in the text",
+                preview.url
+            )));
+        }
+    }
+    if outgoing.view_once {
+        let media = match outgoing.attachments.as_slice() {
+            [(spec, _)] => {
+                spec.content_type.starts_with("image/") || spec.content_type.starts_with("video/")
+            }
+            _ => false,
+        };
+        if has_text || !media || outgoing.sticker.is_some() {
+            return Err(Error::Invalid(
+                "A view-once message is a single photo or video, with no text".to_string(),
+            ));
+        }
+    }
+    for (spec, data) in &outgoing.attachments {
+        let name = spec.file_name.as_deref().unwrap_or("attachment");
+        if data.is_empty() {
+            return Err(Error::Invalid(format!("{} is empty", name)));
+        }
+        if data.len() > MAX_ATTACHMENT_BYTES {
+            return Err(Error::Invalid(format!(
+                "{} is {} bytes; Signal's limit is {}",
+                name,
+                data.len(),
+                MAX_ATTACHMENT_BYTES
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Split a message whose text is too long to send into parts that fit, each
+/// ending `(n/m)`, to send in order. Parts break between paragraphs if they
+/// can, then lines, sentences and words. Styles are cut to the part they fall
+/// in, and attachments go with the first part.
+pub fn split(outgoing: Outgoing) -> Vec<Outgoing> {
+    let chars: Vec<char> = outgoing
+        .text
+        .as_deref()
+        .unwrap_or_default()
+        .chars()
+        .collect();
+    if chars.len() <= MAX_TEXT_CHARS {
+        return vec![outgoing];
+    }
+
+    // Each part's span of the text, in chars
+    let budget = MAX_TEXT_CHARS - PART_MARKER_CHARS;
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let mut cut = chars.len();
+        if cut - pos > budget {
+            let window = pos + budget / 2..pos + budget;
+            let after = |i: usize| chars.get(i).is_none_or(|c| c.is_whitespace());
+            cut = window
+                .clone()
+                .rev()
+                .find(|&i| chars[i] == '\n' && chars[i - 1] == '\n')
+                .or_else(|| window.clone().rev().find(|&i| chars[i] == '\n'))
+                .or_else(|| {
+                    window
+                        .clone()
+                        .rev()
+                        .find(|&i| matches!(chars[i - 1], '.' | '!' | '?') && after(i))
+                })
+                .or_else(|| window.clone().rev().find(|&i| chars[i].is_whitespace()))
+                .unwrap_or(pos + budget);
+        }
+        let mut end = cut;
+        while end > pos && chars[end - 1].is_whitespace() {
+            end -= 1;
+        }
+        if end > pos {
+            spans.push((pos, end));
+        }
+        pos = cut;
+        while pos < chars.len() && chars[pos].is_whitespace() {
+            pos += 1;
+        }
+    }
+
+    let total = spans.len();
+    let mut attachments = Some(outgoing.attachments);
+    let mut preview = outgoing.preview;
+    spans
+        .into_iter()
+        .enumerate()
+        .map(|(i, (start, end))| {
+            let mut text: String = chars[start..end].iter().collect();
+            text.push_str(&format!(" ({}/{})", i + 1, total));
+            let styles = outgoing
+                .styles
+                .iter()
+                .filter_map(|range| {
+                    let from = range.start.max(start);
+                    let to = (range.start + range.length).min(end);
+                    (to > from).then(|| StyleRange {
+                        style: range.style,
+                        start: from - start,
+                        length: to - from,
+                    })
+                })
+                .collect();
+            // The preview goes with the part holding its link
+            let preview = match &preview {
+                Some(p) if text.contains(&p.url) => preview.take(),
+                _ => None,
+            };
+            Outgoing {
+                text: Some(text),
+                attachments: attachments.take().unwrap_or_default(),
+                styles,
+                preview,
+                sticker: None,
+                view_once: false,
+                contact: None,
+            }
+        })
+        .collect()
+}
+
+/// Upload a link preview's image and build the preview to attach.
+async fn build_preview(manager: &SignalManager, preview: LinkPreview) -> Result<Preview> {
+    let image = match preview.image {
+        Some(image) => outbound::upload_attachments(manager, vec![image])
+            .await?
+            .pop(),
+        None => None,
+    };
+    Ok(Preview {
+        url: Some(preview.url),
+        title: preview.title,
+        description: preview.description,
+        image,
+        ..Default::default()
+    })
+}
+
+/// Upload a sticker's image and build the sticker to attach.
+async fn build_sticker(manager: &SignalManager, sticker: OutgoingSticker) -> Result<Sticker> {
+    let data = outbound::upload_attachments(manager, vec![sticker.image])
+        .await?
+        .pop();
+    Ok(Sticker {
+        pack_id: Some(sticker.pack_id),
+        pack_key: Some(sticker.pack_key),
+        sticker_id: Some(sticker.sticker_id),
+        data,
+        emoji: sticker.emoji,
+    })
+}
+
+/// Send a message, giving each recipient their own copy.
+///
+/// With `best_effort`, a recipient blocked by `policy` or whose send fails is
+/// recorded in [`SendReport::failed`] and the rest still get the message.
+/// Otherwise the first failure is returned. Fails if nobody got the message.
+#[instrument(skip_all, fields(recipients = recipients.len()))]
+pub async fn send(
+    manager: &mut SignalManager,
+    mut recipients: Vec<Uuid>,
+    outgoing: Outgoing,
+    policy: IdentityPolicy,
+    best_effort: bool,
+) -> Result<SendReport> {
+    outbound::ensure_allowed(outbound::Outbound::Message)?;
+    validate(&outgoing)?;
+
+    let mut failed: Vec<String> = Vec::new();
+    let mut errors = Vec::new();
+    let mut identity_changed = Vec::new();
+    if policy != IdentityPolicy::Trust {
+        let conn = identity::open_identity_db()?;
+        let mut blocked = Vec::new();
+        for &uuid in &recipients {
+            let (allowed, event) = check_identity(manager, &conn, uuid, policy).await?;
+            if event.is_some() || !allowed {
+                identity_changed.push(uuid);
+            }
+            if !allowed {
+                blocked.push(uuid);
+            }
+        }
+        if !best_effort && !blocked.is_empty() {
+            return Err(Error::UntrustedIdentity(blocked[0]));
+        }
+        recipients.retain(|uuid| !blocked.contains(uuid));
+        failed.extend(blocked.iter().map(|uuid| uuid.to_string()));
+        errors.extend(
+            blocked
+                .iter()
+                .map(|&uuid| (uuid, Error::UntrustedIdentity(uuid).to_string())),
+        );
+    }
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+
+    let attachments = if outgoing.attachments.is_empty() {
+        Vec::new()
+    } else {
+        outbound::upload_attachments(manager, outgoing.attachments).await?
+    };
+    let preview = match outgoing.preview {
+        Some(preview) => vec![build_preview(manager, preview).await?],
+        None => Vec::new(),
+    };
+    let sticker = match outgoing.sticker {
+        Some(sticker) => Some(build_sticker(manager, sticker).await?),
+        None => None,
+    };
+
+    // Build message
+    let body_ranges = match &outgoing.text {
+        Some(text) => styles::body_ranges(text, &outgoing.styles),
+        None => Vec::new(),
+    };
+    let data_message = DataMessage {
+        body: outgoing.text,
+        body_ranges,
+        attachments,
+        preview,
+        sticker,
+        is_view_once: outgoing.view_once.then_some(true),
+        contact: outgoing.contact.into_iter().collect(),
+        timestamp: Some(timestamp),
+        ..Default::default()
+    };
+
+    receive::drain_before_send(manager, policy).await?;
+
+    let expiry_db = expiry::open_expiry_db()?;
+    let mut delivered = 0;
+    for &uuid in &recipients {
+        // Each chat has its own disappearing-messages timer
+        let mut message = data_message.clone();
+        if let Some((seconds, version)) = expiry::outgoing_timer(manager, &expiry_db, uuid).await? {
+            message.expire_timer = Some(seconds);
+            message.expire_timer_version = Some(version);
+        }
+        let result = outbound::send_message(
+            manager,
+            ServiceId::Aci(uuid.into()),
+            ContentBody::DataMessage(message),
+            timestamp,
+        )
+        .await;
+        match result {
+            Ok(()) => delivered += 1,
+            Err(e) if best_effort => {
+                warn!("Failed to send to {}: {}", uuid, e);
+                failed.push(uuid.to_string());
+                errors.push((uuid, e.to_string()));
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+
+        // Usage accounting must never turn a delivered message into a failure
+        if let Err(e) = usage::open_usage_db()
+            .and_then(|conn| usage::record(&conn, &uuid.to_string(), usage::UsageKind::Send))
+        {
+            warn!("Failed to record usage: {}", e);
+        }
+        if let Err(e) = search::add_stored(manager, &Thread::Contact(uuid), timestamp).await {
+            warn!("Failed to index sent message: {}", e);
+        }
+        if let Err(e) =
+            expiry::observe_stored(manager, &expiry_db, &Thread::Contact(uuid), timestamp).await
+        {
+            warn!("Failed to track disappearing message: {}", e);
+        }
+    }
+
+    if delivered == 0 {
+        return Err(Error::Invalid(
+            "Message could not be sent to any recipient".to_string(),
+        ));
+    }
+
+    Ok(SendReport {
+        timestamp,
+        delivered,
+        failed,
+        errors,
+        identity_changed,
+    })
+}
+
+/// Send a message to a group, mentioning `mentions`, or failing those the
+/// members named by `@Name` tokens in the text (see [`mentions::find`]).
+///
+/// Every member gets the same message, so a member blocked by `policy` fails
+/// the whole send.
+#[instrument(skip_all)]
+pub async fn send_to_group(
+    manager: &mut SignalManager,
+    master_key: [u8; 32],
+    outgoing: Outgoing,
+    mentions: &[Mention],
+    policy: IdentityPolicy,
+) -> Result<SendReport> {
+    outbound::ensure_allowed(outbound::Outbound::Message)?;
+    validate(&outgoing)?;
+
+    let thread = Thread::Group(master_key);
+    let chat_id = thread_chat_id(&thread);
+    let group = manager
+        .store()
+        .group(master_key)
+        .await?
+        .ok_or_else(|| Error::InvalidRecipient(format!("No group with ID {}", chat_id)))?;
+    let my_uuid = manager.whoami().await?.aci;
+    let members: Vec<Uuid> = group
+        .members
+        .iter()
+        .map(|m| Uuid::from(m.aci))
+        .filter(|&uuid| uuid != my_uuid)
+        .collect();
+
+    let mut identity_changed = Vec::new();
+    if policy != IdentityPolicy::Trust {
+        let conn = identity::open_identity_db()?;
+        for &uuid in &members {
+            let (allowed, event) = check_identity(manager, &conn, uuid, policy).await?;
+            if event.is_some() || !allowed {
+                identity_changed.push(uuid);
+            }
+            if !allowed {
+                return Err(Error::UntrustedIdentity(uuid));
+            }
+        }
+    }
+
+    let (body, body_ranges) = match outgoing.text {
+        Some(text) => {
+            let mentions = if mentions.is_empty() {
+                mentions::find(manager, &members, &text).await?
+            } else {
+                mentions.to_vec()
+            };
+            if let Some(stranger) = mentions.iter().find(|m| !members.contains(&m.uuid)) {
+                return Err(Error::Invalid(format!(
+                    "{} isn't a member of the group",
+                    stranger.uuid
+                )));
+            }
+            let (body, ranges) = mentions::apply(&text, &mentions, &outgoing.styles)?;
+            (Some(body), ranges)
+        }
+        None if !mentions.is_empty() => {
+            return Err(Error::Invalid("Mentions need message text".to_string()))
+        }
+        None => (None, Vec::new()),
+    };
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let attachments = if outgoing.attachments.is_empty() {
+        Vec::new()
+    } else {
+        outbound::upload_attachments(manager, outgoing.attachments).await?
+    };
+    let preview = match outgoing.preview {
+        Some(preview) => vec![build_preview(manager, preview).await?],
+        None => Vec::new(),
+    };
+    let sticker = match outgoing.sticker {
+        Some(sticker) => Some(build_sticker(manager, sticker).await?),
+        None => None,
+    };
+
+    let expiry_db = expiry::open_expiry_db()?;
+    let timer = expiry::timer(&expiry_db, &chat_id)?.filter(|&(seconds, _)| seconds > 0);
+    let data_message = DataMessage {
+        body,
+        body_ranges,
+        attachments,
+        preview,
+        sticker,
+        is_view_once: outgoing.view_once.then_some(true),
+        contact: outgoing.contact.into_iter().collect(),
+        group_v2: Some(GroupContextV2 {
+            master_key: Some(master_key.to_vec()),
+            revision: Some(group.revision),
+            ..Default::default()
+        }),
+        expire_timer: timer.map(|(seconds, _)| seconds),
+        timestamp: Some(timestamp),
+        ..Default::default()
+    };
+
+    receive::drain_before_send(manager, policy).await?;
+    outbound::send_to_group(
+        manager,
+        &master_key,
+        ContentBody::DataMessage(data_message),
+        timestamp,
+    )
+    .await?;
+
+    if let Err(e) = usage::open_usage_db()
+        .and_then(|conn| usage::record(&conn, &chat_id, usage::UsageKind::Send))
+    {
+        warn!("Failed to record usage: {}", e);
+    }
+    if let Err(e) = search::add_stored(manager, &thread, timestamp).await {
+        warn!("Failed to index sent message: {}", e);
+    }
+    if let Err(e) = expiry::observe_stored(manager, &expiry_db, &thread, timestamp).await {
+        warn!("Failed to track disappearing message: {}", e);
+    }
+
+    Ok(SendReport {
+        timestamp,
+        delivered: members.len(),
+        failed: Vec::new(),
+        errors: Vec::new(),
+        identity_changed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::styles::TextStyle;
+
+    fn text(text: &str, styles: Vec<StyleRange>) -> Outgoing {
+        Outgoing {
+            text: Some(text.to_string()),
+            attachments: Vec::new(),
+            styles,
+            preview: None,
+            sticker: None,
+            view_once: false,
+            contact: None,
+        }
+    }
+
+    fn style(style: TextStyle, start: usize, length: usize) -> StyleRange {
+        StyleRange {
+            style,
+            start,
+            length,
+        }
+    }
+
+    /// Each part's text without its `(n/m)`, checking the marker
+    fn bodies(parts: &[Outgoing]) -> Vec<String> {
+        let total = parts.len();
+        parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| {
+                let text = part.text.as_deref().unwrap();
+                assert!(
+                    text.chars().count() <= MAX_TEXT_CHARS,
+                    "part {} too long",
+                    i
+                );
+                validate(part).unwrap();
+                text.strip_suffix(&format!(" ({}/{})", i + 1, total))
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn short_text_is_left_alone() {
+        let text_at_limit = "a".repeat(MAX_TEXT_CHARS);
+        let parts = split(text(&text_at_limit, Vec::new()));
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].text.as_deref(), Some(text_at_limit.as_str()));
+    }
+
+    #[test]
+    fn splits_between_paragraphs_first() {
+        let a = "a".repeat(1200);
+        let b = "b".repeat(1200);
+        let mut outgoing = text(&format!("{}\n\n{}", a, b), Vec::new());
+        outgoing.attachments = vec![(AttachmentSpec::default(), vec![1])];
+        let parts = split(outgoing);
+        assert_eq!(bodies(&parts), [a, b]);
+        // Attachments go with the first part only
+        assert_eq!(parts[0].attachments.len(), 1);
+        assert!(parts[1].attachments.is_empty());
+    }
+
+    #[test]
+    fn splits_after_a_sentence_without_paragraphs() {
+        let text_in = "Hello there. ".repeat(200);
+        let bodies = bodies(&split(text(&text_in, Vec::new())));
+        assert!(bodies.len() > 1);
+        for body in &bodies {
+            assert!(
+                body.starts_with("Hello") && body.ends_with('.'),
+                "{:?}",
+                body
+            );
+        }
+        assert_eq!(bodies.join(" "), text_in.trim_end());
+    }
+
+    #[test]
+    fn counts_characters_not_bytes_or_utf16_units() {
+        // Four bytes and two UTF-16 units each, and nowhere to break
+        let emoji = "😀".repeat(3000);
+        let bodies = bodies(&split(text(&emoji, Vec::new())));
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(
+            bodies[0].chars().count(),
+            MAX_TEXT_CHARS - PART_MARKER_CHARS
+        );
+        assert_eq!(bodies.concat(), emoji);
+    }
+
+    #[test]
+    fn styles_are_cut_at_the_split_and_moved_into_each_part() {
+        let a = "a".repeat(1200);
+        let b = "b".repeat(1200);
+        let parts = split(text(
+            &format!("{}\n\n{}", a, b),
+            vec![
+                // Across the split
+                style(TextStyle::Bold, 1100, 200),
+                // Only over the dropped blank line
+                style(TextStyle::Spoiler, 1200, 2),
+                // Inside the second part
+                style(TextStyle::Italic, 2000, 10),
+            ],
+        ));
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].styles, [style(TextStyle::Bold, 1100, 100)]);
+        assert_eq!(
+            parts[1].styles,
+            [
+                style(TextStyle::Bold, 0, 98),
+                style(TextStyle::Italic, 798, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn styles_over_multibyte_text_stay_on_their_characters() {
+        // The cut falls after 1984 emoji, with no whitespace to drop
+        let emoji = "😀".repeat(3000);
+        let parts = split(text(&emoji, vec![style(TextStyle::Bold, 1980, 10)]));
+        assert_eq!(parts[0].styles, [style(TextStyle::Bold, 1980, 4)]);
+        assert_eq!(parts[1].styles, [style(TextStyle::Bold, 0, 6)]);
+    }
+}
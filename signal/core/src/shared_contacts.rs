@@ -0,0 +1,469 @@
+//! Shared contacts: a contact card sent as a message, which the Signal apps
+//! show with buttons to message or save the contact.
+//!
+//! `send --contact` shares a contact from the store, or the first card in a
+//! vCard (`.vcf`) file; received cards become [`SharedContactOutput`].
+//! Avatars are neither sent nor downloaded.
+
+use presage::libsignal_service::prelude::Uuid;
+use presage::proto::data_message::contact::{Email, Name, Phone, PostalAddress};
+use presage::proto::data_message::Contact;
+use presage::store::ContentsStore;
+
+use crate::model::{ContactAddressOutput, ContactDetailOutput, SharedContactOutput};
+use crate::{Error, Result, SignalManager};
+
+// Phone number and email types
+const HOME: i32 = 1;
+const MOBILE: i32 = 2;
+const WORK: i32 = 3;
+const CUSTOM: i32 = 4;
+
+// Postal address types, numbered differently
+const ADDRESS_HOME: i32 = 1;
+const ADDRESS_WORK: i32 = 2;
+const ADDRESS_CUSTOM: i32 = 3;
+
+/// A card for a contact in the store, with their name and phone number.
+pub async fn from_contact(manager: &SignalManager, uuid: Uuid) -> Result<Contact> {
+    let contact = manager
+        .store()
+        .contact_by_id(&uuid)
+        .await?
+        .ok_or_else(|| Error::Invalid(format!("No contact with UUID {}", uuid)))?;
+    let name = contact.name.trim();
+    if name.is_empty() {
+        return Err(Error::Invalid(format!(
+            "Contact {} has no name to share",
+            uuid
+        )));
+    }
+    let phone = contact
+        .phone_number
+        .map(|p| p.format().to_string())
+        .ok_or_else(|| Error::Invalid(format!("{} has no phone number to share", name)))?;
+    let (given_name, family_name) = match name.split_once(' ') {
+        Some((given, family)) => (given, Some(family.to_string())),
+        None => (name, None),
+    };
+    Ok(Contact {
+        name: Some(Name {
+            given_name: Some(given_name.to_string()),
+            family_name,
+            ..Default::default()
+        }),
+        number: vec![Phone {
+            value: Some(phone),
+            r#type: Some(MOBILE),
+            label: None,
+        }],
+        ..Default::default()
+    })
+}
+
+/// The first card in vCard text (versions 2.1 to 4.0): its name,
+/// organization, phone numbers, emails and postal addresses.
+pub fn from_vcard(text: &str) -> Result<Contact> {
+    // A line starting with a space or tab continues the one before
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    let mut contact = Contact::default();
+    let mut full_name = None;
+    let mut in_card = false;
+    for line in &lines {
+        let Some((head, value)) = line.split_once(':') else {
+            continue;
+        };
+        let mut params = head.split(';');
+        // Properties may be grouped, as in `item1.TEL`
+        let property = params
+            .next()
+            .and_then(|name| name.rsplit('.').next())
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        // `TYPE=cell,voice` in vCard 3 and 4, or bare `CELL` in 2.1
+        let types: Vec<String> = params
+            .flat_map(|param| {
+                let param = param.to_ascii_lowercase();
+                match param.split_once('=') {
+                    Some(("type", values)) => values
+                        .split(',')
+                        .map(|v| v.trim_matches('"').to_string())
+                        .collect(),
+                    Some(_) => Vec::new(),
+                    None => vec![param],
+                }
+            })
+            .collect();
+        let has_type = |name: &str| types.iter().any(|t| t == name);
+
+        match property.as_str() {
+            "BEGIN" if value.eq_ignore_ascii_case("VCARD") => in_card = true,
+            "END" if in_card && value.eq_ignore_ascii_case("VCARD") => break,
+            _ if !in_card => {}
+            "FN" => full_name = Some(unescape(value)),
+            "N" => {
+                let mut parts = components(value).into_iter().map(non_empty);
+                let name = contact.name.get_or_insert_with(Name::default);
+                name.family_name = parts.next().flatten();
+                name.given_name = parts.next().flatten();
+                name.middle_name = parts.next().flatten();
+                name.prefix = parts.next().flatten();
+                name.suffix = parts.next().flatten();
+            }
+            "NICKNAME" => {
+                contact.name.get_or_insert_with(Name::default).nickname = non_empty(unescape(value))
+            }
+            "ORG" => contact.organization = components(value).into_iter().find_map(non_empty),
+            "TEL" => {
+                let number = unescape(value);
+                let kind = if has_type("home") {
+                    HOME
+                } else if has_type("work") {
+                    WORK
+                } else {
+                    MOBILE
+                };
+                contact.number.push(Phone {
+                    value: non_empty(number.trim_start_matches("tel:").to_string()),
+                    r#type: Some(kind),
+                    label: None,
+                });
+            }
+            "EMAIL" => {
+                let kind = if has_type("work") { WORK } else { HOME };
+                contact.email.push(Email {
+                    value: non_empty(unescape(value)),
+                    r#type: Some(kind),
+                    label: None,
+                });
+            }
+            "ADR" => {
+                let mut parts = components(value).into_iter().map(non_empty);
+                let pobox = parts.next().flatten();
+                // The extended address (e.g. an apartment) has no field of
+                // its own, so it's kept with the street
+                let extended = parts.next().flatten();
+                let street = parts.next().flatten();
+                let street = match (street, extended) {
+                    (Some(street), Some(extended)) => Some(format!("{}, {}", street, extended)),
+                    (street, extended) => street.or(extended),
+                };
+                let kind = if has_type("work") {
+                    ADDRESS_WORK
+                } else {
+                    ADDRESS_HOME
+                };
+                contact.address.push(PostalAddress {
+                    r#type: Some(kind),
+                    label: None,
+                    street,
+                    pobox,
+                    neighborhood: None,
+                    city: parts.next().flatten(),
+                    region: parts.next().flatten(),
+                    postcode: parts.next().flatten(),
+                    country: parts.next().flatten(),
+                });
+            }
+            _ => {}
+        }
+    }
+    contact.number.retain(|phone| phone.value.is_some());
+    contact.email.retain(|email| email.value.is_some());
+
+    // A card with only a formatted name gets it split into given and family
+    if contact
+        .name
+        .as_ref()
+        .is_none_or(|name| display_name(name).is_none())
+    {
+        if let Some(full_name) = full_name
+            .as_deref()
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+        {
+            let name = contact.name.get_or_insert_with(Name::default);
+            let (given, family) = match full_name.split_once(' ') {
+                Some((given, family)) => (given, Some(family.to_string())),
+                None => (full_name, None),
+            };
+            name.given_name = Some(given.to_string());
+            name.family_name = family;
+        }
+    }
+    if contact.name.as_ref().and_then(display_name).is_none() {
+        return Err(Error::Invalid(
+            "vCard has no contact with a name".to_string(),
+        ));
+    }
+    Ok(contact)
+}
+
+/// A vCard value with its escapes undone
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n' | 'N') => out.push('\n'),
+                Some(c) => out.push(c),
+                None => {}
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The `;`-separated parts of a structured vCard value, unescaped
+fn components(value: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => {
+                part.push(if matches!(c, 'n' | 'N') { '\n' } else { c });
+                escaped = false;
+            }
+            '\\' => escaped = true,
+            ';' => parts.push(std::mem::take(&mut part)),
+            c => part.push(c),
+        }
+    }
+    parts.push(part);
+    parts
+}
+
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// A card's name as it would be shown, falling back to the nickname
+fn display_name(name: &Name) -> Option<String> {
+    let full = [
+        &name.prefix,
+        &name.given_name,
+        &name.middle_name,
+        &name.family_name,
+        &name.suffix,
+    ]
+    .into_iter()
+    .flatten()
+    .map(|part| part.trim())
+    .filter(|part| !part.is_empty())
+    .collect::<Vec<_>>()
+    .join(" ");
+    non_empty(full).or_else(|| name.nickname.clone().and_then(non_empty))
+}
+
+fn detail_type(kind: Option<i32>) -> Option<&'static str> {
+    match kind? {
+        HOME => Some("home"),
+        MOBILE => Some("mobile"),
+        WORK => Some("work"),
+        CUSTOM => Some("custom"),
+        _ => None,
+    }
+}
+
+fn address_type(kind: Option<i32>) -> Option<&'static str> {
+    match kind? {
+        ADDRESS_HOME => Some("home"),
+        ADDRESS_WORK => Some("work"),
+        ADDRESS_CUSTOM => Some("custom"),
+        _ => None,
+    }
+}
+
+/// A shared contact as JSON output.
+pub fn render(contact: &Contact) -> SharedContactOutput {
+    let detail = |value: &Option<String>, kind: Option<i32>, label: &Option<String>| {
+        Some(ContactDetailOutput {
+            value: value.clone().and_then(non_empty)?,
+            kind: detail_type(kind),
+            label: label.clone().and_then(non_empty),
+        })
+    };
+    let field = |value: &Option<String>| value.clone().and_then(non_empty);
+    SharedContactOutput {
+        name: contact.name.as_ref().and_then(display_name),
+        organization: field(&contact.organization),
+        phones: contact
+            .number
+            .iter()
+            .filter_map(|phone| detail(&phone.value, phone.r#type, &phone.label))
+            .collect(),
+        emails: contact
+            .email
+            .iter()
+            .filter_map(|email| detail(&email.value, email.r#type, &email.label))
+            .collect(),
+        addresses: contact
+            .address
+            .iter()
+            .map(|address| ContactAddressOutput {
+                kind: address_type(address.r#type),
+                label: field(&address.label),
+                street: field(&address.street),
+                pobox: field(&address.pobox),
+                neighborhood: field(&address.neighborhood),
+                city: field(&address.city),
+                region: field(&address.region),
+                postcode: field(&address.postcode),
+                country: field(&address.country),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn some(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    fn phone(value: &str, kind: i32) -> Phone {
+        Phone {
+            value: some(value),
+            r#type: Some(kind),
+            label: None,
+        }
+    }
+
+    #[test]
+    fn reads_the_first_card() {
+        let vcard = "\
+BEGIN:VCARD
+VERSION:4.0
+FN:Dr. Jane Q. Doe
+N:Doe;Jane;Q.;Dr.;
+ORG:Example\\, Inc.;Research
+TEL;TYPE=cell,voice:tel:+15551234567
+TEL;TYPE=\"work\":+15557654321
+TEL;TYPE=home:
+EMAIL;TYPE=work:jane@example.com
+item1.EMAIL:jane@home.example
+ADR;TYPE=home:;Apt 4;123 Main St;Springfield;IL;62701;USA
+END:VCARD
+BEGIN:VCARD
+FN:Someone Else
+TEL:+15550000000
+END:VCARD
+";
+        let contact = from_vcard(vcard).unwrap();
+        assert_eq!(
+            contact.name,
+            Some(Name {
+                given_name: some("Jane"),
+                family_name: some("Doe"),
+                prefix: some("Dr."),
+                middle_name: some("Q."),
+                ..Default::default()
+            })
+        );
+        assert_eq!(contact.organization, some("Example, Inc."));
+        // The empty number is dropped
+        assert_eq!(
+            contact.number,
+            [phone("+15551234567", MOBILE), phone("+15557654321", WORK)]
+        );
+        assert_eq!(
+            contact.email,
+            [
+                Email {
+                    value: some("jane@example.com"),
+                    r#type: Some(WORK),
+                    label: None,
+                },
+                Email {
+                    value: some("jane@home.example"),
+                    r#type: Some(HOME),
+                    label: None,
+                },
+            ]
+        );
+        assert_eq!(
+            contact.address,
+            [PostalAddress {
+                r#type: Some(ADDRESS_HOME),
+                street: some("123 Main St, Apt 4"),
+                city: some("Springfield"),
+                region: some("IL"),
+                postcode: some("62701"),
+                country: some("USA"),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn reads_vcard_2_1_with_folded_lines() {
+        let vcard = "BEGIN:VCARD\r\nVERSION:2.1\r\nN:Smith;John\r\nTEL;HOME:+1555\r\n 0001\r\nNOTE:Line one\\nline two\r\nEND:VCARD\r\n";
+        let contact = from_vcard(vcard).unwrap();
+        assert_eq!(
+            contact.name.as_ref().and_then(display_name),
+            some("John Smith")
+        );
+        assert_eq!(contact.number, [phone("+15550001", HOME)]);
+    }
+
+    #[test]
+    fn falls_back_to_the_formatted_name_or_nickname() {
+        let name = |vcard: &str| from_vcard(vcard).unwrap().name.unwrap();
+        let ada = name("BEGIN:VCARD\nN:;;;;\nFN:Ada King Lovelace\nEND:VCARD");
+        assert_eq!(ada.given_name, some("Ada"));
+        assert_eq!(ada.family_name, some("King Lovelace"));
+        let nick = name("BEGIN:VCARD\nNICKNAME:Ace\nEND:VCARD");
+        assert_eq!(display_name(&nick), some("Ace"));
+    }
+
+    #[test]
+    fn refuses_cards_without_a_name() {
+        for vcard in [
+            "",
+            "not a vcard",
+            "BEGIN:VCARD\nTEL:+15551234567\nEND:VCARD",
+            // Outside the card
+            "FN:Jane Doe\nBEGIN:VCARD\nTEL:+15551234567\nEND:VCARD",
+            "BEGIN:VCARD\nFN:  \nEND:VCARD",
+        ] {
+            assert!(from_vcard(vcard).is_err(), "{:?}", vcard);
+        }
+    }
+
+    #[test]
+    fn unescapes_values() {
+        assert_eq!(unescape(r"a\,b\;c\\d\ne\N"), "a,b;c\\d\ne\n");
+        assert_eq!(components(r"a\;b;;c\,d"), ["a;b", "", "c,d"]);
+    }
+
+    #[test]
+    fn renders_cards_as_output() {
+        let contact = from_vcard(
+            "BEGIN:VCARD\nN:Doe;Jane\nTEL;TYPE=work:+15557654321\nADR;TYPE=work:;;1 Loop;Cupertino\nEND:VCARD",
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(render(&contact)).unwrap(),
+            json!({
+                "name": "Jane Doe",
+                "phones": [{"value": "+15557654321", "type": "work"}],
+                "addresses": [{"type": "work", "street": "1 Loop", "city": "Cupertino"}],
+            })
+        );
+    }
+}
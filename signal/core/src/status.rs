@@ -0,0 +1,110 @@
+//! Local facts for `status`: what's in the data dir, and when `receive` last
+//! finished.
+//!
+//! Everything here is read without loading the manager, so it's reported
+//! even when the account isn't linked or a `serve` daemon holds the store.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+
+use crate::timestamp::Timestamp;
+use crate::{get_data_dir, unlock, Result};
+
+/// Rows in the message store, each `None` if its table couldn't be read.
+#[derive(Serialize)]
+pub struct StoreCounts {
+    pub contacts: Option<i64>,
+    pub groups: Option<i64>,
+    pub messages: Option<i64>,
+}
+
+fn marker_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("last-receive"))
+}
+
+/// Record that a `receive` drained the queue.
+pub fn record_receive() -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    std::fs::write(marker_path()?, now.to_string())?;
+    Ok(())
+}
+
+/// When a `receive` last drained the queue, if one has.
+pub fn last_receive() -> Result<Option<Timestamp>> {
+    let path = marker_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let millis = std::fs::read_to_string(&path)?.trim().parse::<u64>().ok();
+    Ok(millis.map(Timestamp::from_millis))
+}
+
+/// Bytes under a path, following no symlinks
+fn size_of(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| size_of(&entry.path()))
+        .sum()
+}
+
+/// The data dir's total size in bytes, and each database's, with its WAL, by
+/// file name.
+pub fn sizes() -> Result<(u64, BTreeMap<String, u64>)> {
+    let data_dir = get_data_dir()?;
+    let mut databases = BTreeMap::new();
+    if data_dir.exists() {
+        for entry in std::fs::read_dir(&data_dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !name.ends_with(".db") {
+                continue;
+            }
+            let size = ["", "-wal", "-shm"]
+                .iter()
+                .map(|suffix| size_of(&data_dir.join(format!("{}{}", name, suffix))))
+                .sum();
+            databases.insert(name.to_string(), size);
+        }
+    }
+    Ok((size_of(&data_dir), databases))
+}
+
+/// Count the contacts, groups and messages in the message store, read-only.
+pub fn store_counts() -> Result<StoreCounts> {
+    let path = get_data_dir()?.join("signal.db");
+    let conn = path
+        .exists()
+        .then(|| Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY))
+        .transpose()?
+        // Counts are left out rather than failing if it can't be unlocked
+        .filter(|conn| unlock(conn).is_ok());
+    let count = |table: &str| {
+        conn.as_ref()?
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .ok()
+    };
+    Ok(StoreCounts {
+        contacts: count("contacts"),
+        groups: count("groups"),
+        messages: count("thread_messages"),
+    })
+}
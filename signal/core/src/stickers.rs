@@ -0,0 +1,169 @@
+//! Sticker packs and stickers.
+//!
+//! Packs installed on the phone sync into the store, images included, during
+//! `receive`; a sticker can only be sent from an installed pack. A received
+//! sticker's image is saved in the sticker directory (see [`set_sticker_dir`])
+//! as `<pack_id>/<sticker_id>.<ext>`, so output can point at the file.
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use presage::libsignal_service::sender::AttachmentSpec;
+use presage::proto::data_message::Sticker;
+use presage::store::ContentsStore;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::model::StickerOutput;
+use crate::send::{Outgoing, OutgoingSticker};
+use crate::{Error, Result, SignalManager};
+
+/// Where received stickers' images are saved, set once at startup
+static STICKER_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_sticker_dir(dir: PathBuf) {
+    let _ = STICKER_DIR.set(dir);
+}
+
+/// An installed sticker pack, as `sticker list` shows it
+#[derive(Serialize)]
+pub struct StickerPackOutput {
+    /// Hex pack ID, for `sticker send`
+    pub id: String,
+    pub title: String,
+    pub author: String,
+    pub stickers: Vec<StickerInfo>,
+}
+
+#[derive(Serialize)]
+pub struct StickerInfo {
+    pub id: u32,
+    /// Emoji the sticker stands for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emoji: Option<String>,
+}
+
+/// Installed sticker packs, by title.
+pub async fn list(manager: &SignalManager) -> Result<Vec<StickerPackOutput>> {
+    let mut packs = Vec::new();
+    for pack in manager.store().sticker_packs().await? {
+        let pack = pack?;
+        packs.push(StickerPackOutput {
+            id: hex::encode(&pack.id),
+            title: pack.manifest.title,
+            author: pack.manifest.author,
+            stickers: pack
+                .manifest
+                .stickers
+                .into_iter()
+                .map(|sticker| StickerInfo {
+                    id: sticker.id,
+                    emoji: sticker.emoji.filter(|e| !e.is_empty()),
+                })
+                .collect(),
+        });
+    }
+    packs.sort_by(|a, b| a.title.cmp(&b.title));
+    Ok(packs)
+}
+
+/// A message carrying sticker `sticker_id` of installed pack `pack_id` (hex).
+pub async fn outgoing(manager: &SignalManager, pack_id: &str, sticker_id: u32) -> Result<Outgoing> {
+    let id = hex::decode(pack_id)
+        .map_err(|_| Error::Invalid(format!("Invalid sticker pack ID: {}", pack_id)))?;
+    let pack = manager
+        .store()
+        .sticker_pack(&id)
+        .await?
+        .ok_or_else(|| Error::Invalid(format!("No installed sticker pack {}", pack_id)))?;
+    let sticker = pack
+        .manifest
+        .stickers
+        .into_iter()
+        .find(|sticker| sticker.id == sticker_id)
+        .ok_or_else(|| Error::Invalid(format!("Pack {} has no sticker {}", pack_id, sticker_id)))?;
+    let data = sticker.bytes.filter(|b| !b.is_empty()).ok_or_else(|| {
+        Error::Invalid(format!(
+            "Sticker {} of pack {} hasn't been downloaded yet",
+            sticker_id, pack_id
+        ))
+    })?;
+    let spec = AttachmentSpec {
+        content_type: sticker
+            .content_type
+            .unwrap_or_else(|| "image/webp".to_string()),
+        length: data.len(),
+        ..Default::default()
+    };
+    Ok(Outgoing {
+        text: None,
+        attachments: Vec::new(),
+        styles: Vec::new(),
+        preview: None,
+        sticker: Some(OutgoingSticker {
+            pack_id: pack.id,
+            pack_key: pack.key,
+            sticker_id,
+            emoji: sticker.emoji,
+            image: (spec, data),
+        }),
+        view_once: false,
+        contact: None,
+    })
+}
+
+/// File extension for a sticker image's content type
+fn extension(content_type: &str) -> &'static str {
+    match content_type {
+        "image/png" | "image/apng" => "png",
+        "image/gif" => "gif",
+        "application/x-lottie" | "application/json" => "json",
+        _ => "webp",
+    }
+}
+
+/// A received sticker, with the path of its image if it's been saved. With
+/// `download`, an image not saved yet is downloaded; a failure is logged and
+/// leaves the path out.
+pub async fn render(manager: &SignalManager, sticker: &Sticker, download: bool) -> StickerOutput {
+    let pack_id = hex::encode(sticker.pack_id.as_deref().unwrap_or_default());
+    let sticker_id = sticker.sticker_id.unwrap_or_default();
+    let path = match (STICKER_DIR.get(), &sticker.data) {
+        (Some(dir), Some(pointer)) => {
+            let content_type = pointer.content_type.as_deref().unwrap_or_default();
+            let file = format!("{}.{}", sticker_id, extension(content_type));
+            let path = dir.join(&pack_id).join(file);
+            if path.exists() {
+                Some(path)
+            } else if download {
+                let saved = async {
+                    let data = manager.get_attachment(pointer).await?;
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    std::fs::write(&path, data)?;
+                    Ok::<_, Error>(path)
+                };
+                match saved.await {
+                    Ok(path) => Some(path),
+                    Err(e) => {
+                        warn!(
+                            "Failed to download sticker {}/{}: {}",
+                            pack_id, sticker_id, e
+                        );
+                        None
+                    }
+                }
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    StickerOutput {
+        pack_id,
+        sticker_id,
+        emoji: sticker.emoji.clone().filter(|e| !e.is_empty()),
+        path,
+    }
+}
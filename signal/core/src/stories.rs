@@ -0,0 +1,107 @@
+//! Stories, when included (see [`set_included`]).
+//!
+//! By default `receive` drops stories as they arrive. When they're included,
+//! each becomes a `story` event in its author's chat (or its group's, for a
+//! group story), with the text of a text story or the caption of a photo or
+//! video, and the media saved in the story directory (see [`set_story_dir`])
+//! as `<id>.<ext>`. Stories aren't kept in the store, so they don't show up in
+//! `messages`; replies to them do, as messages in the chat with the story's ID
+//! in `story_id`.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use presage::proto::story_message::Attachment;
+use presage::proto::{DataMessage, StoryMessage};
+use tracing::warn;
+
+use crate::model::{message_id, EventOutput};
+use crate::timestamp::Timestamp;
+use crate::{Error, SignalManager};
+
+/// Where story media is saved, set once at startup
+static STORY_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+static INCLUDED: OnceLock<bool> = OnceLock::new();
+
+pub fn set_story_dir(dir: PathBuf) {
+    let _ = STORY_DIR.set(dir);
+}
+
+/// Return stories from every later `receive` instead of dropping them. Only
+/// the first call takes effect.
+pub fn set_included(included: bool) {
+    let _ = INCLUDED.set(included);
+}
+
+pub fn included() -> bool {
+    INCLUDED.get().copied().unwrap_or(false)
+}
+
+/// The ID of the story a message replies to, if it's a story reply
+pub fn story_id(dm: &DataMessage) -> Option<String> {
+    let context = dm.story_context.as_ref()?;
+    Some(message_id(
+        context.sent_timestamp?,
+        context.author_aci.as_deref()?,
+    ))
+}
+
+/// A story from `sender_aci`, sent at `timestamp`, as an event. Its media is
+/// downloaded if it hasn't been; a failure is logged and leaves the path out.
+pub async fn render(
+    manager: &SignalManager,
+    sender_aci: &str,
+    timestamp: u64,
+    story: &StoryMessage,
+) -> EventOutput {
+    let id = message_id(timestamp, sender_aci);
+    let chat_id = match story.group.as_ref().and_then(|g| g.master_key.as_ref()) {
+        Some(master_key) => hex::encode(master_key),
+        None => sender_aci.to_string(),
+    };
+    let (text, media) = match &story.attachment {
+        Some(Attachment::TextAttachment(text)) => (text.text.clone(), None),
+        Some(Attachment::FileAttachment(pointer)) => {
+            let media = match STORY_DIR.get() {
+                Some(dir) => {
+                    let extension = pointer
+                        .file_name
+                        .as_deref()
+                        .and_then(|name| Path::new(name).extension()?.to_str())
+                        .or_else(|| pointer.content_type.as_deref()?.split('/').nth(1))
+                        .filter(|ext| ext.chars().all(|c| c.is_ascii_alphanumeric()))
+                        .unwrap_or("bin");
+                    let path = dir.join(format!("{}.{}", id, extension));
+                    let saved = async {
+                        if !path.exists() {
+                            let data = manager.get_attachment(pointer).await?;
+                            std::fs::create_dir_all(dir)?;
+                            std::fs::write(&path, data)?;
+                        }
+                        Ok::<_, Error>(path)
+                    };
+                    match saved.await {
+                        Ok(path) => Some(path),
+                        Err(e) => {
+                            warn!("Failed to download story media of {}: {}", id, e);
+                            None
+                        }
+                    }
+                }
+                None => None,
+            };
+            (pointer.caption.clone(), media)
+        }
+        None => (None, None),
+    };
+    EventOutput::Story {
+        id,
+        chat_id,
+        sender: sender_aci.to_string(),
+        timestamp: Timestamp::from_millis(timestamp),
+        text: text.filter(|t| !t.is_empty()),
+        media,
+        allows_replies: story.allows_replies.unwrap_or(false),
+    }
+}
@@ -0,0 +1,283 @@
+//! Text styles: bold, italic, strikethrough, monospace and spoilers.
+//!
+//! A style is a body range carrying the style over a span of the text. On the
+//! wire, offsets count UTF-16 code units; here, as in the CLI's JSON, they
+//! count characters. `send --markdown` writes styles with a small Markdown
+//! subset: `*bold*`, `_italic_`, `~strikethrough~` and ```` ```monospace``` ````.
+
+use presage::proto::body_range::{AssociatedValue, Style};
+use presage::proto::BodyRange;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextStyle {
+    Bold,
+    Italic,
+    Strikethrough,
+    Monospace,
+    Spoiler,
+}
+
+impl TextStyle {
+    fn to_proto(self) -> Style {
+        match self {
+            TextStyle::Bold => Style::Bold,
+            TextStyle::Italic => Style::Italic,
+            TextStyle::Strikethrough => Style::Strikethrough,
+            TextStyle::Monospace => Style::Monospace,
+            TextStyle::Spoiler => Style::Spoiler,
+        }
+    }
+
+    fn from_proto(style: Style) -> Option<Self> {
+        match style {
+            Style::Bold => Some(TextStyle::Bold),
+            Style::Italic => Some(TextStyle::Italic),
+            Style::Strikethrough => Some(TextStyle::Strikethrough),
+            Style::Monospace => Some(TextStyle::Monospace),
+            Style::Spoiler => Some(TextStyle::Spoiler),
+            Style::None => None,
+        }
+    }
+
+    /// The style a Markdown delimiter character stands for
+    fn from_marker(c: char) -> Option<Self> {
+        match c {
+            '*' => Some(TextStyle::Bold),
+            '_' => Some(TextStyle::Italic),
+            '~' => Some(TextStyle::Strikethrough),
+            _ => None,
+        }
+    }
+}
+
+/// A style over a span of the text, in characters
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StyleRange {
+    pub style: TextStyle,
+    pub start: usize,
+    pub length: usize,
+}
+
+const FENCE: [char; 3] = ['`'; 3];
+
+/// Strip Markdown delimiters from `text`, returning the plain text and the
+/// styles they marked.
+///
+/// Like chat apps rather than full Markdown: `*`, `_` and `~` open only at
+/// the start of a word and before a non-space, close only at the end of one,
+/// and don't span lines, so `snake_case` and `2*3*4` stay as they are.
+/// Delimiters without a partner are kept as text. Text inside ```` ``` ````
+/// fences is monospace and not parsed further.
+pub fn parse_markdown(text: &str) -> (String, Vec<StyleRange>) {
+    let chars: Vec<char> = text.chars().collect();
+    let fence_at = |i: usize| chars.get(i..i + 3) == Some(&FENCE[..]);
+    let mut removed = vec![false; chars.len()];
+    // Styled spans, as (style, first char, char after the last) of `text`
+    let mut spans: Vec<(TextStyle, usize, usize)> = Vec::new();
+
+    let mut i = 0;
+    while i < chars.len() {
+        if fence_at(i) {
+            let close = (i + 4..chars.len()).find(|&j| fence_at(j));
+            if let Some(j) = close {
+                removed[i..i + 3].fill(true);
+                removed[j..j + 3].fill(true);
+                spans.push((TextStyle::Monospace, i + 3, j));
+                i = j + 3;
+                continue;
+            }
+        }
+        let marker = chars[i];
+        let Some(style) = TextStyle::from_marker(marker) else {
+            i += 1;
+            continue;
+        };
+        let opens = !removed[i]
+            && (i == 0 || !chars[i - 1].is_alphanumeric())
+            && chars.get(i + 1).is_some_and(|c| !c.is_whitespace());
+        if opens {
+            let close = (i + 2..chars.len())
+                .take_while(|&j| chars[j] != '\n' && !fence_at(j))
+                .find(|&j| {
+                    chars[j] == marker
+                        && !removed[j]
+                        && !chars[j - 1].is_whitespace()
+                        && chars.get(j + 1).is_none_or(|c| !c.is_alphanumeric())
+                });
+            if let Some(j) = close {
+                removed[i] = true;
+                removed[j] = true;
+                spans.push((style, i + 1, j));
+            }
+        }
+        i += 1;
+    }
+
+    // Where each char of `text` lands in the plain text
+    let mut plain = String::new();
+    let mut offsets = Vec::with_capacity(chars.len() + 1);
+    let mut count = 0;
+    for (&c, &removed) in chars.iter().zip(&removed) {
+        offsets.push(count);
+        if !removed {
+            plain.push(c);
+            count += 1;
+        }
+    }
+    offsets.push(count);
+
+    let mut styles: Vec<StyleRange> = spans
+        .into_iter()
+        .map(|(style, start, end)| StyleRange {
+            style,
+            start: offsets[start],
+            length: offsets[end] - offsets[start],
+        })
+        .filter(|range| range.length > 0)
+        .collect();
+    styles.sort_by_key(|range| range.start);
+    (plain, styles)
+}
+
+/// The body ranges for `styles` over `text`.
+pub fn body_ranges(text: &str, styles: &[StyleRange]) -> Vec<BodyRange> {
+    // UTF-16 offset of each char, and of the end
+    let mut offsets = vec![0];
+    for c in text.chars() {
+        offsets.push(offsets[offsets.len() - 1] + c.len_utf16() as u32);
+    }
+    let end = offsets.len() - 1;
+    styles
+        .iter()
+        .map(|range| {
+            let start = offsets[range.start.min(end)];
+            let stop = offsets[(range.start + range.length).min(end)];
+            BodyRange {
+                start: Some(start),
+                length: Some(stop - start),
+                associated_value: Some(AssociatedValue::Style(range.style.to_proto() as i32)),
+            }
+        })
+        .collect()
+}
+
+/// The styles among a received message's body ranges, with `offsets` giving
+/// the char of the shown text at each UTF-16 offset of the body (and its end).
+pub fn from_body_ranges(ranges: &[BodyRange], offsets: &[usize]) -> Vec<StyleRange> {
+    let Some(end) = offsets.len().checked_sub(1) else {
+        return Vec::new();
+    };
+    let mut styles: Vec<StyleRange> = ranges
+        .iter()
+        .filter_map(|range| {
+            let Some(AssociatedValue::Style(style)) = range.associated_value else {
+                return None;
+            };
+            let style = TextStyle::from_proto(Style::try_from(style).ok()?)?;
+            let start = (range.start? as usize).min(end);
+            let stop = (start + range.length? as usize).min(end);
+            Some(StyleRange {
+                style,
+                start: offsets[start],
+                length: offsets[stop] - offsets[start],
+            })
+        })
+        .filter(|range| range.length > 0)
+        .collect();
+    styles.sort_by_key(|range| range.start);
+    styles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TextStyle::*;
+
+    #[test]
+    fn parse_markdown_cases() {
+        // Markdown, the plain text, and its styles as (style, start, length)
+        type Case = (
+            &'static str,
+            &'static str,
+            &'static [(TextStyle, usize, usize)],
+        );
+        let cases: &[Case] = &[
+            ("plain", "plain", &[]),
+            (
+                "*bold* and _italic_",
+                "bold and italic",
+                &[(Bold, 0, 4), (Italic, 9, 6)],
+            ),
+            ("~gone~", "gone", &[(Strikethrough, 0, 4)]),
+            // Nested
+            (
+                "*bold _both_ bold*",
+                "bold both bold",
+                &[(Bold, 0, 14), (Italic, 5, 4)],
+            ),
+            // Inside words
+            ("snake_case_name", "snake_case_name", &[]),
+            ("2*3*4", "2*3*4", &[]),
+            // Unclosed, or not opening
+            ("*unclosed", "*unclosed", &[]),
+            ("* not bold*", "* not bold*", &[]),
+            ("**", "**", &[]),
+            ("*bold _not italic*", "bold _not italic", &[(Bold, 0, 16)]),
+            // Across lines
+            ("*a\nb*", "*a\nb*", &[]),
+            // Fences
+            (
+                "run ```*not bold*``` now",
+                "run *not bold* now",
+                &[(Monospace, 4, 10)],
+            ),
+            ("```unclosed", "```unclosed", &[]),
+            // Offsets count chars, however many bytes or UTF-16 units
+            (
+                "😀 *hi* é _x_",
+                "😀 hi é x",
+                &[(Bold, 2, 2), (Italic, 7, 1)],
+            ),
+        ];
+        for &(markdown, plain, styles) in cases {
+            let styles: Vec<StyleRange> = styles
+                .iter()
+                .map(|&(style, start, length)| StyleRange {
+                    style,
+                    start,
+                    length,
+                })
+                .collect();
+            assert_eq!(
+                parse_markdown(markdown),
+                (plain.to_string(), styles),
+                "{:?}",
+                markdown
+            );
+        }
+    }
+
+    #[test]
+    fn body_ranges_count_utf16_units() {
+        let (text, styles) = parse_markdown("😀 *hi* é _x_");
+        let ranges: Vec<_> = body_ranges(&text, &styles)
+            .iter()
+            .map(|range| (range.start, range.length))
+            .collect();
+        // The emoji is two units
+        assert_eq!(ranges, [(Some(3), Some(2)), (Some(8), Some(1))]);
+
+        // And back again
+        let mut offsets = Vec::new();
+        for (i, c) in text.chars().enumerate() {
+            offsets.extend(std::iter::repeat_n(i, c.len_utf16()));
+        }
+        offsets.push(text.chars().count());
+        assert_eq!(
+            from_body_ranges(&body_ranges(&text, &styles), &offsets),
+            styles
+        );
+    }
+}
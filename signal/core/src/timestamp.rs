@@ -0,0 +1,131 @@
+//! Message timestamps in output, written in the format `--time-format` picks.
+//!
+//! Signal timestamps are unix milliseconds. Output has always given them as
+//! unix seconds, which stays the default; `unix-ms` keeps the full precision,
+//! so messages sent within the same second still order, and `iso8601` writes
+//! RFC 3339 strings in UTC with milliseconds.
+
+use std::cell::Cell;
+use std::sync::OnceLock;
+
+use chrono::{DateTime, SecondsFormat};
+use serde::{Deserialize, Serialize, Serializer};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum TimeFormat {
+    /// Unix seconds
+    #[default]
+    Unix,
+    /// Unix milliseconds
+    UnixMs,
+    /// RFC 3339, e.g. `2025-01-15T09:30:00.123Z`
+    Iso8601,
+}
+
+static TIME_FORMAT: OnceLock<TimeFormat> = OnceLock::new();
+
+thread_local! {
+    static OVERRIDE: Cell<Option<TimeFormat>> = const { Cell::new(None) };
+}
+
+pub fn set_time_format(format: TimeFormat) {
+    let _ = TIME_FORMAT.set(format);
+}
+
+/// The format timestamps are currently serialized in
+pub fn time_format() -> TimeFormat {
+    OVERRIDE
+        .get()
+        .or_else(|| TIME_FORMAT.get().copied())
+        .unwrap_or_default()
+}
+
+/// Run `f` with timestamps serialized in `format`, e.g. to answer a client
+/// that asked for a different format than this process's.
+pub fn with_time_format<R>(format: TimeFormat, f: impl FnOnce() -> R) -> R {
+    let previous = OVERRIDE.replace(Some(format));
+    let result = f();
+    OVERRIDE.set(previous);
+    result
+}
+
+/// A point in time as unix milliseconds, serialized per [`time_format`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    pub fn from_millis(millis: u64) -> Self {
+        Timestamp(millis)
+    }
+
+    pub fn millis(self) -> u64 {
+        self.0
+    }
+
+    pub fn secs(self) -> i64 {
+        (self.0 / 1000) as i64
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match time_format() {
+            TimeFormat::Unix => serializer.serialize_i64(self.secs()),
+            TimeFormat::UnixMs => serializer.serialize_u64(self.0),
+            TimeFormat::Iso8601 => match DateTime::from_timestamp_millis(self.0 as i64) {
+                Some(time) => {
+                    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Millis, true))
+                }
+                // Out of chrono's range; only a corrupt timestamp gets here
+                None => serializer.serialize_u64(self.0),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(format: TimeFormat, timestamp: Timestamp) -> String {
+        with_time_format(format, || serde_json::to_string(&timestamp).unwrap())
+    }
+
+    #[test]
+    fn serializes_in_each_format() {
+        let timestamp = Timestamp::from_millis(1736933400123);
+        assert_eq!(json(TimeFormat::Unix, timestamp), "1736933400");
+        assert_eq!(json(TimeFormat::UnixMs, timestamp), "1736933400123");
+        assert_eq!(
+            json(TimeFormat::Iso8601, timestamp),
+            "\"2025-01-15T09:30:00.123Z\""
+        );
+        assert_eq!(
+            json(TimeFormat::Iso8601, Timestamp::from_millis(0)),
+            "\"1970-01-01T00:00:00.000Z\""
+        );
+    }
+
+    #[test]
+    fn out_of_range_timestamps_stay_numbers() {
+        // Past chrono's last year, 262143
+        let millis = 10u64.pow(17);
+        assert_eq!(
+            json(TimeFormat::Iso8601, Timestamp::from_millis(millis)),
+            millis.to_string()
+        );
+    }
+
+    #[test]
+    fn overrides_nest_and_restore() {
+        with_time_format(TimeFormat::UnixMs, || {
+            assert_eq!(time_format(), TimeFormat::UnixMs);
+            with_time_format(TimeFormat::Iso8601, || {
+                assert_eq!(time_format(), TimeFormat::Iso8601);
+            });
+            assert_eq!(time_format(), TimeFormat::UnixMs);
+        });
+    }
+}
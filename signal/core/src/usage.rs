@@ -0,0 +1,140 @@
+//! Per-chat usage accounting.
+//!
+//! Records every message this tool sends and every `--on-message` hook run
+//! with a timestamp, so operators can attribute LLM spend to the
+//! conversations that caused it. Kept in `local.db`, because presage's store
+//! is off-limits.
+//!
+//! Automatic replies (auto-reply rules and hook output) are also kept by
+//! sent time, so `receive` can hold back a chat's next one for a while and
+//! ignore replies to them: two bots answering each other would otherwise
+//! never stop.
+
+use std::collections::BTreeMap;
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::{local, Result};
+
+/// What a usage row counts.
+#[derive(Clone, Copy)]
+pub enum UsageKind {
+    /// A message sent by this tool
+    Send,
+    /// An `--on-message` hook run for a received message
+    Hook,
+}
+
+impl UsageKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            UsageKind::Send => "send",
+            UsageKind::Hook => "hook",
+        }
+    }
+}
+
+/// Aggregated usage for a single chat.
+#[derive(Serialize, Default)]
+pub struct ChatUsage {
+    pub chat_id: String,
+    pub messages_sent: i64,
+    pub hooks_run: i64,
+    pub first_at: i64,
+    pub last_at: i64,
+}
+
+pub fn open_usage_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// Record one usage event for a chat.
+pub fn record(conn: &Connection, chat_id: &str, kind: UsageKind) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    conn.execute(
+        "INSERT INTO usage (chat_id, kind, at) VALUES (?1, ?2, ?3)",
+        rusqlite::params![chat_id, kind.as_str(), now],
+    )?;
+
+    Ok(())
+}
+
+/// Record an automatic reply sent to a chat at `timestamp` (milliseconds).
+pub fn record_auto_reply(conn: &Connection, chat_id: &str, timestamp: u64) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO auto_replies (chat_id, timestamp) VALUES (?1, ?2)",
+        rusqlite::params![chat_id, timestamp as i64],
+    )?;
+    Ok(())
+}
+
+/// When the last automatic reply to a chat was sent, in milliseconds.
+pub fn last_auto_reply(conn: &Connection, chat_id: &str) -> Result<Option<u64>> {
+    Ok(conn
+        .query_row(
+            "SELECT MAX(timestamp) FROM auto_replies WHERE chat_id = ?1",
+            [chat_id],
+            |row| row.get::<_, Option<i64>>(0),
+        )
+        .optional()?
+        .flatten()
+        .map(|ts| ts as u64))
+}
+
+/// Whether the message sent at `timestamp` in a chat was an automatic reply.
+pub fn is_auto_reply(conn: &Connection, chat_id: &str, timestamp: u64) -> Result<bool> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM auto_replies WHERE chat_id = ?1 AND timestamp = ?2",
+            rusqlite::params![chat_id, timestamp as i64],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// Per-chat totals for events at or after `since` (unix seconds), busiest first.
+pub fn summarize(conn: &Connection, since: i64) -> Result<Vec<ChatUsage>> {
+    let mut stmt = conn.prepare(
+        "SELECT chat_id, kind, COUNT(*), MIN(at), MAX(at)
+         FROM usage WHERE at >= ?1
+         GROUP BY chat_id, kind",
+    )?;
+    let rows = stmt.query_map([since], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, i64>(3)?,
+            row.get::<_, i64>(4)?,
+        ))
+    })?;
+
+    let mut by_chat: BTreeMap<String, ChatUsage> = BTreeMap::new();
+    for row in rows {
+        let (chat_id, kind, count, first_at, last_at) = row?;
+        let entry = by_chat.entry(chat_id.clone()).or_insert_with(|| ChatUsage {
+            chat_id,
+            first_at,
+            last_at,
+            ..Default::default()
+        });
+        if kind == UsageKind::Send.as_str() {
+            entry.messages_sent += count;
+        } else if kind == UsageKind::Hook.as_str() {
+            entry.hooks_run += count;
+        }
+        entry.first_at = entry.first_at.min(first_at);
+        entry.last_at = entry.last_at.max(last_at);
+    }
+
+    let mut usage: Vec<ChatUsage> = by_chat.into_values().collect();
+    usage.sort_by_key(|u| std::cmp::Reverse(u.messages_sent + u.hooks_run));
+    Ok(usage)
+}
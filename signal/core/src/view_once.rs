@@ -0,0 +1,193 @@
+//! View-once photos and videos.
+//!
+//! The Signal apps show view-once media a single time, then delete it and
+//! send `SyncMessage.ViewOnceOpen` so the owner's other devices do too. Here,
+//! `receive` downloads a view-once message's media the first time it sees the
+//! message, into the quarantine directory (see [`set_quarantine_dir`]), and
+//! sends the same sync so the phone shows it as viewed. Opened messages are
+//! recorded, as are ones opened on another device first, and their media is
+//! never downloaded again.
+
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
+
+use presage::libsignal_service::content::ContentBody;
+use presage::libsignal_service::prelude::Uuid;
+use presage::libsignal_service::protocol::ServiceId;
+use presage::proto::sync_message::ViewOnceOpen;
+use presage::proto::{AttachmentPointer, DataMessage, SyncMessage};
+use rusqlite::{Connection, OptionalExtension};
+use tracing::warn;
+
+use crate::model::{message_id, parse_message_id};
+use crate::{local, outbound, Error, Result, SignalManager};
+
+/// Where view-once media is saved, set once at startup
+static QUARANTINE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_quarantine_dir(dir: PathBuf) {
+    let _ = QUARANTINE_DIR.set(dir);
+}
+
+pub fn open_view_once_db() -> Result<Connection> {
+    local::open_local_db()
+}
+
+/// Whether a message counts as view-once
+pub fn is_view_once(dm: &DataMessage) -> bool {
+    dm.is_view_once == Some(true)
+}
+
+fn is_opened(conn: &Connection, sender_aci: &str, timestamp: u64) -> Result<bool> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM opened WHERE sender_aci = ?1 AND timestamp = ?2",
+            rusqlite::params![sender_aci, timestamp as i64],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// Record a message as opened; the first time counts.
+fn record_opened(conn: &Connection, sender_aci: &str, timestamp: u64) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    conn.execute(
+        "INSERT OR IGNORE INTO opened (sender_aci, timestamp, opened_at) VALUES (?1, ?2, ?3)",
+        rusqlite::params![sender_aci, timestamp as i64, now],
+    )?;
+    Ok(())
+}
+
+/// Record a view-once message opened on another device, so its media isn't
+/// downloaded here.
+pub fn process_sync_open(conn: &Connection, open: &ViewOnceOpen) -> Result<()> {
+    if let (Some(sender_aci), Some(timestamp)) = (&open.sender_aci, open.timestamp) {
+        record_opened(conn, sender_aci, timestamp)?;
+    }
+    Ok(())
+}
+
+/// A file name for a view-once attachment, keeping its original extension
+/// where there is one
+fn file_name(id: &str, index: usize, pointer: &AttachmentPointer) -> String {
+    let extension = pointer
+        .file_name
+        .as_deref()
+        .and_then(|name| Path::new(name).extension()?.to_str())
+        .or_else(|| pointer.content_type.as_deref()?.split('/').nth(1))
+        .filter(|ext| ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or("bin");
+    format!("{}-{}.{}", id, index, extension)
+}
+
+/// Media in the quarantine directory with no opened message recorded for it,
+/// such as files left behind when `local.db` was reset.
+pub fn unreferenced_media(conn: &Connection) -> Result<Vec<PathBuf>> {
+    let Some(dir) = QUARANTINE_DIR.get().filter(|dir| dir.exists()) else {
+        return Ok(Vec::new());
+    };
+    let mut unreferenced = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        // Named `<message ID>-<index>.<ext>` (see `file_name`)
+        let message = path
+            .file_stem()
+            .and_then(|stem| stem.to_str()?.rsplit_once('-'))
+            .and_then(|(id, _)| parse_message_id(id).ok());
+        let referenced = match message {
+            Some((timestamp, Some(sender_aci))) => is_opened(conn, sender_aci, timestamp)?,
+            _ => false,
+        };
+        if !referenced && path.is_file() {
+            unreferenced.push(path);
+        }
+    }
+    unreferenced.sort();
+    Ok(unreferenced)
+}
+
+/// Open a received view-once message: download its media into the quarantine
+/// directory and record it as opened. Returns the saved files, or `None` if
+/// it was opened before (here or on another device). A failed download is
+/// logged; the message still counts as opened, as the media can't be fetched
+/// twice.
+pub async fn open(
+    manager: &SignalManager,
+    conn: &Connection,
+    sender_aci: &str,
+    dm: &DataMessage,
+) -> Result<Option<Vec<PathBuf>>> {
+    let timestamp = dm.timestamp.unwrap_or(0);
+    if is_opened(conn, sender_aci, timestamp)? {
+        return Ok(None);
+    }
+    let id = message_id(timestamp, sender_aci);
+    let mut paths = Vec::new();
+    if let Some(dir) = QUARANTINE_DIR.get() {
+        for (index, pointer) in dm.attachments.iter().enumerate() {
+            let path = dir.join(file_name(&id, index, pointer));
+            let saved = async {
+                let data = manager.get_attachment(pointer).await?;
+                // Only this user should be able to look at it
+                std::fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(dir)?;
+                std::fs::write(&path, data)?;
+                Ok::<_, Error>(())
+            };
+            match saved.await {
+                Ok(()) => paths.push(path),
+                Err(e) => warn!("Failed to download view-once media of {}: {}", id, e),
+            }
+        }
+    }
+    record_opened(conn, sender_aci, timestamp)?;
+    Ok(Some(paths))
+}
+
+/// Tell my other devices (`me` is my ACI) that these view-once messages, as
+/// (sender, sent timestamp), were opened. Best-effort: they're already
+/// recorded here.
+pub async fn sync_opens(manager: &mut SignalManager, me: Uuid, opened: Vec<(String, u64)>) {
+    if opened.is_empty() {
+        return;
+    }
+    if let Err(e) = outbound::ensure_allowed(outbound::Outbound::Message) {
+        warn!("Not syncing view-once opens: {}", e);
+        return;
+    }
+    for (sender_aci, timestamp) in opened {
+        let now = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let sync = SyncMessage {
+            view_once_open: Some(ViewOnceOpen {
+                sender_aci: Some(sender_aci.clone()),
+                timestamp: Some(timestamp),
+            }),
+            ..Default::default()
+        };
+        if let Err(e) = outbound::send_message(
+            manager,
+            ServiceId::Aci(me.into()),
+            ContentBody::SynchronizeMessage(sync),
+            now,
+        )
+        .await
+        {
+            warn!(
+                "Failed to sync view-once open of {}: {}",
+                message_id(timestamp, &sender_aci),
+                e
+            );
+        }
+    }
+}
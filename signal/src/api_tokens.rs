@@ -0,0 +1,234 @@
+//! Scoped tokens for `serve`.
+//!
+//! The server's own token (`--token`) can do everything. Tokens issued with
+//! `api-token create` are limited to some operations and, optionally, some
+//! chats, so a third-party widget can be given e.g. send-only access to one
+//! group. Only a SHA-256 hash of each token is stored; the token itself is
+//! shown once, when it's created.
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use signal_core::local;
+
+/// What a scoped token may do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    /// List chats, read messages and stream events
+    Read,
+    /// Send messages
+    Send,
+}
+
+/// What a request's token allows.
+#[derive(Clone, Debug)]
+pub struct Scope {
+    /// Chats the token is limited to; `None` for every chat
+    pub chats: Option<Vec<String>>,
+    pub operations: Vec<Operation>,
+}
+
+impl Scope {
+    /// The server token's scope
+    pub fn full() -> Self {
+        Scope {
+            chats: None,
+            operations: vec![Operation::Read, Operation::Send],
+        }
+    }
+
+    pub fn allows_chat(&self, chat_id: &str) -> bool {
+        self.chats
+            .as_ref()
+            .is_none_or(|chats| chats.iter().any(|c| c == chat_id))
+    }
+
+    pub fn allows(&self, operation: Operation) -> bool {
+        self.operations.contains(&operation)
+    }
+}
+
+/// A stored token, without the token itself.
+#[derive(Serialize)]
+pub struct TokenInfo {
+    pub name: String,
+    /// Absent when the token may use every chat
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chats: Option<Vec<String>>,
+    pub operations: Vec<Operation>,
+    pub created_at: i64,
+}
+
+pub fn open_tokens_db() -> Result<Connection> {
+    local::open_local_db().context("Failed to open local.db")
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Issue a token with the given scope. Returns the token, which can't be
+/// recovered later.
+pub fn create(conn: &Connection, name: &str, scope: &Scope) -> Result<String> {
+    let exists = conn
+        .query_row("SELECT 1 FROM api_tokens WHERE name = ?1", [name], |_| {
+            Ok(())
+        })
+        .optional()?
+        .is_some();
+    if exists {
+        anyhow::bail!("A token named '{}' already exists", name);
+    }
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+
+    let chats = scope
+        .chats
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let operations = serde_json::to_string(&scope.operations)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    conn.execute(
+        "INSERT INTO api_tokens (name, token_hash, chats, operations, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        rusqlite::params![name, hash(&token), chats, operations, now],
+    )?;
+
+    Ok(token)
+}
+
+/// The scope of an issued token, or `None` if it isn't one.
+pub fn lookup(conn: &Connection, token: &str) -> Result<Option<Scope>> {
+    let row: Option<(Option<String>, String)> = conn
+        .query_row(
+            "SELECT chats, operations FROM api_tokens WHERE token_hash = ?1",
+            [hash(token)],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let Some((chats, operations)) = row else {
+        return Ok(None);
+    };
+    Ok(Some(Scope {
+        chats: chats.as_deref().map(serde_json::from_str).transpose()?,
+        operations: serde_json::from_str(&operations)?,
+    }))
+}
+
+/// Every issued token, oldest first.
+pub fn list(conn: &Connection) -> Result<Vec<TokenInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, chats, operations, created_at FROM api_tokens
+         ORDER BY created_at, name",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, i64>(3)?,
+        ))
+    })?;
+
+    let mut tokens = Vec::new();
+    for row in rows {
+        let (name, chats, operations, created_at) = row?;
+        tokens.push(TokenInfo {
+            name,
+            chats: chats.as_deref().map(serde_json::from_str).transpose()?,
+            operations: serde_json::from_str(&operations)?,
+            created_at,
+        });
+    }
+    Ok(tokens)
+}
+
+/// Revoke a token. Returns whether there was one with that name.
+pub fn revoke(conn: &Connection, name: &str) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM api_tokens WHERE name = ?1", [name])? > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        local::migrate(&mut conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn scope_limits_chats_and_operations() {
+        let full = Scope::full();
+        assert!(full.allows(Operation::Read) && full.allows(Operation::Send));
+        assert!(full.allows_chat("any"));
+
+        let scope = Scope {
+            chats: Some(vec!["a".to_string()]),
+            operations: vec![Operation::Send],
+        };
+        assert!(scope.allows(Operation::Send));
+        assert!(!scope.allows(Operation::Read));
+        assert!(scope.allows_chat("a"));
+        assert!(!scope.allows_chat("b"));
+    }
+
+    #[test]
+    fn chat_ids_match_once_canonical() {
+        use signal_core::{parse_thread, thread_chat_id};
+        let uuid = "a3b1c2d4-0000-4000-8000-00000000abcd";
+        let scope = Scope {
+            chats: Some(vec![uuid.to_string()]),
+            operations: vec![Operation::Read],
+        };
+        let given = uuid.to_uppercase();
+        assert!(!scope.allows_chat(&given));
+        assert!(scope.allows_chat(&thread_chat_id(&parse_thread(&given).unwrap())));
+    }
+
+    #[test]
+    fn lookup_returns_the_scope_a_token_was_issued_with() {
+        let conn = open();
+        let scoped = Scope {
+            chats: Some(vec!["a".to_string(), "b".to_string()]),
+            operations: vec![Operation::Read],
+        };
+        let token = create(&conn, "widget", &scoped).unwrap();
+        let unscoped = create(&conn, "all", &Scope::full()).unwrap();
+
+        let found = lookup(&conn, &token).unwrap().unwrap();
+        assert_eq!(found.chats, scoped.chats);
+        assert_eq!(found.operations, scoped.operations);
+        let found = lookup(&conn, &unscoped).unwrap().unwrap();
+        assert_eq!(found.chats, None);
+        assert_eq!(found.operations, Scope::full().operations);
+
+        assert!(lookup(&conn, "not a token").unwrap().is_none());
+        // Only the hash is stored
+        assert!(lookup(&conn, &hash(&token)).unwrap().is_none());
+    }
+
+    #[test]
+    fn names_are_unique_and_revoking_ends_a_token() {
+        let conn = open();
+        let token = create(&conn, "widget", &Scope::full()).unwrap();
+        assert!(create(&conn, "widget", &Scope::full()).is_err());
+
+        assert!(revoke(&conn, "widget").unwrap());
+        assert!(!revoke(&conn, "widget").unwrap());
+        assert!(lookup(&conn, &token).unwrap().is_none());
+    }
+}
@@ -0,0 +1,274 @@
+//! `backup create|restore`: move a linked device's state to another machine
+//! as one encrypted file.
+//!
+//! The archive holds a consistent snapshot of every database (the message
+//! store, which includes the account's keys, and the local ones such as read
+//! state), the config, policy and encrypted secrets files, and downloaded
+//! avatars. It's encrypted like the secrets file, with the passphrase in
+//! `SIGNAL_CLI_BACKUP_PASSPHRASE` or, failing that, the
+//! `signal-backup-passphrase` secret, so scheduled backups need no passphrase
+//! in their environment.
+//!
+//! Inside the encryption the layout is `MAGIC`, then per file: name length
+//! (u16, big-endian), name, data length (u64, big-endian), data. Names are
+//! `data/<file>` for the data dir and `avatars/<path>` for the avatar dir.
+
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::replicate::{snapshot, PLAIN_FILES};
+use crate::{daemon, get_data_dir, secrets};
+
+pub const PASSPHRASE_ENV: &str = "SIGNAL_CLI_BACKUP_PASSPHRASE";
+
+/// Secret the passphrase is looked up in when the variable isn't set
+pub const PASSPHRASE_SECRET: &str = "signal-backup-passphrase";
+
+const MAGIC: &[u8] = b"jean-claude-signal-backup-1\n";
+
+#[derive(Serialize)]
+pub struct BackupOutput {
+    pub success: bool,
+    pub path: String,
+    /// Archive names of the files backed up or restored
+    pub files: Vec<String>,
+}
+
+fn passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    secrets::get(PASSPHRASE_SECRET)?
+        .map(|(passphrase, _)| passphrase)
+        .with_context(|| {
+            format!(
+                "Set {} or the '{}' secret to encrypt or decrypt backups",
+                PASSPHRASE_ENV, PASSPHRASE_SECRET
+            )
+        })
+}
+
+fn ensure_daemon_stopped() -> Result<()> {
+    if daemon::is_running()? {
+        anyhow::bail!("A `serve` daemon is using the data dir; stop it first");
+    }
+    Ok(())
+}
+
+fn push_entry(archive: &mut Vec<u8>, name: &str, data: &[u8]) -> Result<()> {
+    let name_len = u16::try_from(name.len()).context("File name too long")?;
+    archive.extend_from_slice(&name_len.to_be_bytes());
+    archive.extend_from_slice(name.as_bytes());
+    archive.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    archive.extend_from_slice(data);
+    Ok(())
+}
+
+/// The next `len` bytes of `rest`
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if rest.len() < len {
+        anyhow::bail!("Backup is truncated");
+    }
+    let (head, tail) = rest.split_at(len);
+    *rest = tail;
+    Ok(head)
+}
+
+/// Split an archive into (name, data) entries.
+fn entries(archive: &[u8]) -> Result<Vec<(String, &[u8])>> {
+    let mut rest = archive
+        .strip_prefix(MAGIC)
+        .context("Not a jean-claude Signal backup")?;
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let name_len = u16::from_be_bytes(take(&mut rest, 2)?.try_into()?);
+        let name = take(&mut rest, name_len as usize)?;
+        let name = String::from_utf8(name.to_vec()).context("Invalid file name")?;
+        let data_len = u64::from_be_bytes(take(&mut rest, 8)?.try_into()?);
+        let data = take(&mut rest, usize::try_from(data_len)?)?;
+        entries.push((name, data));
+    }
+    Ok(entries)
+}
+
+/// `path` with `suffix` appended to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Write a file readable only by its owner, under a temporary name first so
+/// a failure never leaves a partial file in place.
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let partial = with_suffix(path, ".partial");
+    // Created private rather than narrowed after writing, so the data is never
+    // readable by others; a leftover would keep its own mode, so it goes first
+    let _ = std::fs::remove_file(&partial);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&partial)
+        .and_then(|mut file| file.write_all(data))
+        .with_context(|| format!("Failed to write {}", partial.display()))?;
+    std::fs::rename(&partial, path).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Every file under `dir`, as paths relative to it
+fn walk(dir: &Path, prefix: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let relative = prefix.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            walk(&entry.path(), &relative, files)?;
+        } else {
+            files.push(relative);
+        }
+    }
+    Ok(())
+}
+
+/// Snapshot the data dir's files and the avatars into one (plaintext)
+/// archive. Database snapshots go through `staging`.
+fn build_archive(
+    data_dir: &Path,
+    names: &[String],
+    avatar_dir: &Path,
+    staging: &Path,
+) -> Result<(Vec<u8>, Vec<String>)> {
+    let mut archive = MAGIC.to_vec();
+    let mut files = Vec::new();
+    for name in names {
+        let source = data_dir.join(name);
+        let data = if name.ends_with(".db") {
+            let copy = staging.join(name);
+            snapshot(&source, &copy)?;
+            std::fs::read(&copy)?
+        } else {
+            std::fs::read(&source)
+                .with_context(|| format!("Failed to read {}", source.display()))?
+        };
+        let entry = format!("data/{}", name);
+        push_entry(&mut archive, &entry, &data)?;
+        files.push(entry);
+    }
+
+    let mut avatars = Vec::new();
+    if avatar_dir.is_dir() {
+        walk(avatar_dir, Path::new(""), &mut avatars)?;
+    }
+    avatars.sort();
+    for relative in avatars {
+        let Some(relative_name) = relative.to_str() else {
+            continue;
+        };
+        let entry = format!("avatars/{}", relative_name);
+        push_entry(
+            &mut archive,
+            &entry,
+            &std::fs::read(avatar_dir.join(&relative))?,
+        )?;
+        files.push(entry);
+    }
+    Ok((archive, files))
+}
+
+pub fn create(path: &Path, avatar_dir: &Path) -> Result<BackupOutput> {
+    let passphrase = passphrase()?;
+    ensure_daemon_stopped()?;
+    let data_dir = get_data_dir()?;
+
+    let mut names: Vec<String> = std::fs::read_dir(&data_dir)
+        .with_context(|| format!("Failed to read {}", data_dir.display()))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".db") || PLAIN_FILES.contains(&name.as_str()))
+        .collect();
+    names.sort();
+    if !names.iter().any(|name| name == "signal.db") {
+        anyhow::bail!("Nothing to back up: no store in {}", data_dir.display());
+    }
+
+    // Snapshots are plaintext, so they stay inside the data dir
+    let staging = data_dir.join("backup-staging");
+    let _ = std::fs::remove_dir_all(&staging);
+    std::fs::create_dir_all(&staging)?;
+    let archive = build_archive(&data_dir, &names, avatar_dir, &staging);
+    let _ = std::fs::remove_dir_all(&staging);
+    let (archive, files) = archive?;
+
+    write_private(path, &secrets::encrypt(&passphrase, &archive)?)?;
+
+    Ok(BackupOutput {
+        success: true,
+        path: path.display().to_string(),
+        files,
+    })
+}
+
+/// A relative path that stays inside the dir it's joined to
+fn safe_relative(name: &str) -> Option<&Path> {
+    let path = Path::new(name);
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then_some(path)
+}
+
+pub fn restore(path: &Path, avatar_dir: &Path, force: bool) -> Result<BackupOutput> {
+    let passphrase = passphrase()?;
+    ensure_daemon_stopped()?;
+    let data_dir = get_data_dir()?;
+    if data_dir.join("signal.db").exists() && !force {
+        anyhow::bail!(
+            "{} already has a store; pass --force to replace it",
+            data_dir.display()
+        );
+    }
+
+    let encrypted =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let archive = secrets::decrypt(&passphrase, &encrypted)
+        .with_context(|| format!("Failed to open backup {}", path.display()))?;
+
+    // Check every entry before writing anything
+    let mut targets = Vec::new();
+    for (name, data) in entries(&archive)? {
+        let target = match name.split_once('/') {
+            Some(("data", file)) if !file.contains('/') && safe_relative(file).is_some() => {
+                data_dir.join(file)
+            }
+            Some(("avatars", relative)) => match safe_relative(relative) {
+                Some(relative) => avatar_dir.join(relative),
+                None => anyhow::bail!("Backup has an unsafe path: {}", name),
+            },
+            _ => anyhow::bail!("Backup has an unexpected entry: {}", name),
+        };
+        targets.push((name, target, data));
+    }
+
+    std::fs::create_dir_all(&data_dir)?;
+    let mut files = Vec::new();
+    for (name, target, data) in targets {
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if name.ends_with(".db") {
+            // A leftover WAL would be replayed over the restored database
+            for suffix in ["-wal", "-shm"] {
+                let _ = std::fs::remove_file(with_suffix(&target, suffix));
+            }
+        }
+        write_private(&target, data)?;
+        files.push(name);
+    }
+
+    Ok(BackupOutput {
+        success: true,
+        path: path.display().to_string(),
+        files,
+    })
+}
@@ -0,0 +1,120 @@
+//! Optional defaults from `config.toml` in the data dir.
+//!
+//! Every setting has a matching CLI flag, and flags always win. Unknown keys
+//! are rejected so a typo doesn't silently fall back to the default.
+//!
+//! ```toml
+//! device_name = "jean-claude-laptop"
+//! server = "staging"
+//! attachment_dir = "/home/me/signal-files"
+//! output = "compact"
+//! max_results = 20
+//! read_receipts = "send"
+//! webhook_url = "http://localhost:9000/signal"
+//! ignore_content = ["stories", "typing", "receipts", "calls"]
+//! include_stories = true
+//! time_format = "iso8601"
+//! identity_policy = "block"
+//! retention_days = 365
+//! max_messages_per_chat = 10000
+//!
+//! [on_message]
+//! "abc123-def456-..." = "~/bin/autoreply"
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use presage::libsignal_service::configuration::SignalServers;
+use serde::Deserialize;
+
+use signal_core::identity::IdentityPolicy;
+use signal_core::read_sync::ReadReceiptPolicy;
+use signal_core::receive::ContentKind;
+use signal_core::timestamp::TimeFormat;
+
+use crate::get_data_dir;
+
+/// How JSON output is printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Indented, one field per line
+    #[default]
+    Pretty,
+    /// A single line per result
+    Compact,
+}
+
+/// Which Signal servers `link` and `register` talk to. The account
+/// remembers, so every later command uses the same ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Server {
+    /// Signal's real servers
+    #[default]
+    Production,
+    /// Signal's staging servers, for testing with throwaway accounts
+    Staging,
+}
+
+impl From<Server> for SignalServers {
+    fn from(server: Server) -> Self {
+        match server {
+            Server::Production => SignalServers::Production,
+            Server::Staging => SignalServers::Staging,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Default for `link --device-name`
+    pub device_name: Option<String>,
+    /// Default for `--server` on `link` and `register`
+    pub server: Option<Server>,
+    /// Where downloaded files (avatars, stickers, view-once and story media) are saved,
+    /// instead of the data dir
+    pub attachment_dir: Option<PathBuf>,
+    /// Default for `--output`
+    pub output: Option<OutputFormat>,
+    /// Default for `-n/--max-results` on `chats` and `messages`
+    pub max_results: Option<usize>,
+    /// Default for `mark-read --read-receipts`
+    pub read_receipts: Option<ReadReceiptPolicy>,
+    /// Default for `receive --webhook`
+    pub webhook_url: Option<String>,
+    /// Default for `--ignore-content`
+    pub ignore_content: Option<Vec<ContentKind>>,
+    /// Default for `--include-stories`
+    pub include_stories: Option<bool>,
+    /// Default for `--time-format`
+    pub time_format: Option<TimeFormat>,
+    /// Default for `--identity-policy`
+    pub identity_policy: Option<IdentityPolicy>,
+    /// Default for `prune --retention-days`
+    pub retention_days: Option<u32>,
+    /// Default for `prune --max-messages-per-chat`
+    pub max_messages_per_chat: Option<usize>,
+    /// `receive --on-message` commands by chat ID, used when the flag isn't given
+    #[serde(default)]
+    pub on_message: BTreeMap<String, String>,
+}
+
+pub fn get_config_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("config.toml"))
+}
+
+/// Load the config file, or defaults if there isn't one.
+pub fn load() -> Result<Config> {
+    let path = get_config_path()?;
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("Invalid config file {}", path.display()))
+}
@@ -0,0 +1,458 @@
+//! Control socket for a running `serve`, so CLI commands don't open the store
+//! while the daemon holds it.
+//!
+//! The socket is `run/daemon.sock` in the account's data dir. Each line a client
+//! writes is a JSON [`Request`] mirroring a CLI command; the daemon answers
+//! with one line, `{"output": ...}` holding the command's usual JSON output, or
+//! `{"error": {"code": ..., "message": ...}}` as the CLI prints on failure.
+//! The CLI checks for the socket before loading the manager and proxies
+//! through it when a daemon answers. A socket left behind by a daemon that
+//! didn't exit cleanly refuses connections and is ignored.
+
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use signal_core::identity::IdentityPolicy;
+use signal_core::model::MessageOrder;
+use signal_core::read_sync::{self, ReadReceiptPolicy};
+use signal_core::search::{self, SearchFilters};
+use signal_core::timestamp::{self, TimeFormat};
+use signal_core::{
+    chats, conversations, get_data_dir, history, outbound, outbox, receive, stickers, SignalManager,
+};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::errors::{ErrorBody, ErrorCode};
+use crate::export::ExportFormat;
+use crate::{
+    avatar, doctor, expire_set, export_chat, get_profile, list_devices, list_identities, mark_read,
+    ping, prune, remove_device, send_batch, send_message, send_output, send_sticker, show_list,
+    status, sync_contacts, trust_identity, update_list, verify_groups, whoami, BatchMessage,
+    MarkUnreadOutput, SendMessage,
+};
+
+/// A CLI command the daemon can run with its own manager. Paths are
+/// absolute, since the daemon's working directory isn't the client's.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Whoami,
+    Ping,
+    Status {
+        deep: bool,
+    },
+    DevicesList,
+    DevicesRemove {
+        id: u32,
+    },
+    /// `lists add`, or `lists remove` with `remove`
+    ListsUpdate {
+        name: String,
+        recipients: Vec<String>,
+        remove: bool,
+    },
+    ListsShow {
+        name: String,
+    },
+    Chats {
+        max_results: usize,
+        #[serde(default)]
+        unread_counts: bool,
+        /// Where to save avatars, for `--with-avatars`
+        #[serde(default)]
+        avatar_dir: Option<PathBuf>,
+    },
+    Avatar {
+        chat_id: String,
+        avatar_dir: PathBuf,
+    },
+    Conversations {
+        max_results: usize,
+    },
+    Messages {
+        chat_id: String,
+        max_results: usize,
+        order_by: MessageOrder,
+        verify_ordering: bool,
+        /// Unix milliseconds; see [`history::thread_messages`]
+        #[serde(default)]
+        as_of: Option<u64>,
+        #[serde(default)]
+        page: history::Page,
+    },
+    Export {
+        chat_id: String,
+        format: ExportFormat,
+        output: PathBuf,
+        with_attachments: bool,
+    },
+    Send(SendMessage),
+    SendBatch {
+        messages: Vec<BatchMessage>,
+        interval_ms: u64,
+        dry_run: bool,
+        markdown: bool,
+    },
+    Flush {
+        all: bool,
+    },
+    Receive,
+    SyncContacts {
+        timeout_secs: u64,
+    },
+    Search {
+        query: String,
+        filters: SearchFilters,
+        max_results: usize,
+        reindex: bool,
+    },
+    MarkRead {
+        chat_ids: Vec<String>,
+        /// Single messages, as (chat ID, message ID)
+        #[serde(default)]
+        messages: Vec<(String, String)>,
+        read_receipts: ReadReceiptPolicy,
+    },
+    MarkUnread {
+        chat_id: String,
+        after: Option<String>,
+    },
+    ExpireSet {
+        chat_id: String,
+        seconds: u32,
+    },
+    StickerList,
+    StickerSend {
+        chat_id: String,
+        pack_id: String,
+        sticker_id: u32,
+    },
+    GroupsVerify,
+    IdentityList,
+    IdentityTrust {
+        uuid: String,
+    },
+    ProfileGet {
+        uuid: String,
+    },
+    Prune {
+        retention_days: Option<u32>,
+        max_messages_per_chat: Option<usize>,
+    },
+    Doctor {
+        fix: bool,
+    },
+}
+
+/// A request as sent over the socket, with the client's output settings
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(flatten)]
+    pub request: Request,
+    /// Absent from clients that predate `--time-format`
+    #[serde(default)]
+    pub time_format: TimeFormat,
+    /// The client's `--read-only`, which holds even if the daemon's isn't set
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl Envelope {
+    /// A request with this process's settings
+    pub fn new(request: Request) -> Self {
+        Envelope {
+            request,
+            time_format: timestamp::time_format(),
+            read_only: outbound::is_read_only(),
+        }
+    }
+}
+
+/// The answer to a request: one line, `{"output": ...}` or `{"error": ...}`
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Response {
+    Output(Value),
+    Error(ErrorBody),
+}
+
+/// The dir sockets are bound in, `run/` in the data dir, made owner-only.
+/// Connecting needs search permission on it, so other users can't reach a
+/// socket there whatever mode it was bound with.
+pub fn socket_dir() -> Result<PathBuf> {
+    let dir = get_data_dir()?.join("run");
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    // One created before the mode was set, or by hand
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+
+pub fn socket_path() -> Result<PathBuf> {
+    Ok(socket_dir()?.join("daemon.sock"))
+}
+
+/// Whether a daemon is listening. Commands that replace or remove the store,
+/// rather than use it, refuse to run while one is.
+pub fn is_running() -> Result<bool> {
+    Ok(std::os::unix::net::UnixStream::connect(socket_path()?).is_ok())
+}
+
+/// Serialize output with the client's time format rather than the daemon's
+fn to_value<T: Serialize>(time_format: TimeFormat, value: T) -> Result<Value> {
+    Ok(timestamp::with_time_format(time_format, || {
+        serde_json::to_value(value)
+    })?)
+}
+
+/// Run a request against the daemon's manager, producing the same JSON the
+/// CLI prints for the command. A read-only client can't send, whether or not
+/// the daemon is read-only.
+pub async fn execute(
+    manager: &mut SignalManager,
+    envelope: Envelope,
+    policy: IdentityPolicy,
+) -> Result<Value> {
+    // Requests run one at a time, so the mode can be switched for each
+    let daemon_read_only = outbound::is_read_only();
+    outbound::set_read_only(daemon_read_only || envelope.read_only);
+    let result = run(manager, envelope.request, policy, envelope.time_format).await;
+    outbound::set_read_only(daemon_read_only);
+    result
+}
+
+async fn run(
+    manager: &mut SignalManager,
+    request: Request,
+    policy: IdentityPolicy,
+    time_format: TimeFormat,
+) -> Result<Value> {
+    Ok(match request {
+        Request::Whoami => to_value(time_format, whoami(manager).await?)?,
+        Request::Ping => to_value(time_format, ping(manager).await?)?,
+        Request::Status { deep } => to_value(time_format, status(Some(manager), deep).await?)?,
+        Request::DevicesList => to_value(time_format, list_devices(manager).await?)?,
+        Request::DevicesRemove { id } => to_value(time_format, remove_device(manager, id).await?)?,
+        Request::ListsUpdate {
+            name,
+            recipients,
+            remove,
+        } => to_value(
+            time_format,
+            update_list(manager, name, &recipients, remove).await?,
+        )?,
+        Request::ListsShow { name } => to_value(time_format, show_list(manager, name).await?)?,
+        Request::Chats {
+            max_results,
+            unread_counts,
+            avatar_dir,
+        } => to_value(
+            time_format,
+            chats::list_chats(manager, max_results, avatar_dir.as_deref(), unread_counts).await?,
+        )?,
+        Request::Avatar {
+            chat_id,
+            avatar_dir,
+        } => to_value(time_format, avatar(manager, chat_id, &avatar_dir).await?)?,
+        Request::Conversations { max_results } => to_value(
+            time_format,
+            conversations::list_conversations(manager, max_results).await?,
+        )?,
+        Request::Messages {
+            chat_id,
+            max_results,
+            order_by,
+            verify_ordering,
+            as_of,
+            page,
+        } => to_value(
+            time_format,
+            history::thread_messages(
+                manager,
+                &chat_id,
+                max_results,
+                order_by,
+                verify_ordering,
+                as_of,
+                &page,
+            )
+            .await?,
+        )?,
+        Request::Export {
+            chat_id,
+            format,
+            output,
+            with_attachments,
+        } => to_value(
+            time_format,
+            export_chat(
+                manager,
+                &chat_id,
+                format,
+                output,
+                with_attachments,
+                time_format,
+            )
+            .await?,
+        )?,
+        Request::Send(message) => {
+            to_value(time_format, send_message(manager, message, policy).await?)?
+        }
+        Request::SendBatch {
+            messages,
+            interval_ms,
+            dry_run,
+            markdown,
+        } => to_value(
+            time_format,
+            send_batch(manager, messages, policy, interval_ms, dry_run, markdown).await?,
+        )?,
+        Request::Flush { all } => {
+            to_value(time_format, outbox::flush(manager, policy, all).await?)?
+        }
+        Request::Receive => to_value(time_format, receive::receive(manager, policy).await?.items)?,
+        Request::SyncContacts { timeout_secs } => to_value(
+            time_format,
+            sync_contacts(manager, policy, timeout_secs).await?,
+        )?,
+        Request::Search {
+            query,
+            filters,
+            max_results,
+            reindex,
+        } => {
+            let mut conn = search::open_search_db()?;
+            if reindex {
+                search::reindex(manager, &mut conn).await?;
+            }
+            to_value(
+                time_format,
+                search::search(manager, &mut conn, &query, &filters, max_results).await?,
+            )?
+        }
+        Request::MarkRead {
+            chat_ids,
+            messages,
+            read_receipts,
+        } => to_value(
+            time_format,
+            mark_read(manager, &chat_ids, &messages, read_receipts).await?,
+        )?,
+        Request::MarkUnread { chat_id, after } => {
+            let messages_marked =
+                read_sync::mark_unread(manager, &chat_id, after.as_deref()).await?;
+            to_value(
+                time_format,
+                MarkUnreadOutput {
+                    success: true,
+                    chat_id,
+                    messages_marked,
+                },
+            )?
+        }
+        Request::ExpireSet { chat_id, seconds } => {
+            to_value(time_format, expire_set(manager, chat_id, seconds).await?)?
+        }
+        Request::StickerList => to_value(time_format, stickers::list(manager).await?)?,
+        Request::StickerSend {
+            chat_id,
+            pack_id,
+            sticker_id,
+        } => {
+            let report = send_sticker(manager, &chat_id, &pack_id, sticker_id, policy).await?;
+            to_value(time_format, send_output(report, None))?
+        }
+        Request::GroupsVerify => to_value(time_format, verify_groups(manager).await?)?,
+        Request::IdentityList => to_value(time_format, list_identities(manager).await?)?,
+        Request::IdentityTrust { uuid } => {
+            to_value(time_format, trust_identity(manager, uuid).await?)?
+        }
+        Request::ProfileGet { uuid } => to_value(time_format, get_profile(manager, uuid).await?)?,
+        Request::Prune {
+            retention_days,
+            max_messages_per_chat,
+        } => to_value(
+            time_format,
+            prune::prune(manager, retention_days, max_messages_per_chat).await?,
+        )?,
+        Request::Doctor { fix } => {
+            to_value(time_format, doctor::doctor(Some(manager), fix).await?)?
+        }
+    })
+}
+
+/// Send a request to a running daemon. Returns `None` when no daemon is
+/// listening, in which case the caller should run the command itself.
+pub async fn proxy(request: &Request) -> Result<Option<Value>> {
+    proxy_envelope(&Envelope::new(request.clone())).await
+}
+
+/// [`proxy`] with settings other than this process's
+pub async fn proxy_envelope(envelope: &Envelope) -> Result<Option<Value>> {
+    let path = socket_path()?;
+    let stream = match UnixStream::connect(&path).await {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to connect to {}", path.display()))
+        }
+    };
+
+    let (reader, mut writer) = stream.into_split();
+    let mut line = serde_json::to_string(envelope)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    let mut response = String::new();
+    BufReader::new(reader).read_line(&mut response).await?;
+    if response.is_empty() {
+        anyhow::bail!("Daemon closed the connection without responding");
+    }
+    match serde_json::from_str(&response).context("Invalid response from daemon")? {
+        Response::Output(value) => Ok(Some(value)),
+        Response::Error(error) => Err(error.into()),
+    }
+}
+
+/// Answer one client connection, a request per line, until it closes.
+pub async fn handle_connection<F, Fut>(stream: UnixStream, run: F) -> Result<()>
+where
+    F: Fn(Envelope) -> Fut,
+    Fut: std::future::Future<Output = Result<Value>>,
+{
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Envelope>(&line) {
+            Ok(envelope) => match run(envelope).await {
+                Ok(value) => Response::Output(value),
+                Err(e) => Response::Error(ErrorBody::new(&e)),
+            },
+            Err(e) => Response::Error(ErrorBody {
+                code: ErrorCode::InvalidInput,
+                message: format!("Invalid request: {}", e),
+            }),
+        };
+        let mut line = serde_json::to_string(&response)?;
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+    }
+    Ok(())
+}
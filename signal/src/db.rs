@@ -0,0 +1,199 @@
+//! `db upgrade`: bring the databases up to this build's schemas, safely.
+//!
+//! Every command already migrates as it opens a database: the message store
+//! applies presage's pending migrations, and `local.db` applies its own
+//! (moving in the per-feature files that came before it). This does the
+//! same for both at once, but first snapshots each into `backups/<time>/` and
+//! afterwards checks that no table lost rows. If one did, or a migration
+//! fails, the snapshots are put back, so a new binary never leaves the data
+//! dir half-migrated.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+
+use signal_core::{get_data_dir, local, open_store, unlock};
+
+use crate::{daemon, replicate};
+
+#[derive(Serialize)]
+pub struct DatabaseReport {
+    pub name: String,
+    /// The store's latest applied migration, or a local database's
+    /// `user_version`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_before: Option<i64>,
+    /// Absent for a file moved into `local.db`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_after: Option<i64>,
+    /// Rows per table after the upgrade; a moved file's are counted in
+    /// `local.db`
+    pub tables: BTreeMap<String, i64>,
+}
+
+#[derive(Serialize)]
+pub struct UpgradeOutput {
+    pub success: bool,
+    pub backup_dir: String,
+    pub databases: Vec<DatabaseReport>,
+}
+
+/// Open a database without writing to it, unlocked with the passphrase if
+/// there is one. Shared by `db upgrade`, `doctor` and `version`.
+pub fn open_read_only(path: &Path) -> Result<Connection> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    unlock(&conn)?;
+    Ok(conn)
+}
+
+fn version(path: &Path, name: &str) -> Result<Option<i64>> {
+    let conn = open_read_only(path)?;
+    if name == "signal.db" {
+        // sqlx records migrations here; without the table there's no version
+        return Ok(conn
+            .query_row(
+                "SELECT MAX(version) FROM _sqlx_migrations WHERE success",
+                [],
+                |row| row.get(0),
+            )
+            .ok()
+            .flatten());
+    }
+    Ok(Some(
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))?,
+    ))
+}
+
+fn row_counts(path: &Path) -> Result<BTreeMap<String, i64>> {
+    let conn = open_read_only(path)?;
+    let tables: Vec<String> = conn
+        .prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut counts = BTreeMap::new();
+    for table in tables {
+        let count = conn.query_row(
+            &format!("SELECT COUNT(*) FROM \"{}\"", table.replace('"', "\"\"")),
+            [],
+            |row| row.get(0),
+        )?;
+        counts.insert(table, count);
+    }
+    Ok(counts)
+}
+
+/// Open a database the way the commands do, which migrates it. Opening
+/// `local.db` also moves in the files that came before it.
+async fn migrate(name: &str) -> Result<()> {
+    if name == "signal.db" {
+        open_store().await?;
+    } else if name == "local.db" || local::ADOPTED.iter().any(|(file, _)| *file == name) {
+        local::open_local_db()?;
+    }
+    // Anything else isn't ours (or is from a newer build); left as it is
+    Ok(())
+}
+
+/// Put the snapshots back over the databases.
+fn restore(data_dir: &Path, backup_dir: &Path, names: &[String]) -> Result<()> {
+    // One the upgrade created holds copies of the files being put back, and
+    // records them as moved in already
+    if !names.iter().any(|name| name == "local.db") {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(data_dir.join(format!("local.db{}", suffix)));
+        }
+    }
+    for name in names {
+        let path = data_dir.join(name);
+        // A stale WAL would be replayed over the restored copy
+        for suffix in ["-wal", "-shm"] {
+            let _ = std::fs::remove_file(data_dir.join(format!("{}{}", name, suffix)));
+        }
+        std::fs::copy(backup_dir.join(name), &path)
+            .with_context(|| format!("Failed to restore {}", path.display()))?;
+    }
+    Ok(())
+}
+
+pub async fn upgrade() -> Result<UpgradeOutput> {
+    let data_dir = get_data_dir()?;
+    if daemon::is_running()? {
+        anyhow::bail!("A `serve` daemon is using the databases; stop it before upgrading");
+    }
+
+    let mut names: Vec<String> = std::fs::read_dir(&data_dir)
+        .with_context(|| format!("Failed to read {}", data_dir.display()))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".db"))
+        .collect();
+    names.sort();
+
+    let backup_dir: PathBuf = data_dir
+        .join("backups")
+        .join(chrono::Local::now().format("%Y%m%d-%H%M%S").to_string());
+    std::fs::create_dir_all(&backup_dir)
+        .with_context(|| format!("Failed to create {}", backup_dir.display()))?;
+
+    let mut before = Vec::new();
+    for name in &names {
+        let path = data_dir.join(name);
+        replicate::snapshot(&path, &backup_dir.join(name))?;
+        before.push((version(&path, name)?, row_counts(&path)?));
+    }
+
+    let mut databases = Vec::new();
+    let mut problems = Vec::new();
+    for (name, (version_before, counts_before)) in names.iter().zip(before) {
+        if let Err(e) = migrate(name).await {
+            problems.push(format!("{}: {:#}", name, e));
+            continue;
+        }
+        let path = data_dir.join(name);
+        // A file from before `local.db` is gone once it's been copied there
+        let moved = !path.exists();
+        let mut tables = row_counts(&if moved {
+            data_dir.join("local.db")
+        } else {
+            path.clone()
+        })?;
+        if moved {
+            tables.retain(|table, _| counts_before.contains_key(table));
+        }
+        for (table, count) in &counts_before {
+            // Tables a migration dropped or renamed are its business
+            if let Some(after) = tables.get(table).filter(|&&after| after < *count) {
+                problems.push(format!(
+                    "{}: {} went from {} rows to {}",
+                    name, table, count, after
+                ));
+            }
+        }
+        databases.push(DatabaseReport {
+            name: name.clone(),
+            version_before,
+            version_after: if moved { None } else { version(&path, name)? },
+            tables,
+        });
+    }
+
+    if !problems.is_empty() {
+        restore(&data_dir, &backup_dir, &names)?;
+        anyhow::bail!(
+            "Upgrade failed, databases restored from {}: {}",
+            backup_dir.display(),
+            problems.join("; ")
+        );
+    }
+
+    Ok(UpgradeOutput {
+        success: true,
+        backup_dir: backup_dir.display().to_string(),
+        databases,
+    })
+}
@@ -0,0 +1,232 @@
+//! `doctor`: check the data dir for damage and leftovers, and repair what can
+//! be repaired.
+//!
+//! Every database gets SQLite's integrity and foreign key checks, and its
+//! schema version is compared with this build's. Read syncs for messages that
+//! never arrived and view-once media with no record of its message are found
+//! too. With `--fix`, stale schemas are upgraded (as `db upgrade` does, with
+//! snapshots), rows that break a foreign key and orphaned reads are deleted,
+//! and unreferenced media is removed. Corruption isn't fixed here: restore a
+//! backup instead.
+
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use signal_core::{get_data_dir, local, read_sync, unlock, view_once, SignalManager};
+
+use crate::db;
+
+/// Integrity check messages reported per database
+const MAX_INTEGRITY_ERRORS: usize = 5;
+
+#[derive(Serialize)]
+pub struct Problem {
+    /// `integrity`, `schema`, `foreign_key`, `orphaned_read` or
+    /// `unreferenced_media`
+    pub kind: &'static str,
+    /// The database or file it's in
+    pub path: String,
+    pub detail: String,
+    pub fixed: bool,
+}
+
+#[derive(Serialize)]
+pub struct DoctorOutput {
+    /// Nothing left to fix
+    pub healthy: bool,
+    pub problems: Vec<Problem>,
+}
+
+fn problem(kind: &'static str, path: &str, detail: String) -> Problem {
+    Problem {
+        kind,
+        path: path.to_string(),
+        detail,
+        fixed: false,
+    }
+}
+
+fn integrity(conn: &Connection) -> Result<Vec<String>> {
+    let messages: Vec<String> = conn
+        .prepare("PRAGMA integrity_check")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(messages
+        .into_iter()
+        .filter(|m| m != "ok")
+        .take(MAX_INTEGRITY_ERRORS)
+        .collect())
+}
+
+/// Rows whose foreign key points at nothing, as (table, rowid, parent table)
+fn foreign_key_violations(conn: &Connection) -> Result<Vec<(String, i64, String)>> {
+    Ok(conn
+        .prepare("PRAGMA foreign_key_check")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+/// The message store's failed migrations, `local.db`'s version if it isn't
+/// this build's, or a file that hasn't been moved into `local.db` yet
+fn schema_problem(conn: &Connection, name: &str) -> Result<Option<(String, bool)>> {
+    if name == "signal.db" {
+        let failed: Vec<i64> =
+            match conn.prepare("SELECT version FROM _sqlx_migrations WHERE NOT success") {
+                Ok(mut stmt) => stmt
+                    .query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()?,
+                // No migrations table yet: nothing has been stored
+                Err(_) => Vec::new(),
+            };
+        return Ok((!failed.is_empty()).then(|| {
+            let detail = format!("Migrations {:?} failed", failed);
+            (detail, false)
+        }));
+    }
+    if local::ADOPTED.iter().any(|(file, _)| *file == name) {
+        return Ok(Some(("Not yet moved into local.db".to_string(), true)));
+    }
+    if name != "local.db" {
+        return Ok(None);
+    }
+    let expected = local::schema_version();
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(if version < expected {
+        Some((
+            format!("Schema version {} is older than {}", version, expected),
+            true,
+        ))
+    } else if version > expected {
+        Some((
+            format!(
+                "Schema version {} is from a newer build (this one uses {})",
+                version, expected
+            ),
+            false,
+        ))
+    } else {
+        None
+    })
+}
+
+/// Check the data dir. Orphaned reads are only looked for with `manager`,
+/// the store loaded as linked.
+pub async fn doctor(manager: Option<&SignalManager>, fix: bool) -> Result<DoctorOutput> {
+    let data_dir = get_data_dir()?;
+    let mut names: Vec<String> = std::fs::read_dir(&data_dir)
+        .with_context(|| format!("Failed to read {}", data_dir.display()))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".db"))
+        .collect();
+    names.sort();
+
+    let mut problems = Vec::new();
+    let mut stale_schema = Vec::new();
+    for name in &names {
+        let path = data_dir.join(name);
+        let conn = db::open_read_only(&path)?;
+        let errors = integrity(&conn)?;
+        let corrupt = !errors.is_empty();
+        for error in errors {
+            problems.push(problem("integrity", name, error));
+        }
+        // A damaged database is left alone until it's restored
+        if corrupt {
+            continue;
+        }
+        if let Some((detail, fixable)) = schema_problem(&conn, name)? {
+            if fixable {
+                stale_schema.push(problems.len());
+            }
+            problems.push(problem("schema", name, detail));
+        }
+        let violations = foreign_key_violations(&conn)?;
+        if violations.is_empty() {
+            continue;
+        }
+        drop(conn);
+        let conn = if fix {
+            let conn = Connection::open(&path)?;
+            unlock(&conn)?;
+            Some(conn)
+        } else {
+            None
+        };
+        for (table, rowid, parent) in violations {
+            let mut found = problem(
+                "foreign_key",
+                name,
+                format!(
+                    "Row {} of {} refers to a missing {} row",
+                    rowid, table, parent
+                ),
+            );
+            if let Some(conn) = &conn {
+                conn.execute(
+                    &format!(
+                        "DELETE FROM \"{}\" WHERE rowid = ?1",
+                        table.replace('"', "\"\"")
+                    ),
+                    [rowid],
+                )?;
+                found.fixed = true;
+            }
+            problems.push(found);
+        }
+    }
+    if fix && !stale_schema.is_empty() {
+        db::upgrade().await?;
+        for index in stale_schema {
+            problems[index].fixed = true;
+        }
+    }
+
+    // Reads can only be matched to messages with the store loaded
+    if let Some(manager) = manager {
+        let mut conn = read_sync::open_read_sync_db()?;
+        let before = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64
+            - read_sync::ORPHANED_READ_DAYS * 24 * 60 * 60;
+        let orphaned = read_sync::orphaned_reads(manager, &conn, before).await?;
+        if fix {
+            read_sync::forget_reads(&mut conn, &orphaned)?;
+        }
+        for (sender_aci, timestamp) in orphaned {
+            let mut found = problem(
+                "orphaned_read",
+                "local.db",
+                format!(
+                    "Read of a message from {} sent at {} that never arrived",
+                    sender_aci, timestamp
+                ),
+            );
+            found.fixed = fix;
+            problems.push(found);
+        }
+    }
+
+    let conn = view_once::open_view_once_db()?;
+    for path in view_once::unreferenced_media(&conn)? {
+        let mut found = problem(
+            "unreferenced_media",
+            &path.display().to_string(),
+            "View-once media with no record of its message".to_string(),
+        );
+        if fix {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            found.fixed = true;
+        }
+        problems.push(found);
+    }
+
+    Ok(DoctorOutput {
+        healthy: problems.iter().all(|p| p.fixed),
+        problems,
+    })
+}
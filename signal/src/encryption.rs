@@ -0,0 +1,224 @@
+//! Encrypting the databases with a passphrase.
+//!
+//! The message store and every local database are SQLCipher databases, all
+//! keyed with one passphrase, or plaintext without one. The passphrase comes
+//! from `--passphrase-file`, or else, if the store is encrypted, from the
+//! `signal-db-passphrase` secret (see [`secrets`]). `rekey` encrypts
+//! plaintext databases, changes the passphrase, or removes it. Kept only in
+//! the OS keychain, a generated passphrase lets `serve` start unattended with
+//! nothing on disk that opens the databases.
+
+use std::path::Path;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use signal_core::{is_encrypted, lock, unlock};
+
+use crate::secrets::{self, SecretStore};
+use crate::{daemon, get_data_dir};
+
+/// Secret the passphrase is looked up in when no file is given
+pub const PASSPHRASE_SECRET: &str = "signal-db-passphrase";
+
+#[derive(Serialize)]
+pub struct RekeyOutput {
+    /// Whether the databases are now encrypted
+    pub encrypted: bool,
+    /// The databases rewritten with the new passphrase
+    pub databases: Vec<String>,
+    /// Where the `signal-db-passphrase` secret was updated (or deleted, when
+    /// decrypting), if the passphrase is kept there
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<SecretStore>,
+}
+
+/// Read a passphrase from a file, without its trailing newline.
+pub fn read_passphrase_file(path: &Path) -> Result<String> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let passphrase = text.trim_end_matches(['\r', '\n']);
+    if passphrase.is_empty() {
+        anyhow::bail!("{} is empty", path.display());
+    }
+    Ok(passphrase.to_string())
+}
+
+/// A random passphrase, for one nobody has to type
+pub fn generate_passphrase() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// The passphrase to open the databases with: from `file` if given, or the
+/// secret if the store is encrypted.
+pub fn passphrase(file: Option<&Path>) -> Result<Option<String>> {
+    if let Some(file) = file {
+        return read_passphrase_file(file).map(Some);
+    }
+    let store = get_data_dir()?.join("signal.db");
+    if !store.exists() || !is_encrypted(&store)? {
+        return Ok(None);
+    }
+    Ok(secrets::get(PASSPHRASE_SECRET)?.map(|(value, _)| value))
+}
+
+/// Copy a database to `dest`, encrypted with `passphrase`, or plaintext if
+/// it's empty.
+fn export(source: &Path, dest: &Path, passphrase: &str) -> Result<()> {
+    let _ = std::fs::remove_file(dest);
+    let conn = Connection::open(source)?;
+    unlock(&conn)?;
+    conn.execute(
+        "ATTACH DATABASE ?1 AS rekeyed KEY ?2",
+        rusqlite::params![dest.to_string_lossy(), passphrase],
+    )?;
+    conn.query_row("SELECT sqlcipher_export('rekeyed')", [], |_| Ok(()))?;
+    // Copied explicitly: local databases are migrated by it
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    conn.execute_batch(&format!("PRAGMA rekeyed.user_version = {}", version))?;
+    conn.execute("DETACH DATABASE rekeyed", [])?;
+    Ok(())
+}
+
+/// Move a database's rekeyed copy into place, keeping the original (and its
+/// WAL, which the new file mustn't replay) as `.old` until [`unswap`] or the
+/// rekey finishing removes it.
+fn swap(data_dir: &Path, name: &str) -> std::io::Result<()> {
+    let path = data_dir.join(name);
+    let wal = data_dir.join(format!("{}-wal", name));
+    let old_wal = data_dir.join(format!("{}.old-wal", name));
+    let _ = std::fs::remove_file(&old_wal);
+    let _ = std::fs::remove_file(data_dir.join(format!("{}-shm", name)));
+    if wal.exists() {
+        std::fs::rename(&wal, &old_wal)?;
+    }
+    std::fs::rename(&path, data_dir.join(format!("{}.old", name)))?;
+    std::fs::rename(data_dir.join(format!("{}.rekey", name)), &path)
+}
+
+/// Put back the original [`swap`] moved aside, if it got that far.
+fn unswap(data_dir: &Path, name: &str) -> std::io::Result<()> {
+    let old = data_dir.join(format!("{}.old", name));
+    let old_wal = data_dir.join(format!("{}.old-wal", name));
+    let wal = data_dir.join(format!("{}-wal", name));
+    let _ = std::fs::remove_file(data_dir.join(format!("{}-shm", name)));
+    if old.exists() {
+        std::fs::rename(&old, data_dir.join(name))?;
+    }
+    if old_wal.exists() {
+        std::fs::rename(&old_wal, &wal)?;
+    }
+    Ok(())
+}
+
+/// Rewrite every database with `new_passphrase`, or plaintext with `None`.
+/// Each is copied first, and only once all are is any replaced. Originals are
+/// kept until every one is, so a failure part way puts back those already
+/// replaced; the error says which, and any that couldn't be put back.
+///
+/// The new passphrase is saved as the secret in `save_to`, or wherever the
+/// secret is kept now, before anything is replaced, and put back with them.
+pub fn rekey(new_passphrase: Option<String>, save_to: Option<SecretStore>) -> Result<RekeyOutput> {
+    let data_dir = get_data_dir()?;
+    if daemon::is_running()? {
+        anyhow::bail!("A `serve` daemon is using the databases; stop it before rekeying");
+    }
+    // Keep receives and sends out while the files are swapped
+    lock::lock_instance()?;
+    let _lock = lock::lock_store()?;
+    // Checked first, so a secret that can't be updated stops the rekey
+    let current = secrets::get(PASSPHRASE_SECRET)?;
+    let current_store = current.as_ref().map(|(_, store)| *store);
+    let secret = save_to.or(current_store);
+
+    let mut names: Vec<String> = std::fs::read_dir(&data_dir)
+        .with_context(|| format!("Failed to read {}", data_dir.display()))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".db"))
+        .collect();
+    names.sort();
+
+    let copy = |name: &str| data_dir.join(format!("{}.rekey", name));
+    let new = new_passphrase.as_deref().unwrap_or("");
+    let discard = || {
+        for name in &names {
+            let _ = std::fs::remove_file(copy(name));
+        }
+    };
+    for name in &names {
+        if let Err(e) = export(&data_dir.join(name), &copy(name), new) {
+            discard();
+            return Err(e.context(format!("Failed to rekey {}", name)));
+        }
+    }
+    // A generated passphrase is nowhere else, so it's saved before the swap
+    if let (Some(store), Some(passphrase)) = (secret, &new_passphrase) {
+        if let Err(e) = secrets::set(store, PASSPHRASE_SECRET, passphrase) {
+            discard();
+            return Err(e);
+        }
+    }
+    for (i, name) in names.iter().enumerate() {
+        let Err(e) = swap(&data_dir, name) else {
+            continue;
+        };
+        // This one too: it may have been moved aside before failing
+        let mut put_back = Vec::new();
+        let mut stuck = Vec::new();
+        for name in &names[..=i] {
+            match unswap(&data_dir, name) {
+                Ok(()) if name.as_str() == names[i] => {}
+                Ok(()) => put_back.push(name.as_str()),
+                Err(e) => stuck.push(format!("{} (the original is {}.old: {})", name, name, e)),
+            }
+        }
+        discard();
+        if let (Some(store), Some(_)) = (secret, &new_passphrase) {
+            let restored = match &current {
+                Some((old, old_store)) if *old_store == store => {
+                    secrets::set(store, PASSPHRASE_SECRET, old)
+                }
+                _ => secrets::remove(store, PASSPHRASE_SECRET).map(|_| ()),
+            };
+            if let Err(e) = restored {
+                stuck.push(format!("the {} secret ({})", PASSPHRASE_SECRET, e));
+            }
+        }
+        let mut message = format!("Failed to replace {}: {}", name, e);
+        if !put_back.is_empty() {
+            message.push_str(&format!("; put back {}", put_back.join(", ")));
+        }
+        if stuck.is_empty() {
+            message.push_str(", so every database is as it was");
+        } else {
+            message.push_str(&format!("; couldn't put back {}", stuck.join(", ")));
+        }
+        anyhow::bail!(message);
+    }
+    for name in &names {
+        let _ = std::fs::remove_file(data_dir.join(format!("{}.old", name)));
+        let _ = std::fs::remove_file(data_dir.join(format!("{}.old-wal", name)));
+    }
+
+    match (current_store, &new_passphrase) {
+        (Some(_), None) => {
+            secrets::delete(PASSPHRASE_SECRET)?;
+        }
+        // Moved: the old copy would be stale, and the keyring is read first
+        (Some(old), Some(_)) if Some(old) != secret => {
+            secrets::remove(old, PASSPHRASE_SECRET)?;
+        }
+        _ => {}
+    }
+
+    Ok(RekeyOutput {
+        encrypted: new_passphrase.is_some(),
+        databases: names,
+        secret,
+    })
+}
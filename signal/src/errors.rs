@@ -0,0 +1,165 @@
+//! Machine-readable failures.
+//!
+//! When a command fails, the CLI prints
+//! `{"error": {"code": "NOT_LINKED", "message": "..."}}` on stdout instead of
+//! a message on stderr, and exits with a status for the kind of failure (see
+//! [`ErrorCode::exit_code`]), so scripts can branch on it without parsing
+//! messages. The daemon answers proxied requests with the same object, so a
+//! command keeps its code whether or not `serve` is running.
+
+use std::process::ExitCode;
+
+use serde::{Deserialize, Serialize};
+use signal_core::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// No linked account, or the server no longer recognizes this device
+    NotLinked,
+    /// A `link` was interrupted; running it again starts over
+    LinkInterrupted,
+    /// The Signal servers couldn't be reached or rejected the request
+    Network,
+    /// Another instance is receiving for this account
+    Busy,
+    /// The server is throttling this account
+    RateLimited,
+    /// A chat ID, UUID or contact name that doesn't resolve to one recipient
+    InvalidRecipient,
+    /// The command line didn't parse: an unknown flag, a missing argument
+    Usage,
+    /// Arguments or input that can't be acted on as given
+    InvalidInput,
+    /// The message store or a local database failed
+    Store,
+    /// The databases are encrypted and the passphrase is missing or wrong
+    Encrypted,
+    /// A contact's safety number changed and the identity policy blocks them
+    UntrustedIdentity,
+    /// `--read-only` refused an action that would send something
+    ReadOnly,
+    /// Anything else
+    Failed,
+}
+
+impl ErrorCode {
+    fn of_core(e: &Error) -> Self {
+        match e {
+            Error::NotLinked | Error::Unlinked => ErrorCode::NotLinked,
+            Error::LinkInterrupted => ErrorCode::LinkInterrupted,
+            Error::Network(_) => ErrorCode::Network,
+            Error::Busy => ErrorCode::Busy,
+            Error::RateLimited => ErrorCode::RateLimited,
+            Error::UntrustedIdentity(_) => ErrorCode::UntrustedIdentity,
+            Error::InvalidRecipient(_) => ErrorCode::InvalidRecipient,
+            Error::Invalid(_) => ErrorCode::InvalidInput,
+            Error::ReadOnly(_) => ErrorCode::ReadOnly,
+            Error::Storage(_) => ErrorCode::Store,
+            Error::Encrypted => ErrorCode::Encrypted,
+        }
+    }
+
+    /// The code for the first classifiable error in the chain
+    pub fn of(e: &anyhow::Error) -> Self {
+        e.chain()
+            .find_map(|cause| {
+                if let Some(e) = cause.downcast_ref::<Error>() {
+                    Some(Self::of_core(e))
+                } else if let Some(e) = cause.downcast_ref::<ErrorBody>() {
+                    Some(e.code)
+                } else if cause.is::<rusqlite::Error>() {
+                    Some(ErrorCode::Store)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(ErrorCode::Failed)
+    }
+
+    /// Exit status: 2 usage or bad input, 3 not linked, 4 network, 5 rate
+    /// limited, 6 store or passphrase, 7 unknown recipient, 8 busy, 1 anything else
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorCode::Usage | ErrorCode::InvalidInput => 2,
+            ErrorCode::NotLinked | ErrorCode::LinkInterrupted => 3,
+            ErrorCode::Network => 4,
+            ErrorCode::RateLimited => 5,
+            ErrorCode::Store | ErrorCode::Encrypted => 6,
+            ErrorCode::InvalidRecipient => 7,
+            ErrorCode::Busy => 8,
+            ErrorCode::UntrustedIdentity | ErrorCode::ReadOnly | ErrorCode::Failed => 1,
+        }
+    }
+}
+
+/// The `error` object of a failed command's output
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorBody {
+    pub fn new(e: &anyhow::Error) -> Self {
+        ErrorBody {
+            code: ErrorCode::of(e),
+            message: format!("{:#}", e),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ErrorBody {}
+
+#[derive(Serialize)]
+struct ErrorOutput {
+    error: ErrorBody,
+}
+
+/// Print a failed command's error and return its exit status.
+pub fn report(e: &anyhow::Error) -> ExitCode {
+    print_error(ErrorBody::new(e))
+}
+
+/// Report a command line that didn't parse. Help and version requests aren't
+/// failures and print as clap prints them.
+pub fn report_usage(e: clap::Error) -> ExitCode {
+    use clap::error::ErrorKind;
+    if matches!(
+        e.kind(),
+        ErrorKind::DisplayHelp
+            | ErrorKind::DisplayVersion
+            | ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand
+    ) {
+        let _ = e.print();
+        return ExitCode::from(e.exit_code() as u8);
+    }
+    // The rendered error without its `error: ` prefix and usage footer
+    let rendered = e.render().to_string();
+    let message = rendered
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_start_matches("error: ")
+        .to_string();
+    print_error(ErrorBody {
+        code: ErrorCode::Usage,
+        message,
+    })
+}
+
+fn print_error(error: ErrorBody) -> ExitCode {
+    let code = error.code.exit_code();
+    if let Err(e) = crate::print_json(&ErrorOutput { error }) {
+        eprintln!("Error: {:#}", e);
+    }
+    ExitCode::from(code)
+}
@@ -0,0 +1,439 @@
+//! `export`: write a chat's full history to a file, for archiving or for
+//! handing a conversation to another tool.
+//!
+//! Messages come from the local store, oldest first. Reactions, payments and
+//! other events are left out. With attachments, each one is downloaded into
+//! a directory next to the output file and referenced by a path relative to
+//! it, so the export can be moved as a whole.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{Local, TimeZone};
+use clap::ValueEnum;
+use presage::libsignal_service::content::ContentBody;
+use presage::proto::AttachmentPointer;
+use presage::store::{ContentsStore, Thread};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use signal_core::model::{message_id, EventOutput};
+use signal_core::timestamp::{self, TimeFormat, Timestamp};
+use signal_core::{parse_thread, view_once, SignalManager};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    Html,
+}
+
+#[derive(Serialize)]
+struct ExportedChat {
+    id: String,
+    name: String,
+    is_group: bool,
+    exported_at: i64,
+    messages: Vec<ExportedMessage>,
+}
+
+#[derive(Serialize)]
+struct ExportedMessage {
+    id: String,
+    sender: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sender_name: Option<String>,
+    timestamp: Timestamp,
+    text: String,
+    is_outgoing: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<ExportedAttachment>,
+}
+
+#[derive(Serialize)]
+struct ExportedAttachment {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u32>,
+    /// Relative to the export file; only when attachments were copied and
+    /// the download worked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+/// What an export wrote, for the command's output
+pub struct ExportSummary {
+    pub messages: usize,
+    pub attachments_copied: usize,
+}
+
+/// A file name for a downloaded attachment: unique per message and position,
+/// keeping the original extension where there is one.
+fn attachment_file_name(id: &str, index: usize, pointer: &AttachmentPointer) -> String {
+    let extension = pointer
+        .file_name
+        .as_deref()
+        .and_then(|name| Path::new(name).extension()?.to_str())
+        .or_else(|| pointer.content_type.as_deref()?.split('/').nth(1))
+        .filter(|ext| ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or("bin");
+    format!("{}-{}.{}", id, index, extension)
+}
+
+async fn chat_name(manager: &SignalManager, thread: &Thread) -> Result<String> {
+    let store = manager.store();
+    Ok(match thread {
+        Thread::Contact(uuid) => store
+            .contact_by_id(uuid)
+            .await?
+            .map(|contact| contact.name)
+            .unwrap_or_default(),
+        Thread::Group(master_key) => store
+            .group(*master_key)
+            .await?
+            .map(|group| group.title)
+            .unwrap_or_default(),
+    })
+}
+
+/// Write every stored message in `chat_id` to `output`, with JSON
+/// timestamps in `time_format`.
+pub async fn export(
+    manager: &SignalManager,
+    chat_id: &str,
+    format: ExportFormat,
+    output: &Path,
+    with_attachments: bool,
+    time_format: TimeFormat,
+) -> Result<ExportSummary> {
+    let thread = parse_thread(chat_id)?;
+    let store = manager.store();
+    let my_uuid = manager.whoami().await?.aci;
+
+    let names: HashMap<String, String> = store
+        .contacts()
+        .await?
+        .flatten()
+        .filter(|contact| !contact.name.is_empty())
+        .map(|contact| (contact.uuid.to_string(), contact.name))
+        .collect();
+
+    // e.g. `chat.html` -> `chat_attachments/`
+    let stem = output
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("export");
+    let attachment_dir_name = format!("{}_attachments", stem);
+    let attachment_dir: PathBuf = output
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(&attachment_dir_name);
+
+    let mut messages = Vec::new();
+    let mut attachments_copied = 0;
+    for content in store.messages(&thread, ..).await?.flatten() {
+        let ContentBody::DataMessage(dm) = &content.body else {
+            continue;
+        };
+        let sender_uuid = content.metadata.sender.raw_uuid();
+        let sender = sender_uuid.to_string();
+        if EventOutput::from_data_message(chat_id, &sender, dm).is_some() {
+            continue;
+        }
+        let text = dm.body.clone().unwrap_or_default();
+        if text.is_empty() && dm.attachments.is_empty() {
+            continue;
+        }
+        let ts = dm.timestamp.unwrap_or(0);
+        let id = message_id(ts, &sender);
+
+        // View-once media is only ever downloaded by `receive`, once
+        let copy = with_attachments && !view_once::is_view_once(dm);
+        let mut attachments = Vec::new();
+        for (index, pointer) in dm.attachments.iter().enumerate() {
+            let mut path = None;
+            if copy {
+                let file_name = attachment_file_name(&id, index, pointer);
+                // A missing attachment (e.g. expired from the CDN) shouldn't
+                // stop the rest of the export
+                match manager.get_attachment(pointer).await {
+                    Ok(data) => {
+                        std::fs::create_dir_all(&attachment_dir).with_context(|| {
+                            format!("Failed to create {}", attachment_dir.display())
+                        })?;
+                        std::fs::write(attachment_dir.join(&file_name), data)?;
+                        attachments_copied += 1;
+                        path = Some(format!("{}/{}", attachment_dir_name, file_name));
+                    }
+                    Err(e) => warn!("Failed to download attachment of {}: {}", id, e),
+                }
+            }
+            attachments.push(ExportedAttachment {
+                content_type: pointer.content_type.clone(),
+                file_name: pointer.file_name.clone(),
+                size: pointer.size,
+                path,
+            });
+        }
+
+        messages.push(ExportedMessage {
+            id,
+            sender_name: names.get(&sender).cloned(),
+            sender,
+            timestamp: Timestamp::from_millis(ts),
+            text,
+            is_outgoing: sender_uuid == my_uuid,
+            attachments,
+        });
+    }
+
+    let chat = ExportedChat {
+        id: chat_id.to_string(),
+        name: chat_name(manager, &thread).await?,
+        is_group: matches!(thread, Thread::Group(_)),
+        exported_at: Local::now().timestamp(),
+        messages,
+    };
+    let rendered = match format {
+        ExportFormat::Json => {
+            timestamp::with_time_format(time_format, || serde_json::to_string_pretty(&chat))?
+        }
+        ExportFormat::Csv => to_csv(&chat),
+        ExportFormat::Html => to_html(&chat),
+    };
+    std::fs::write(output, rendered)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+
+    Ok(ExportSummary {
+        messages: chat.messages.len(),
+        attachments_copied,
+    })
+}
+
+fn format_time(timestamp: i64) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// An attachment's local path, or failing that its original name
+fn attachment_label(attachment: &ExportedAttachment) -> &str {
+    attachment
+        .path
+        .as_deref()
+        .or(attachment.file_name.as_deref())
+        .unwrap_or("[attachment]")
+}
+
+/// Quote a CSV field if it needs it (RFC 4180)
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn to_csv(chat: &ExportedChat) -> String {
+    let mut out = String::from("id,time,sender,sender_name,is_outgoing,text,attachments\r\n");
+    for message in &chat.messages {
+        let attachments: Vec<&str> = message.attachments.iter().map(attachment_label).collect();
+        let fields = [
+            message.id.clone(),
+            format_time(message.timestamp.secs()),
+            message.sender.clone(),
+            message.sender_name.clone().unwrap_or_default(),
+            message.is_outgoing.to_string(),
+            message.text.clone(),
+            attachments.join(";"),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        out.push_str(&row.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const STYLE: &str = "body{font-family:sans-serif;max-width:48em;margin:2em auto;padding:0 1em}
+.message{margin:.75em 0;padding:.5em .75em;border-radius:.5em;background:#eee}
+.outgoing{background:#d4e6ff;margin-left:4em}
+.meta{font-size:.8em;color:#666}
+.text{white-space:pre-wrap}
+img{max-width:100%}";
+
+fn to_html(chat: &ExportedChat) -> String {
+    let title = escape_html(if chat.name.is_empty() {
+        &chat.id
+    } else {
+        &chat.name
+    });
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        title, STYLE, title
+    );
+    for message in &chat.messages {
+        let sender = match (&message.sender_name, message.is_outgoing) {
+            (_, true) => "Me".to_string(),
+            (Some(name), false) => escape_html(name),
+            (None, false) => escape_html(&message.sender),
+        };
+        let class = if message.is_outgoing {
+            "message outgoing"
+        } else {
+            "message"
+        };
+        let _ = write!(
+            out,
+            "<div class=\"{}\" id=\"m{}\">\n<div class=\"meta\">{} &middot; {}</div>\n",
+            class,
+            message.id,
+            sender,
+            format_time(message.timestamp.secs())
+        );
+        if !message.text.is_empty() {
+            let _ = writeln!(
+                out,
+                "<div class=\"text\">{}</div>",
+                escape_html(&message.text)
+            );
+        }
+        for attachment in &message.attachments {
+            let label = escape_html(attachment_label(attachment));
+            let is_image = attachment
+                .content_type
+                .as_deref()
+                .is_some_and(|t| t.starts_with("image/"));
+            let _ = match &attachment.path {
+                Some(path) if is_image => writeln!(
+                    out,
+                    "<div><a href=\"{0}\"><img src=\"{0}\" alt=\"{0}\"></a></div>",
+                    escape_html(path)
+                ),
+                Some(path) => writeln!(
+                    out,
+                    "<div><a href=\"{}\">{}</a></div>",
+                    escape_html(path),
+                    label
+                ),
+                None => writeln!(out, "<div>{}</div>", label),
+            };
+        }
+        out.push_str("</div>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(text: &str, sender_name: Option<&str>) -> ExportedChat {
+        ExportedChat {
+            id: "chat".to_string(),
+            name: "<Friends & Family>".to_string(),
+            is_group: true,
+            exported_at: 0,
+            messages: vec![ExportedMessage {
+                id: "1700000000000_a".to_string(),
+                sender: "a".to_string(),
+                sender_name: sender_name.map(str::to_string),
+                timestamp: Timestamp::from_millis(1700000000000),
+                text: text.to_string(),
+                is_outgoing: false,
+                attachments: vec![
+                    ExportedAttachment {
+                        content_type: Some("image/png".to_string()),
+                        file_name: Some("a.png".to_string()),
+                        size: None,
+                        path: Some("chat-attachments/x\"y.png".to_string()),
+                    },
+                    ExportedAttachment {
+                        content_type: None,
+                        file_name: Some("notes, v2.txt".to_string()),
+                        size: None,
+                        path: None,
+                    },
+                ],
+            }],
+        }
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn csv_rows_quote_text_and_attachments() {
+        let csv = to_csv(&chat("Hi, \"you\"\nthere", Some("Alice")));
+        let (header, row) = csv.split_once("\r\n").unwrap();
+        assert_eq!(
+            header,
+            "id,time,sender,sender_name,is_outgoing,text,attachments"
+        );
+        assert!(row.starts_with("1700000000000_a,"), "{}", row);
+        assert!(
+            row.ends_with(
+                ",a,Alice,false,\"Hi, \"\"you\"\"\nthere\",\"chat-attachments/x\"\"y.png;notes, v2.txt\"\r\n"
+            ),
+            "{}",
+            row
+        );
+    }
+
+    #[test]
+    fn html_escapes_everything_from_messages() {
+        assert_eq!(
+            escape_html("<b>\"Tom\" & 'Jerry'</b>"),
+            "&lt;b&gt;&quot;Tom&quot; &amp; 'Jerry'&lt;/b&gt;"
+        );
+
+        let html = to_html(&chat("<script>alert(1)</script>", Some("<i>Eve</i>")));
+        assert!(html.contains("<title>&lt;Friends &amp; Family&gt;</title>"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(html.contains("&lt;i&gt;Eve&lt;/i&gt;"));
+        assert!(html.contains(
+            "<a href=\"chat-attachments/x&quot;y.png\"><img src=\"chat-attachments/x&quot;y.png\""
+        ));
+        assert!(!html.contains("<script>") && !html.contains("<i>"));
+    }
+
+    #[test]
+    fn attachment_names_keep_safe_extensions() {
+        let pointer = |file_name: Option<&str>, content_type: Option<&str>| AttachmentPointer {
+            file_name: file_name.map(str::to_string),
+            content_type: content_type.map(str::to_string),
+            ..Default::default()
+        };
+        let name = |pointer| attachment_file_name("id", 0, &pointer);
+        assert_eq!(name(pointer(Some("photo.JPG"), None)), "id-0.JPG");
+        assert_eq!(name(pointer(None, Some("image/png"))), "id-0.png");
+        assert_eq!(name(pointer(Some("x.tar/../gz"), None)), "id-0.bin");
+        assert_eq!(
+            name(pointer(Some("../../etc"), Some("text/x-c++"))),
+            "id-0.bin"
+        );
+        assert_eq!(name(pointer(None, None)), "id-0.bin");
+    }
+}
@@ -0,0 +1,83 @@
+//! `receive --on-message`: run a command for each received message.
+//!
+//! The command runs under `sh -c` with the message JSON (as `receive` prints
+//! it) on stdin and `JC_SIGNAL_CHAT_ID` set. Whatever it prints to stdout,
+//! trimmed, is sent back to the chat as a reply; printing nothing sends
+//! nothing. A hook that fails, times out or exits non-zero is logged and its
+//! output dropped. Whether a hook runs at all, and what happens to its reply,
+//! is up to the chat's [response policy](crate::policy).
+
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// How long a hook may run before it's killed
+const HOOK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Which command handles each chat's messages
+#[derive(Default)]
+pub struct Hooks {
+    /// From `--on-message`; applies to every chat
+    all: Option<String>,
+    /// From the config file, by chat ID
+    per_chat: BTreeMap<String, String>,
+}
+
+impl Hooks {
+    pub fn new(all: Option<String>, per_chat: BTreeMap<String, String>) -> Self {
+        Hooks { all, per_chat }
+    }
+
+    pub fn command_for(&self, chat_id: &str) -> Option<&str> {
+        self.all
+            .as_deref()
+            .or_else(|| self.per_chat.get(chat_id).map(String::as_str))
+    }
+}
+
+/// Incoming messages among `receive` output items, with their chat ID.
+/// Events (which have a `type`) and my own messages don't get responses.
+pub fn incoming(items: &[Value]) -> impl Iterator<Item = (&str, &Value)> {
+    items
+        .iter()
+        .filter(|item| item.get("type").is_none())
+        .filter(|item| item.get("is_outgoing") != Some(&Value::Bool(true)))
+        .filter_map(|item| Some((item.get("chat_id")?.as_str()?, item)))
+}
+
+/// Run a hook on one message, returning its reply if it printed one.
+pub async fn run(command: &str, chat_id: &str, message: &Value) -> Result<Option<String>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("JC_SIGNAL_CHAT_ID", chat_id)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run hook: {}", command))?;
+
+    // Written alongside the wait, so a hook that never reads stdin can't
+    // outlast the timeout, and one that closes it early is fine
+    if let Some(mut stdin) = child.stdin.take() {
+        let input = message.to_string();
+        tokio::spawn(async move {
+            let _ = stdin.write_all(input.as_bytes()).await;
+        });
+    }
+
+    let output = tokio::time::timeout(HOOK_TIMEOUT, child.wait_with_output())
+        .await
+        .with_context(|| format!("Hook timed out after {}s", HOOK_TIMEOUT.as_secs()))??;
+    if !output.status.success() {
+        anyhow::bail!("Hook exited with {}", output.status);
+    }
+
+    let reply = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok((!reply.is_empty()).then_some(reply))
+}
@@ -0,0 +1,323 @@
+//! `send --link-preview`: fetch a preview of the first link in a message.
+//!
+//! Like the official clients, the sender fetches the page and sends its title,
+//! description and image along with the message; recipients never fetch it.
+//! Only `https` links are previewed. The title and description come from the
+//! page's Open Graph tags, falling back to `<title>` and the `description`
+//! meta tag; the image is `og:image`.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use presage::libsignal_service::sender::AttachmentSpec;
+use reqwest::header::CONTENT_TYPE;
+use signal_core::send::LinkPreview;
+use tracing::warn;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most of a page read looking for its tags
+const MAX_PAGE_BYTES: usize = 1024 * 1024;
+
+/// Largest preview image sent
+const MAX_IMAGE_BYTES: usize = 2 * 1024 * 1024;
+
+/// Longest title or description kept, in characters
+const MAX_TEXT_CHARS: usize = 300;
+
+/// The first `https` link in `text`, without trailing punctuation.
+pub fn first_link(text: &str) -> Option<&str> {
+    text.split_whitespace()
+        .filter_map(|word| word.find("https://").map(|i| &word[i..]))
+        .map(|link| link.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '"', '\'']))
+        .find(|link| link.len() > "https://".len())
+}
+
+/// Fetch a preview of `url`.
+pub async fn fetch(url: &str) -> Result<LinkPreview> {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .context("Failed to create HTTP client")?;
+    let page_url: reqwest::Url = url
+        .parse()
+        .with_context(|| format!("Invalid link: {}", url))?;
+
+    let (content_type, page) = get(&client, page_url.clone(), MAX_PAGE_BYTES).await?;
+    if !content_type.starts_with("text/html") {
+        anyhow::bail!("{} isn't a web page ({})", url, content_type);
+    }
+    let html = String::from_utf8_lossy(&page);
+    let tags = meta_tags(&html);
+    let tag = |names: &[&str]| {
+        names.iter().find_map(|name| {
+            tags.iter()
+                .find(|(key, value)| key.eq_ignore_ascii_case(name) && !value.is_empty())
+                .map(|(_, value)| clip(value))
+        })
+    };
+    let title = tag(&["og:title", "twitter:title"]).or_else(|| title_element(&html));
+    let description = tag(&["og:description", "twitter:description", "description"]);
+    if title.is_none() && description.is_none() {
+        anyhow::bail!("{} has no title or description", url);
+    }
+
+    // A missing or broken image still leaves a useful preview
+    let image = match tag(&["og:image", "og:image:url", "twitter:image"]) {
+        Some(src) => match fetch_image(&client, &page_url, &src).await {
+            Ok(image) => Some(image),
+            Err(e) => {
+                warn!("No image for the link preview: {:#}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    Ok(LinkPreview {
+        url: url.to_string(),
+        title,
+        description,
+        image,
+    })
+}
+
+async fn fetch_image(
+    client: &reqwest::Client,
+    page_url: &reqwest::Url,
+    src: &str,
+) -> Result<(AttachmentSpec, Vec<u8>)> {
+    let url = page_url
+        .join(src)
+        .with_context(|| format!("Invalid image URL: {}", src))?;
+    let (content_type, data) = get(client, url.clone(), MAX_IMAGE_BYTES).await?;
+    if !content_type.starts_with("image/") {
+        anyhow::bail!("{} isn't an image ({})", url, content_type);
+    }
+    let spec = AttachmentSpec {
+        content_type,
+        length: data.len(),
+        ..Default::default()
+    };
+    Ok((spec, data))
+}
+
+/// GET `url`, failing on an error status or a body over `limit` bytes.
+/// Returns the content type and body.
+async fn get(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    limit: usize,
+) -> Result<(String, Vec<u8>)> {
+    let mut response = client
+        .get(url.clone())
+        .send()
+        .await
+        .with_context(|| format!("Failed to fetch {}", url))?
+        .error_for_status()?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > limit {
+            // A page's tags are in its head, so a long page is cut short; an
+            // image can't be
+            if content_type.starts_with("text/html") {
+                body.truncate(limit);
+                break;
+            }
+            anyhow::bail!("{} is over {} bytes", url, limit);
+        }
+    }
+    Ok((content_type, body))
+}
+
+/// The `property` or `name`, and `content`, of each `<meta>` tag
+fn meta_tags(html: &str) -> Vec<(String, String)> {
+    let lower = html.to_ascii_lowercase();
+    let mut tags = Vec::new();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("<meta") {
+        let start = from + i + "<meta".len();
+        let Some(len) = lower[start..].find('>') else {
+            break;
+        };
+        let attributes = attributes(&html[start..start + len]);
+        let value = |name: &str| {
+            attributes
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        if let (Some(key), Some(content)) = (
+            value("property").or_else(|| value("name")),
+            value("content"),
+        ) {
+            tags.push((key, content));
+        }
+        from = start + len;
+    }
+    tags
+}
+
+/// A tag's attributes, with lowercase names and decoded values
+fn attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = tag.trim_start();
+    while let Some(eq) = rest.find('=') {
+        let name = rest[..eq]
+            .trim()
+            .rsplit(char::is_whitespace)
+            .next()
+            .unwrap_or_default();
+        let after = rest[eq + 1..].trim_start();
+        let (value, next) = match after.chars().next() {
+            Some(quote @ ('"' | '\'')) => match after[1..].find(quote) {
+                Some(end) => (&after[1..end + 1], &after[end + 2..]),
+                None => (&after[1..], ""),
+            },
+            _ => {
+                let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                (after[..end].trim_end_matches('/'), &after[end..])
+            }
+        };
+        attributes.push((name.to_ascii_lowercase(), decode_entities(value)));
+        rest = next;
+    }
+    attributes
+}
+
+/// The text of the page's `<title>`
+fn title_element(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    let title = clip(&decode_entities(&html[start..end]));
+    (!title.is_empty()).then_some(title)
+}
+
+/// Collapse whitespace and cut to [`MAX_TEXT_CHARS`]
+fn clip(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(MAX_TEXT_CHARS) {
+        Some((i, _)) => format!("{}…", text[..i].trim_end()),
+        None => text,
+    }
+}
+
+/// Decode the HTML character references pages commonly use in tags
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let decoded = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => match entity.strip_prefix('#') {
+                    Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16)
+                        .ok()
+                        .and_then(char::from_u32),
+                    Some(dec) => dec.parse().ok().and_then(char::from_u32),
+                    None => None,
+                },
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_first_https_link() {
+        assert_eq!(
+            first_link("see (https://example.com/a?b=c), or https://example.org"),
+            Some("https://example.com/a?b=c")
+        );
+        assert_eq!(
+            first_link("link:https://example.com."),
+            Some("https://example.com")
+        );
+        assert_eq!(first_link("http://example.com https://"), None);
+        assert_eq!(first_link("no links here"), None);
+    }
+
+    #[test]
+    fn reads_meta_tags_however_they_are_written() {
+        let html = r#"<html><head>
+            <META property="og:title" content="Caf&eacute; &amp; Bar">
+            <meta name='description' content='It&#39;s &quot;nice&quot;'/>
+            <meta content="https://example.com/i.png" property="og:image">
+            <meta name=twitter:title content=Unquoted/>
+            <meta charset="utf-8">
+            </head></html>"#;
+        assert_eq!(
+            meta_tags(html),
+            [
+                ("og:title".to_string(), "Caf&eacute; & Bar".to_string()),
+                ("description".to_string(), "It's \"nice\"".to_string()),
+                (
+                    "og:image".to_string(),
+                    "https://example.com/i.png".to_string()
+                ),
+                ("twitter:title".to_string(), "Unquoted".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_title_element() {
+        assert_eq!(
+            title_element("<head><TITLE lang=en>\n  A &lt;b&gt;  page\n</title></head>"),
+            Some("A <b> page".to_string())
+        );
+        assert_eq!(title_element("<title>  </title>"), None);
+        assert_eq!(title_element("<title>Unclosed"), None);
+    }
+
+    #[test]
+    fn decodes_common_entities_and_leaves_the_rest() {
+        assert_eq!(
+            decode_entities("&lt;&#65;&#x42;&nbsp;&apos;&gt; &bogus; & &#xZZ; &amp"),
+            "<AB '> &bogus; & &#xZZ; &amp"
+        );
+        assert_eq!(decode_entities("é&amp;é"), "é&é");
+    }
+
+    #[test]
+    fn clips_long_text_by_characters() {
+        assert_eq!(clip("  a\n\tb  c "), "a b c");
+        let long = "é".repeat(MAX_TEXT_CHARS + 5);
+        assert_eq!(clip(&long), format!("{}…", "é".repeat(MAX_TEXT_CHARS)));
+        let at_limit = "é".repeat(MAX_TEXT_CHARS);
+        assert_eq!(clip(&at_limit), at_limit);
+    }
+}
@@ -3,28 +3,65 @@
 //! Provides JSON-based CLI for sending/receiving Signal messages,
 //! designed for integration with jean-claude.
 
-use std::path::PathBuf;
-use std::process::Command as ProcessCommand;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command as ProcessCommand, ExitCode};
+use std::sync::OnceLock;
 use std::time::UNIX_EPOCH;
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use directories::ProjectDirs;
-use futures::{channel::oneshot, future, pin_mut, StreamExt};
-use presage::libsignal_service::configuration::SignalServers;
+use clap::{Args, Parser, Subcommand};
+use config::{OutputFormat, Server};
+use export::ExportFormat;
+use futures::{channel::oneshot, future};
 use presage::libsignal_service::content::ContentBody;
-use presage::libsignal_service::prelude::Uuid;
-use presage::libsignal_service::protocol::ServiceId;
-use presage::manager::Registered;
-use presage::model::identity::OnNewIdentity;
-use presage::model::messages::Received;
-use presage::proto::{sync_message, DataMessage};
+use presage::libsignal_service::prelude::{ProfileKey, Uuid};
+use presage::libsignal_service::sender::AttachmentSpec;
 use presage::store::{ContentsStore, Thread};
 use presage::Manager;
-use presage_store_sqlite::SqliteStore;
 use rusqlite::Connection;
-use serde::Serialize;
-use tracing::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::prelude::*;
+
+use signal_core::identity::{self, IdentityPolicy};
+use signal_core::link::Recovery;
+use signal_core::mentions::Mention;
+use signal_core::model::{parse_message_id, MessageOrder, SharedContactOutput};
+use signal_core::read_sync::{self, ReadReceiptPolicy};
+use signal_core::receive::ContentKind;
+use signal_core::send::{self, Outgoing, SendReport};
+use signal_core::styles::{self, StyleRange};
+use signal_core::timestamp::{self, TimeFormat, Timestamp};
+use signal_core::{
+    avatars, chats, conversations, drafts, events, expiry, get_data_dir, get_db_path, history,
+    is_account_dir, link, lists, load_registered_manager, lock, open_store, outbound, outbox,
+    parse_thread, receive, resolve_recipient, search, set_account, set_passphrase, shared_contacts,
+    status, stickers, stories, thread_chat_id, usage, view_once, voice_notes, SignalManager,
+};
+
+mod api_tokens;
+mod backup;
+mod config;
+mod daemon;
+mod db;
+mod doctor;
+mod encryption;
+mod errors;
+mod export;
+mod hooks;
+mod link_preview;
+mod mcp;
+mod policy;
+mod prune;
+mod registration;
+mod replicate;
+mod secrets;
+mod serve;
+mod trace;
+mod version;
+mod webhook;
 
 /// Signal CLI - send and receive Signal messages
 #[derive(Parser)]
@@ -35,6 +72,56 @@ struct Cli {
     #[arg(short, long, global = true)]
     verbose: bool,
 
+    /// How to handle a contact's identity key (safety number) changing
+    /// [default: trust, or `identity_policy` from config.toml]
+    #[arg(long, global = true, value_enum)]
+    identity_policy: Option<IdentityPolicy>,
+
+    /// Observer mode: archive and notify only; refuse to send anything
+    #[arg(long, global = true)]
+    read_only: bool,
+
+    /// If another instance is receiving, wait for it to finish instead of
+    /// failing with BUSY
+    #[arg(long, global = true)]
+    wait: bool,
+
+    /// Drop these kinds of incoming content without saving or returning them
+    /// [default: none, or `ignore_content` from config.toml]
+    #[arg(long, global = true, value_enum, value_delimiter = ',')]
+    ignore_content: Option<Vec<ContentKind>>,
+
+    /// Return incoming stories as `story` items, with their media downloaded,
+    /// instead of dropping them [default: `include_stories` from config.toml]
+    #[arg(long, global = true)]
+    include_stories: bool,
+
+    /// How to write message timestamps [default: unix, or `time_format` from
+    /// config.toml]
+    #[arg(long, global = true, value_enum)]
+    time_format: Option<TimeFormat>,
+
+    /// Write span timings to this file as a Chrome trace (open in ui.perfetto.dev)
+    #[arg(long, global = true)]
+    trace_output: Option<PathBuf>,
+
+    /// JSON output style [default: pretty, or `output` from config.toml]
+    #[arg(long, global = true, value_enum)]
+    output: Option<OutputFormat>,
+
+    /// Save downloaded files here instead of the data dir
+    #[arg(long, global = true)]
+    attachment_dir: Option<PathBuf>,
+
+    /// Named account to operate on; each keeps its own data dir
+    #[arg(long, global = true, env = "JC_SIGNAL_ACCOUNT")]
+    account: Option<String>,
+
+    /// File holding the passphrase the databases are encrypted with
+    /// [default: the `signal-db-passphrase` secret, if the store is encrypted]
+    #[arg(long, global = true, env = "JC_SIGNAL_PASSPHRASE_FILE")]
+    passphrase_file: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -42,707 +129,4191 @@ struct Cli {
 #[derive(Subcommand)]
 enum Command {
     /// Link as a secondary device (scan QR code with Signal app)
-    Link {
-        /// Device name shown in Signal settings
-        #[arg(short, long, default_value = "jean-claude")]
-        device_name: String,
+    Link(LinkArgs),
+
+    /// Link this device again, keeping its message history and local state
+    ///
+    /// For when the server no longer accepts this device (NOT_LINKED from a
+    /// device that was linked), e.g. after it was removed on the phone or
+    /// went unused for too long. Provisions a new link into the existing
+    /// store, replacing only its registration. Scanning the code with another
+    /// account's phone fails, and the store is put back as it was.
+    Relink(LinkArgs),
+
+    /// Register as the primary device for a phone number (no phone app needed)
+    ///
+    /// Requests an SMS or voice verification code and returns once it's sent;
+    /// finish with `verify-code`. The session waits 10 minutes for the code and
+    /// ends on the first one given, right or wrong; registering again starts a
+    /// new one.
+    Register {
+        /// Phone number in E.164 format, e.g. +15551234567
+        phone_number: String,
+
+        /// Receive the code by voice call instead of SMS
+        #[arg(long)]
+        voice: bool,
+
+        /// Captcha token (or signalcaptcha:// link) from
+        /// https://signalcaptchas.org/registration/generate.html
+        #[arg(long)]
+        captcha: Option<String>,
+
+        /// Register even if this store is already registered or linked
+        #[arg(long)]
+        force: bool,
+
+        /// Signal servers to register with [default: production]
+        #[arg(long, value_enum)]
+        server: Option<Server>,
+
+        /// Request the code and hold the session for `verify-code` (run by
+        /// `register` itself, in the background)
+        #[arg(long, hide = true)]
+        hold_session: bool,
+    },
+
+    /// Finish a `register` with the verification code Signal sent
+    VerifyCode {
+        /// The code, with or without its dash
+        code: String,
+    },
+
+    /// Unregister this linked device and delete its local data
+    Unlink {
+        /// Only unregister; keep the local database, logs and caches
+        #[arg(long)]
+        keep_data: bool,
     },
 
     /// Show account information
     Whoami,
 
+    /// Check that the Signal servers are reachable and this device's
+    /// credentials still work
+    ///
+    /// Opens the authenticated websocket and makes a request over it, which is
+    /// much cheaper than a `receive`. Fails with NETWORK if the servers can't
+    /// be reached and NOT_LINKED if they no longer accept this device.
+    Ping,
+
+    /// Show the version of this build
+    Version {
+        /// Also report library versions, supported features and database
+        /// schema versions, as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
     /// List chats (contacts and groups combined)
     Chats {
-        /// Maximum number of chats to return
-        #[arg(short = 'n', long, default_value = "50")]
-        max_results: usize,
+        /// Maximum number of chats to return [default: 50]
+        #[arg(short = 'n', long)]
+        max_results: Option<usize>,
+
+        /// Download avatars and include their local file paths
+        #[arg(long)]
+        with_avatars: bool,
+
+        /// Include each chat's count of unread incoming messages
+        #[arg(long)]
+        with_unread_counts: bool,
     },
 
-    /// Send a message (reads message from stdin)
-    Send {
-        /// Recipient UUID
-        recipient: String,
+    /// List chats by most recent message, with a preview, unread count and
+    /// muted/archived/pinned flags; pinned chats first
+    Conversations {
+        /// Maximum number of conversations to return [default: 50]
+        #[arg(short = 'n', long)]
+        max_results: Option<usize>,
     },
 
-    /// Receive pending messages
-    Receive,
+    /// Mute, archive or pin chats (local only)
+    Chat {
+        #[command(subcommand)]
+        action: ChatCommand,
+    },
 
-    /// List messages from a chat
-    Messages {
+    /// Download a chat's avatar and print its local file path
+    Avatar {
         /// Chat ID (UUID for contacts, hex for groups)
         chat_id: String,
+    },
 
-        /// Maximum number of messages to return
-        #[arg(short = 'n', long, default_value = "50")]
-        max_results: usize,
+    /// Send a message (reads message from stdin)
+    Send(SendArgs),
+
+    /// Send the queued messages that are due (see `send --queue`)
+    ///
+    /// A message that fails for lack of network or rate limiting is tried
+    /// again later, waiting twice as long each time; other failures, or too
+    /// many attempts, give up on it. `serve` flushes as it receives.
+    Flush {
+        /// Try every pending message now, even ones waiting to retry
+        #[arg(long)]
+        all: bool,
     },
 
-    /// Show connection status
-    Status,
+    /// Show or remove queued messages
+    Outbox {
+        #[command(subcommand)]
+        action: OutboxCommand,
+    },
 
-    /// Mark messages in a chat as read (local only)
-    MarkRead {
-        /// Chat IDs (UUID for contacts, hex for groups)
-        chat_ids: Vec<String>,
+    /// List installed sticker packs or send a sticker
+    Sticker {
+        #[command(subcommand)]
+        action: StickerCommand,
     },
-}
 
-/// Output types for JSON serialization
+    /// Receive pending messages
+    ///
+    /// Each chat's rule in `policy.toml` (allow, deny, require-approval,
+    /// auto-reply or escalate) decides whether its hook runs and what happens
+    /// to the reply.
+    Receive {
+        /// Also POST each item to this URL, signed with the webhook secret
+        #[arg(long)]
+        webhook: Option<String>,
 
-#[derive(Serialize)]
-struct ChatOutput {
-    id: String,
-    name: String,
-    is_group: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    phone: Option<String>,
-}
+        /// HMAC key for the webhook signature [default: the `signal-webhook-secret` secret]
+        #[arg(long, env = "JC_SIGNAL_WEBHOOK_SECRET", hide_env_values = true)]
+        webhook_secret: Option<String>,
 
-#[derive(Serialize)]
-struct MessageOutput {
-    id: String,
-    chat_id: String,
-    sender: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    sender_name: Option<String>,
-    timestamp: i64,
-    text: String,
-    is_outgoing: bool,
-    is_read: bool,
-}
+        /// Run this shell command for each message, with its JSON on stdin;
+        /// anything it prints is sent back as a reply [default: per-chat
+        /// commands from `on_message` in the config file]
+        #[arg(long)]
+        on_message: Option<String>,
 
-#[derive(Serialize)]
-struct WhoamiOutput {
-    uuid: String,
-    phone: Option<String>,
-    device_id: u32,
-}
+        /// Print only how many messages and events were received, not them
+        #[arg(long)]
+        store_only: bool,
+    },
 
-#[derive(Serialize)]
-struct StatusOutput {
-    linked: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    uuid: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    phone: Option<String>,
-}
+    /// Ask the phone for its contacts and wait until they're synced
+    ///
+    /// Contacts otherwise only sync when the phone happens to send them during
+    /// `receive`. Messages arriving while this waits are saved and logged to
+    /// the event stream as `receive` does.
+    SyncContacts {
+        /// Seconds to wait for the phone's reply
+        #[arg(long, default_value = "60")]
+        timeout: u64,
+    },
 
-#[derive(Serialize)]
-struct SendOutput {
-    success: bool,
-    timestamp: i64,
-}
+    /// Wait for the next incoming message in a chat, and print it
+    ///
+    /// Receives every few seconds until one arrives (through `serve` if it's
+    /// running). Other messages received meanwhile are saved and logged to the
+    /// event stream as `receive` does, but no hooks run for them.
+    AwaitReply {
+        /// Chat ID: a contact's UUID, or a group's chat ID
+        chat_id: String,
 
-#[derive(Serialize)]
-struct LinkOutput {
-    success: bool,
-    uuid: String,
-    device_name: String,
-}
+        /// Give up after this many seconds [default: wait indefinitely]
+        #[arg(long)]
+        timeout: Option<u64>,
 
-#[derive(Serialize)]
-struct MarkReadOutput {
-    success: bool,
-    chats_marked: usize,
-    messages_marked: i64,
-}
+        /// Only a message whose text matches this regex counts
+        #[arg(long)]
+        matching: Option<regex::Regex>,
+    },
 
-fn get_data_dir() -> Result<PathBuf> {
-    let dirs =
-        ProjectDirs::from("", "", "jean-claude").context("Failed to determine data directory")?;
-    let data_dir = dirs.data_dir().join("signal");
-    std::fs::create_dir_all(&data_dir)?;
-    Ok(data_dir)
-}
+    /// List messages from a chat
+    Messages {
+        /// Chat ID (UUID for contacts, hex for groups)
+        chat_id: String,
 
-fn get_db_path() -> Result<String> {
-    let path = get_data_dir()?.join("signal.db");
-    Ok(path.display().to_string())
-}
+        /// Maximum number of messages to return [default: 50]
+        #[arg(short = 'n', long)]
+        max_results: Option<usize>,
 
-/// Track read sync messages from other devices.
-///
-/// Uses a separate SQLite database because presage-store-sqlite doesn't expose
-/// its connection for custom tables. This tracks when messages were read on
-/// other devices (phone), allowing us to show accurate is_read status.
-mod read_sync {
-    use super::*;
+        /// Order by sent time (sender's clock) or by when we received it
+        #[arg(long, value_enum, default_value = "sent")]
+        order_by: MessageOrder,
 
-    fn get_read_sync_db_path() -> Result<PathBuf> {
-        Ok(get_data_dir()?.join("read_sync.db"))
-    }
+        /// Fail if the output order has ties or clock-skewed timestamps
+        #[arg(long)]
+        verify_ordering: bool,
 
-    pub fn open_read_sync_db() -> Result<Connection> {
-        let path = get_read_sync_db_path()?;
-        let conn = Connection::open(&path)?;
+        /// Show the chat as it was at this time, leaving out messages that
+        /// arrived later (e.g. 2h, 3d, or a unix timestamp)
+        #[arg(long)]
+        as_of: Option<String>,
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS read_sync (
-                sender_aci TEXT NOT NULL,
-                timestamp INTEGER NOT NULL,
-                read_at INTEGER NOT NULL,
-                PRIMARY KEY (sender_aci, timestamp)
-            )",
-            [],
-        )?;
+        /// Only messages from this time on, by the --order-by clock (e.g.
+        /// 2025-01-15, 2025-01-15T09:30:00Z, 3d, or a unix timestamp)
+        #[arg(long)]
+        since: Option<String>,
 
-        Ok(conn)
-    }
+        /// Only messages up to this time (same forms as --since)
+        #[arg(long)]
+        until: Option<String>,
 
-    /// Record that a message was read (from SyncMessage.Read)
-    fn mark_as_read(conn: &Connection, sender_aci: &str, timestamp: u64) -> rusqlite::Result<()> {
-        let now = std::time::SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+        /// Only messages older than this message ID; pass the last ID of a
+        /// page to get the next one
+        #[arg(long)]
+        before_id: Option<String>,
 
-        conn.execute(
-            "INSERT OR IGNORE INTO read_sync (sender_aci, timestamp, read_at) VALUES (?1, ?2, ?3)",
-            rusqlite::params![sender_aci, timestamp as i64, now],
-        )?;
+        /// Only messages newer than this message ID
+        #[arg(long)]
+        after_id: Option<String>,
 
-        Ok(())
-    }
+        /// Oldest first; -n then keeps the oldest messages in range
+        #[arg(long)]
+        ascending: bool,
 
-    /// Check if a message has been read.
-    /// Returns false on database errors (safe default: show as unread).
-    pub fn is_read(conn: &Connection, sender_aci: &str, timestamp: u64) -> bool {
-        conn.query_row(
-            "SELECT 1 FROM read_sync WHERE sender_aci = ?1 AND timestamp = ?2",
-            rusqlite::params![sender_aci, timestamp as i64],
-            |_| Ok(()),
-        )
-        .is_ok()
-    }
+        /// Only incoming messages that haven't been read
+        #[arg(long)]
+        unread_only: bool,
+    },
 
-    /// Process SyncMessage read entries in a single transaction.
-    pub fn process_sync_reads(conn: &mut Connection, reads: &[sync_message::Read]) -> Result<usize> {
-        let tx = conn.transaction()?;
-        let mut count = 0;
+    /// Write a chat's full stored history to a file
+    ///
+    /// Messages are oldest first; reactions and other events are left out.
+    Export {
+        /// Chat ID (UUID for contacts, hex for groups)
+        chat_id: String,
 
-        for read in reads {
-            if let (Some(sender_aci), Some(timestamp)) = (&read.sender_aci, read.timestamp) {
-                mark_as_read(&tx, sender_aci, timestamp)?;
-                count += 1;
-            }
-        }
+        #[arg(long, value_enum, default_value = "json")]
+        format: ExportFormat,
 
-        tx.commit()?;
-        Ok(count)
-    }
+        /// File to write
+        #[arg(short, long)]
+        output: PathBuf,
 
-    /// Mark all messages from a sender as read.
-    pub fn mark_sender_read(conn: &mut Connection, sender_aci: &str, timestamps: &[u64]) -> Result<i64> {
-        let tx = conn.transaction()?;
-        let mut count = 0i64;
+        /// Download attachments into `<name>_attachments/` next to the file
+        #[arg(long)]
+        with_attachments: bool,
+    },
 
-        for &ts in timestamps {
-            mark_as_read(&tx, sender_aci, ts)?;
-            count += 1;
-        }
+    /// Search stored messages across all chats
+    ///
+    /// QUERY uses SQLite FTS5 syntax: words match anywhere in a message,
+    /// "quoted phrases" match exactly, prefix* matches word starts, and terms
+    /// can be combined with AND, OR and NOT. Results are best match first,
+    /// with the matching part of each message in the snippet.
+    Search {
+        /// Search terms
+        query: String,
 
-        tx.commit()?;
-        Ok(count)
-    }
-}
+        /// Only search this chat (UUID for contacts, hex for groups)
+        #[arg(long = "chat")]
+        chat_id: Option<String>,
 
-async fn open_store() -> Result<SqliteStore> {
-    let db_path = get_db_path()?;
-    debug!("Opening store at {}", db_path);
+        /// Only messages from this sender (UUID)
+        #[arg(long)]
+        sender: Option<String>,
 
-    SqliteStore::open_with_passphrase(&db_path, None, OnNewIdentity::Trust)
-        .await
-        .context("Failed to open Signal database")
-}
+        /// Only messages sent after this time (e.g. 30d, 12h, or a unix timestamp)
+        #[arg(long)]
+        since: Option<String>,
 
-async fn load_registered_manager() -> Result<Manager<SqliteStore, Registered>> {
-    let store = open_store().await?;
-    Manager::load_registered(store)
-        .await
-        .context("Not linked to Signal. Run 'signal-cli link' first.")
-}
+        /// Only messages sent before this time (same forms as --since)
+        #[arg(long)]
+        until: Option<String>,
 
-async fn cmd_link(device_name: String) -> Result<()> {
-    let db_path = get_db_path()?;
-    debug!("Linking device, store at {}", db_path);
+        /// Maximum number of results [default: 50]
+        #[arg(short = 'n', long)]
+        max_results: Option<usize>,
 
-    let store = SqliteStore::open_with_passphrase(&db_path, None, OnNewIdentity::Trust)
-        .await
-        .context("Failed to open Signal database")?;
+        /// Rebuild the index from the message store first, picking up
+        /// messages stored before the index existed
+        #[arg(long)]
+        reindex: bool,
+    },
 
-    // Check if already registered
-    if Manager::load_registered(store.clone()).await.is_ok() {
-        eprintln!("Already linked to Signal. Use 'signal-cli status' to check.");
-        return Ok(());
-    }
+    /// Show connection status and what's stored locally
+    Status {
+        /// Also check that the servers are reachable and accept this device
+        #[arg(long)]
+        deep: bool,
+    },
 
-    eprintln!("Linking as secondary device...");
-    eprintln!("Open Signal on your phone: Settings > Linked Devices > Link New Device");
-    eprintln!();
+    /// Mark messages in a chat as read (local only)
+    ///
+    /// Everything up to the chat's newest message counts as read, including
+    /// older messages that are only delivered later. Reads synced from the
+    /// phone advance the same per-chat watermark.
+    MarkRead {
+        /// Chat IDs (UUID for contacts, hex for groups)
+        #[arg(required_unless_present = "messages")]
+        chat_ids: Vec<String>,
 
-    // Create channel for provisioning URL
-    let (tx, rx) = oneshot::channel();
+        /// Mark just this message read, leaving earlier ones unread; repeat
+        /// for several
+        #[arg(long = "message", value_name = "CHAT_ID:MESSAGE_ID")]
+        messages: Vec<String>,
 
-    // QR code file path
-    let qr_file = get_data_dir()?.join("qr.png");
-    let qr_file_cleanup = qr_file.clone();
+        /// Also send read receipts to senders [default: off]
+        #[arg(long, value_enum)]
+        read_receipts: Option<ReadReceiptPolicy>,
+    },
 
-    // Run linking and QR code display concurrently
-    let (result, _) = future::join(
-        Manager::link_secondary_device(
-            store,
-            SignalServers::Production,
-            device_name.clone(),
-            tx,
-        ),
-        async move {
-            match rx.await {
-                Ok(url) => {
-                    let url_str = url.to_string();
-
-                    // Save QR code to PNG file and open with system viewer
-                    match qrcode::QrCode::new(&url_str) {
-                        Ok(code) => {
-                            let image = code.render::<image::Luma<u8>>().build();
-                            if image.save(&qr_file).is_ok() {
-                                eprintln!("QR code saved to: {}", qr_file.display());
-                                // Open with system viewer (macOS: open, Linux: xdg-open)
-                                #[cfg(target_os = "macos")]
-                                let _ = ProcessCommand::new("open").arg(&qr_file).spawn();
-                                #[cfg(target_os = "linux")]
-                                let _ = ProcessCommand::new("xdg-open").arg(&qr_file).spawn();
-                            }
-                        }
-                        Err(e) => {
-                            warn!("Failed to generate QR code image: {}", e);
-                        }
-                    }
+    /// Control disappearing messages
+    Expire {
+        #[command(subcommand)]
+        action: ExpireCommand,
+    },
 
-                    // Also print to terminal as fallback
-                    eprintln!();
-                    eprintln!("Scan this QR code with Signal:");
-                    eprintln!("(Signal > Settings > Linked Devices > Link New Device)");
-                    eprintln!();
-                    qr2term::print_qr(&url_str).expect("Failed to render QR code");
-                    eprintln!();
-                    eprintln!("Or open this URL: {}", url_str);
-                }
-                Err(e) => {
-                    eprintln!("Linking cancelled: {:?}", e);
-                }
-            }
-        },
-    )
-    .await;
+    /// Mark messages in a chat as unread again (local only)
+    ///
+    /// Forgets the chat's reads, including ones synced from the phone, so
+    /// the messages come back as unread, e.g. for a handler to retry them.
+    MarkUnread {
+        /// Chat ID (UUID for contacts, hex for groups)
+        chat_id: String,
 
-    // Clean up QR file on success
-    let _ = std::fs::remove_file(&qr_file_cleanup);
+        /// Only messages sent after this one
+        #[arg(long, value_name = "MESSAGE_ID")]
+        after: Option<String>,
+    },
 
-    let manager = result?;
-    let whoami = manager.whoami().await?;
+    /// Show per-chat usage (messages sent and hooks run by this tool)
+    Usage {
+        /// Only count usage newer than this (e.g. 30d, 12h, or a unix timestamp)
+        #[arg(long, default_value = "30d")]
+        since: String,
+    },
 
-    eprintln!("Successfully linked! Device: {}", device_name);
+    /// Manage local distribution lists for broadcast sends
+    Lists {
+        #[command(subcommand)]
+        action: ListsCommand,
+    },
 
-    let output = LinkOutput {
-        success: true,
-        uuid: whoami.aci.to_string(),
-        device_name,
-    };
-    println!("{}", serde_json::to_string_pretty(&output)?);
+    /// Stage a reply per chat before sending it
+    Draft {
+        #[command(subcommand)]
+        action: DraftCommand,
+    },
 
-    Ok(())
-}
+    /// List and remove devices linked to this account
+    Devices {
+        #[command(subcommand)]
+        action: DevicesCommand,
+    },
 
-async fn cmd_whoami() -> Result<()> {
-    let manager = load_registered_manager().await?;
-    let whoami = manager.whoami().await?;
+    /// Inspect stored group state
+    Groups {
+        #[command(subcommand)]
+        action: GroupsCommand,
+    },
 
-    let output = WhoamiOutput {
-        uuid: whoami.aci.to_string(),
-        phone: Some(whoami.number.to_string()),
-        device_id: manager.device_id().into(),
-    };
+    /// List and approve contacts' identity keys
+    Identity {
+        #[command(subcommand)]
+        action: IdentityCommand,
+    },
 
-    println!("{}", serde_json::to_string_pretty(&output)?);
-    Ok(())
-}
+    /// Fetch and decrypt contact profiles
+    Profile {
+        #[command(subcommand)]
+        action: ProfileCommand,
+    },
 
-async fn cmd_chats(max_results: usize) -> Result<()> {
-    let manager = load_registered_manager().await?;
-    let store = manager.store();
+    /// Read the received-event log from a named consumer's position. Events
+    /// every consumer has acked are dropped after a week
+    Events {
+        /// Consumer name; each consumer has an independent ack position
+        consumer: String,
 
-    let mut chats = Vec::new();
+        /// Maximum number of events to return
+        #[arg(short = 'n', long, default_value = "100")]
+        max_results: usize,
 
-    // Add contacts
-    for contact in store.contacts().await?.flatten() {
-        chats.push(ChatOutput {
-            id: contact.uuid.to_string(),
-            name: contact.name.clone(),
-            is_group: false,
-            phone: contact.phone_number.map(|p| p.format().to_string()),
-        });
-    }
+        /// Return events without advancing the consumer's position
+        #[arg(long)]
+        peek: bool,
+    },
 
-    // Add groups
-    for (master_key, group) in store.groups().await?.flatten() {
-        chats.push(ChatOutput {
-            id: hex::encode(master_key),
-            name: group.title.clone(),
-            is_group: true,
-            phone: None,
-        });
-    }
+    /// Manage the store and local databases
+    Db {
+        #[command(subcommand)]
+        action: DbCommand,
+    },
 
-    // Limit results
-    chats.truncate(max_results);
+    /// Check the databases and downloaded media for damage and leftovers
+    ///
+    /// Runs SQLite's integrity and foreign key checks on every database,
+    /// compares schema versions with this build's, and looks for reads of
+    /// messages that never arrived and view-once media with no message.
+    /// Corrupt databases aren't repaired: restore a backup.
+    Doctor {
+        /// Repair what can be: upgrade stale schemas (as `db upgrade` does),
+        /// delete orphaned rows and remove unreferenced media
+        #[arg(long)]
+        fix: bool,
+    },
 
-    println!("{}", serde_json::to_string_pretty(&chats)?);
-    Ok(())
-}
+    /// Encrypt the databases with a passphrase, change it, or remove it
+    ///
+    /// Rewrites the message store and every local database with the new
+    /// passphrase; the current one comes from --passphrase-file or the
+    /// `signal-db-passphrase` secret, as for any command. If the passphrase is
+    /// kept in that secret, the secret is updated too. Stop `serve` first.
+    Rekey {
+        /// File holding the new passphrase
+        #[arg(long, required_unless_present_any = ["generate", "decrypt"])]
+        new_passphrase_file: Option<PathBuf>,
 
-/// Resolve recipient to UUID - accepts UUID directly or contact name
-async fn resolve_recipient(manager: &Manager<SqliteStore, Registered>, recipient: &str) -> Result<Uuid> {
-    // Try parsing as UUID first
-    if let Ok(uuid) = recipient.parse::<Uuid>() {
-        return Ok(uuid);
-    }
+        /// Generate a random passphrase and keep it only in the
+        /// `signal-db-passphrase` secret (in the OS keychain, unless --store
+        /// says otherwise)
+        #[arg(long, conflicts_with = "new_passphrase_file")]
+        generate: bool,
 
-    // Search contacts by name (case-insensitive substring match)
-    let search_lower = recipient.to_lowercase();
-    let mut matches: Vec<_> = manager
-        .store()
-        .contacts()
-        .await?
-        .filter_map(|c| c.ok())
-        .filter(|c| c.name.to_lowercase().contains(&search_lower))
-        .collect();
-
-    match matches.len() {
-        0 => anyhow::bail!(
-            "No contact found matching '{}'. Use a UUID or exact contact name.",
-            recipient
-        ),
-        1 => Ok(matches.remove(0).uuid),
-        _ => {
-            let mut msg = format!(
-                "Multiple contacts match '{}'. Use a UUID or more specific name:\n",
-                recipient
-            );
-            for contact in &matches {
-                let phone = contact
-                    .phone_number
-                    .as_ref()
-                    .map(|p| p.format().to_string())
-                    .unwrap_or_default();
-                msg.push_str(&format!("  - {} ({}) {}\n", contact.name, contact.uuid, phone));
-            }
-            anyhow::bail!(msg)
-        }
-    }
-}
+        /// Save the new passphrase as the `signal-db-passphrase` secret here
+        /// [default: where that secret is kept now, if anywhere]
+        #[arg(long, value_enum)]
+        store: Option<secrets::SecretStore>,
 
-async fn cmd_send(recipient: String) -> Result<()> {
-    let mut manager = load_registered_manager().await?;
+        /// Remove the encryption, leaving the databases plaintext
+        #[arg(long, conflicts_with_all = ["new_passphrase_file", "generate", "store"])]
+        decrypt: bool,
+    },
 
-    // Resolve recipient (UUID or contact name)
-    let uuid = resolve_recipient(&manager, &recipient).await?;
+    /// Delete old messages and leftovers to keep the databases from growing
+    ///
+    /// Deletes messages older than --retention-days and each chat's oldest
+    /// beyond --max-messages-per-chat, disappearing messages whose time is up,
+    /// reads of messages that were pruned or never arrived, view-once media
+    /// with no message, and event log entries acked by every consumer a week
+    /// ago or older than --retention-days. Without either limit, no message
+    /// is deleted for its age. Run it from cron to keep the data dir in
+    /// bounds.
+    Prune {
+        /// Delete messages sent more than this many days ago
+        #[arg(long)]
+        retention_days: Option<u32>,
 
-    // Read message from stdin
-    let text = {
-        use std::io::Read;
-        let mut buf = String::new();
-        std::io::stdin().read_to_string(&mut buf)?;
-        buf.trim().to_string()
-    };
+        /// Keep at most this many of each chat's newest messages
+        #[arg(long)]
+        max_messages_per_chat: Option<usize>,
+    },
 
-    if text.is_empty() {
-        anyhow::bail!("Message cannot be empty");
-    }
+    /// Keep a standby copy of the store and local databases up to date
+    ///
+    /// Snapshots every database that changed, plus config.toml, policy.toml
+    /// and the encrypted secrets file, into the target every --interval
+    /// seconds until stopped. Copying those files into a new host's data dir
+    /// replaces a failed host without re-linking or losing history.
+    Replicate {
+        /// Directory, or host:path to send with rsync over SSH
+        #[arg(long)]
+        to: String,
 
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(UNIX_EPOCH)?
-        .as_millis() as u64;
+        /// Seconds between passes
+        #[arg(long, default_value = "60")]
+        interval: u64,
 
-    // Build message
-    let data_message = DataMessage {
-        body: Some(text),
-        timestamp: Some(timestamp),
-        ..Default::default()
-    };
+        /// Copy once and exit instead of running continuously
+        #[arg(long)]
+        once: bool,
+    },
 
-    // Sync pending messages first
-    let messages = manager
-        .receive_messages()
-        .await
-        .context("failed to initialize messages stream")?;
-    pin_mut!(messages);
+    /// Serve chats, messages, send and mark-read as MCP tools over stdio
+    ///
+    /// Speaks newline-delimited JSON-RPC (Model Context Protocol) on stdin and
+    /// stdout, for agents that call tools instead of running commands.
+    Mcp,
 
-    while let Some(content) = messages.next().await {
-        match content {
-            Received::QueueEmpty => break,
-            Received::Contacts | Received::Content(_) => continue,
-        }
-    }
+    /// Serve a REST API with server-sent events for local clients
+    ///
+    /// GET /chats, GET /chats/{id}/messages, POST /chats/{id}/messages and
+    /// GET /events (SSE). Every request needs `Authorization: Bearer <token>`.
+    ///
+    /// While it runs, other signal-cli invocations that use the message store
+    /// go through it via a socket in the data dir.
+    ///
+    /// What it receives while idle gets the handling `receive` gives it: the
+    /// webhook, `--on-message` hooks and `policy.toml` rules apply as they
+    /// would there.
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8686")]
+        listen: std::net::SocketAddr,
 
-    // Send message
-    manager
-        .send_message(
-            ServiceId::Aci(uuid.into()),
-            ContentBody::DataMessage(data_message),
-            timestamp,
-        )
-        .await?;
+        /// Token clients must present [default: the `signal-api-token` secret]
+        #[arg(long, env = "JC_SIGNAL_API_TOKEN", hide_env_values = true)]
+        token: Option<String>,
 
-    let output = SendOutput {
-        success: true,
-        timestamp: (timestamp / 1000) as i64,
-    };
-    println!("{}", serde_json::to_string_pretty(&output)?);
+        /// Seconds between receives from the server while idle
+        #[arg(long, default_value = "30")]
+        receive_interval: u64,
 
-    Ok(())
-}
+        /// POST each item received while idle to this URL, as `receive --webhook`
+        #[arg(long)]
+        webhook: Option<String>,
 
-async fn cmd_receive() -> Result<()> {
-    let mut manager = load_registered_manager().await?;
+        /// HMAC key for the webhook signature [default: the `signal-webhook-secret` secret]
+        #[arg(long, env = "JC_SIGNAL_WEBHOOK_SECRET", hide_env_values = true)]
+        webhook_secret: Option<String>,
 
-    eprintln!("Receiving messages...");
+        /// Run this shell command for each message received while idle, as
+        /// `receive --on-message` [default: per-chat commands from
+        /// `on_message` in the config file]
+        #[arg(long)]
+        on_message: Option<String>,
+    },
 
-    // Open read sync database
-    let mut read_db = read_sync::open_read_sync_db()?;
+    /// Manage scoped tokens for `serve`, limited to some chats and operations
+    ApiToken {
+        #[command(subcommand)]
+        action: ApiTokenCommand,
+    },
 
-    let mut received_messages = Vec::new();
-    let mut read_sync_count = 0;
+    /// Manage secrets stored in the OS keyring or an encrypted file
+    Secret {
+        #[command(subcommand)]
+        action: SecretCommand,
+    },
 
-    let messages = manager
-        .receive_messages()
-        .await
-        .context("failed to initialize messages stream")?;
-    pin_mut!(messages);
-
-    while let Some(content) = messages.next().await {
-        match content {
-            Received::QueueEmpty => {
-                eprintln!("Queue empty, done syncing");
-                break;
-            }
-            Received::Contacts => {
-                eprintln!("Received contacts sync");
-            }
-            Received::Content(c) => {
-                match &c.body {
-                    ContentBody::DataMessage(dm) => {
-                        let ts = dm.timestamp.unwrap_or(0);
-                        let sender_uuid = c.metadata.sender.raw_uuid();
-                        let sender_aci = sender_uuid.to_string();
-
-                        // Save message to store for later retrieval
-                        let thread = Thread::Contact(sender_uuid);
-                        if let Err(e) = manager.store().save_message(&thread, (*c).clone()).await {
-                            warn!("Failed to save message: {}", e);
-                        }
-
-                        // Check if this message was already read (from a previous sync)
-                        let is_read = read_sync::is_read(&read_db, &sender_aci, ts);
-
-                        received_messages.push(MessageOutput {
-                            id: ts.to_string(),
-                            chat_id: sender_aci.clone(),
-                            sender: sender_aci,
-                            sender_name: None,
-                            timestamp: (ts / 1000) as i64,
-                            text: dm.body.clone().unwrap_or_default(),
-                            is_outgoing: false,
-                            is_read,
-                        });
-                    }
-                    ContentBody::SynchronizeMessage(sm) => {
-                        // Process read sync entries from other devices
-                        if !sm.read.is_empty() {
-                            match read_sync::process_sync_reads(&mut read_db, &sm.read) {
-                                Ok(count) => {
-                                    read_sync_count += count;
-                                    debug!("Processed {} read sync entries", count);
-                                }
-                                Err(e) => {
-                                    warn!("Failed to save read sync: {}", e);
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
-        }
-    }
+    /// Move this device's state to another machine as one encrypted file
+    ///
+    /// The passphrase comes from SIGNAL_CLI_BACKUP_PASSPHRASE, or else the
+    /// `signal-backup-passphrase` secret.
+    Backup {
+        #[command(subcommand)]
+        action: BackupCommand,
+    },
+}
 
-    if read_sync_count > 0 {
-        eprintln!("Synced {} read receipts from other devices", read_sync_count);
-    }
-    eprintln!("Received {} messages", received_messages.len());
-    println!("{}", serde_json::to_string_pretty(&received_messages)?);
+#[derive(Args)]
+struct LinkArgs {
+    /// Device name shown in Signal settings [default: jean-claude]
+    #[arg(short, long)]
+    device_name: Option<String>,
 
-    Ok(())
+    /// Signal servers to link against [default: production]
+    #[arg(long, value_enum)]
+    server: Option<Server>,
+
+    /// Save the QR code PNG here instead of opening it, or with `-` include
+    /// it base64-encoded in the JSON printed with the URL
+    #[arg(long)]
+    qr_output: Option<PathBuf>,
+
+    /// Print the provisioning URL as JSON, with no QR code, as soon as it's
+    /// known
+    #[arg(long, conflicts_with = "qr_output")]
+    url_only: bool,
+
+    /// Give up if the QR code isn't scanned within this many seconds
+    #[arg(long)]
+    timeout: Option<u64>,
 }
 
-async fn cmd_messages(chat_id: String, max_results: usize) -> Result<()> {
-    let manager = load_registered_manager().await?;
-    let store = manager.store();
-    let my_uuid = manager.whoami().await?.aci;
+#[derive(Args)]
+struct SendArgs {
+    /// Recipient UUID or contact name, or a group's chat ID; give several
+    /// contacts to send each their own copy, with a result per recipient
+    #[arg(required_unless_present_any = ["list", "batch"], conflicts_with = "list")]
+    recipients: Vec<String>,
 
-    // Open read sync database for is_read checks
-    let read_db = read_sync::open_read_sync_db()?;
-
-    // Parse chat_id as UUID (contact) or hex (group)
-    let thread = if let Ok(uuid) = chat_id.parse::<Uuid>() {
-        Thread::Contact(uuid)
-    } else if let Ok(master_key) = hex::decode(&chat_id) {
-        let key: [u8; 32] = master_key
-            .try_into()
-            .map_err(|_| anyhow::anyhow!("Group master key must be 32 bytes"))?;
-        Thread::Group(key)
-    } else {
-        anyhow::bail!("Invalid chat_id: must be a UUID or 64-character hex string");
-    };
+    /// Send to every member of a distribution list (see `lists`), one by one
+    #[arg(long)]
+    list: Option<String>,
 
-    // Get messages from store (full range, newest first)
-    let messages_iter = store.messages(&thread, ..).await?;
-    let mut messages: Vec<MessageOutput> = Vec::new();
+    /// Read a JSON array of `{"recipient", "text", "attachments"}` messages
+    /// from stdin and send them in order, with a result per message.
+    /// `attachments` is an optional list of file paths
+    #[arg(long, conflicts_with_all = ["recipients", "list", "attachment_from_stdin"])]
+    batch: bool,
 
-    for content in messages_iter.flatten().take(max_results) {
-        if let ContentBody::DataMessage(dm) = &content.body {
-            let ts = dm.timestamp.unwrap_or(0);
-            let sender_uuid = content.metadata.sender.raw_uuid();
-            let sender_aci = sender_uuid.to_string();
-            let is_outgoing = sender_uuid == my_uuid;
-            let is_read = read_sync::is_read(&read_db, &sender_aci, ts);
+    /// Milliseconds to wait between batch messages, to stay under Signal's
+    /// rate limits
+    #[arg(long, requires = "batch", default_value = "1000", value_name = "MS")]
+    batch_interval: u64,
 
-            messages.push(MessageOutput {
-                id: ts.to_string(),
-                chat_id: chat_id.clone(),
-                sender: sender_aci,
-                sender_name: None,
-                timestamp: (ts / 1000) as i64,
-                text: dm.body.clone().unwrap_or_default(),
-                is_outgoing,
-                is_read,
-            });
-        }
-    }
+    /// Put the message in the outbox instead of sending it now, e.g. while
+    /// offline; `flush` or a running `serve` sends it
+    #[arg(long, conflicts_with = "batch")]
+    queue: bool,
 
-    println!("{}", serde_json::to_string_pretty(&messages)?);
-    Ok(())
-}
+    /// Resolve recipients and check the message, then print what would be
+    /// sent instead of sending it
+    #[arg(long, conflicts_with = "queue")]
+    dry_run: bool,
 
-async fn cmd_status() -> Result<()> {
-    let store_result = open_store().await;
+    /// Mention a group member: their UUID, then the character offset and
+    /// length of the text the mention replaces (e.g. an `@Name`); repeat for
+    /// several. Without it, `@Name` tokens naming a member become mentions
+    #[arg(
+        long = "mention",
+        value_name = "UUID@START:LEN",
+        conflicts_with = "batch"
+    )]
+    mentions: Vec<String>,
 
-    let output = match store_result {
-        Ok(store) => match Manager::load_registered(store).await {
+    /// Read the text as Markdown: `*bold*`, `_italic_`, `~strikethrough~` and
+    /// ```` ```monospace``` ```` become Signal text styles
+    #[arg(long)]
+    markdown: bool,
+
+    /// Send text too long for one message (over 2000 characters) as several,
+    /// split between paragraphs or sentences and numbered `(n/m)`
+    #[arg(long, conflicts_with_all = ["batch", "list", "mentions"])]
+    split: bool,
+
+    /// Fetch the first https link's title, description and image and send
+    /// them as a preview under the message, as the official apps do
+    #[arg(long, conflicts_with_all = ["batch", "queue"])]
+    link_preview: bool,
+
+    /// Send an AAC recording (`.aac`, as the apps record) as a voice note,
+    /// which recipients can play in the chat; stdin isn't read
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "batch", "attachment_from_stdin", "mentions", "markdown", "split", "link_preview"
+        ]
+    )]
+    voice_note: Option<PathBuf>,
+
+    /// Share a contact card: a contact's UUID or name, or a vCard (`.vcf`)
+    /// file. Recipients can message or save the contact; stdin isn't read
+    #[arg(
+        long,
+        value_name = "CONTACT|FILE",
+        conflicts_with_all = [
+            "batch", "queue", "attachment_from_stdin", "voice_note", "mentions", "markdown",
+            "split", "link_preview"
+        ]
+    )]
+    contact: Option<String>,
+
+    /// Read a binary attachment from stdin instead of the message body
+    #[arg(long)]
+    attachment_from_stdin: bool,
+
+    /// MIME type of the stdin attachment
+    #[arg(long, requires = "attachment_from_stdin", default_value = "application/octet-stream")]
+    content_type: String,
+
+    /// File name shown to the recipient for the stdin attachment
+    #[arg(long, requires = "attachment_from_stdin")]
+    filename: Option<String>,
+
+    /// Message body (only with --attachment-from-stdin, since stdin holds the attachment)
+    #[arg(short, long, requires = "attachment_from_stdin")]
+    message: Option<String>,
+
+    /// Send the stdin attachment, a photo or video, as view-once media that
+    /// the recipient can open only once
+    #[arg(long, requires = "attachment_from_stdin", conflicts_with = "message")]
+    view_once: bool,
+}
+
+#[derive(Subcommand)]
+enum ChatCommand {
+    /// Mark chats muted, for an agent to skip
+    Mute(ChatIds),
+
+    /// Clear chats' muted flag
+    Unmute(ChatIds),
+
+    /// Mark chats archived
+    Archive(ChatIds),
+
+    /// Clear chats' archived flag
+    Unarchive(ChatIds),
+
+    /// Pin chats to the top of `conversations`
+    Pin(ChatIds),
+
+    /// Unpin chats
+    Unpin(ChatIds),
+}
+
+#[derive(Args)]
+struct ChatIds {
+    /// Chat IDs (UUID for contacts, hex for groups)
+    #[arg(required = true)]
+    chat_ids: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum OutboxCommand {
+    /// List queued messages: pending ones, and ones given up on with their
+    /// error
+    List,
+
+    /// Remove queued messages without sending them
+    Remove {
+        /// IDs from `outbox list`
+        #[arg(required = true)]
+        ids: Vec<i64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum StickerCommand {
+    /// List the sticker packs installed on the phone, with each sticker's ID
+    /// and emoji
+    List,
+
+    /// Send a sticker from an installed pack
+    Send {
+        /// Contact UUID or group chat ID
+        chat_id: String,
+        /// Hex pack ID from `sticker list`
+        pack_id: String,
+        /// Sticker ID within the pack
+        sticker_id: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExpireCommand {
+    /// Set a 1:1 chat's disappearing-messages timer and tell the contact
+    ///
+    /// Messages sent afterwards disappear this long after they're read.
+    /// Groups' timers can only be changed from the phone.
+    Set {
+        /// Contact UUID
+        chat_id: String,
+
+        /// Timer in seconds; 0 turns disappearing messages off
+        seconds: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum ListsCommand {
+    /// Create an empty list
+    Create {
+        name: String,
+    },
+
+    /// Delete a list
+    Delete {
+        name: String,
+    },
+
+    /// Add contacts to a list
+    Add {
+        name: String,
+
+        /// Contact UUIDs or names
+        #[arg(required = true)]
+        recipients: Vec<String>,
+    },
+
+    /// Remove contacts from a list
+    Remove {
+        name: String,
+
+        /// Contact UUIDs or names
+        #[arg(required = true)]
+        recipients: Vec<String>,
+    },
+
+    /// Show a list's members, or all lists if no name is given
+    Show {
+        name: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ApiTokenCommand {
+    /// Issue a token and print it (it isn't shown again)
+    Create {
+        /// Name to list and revoke the token by
+        name: String,
+
+        /// Operations the token allows
+        #[arg(long = "allow", value_name = "OPERATION", value_enum, required = true)]
+        operations: Vec<api_tokens::Operation>,
+
+        /// Limit the token to these chats [default: every chat]
+        #[arg(long = "chat", value_name = "CHAT_ID")]
+        chats: Vec<String>,
+    },
+
+    /// List issued tokens and their scopes
+    List,
+
+    /// Revoke a token
+    Revoke {
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DraftCommand {
+    /// Save the draft for a chat (reads text from stdin), replacing any other
+    Set {
+        /// Chat ID (UUID for contacts, hex for groups)
+        chat_id: String,
+    },
+
+    /// Show the draft for a chat (text is null if there isn't one)
+    Get {
+        /// Chat ID (UUID for contacts, hex for groups)
+        chat_id: String,
+    },
+
+    /// Discard the draft for a chat
+    Clear {
+        /// Chat ID (UUID for contacts, hex for groups)
+        chat_id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DevicesCommand {
+    /// List all linked devices, including this one
+    List,
+
+    /// Remove a linked device (only permitted from the primary device)
+    Remove {
+        /// Device ID from `devices list`
+        id: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// Migrate every database to this build's schema
+    ///
+    /// Snapshots each database into backups/<time>/ in the data dir first,
+    /// then checks that no table lost rows. If a migration fails or a table
+    /// shrank, the snapshots are restored. Stop `serve` before running this.
+    Upgrade,
+}
+
+#[derive(Subcommand)]
+enum GroupsCommand {
+    /// Check stored groups against their own messages for drift (stale
+    /// revision, missing membership). Local only: the server isn't asked, and
+    /// nothing is repaired
+    Verify,
+}
+
+#[derive(Subcommand)]
+enum IdentityCommand {
+    /// List recorded identity keys, untrusted first
+    List,
+
+    /// Trust a contact's current identity key after verifying the safety number
+    Trust {
+        /// Contact UUID
+        uuid: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProfileCommand {
+    /// Show a contact's profile (cached in the store after the first fetch)
+    Get {
+        /// Contact UUID
+        uuid: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SecretCommand {
+    /// Store a secret (reads the value from stdin)
+    Set {
+        /// Secret name, referenced elsewhere as secret:<name>
+        name: String,
+
+        /// Where to store the secret
+        #[arg(long, value_enum, default_value = "keyring")]
+        store: secrets::SecretStore,
+    },
+
+    /// Print a secret's value
+    Get {
+        /// Secret name
+        name: String,
+    },
+
+    /// Delete a secret from all stores
+    Delete {
+        /// Secret name
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum BackupCommand {
+    /// Write the store, local databases, config files and avatars to a file
+    Create {
+        /// File to write
+        path: PathBuf,
+    },
+
+    /// Replace this data dir's state with a backup's
+    ///
+    /// The device stays linked, so stop using the machine the backup came
+    /// from: two copies of one device can't both receive messages.
+    Restore {
+        /// Backup file from `backup create`
+        path: PathBuf,
+
+        /// Replace an existing store
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Output types for JSON serialization
+
+#[derive(Serialize)]
+struct AvatarOutput {
+    chat_id: String,
+    path: String,
+}
+
+#[derive(Serialize)]
+struct IdentityTrustOutput {
+    success: bool,
+    uuid: String,
+    fingerprint: String,
+}
+
+#[derive(Serialize)]
+struct WhoamiOutput {
+    uuid: String,
+    phone: Option<String>,
+    device_id: u32,
+}
+
+#[derive(Serialize)]
+struct StatusOutput {
+    linked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phone: Option<String>,
+    /// Why a linked device isn't any more: `unlinked_by_server`, until a
+    /// `relink`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    /// When the server was first seen rejecting the device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unlinked_at: Option<Timestamp>,
+    /// A `link` was interrupted; run it again to clean up and start over
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    link_interrupted: bool,
+    data_dir: String,
+    /// Everything in the data dir, in bytes
+    size_bytes: u64,
+    /// Each database's size in bytes, by file name
+    databases: BTreeMap<String, u64>,
+    #[serde(flatten)]
+    counts: status::StoreCounts,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_receive: Option<Timestamp>,
+    outbox_pending: usize,
+    /// With `--deep`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<NetworkStatus>,
+}
+
+#[derive(Serialize)]
+struct NetworkStatus {
+    reachable: bool,
+    credentials_valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PingOutput {
+    /// Time to open the websocket (TLS included) and get the first answer
+    connect_ms: u64,
+    /// Round trip of one request over the open websocket
+    latency_ms: u64,
+    /// The connection is TLS, with Signal's pinned certificate
+    tls: bool,
+    /// The server accepted this device's credentials
+    credentials_valid: bool,
+}
+
+#[derive(Serialize)]
+struct DeviceOutput {
+    id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    created: i64,
+    last_seen: i64,
+    /// Whether this is jean-claude's own link
+    current: bool,
+}
+
+#[derive(Serialize)]
+struct DeviceRemoveOutput {
+    success: bool,
+    id: u32,
+}
+
+#[derive(Serialize)]
+struct UnlinkOutput {
+    success: bool,
+    /// Whether the device was removed from the account (false if it wasn't linked)
+    unlinked: bool,
+    data_deleted: bool,
+}
+
+#[derive(Serialize)]
+struct SendOutput {
+    success: bool,
+    timestamp: Timestamp,
+    /// Number of recipients delivered to (list sends only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipients: Option<usize>,
+    /// Members the message couldn't be sent to (list sends only)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<String>,
+    /// Sent time of each part, when `--split` sent several
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    parts: Vec<Timestamp>,
+}
+
+#[derive(Serialize)]
+struct QueueOutput {
+    success: bool,
+    /// Outbox IDs, one per recipient
+    queued: Vec<i64>,
+}
+
+#[derive(Serialize)]
+struct OutboxRemoveOutput {
+    success: bool,
+    removed: usize,
+}
+
+/// What `send --dry-run` would have sent
+#[derive(Serialize)]
+struct DryRunOutput {
+    /// Always true: nothing was sent
+    dry_run: bool,
+    recipients: Vec<DryRunRecipient>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    styles: Vec<StyleRange>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<DryRunAttachment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_preview: Option<DryRunPreview>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    view_once: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contact: Option<SharedContactOutput>,
+}
+
+#[derive(Serialize)]
+struct DryRunRecipient {
+    uuid: String,
+    /// Contact name, when the recipient is a known contact
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Disappearing-messages timer the message would carry, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expire_timer: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct DryRunAttachment {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    content_type: String,
+    size: usize,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    voice_note: bool,
+    /// Length of a voice note's audio, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct DryRunPreview {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<DryRunAttachment>,
+}
+
+/// One message of a `send --batch`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchMessage {
+    recipient: String,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    attachments: Vec<PathBuf>,
+}
+
+/// What `send` sends, with everything it reads from stdin or files already
+/// read, so a daemon can send it as well as this process
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct SendMessage {
+    /// Contact UUIDs or names, or a group's chat ID
+    #[serde(default)]
+    recipients: Vec<String>,
+    /// A distribution list to send to instead
+    #[serde(default)]
+    list: Option<String>,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    styles: Vec<StyleRange>,
+    #[serde(default)]
+    attachments: Vec<SendAttachment>,
+    #[serde(default)]
+    view_once: bool,
+    #[serde(default)]
+    contact: Option<ContactCard>,
+    /// For a group
+    #[serde(default)]
+    mentions: Vec<Mention>,
+    /// Preview the first link in the text
+    #[serde(default)]
+    link_preview: bool,
+    /// Send long text as several messages
+    #[serde(default)]
+    split: bool,
+    /// Put it in the outbox rather than sending it
+    #[serde(default)]
+    queue: bool,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// An attachment of a [`SendMessage`], kept as the outbox keeps one
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SendAttachment {
+    content_type: String,
+    file_name: Option<String>,
+    #[serde(default)]
+    voice_note: bool,
+    /// Base64
+    data: String,
+}
+
+impl SendAttachment {
+    fn new((spec, data): &(AttachmentSpec, Vec<u8>)) -> Self {
+        use base64::Engine;
+        SendAttachment {
+            content_type: spec.content_type.clone(),
+            file_name: spec.file_name.clone(),
+            voice_note: spec.voice_note == Some(true),
+            data: base64::engine::general_purpose::STANDARD.encode(data),
+        }
+    }
+
+    fn decode(self) -> Result<(AttachmentSpec, Vec<u8>)> {
+        use base64::Engine;
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(&self.data)
+            .context("Invalid attachment data")?;
+        let spec = AttachmentSpec {
+            content_type: self.content_type,
+            length: data.len(),
+            file_name: self.file_name,
+            voice_note: self.voice_note.then_some(true),
+            ..Default::default()
+        };
+        Ok((spec, data))
+    }
+}
+
+/// A contact card to share
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ContactCard {
+    /// A vCard's text
+    Vcard(String),
+    /// A contact's UUID or name, looked up in the store
+    Contact(String),
+}
+
+/// What `send` prints, which depends on how the message went
+#[derive(Serialize)]
+#[serde(untagged)]
+enum SendMessageOutput {
+    Sent(SendOutput),
+    /// Sent to several recipients, or a batch
+    EachRecipient(Vec<RecipientSendOutput>),
+    Queued(QueueOutput),
+    DryRun(Box<DryRunOutput>),
+    /// A split message's parts, or a batch
+    DryRuns(Vec<DryRunOutput>),
+}
+
+/// One recipient's outcome when sending to several, or one message's in a
+/// batch
+#[derive(Serialize)]
+struct RecipientSendOutput {
+    /// As given on the command line
+    recipient: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ListSummaryOutput {
+    name: String,
+    member_count: usize,
+}
+
+#[derive(Serialize)]
+struct ListMemberOutput {
+    uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ListOutput {
+    name: String,
+    members: Vec<ListMemberOutput>,
+}
+
+#[derive(Serialize)]
+struct ListUpdateOutput {
+    success: bool,
+    name: String,
+    /// Members added or removed by this call
+    changed: usize,
+}
+
+#[derive(Serialize)]
+struct DraftOutput {
+    chat_id: String,
+    text: Option<String>,
+    /// When the draft was last saved
+    updated_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ApiTokenCreateOutput {
+    name: String,
+    /// Shown only here; store it now
+    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chats: Option<Vec<String>>,
+    operations: Vec<api_tokens::Operation>,
+}
+
+#[derive(Serialize)]
+struct ApiTokenRevokeOutput {
+    success: bool,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct DraftClearOutput {
+    success: bool,
+    chat_id: String,
+    /// Whether there was a draft to discard
+    cleared: bool,
+}
+
+#[derive(Serialize)]
+struct LinkOutput {
+    success: bool,
+    uuid: String,
+    device_name: String,
+}
+
+#[derive(Serialize)]
+struct ChatFlagOutput {
+    success: bool,
+    chat_ids: Vec<String>,
+    /// "muted", "archived" or "pinned"
+    flag: &'static str,
+    /// The flag's new value
+    value: bool,
+}
+
+#[derive(Serialize)]
+struct ExportOutput {
+    success: bool,
+    path: String,
+    messages: usize,
+    attachments_copied: usize,
+}
+
+#[derive(Serialize)]
+struct MarkReadOutput {
+    success: bool,
+    chats_marked: usize,
+    messages_marked: i64,
+}
+
+#[derive(Serialize)]
+struct MarkUnreadOutput {
+    success: bool,
+    chat_id: String,
+    /// Messages that were read and now aren't
+    messages_marked: i64,
+}
+
+#[derive(Serialize)]
+struct ExpireSetOutput {
+    success: bool,
+    chat_id: String,
+    seconds: u32,
+    /// When the timer update was sent
+    timestamp: Timestamp,
+}
+
+#[derive(Serialize)]
+struct SyncContactsOutput {
+    success: bool,
+    /// Contacts added or changed by the sync
+    contacts_updated: usize,
+    contacts_total: usize,
+    /// Messages that arrived while waiting, available from `events`
+    messages_received: usize,
+}
+
+#[derive(Serialize)]
+struct GroupVerifyOutput {
+    id: String,
+    name: String,
+    stored_revision: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latest_seen_revision: Option<u32>,
+    is_member: bool,
+    ok: bool,
+    issues: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ProfileOutput {
+    uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    about: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    about_emoji: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+    unrestricted_unidentified_access: bool,
+    capabilities: serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct SecretSetOutput {
+    success: bool,
+    name: String,
+    store: secrets::SecretStore,
+}
+
+#[derive(Serialize)]
+struct SecretGetOutput {
+    name: String,
+    value: String,
+    store: secrets::SecretStore,
+}
+
+#[derive(Serialize)]
+struct SecretDeleteOutput {
+    success: bool,
+    name: String,
+    deleted: bool,
+}
+
+#[derive(Serialize)]
+struct ReplicateOutput {
+    success: bool,
+    to: String,
+    /// Files that changed and were copied
+    files: Vec<String>,
+}
+
+/// Output style selected with `--output` or config, set once at startup
+static OUTPUT_FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+/// Print a command result as JSON on stdout
+fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<()> {
+    let json = match OUTPUT_FORMAT.get().copied().unwrap_or_default() {
+        OutputFormat::Pretty => serde_json::to_string_pretty(value)?,
+        OutputFormat::Compact => serde_json::to_string(value)?,
+    };
+    println!("{}", json);
+    Ok(())
+}
+
+/// Parse a `--since` value: a relative age (`30d`, `12h`, `45m`, `2w`), a
+/// unix timestamp in seconds, or an ISO 8601 date (`2025-01-15`, midnight UTC)
+/// or date-time (`2025-01-15T09:30:00Z`). Returns unix seconds.
+fn parse_since(value: &str) -> Result<i64> {
+    if let Ok(ts) = value.parse::<i64>() {
+        return Ok(ts);
+    }
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp());
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp());
+    }
+
+    let invalid = || {
+        format!(
+            "Invalid time '{}': expected e.g. 2025-01-15, 30d, 12h",
+            value
+        )
+    };
+    let (index, unit) = value.char_indices().last().with_context(invalid)?;
+    let amount: i64 = value[..index].parse().with_context(invalid)?;
+    if amount < 0 {
+        anyhow::bail!("Invalid duration '{}': can't be negative", value);
+    }
+    let seconds = match unit {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => anyhow::bail!("Invalid duration '{}': unit must be one of m, h, d, w", value),
+    };
+
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs() as i64;
+    amount
+        .checked_mul(seconds)
+        .and_then(|age| now.checked_sub(age))
+        .with_context(|| format!("Invalid duration '{}': too long", value))
+}
+
+/// An optional time flag (any form [`parse_since`] takes) as unix
+/// milliseconds, which history and search work in
+fn parse_millis(value: Option<&str>) -> Result<Option<u64>> {
+    value
+        .map(|value| Ok(parse_since(value)?.max(0) as u64 * 1000))
+        .transpose()
+}
+
+/// How `link` shows the provisioning URL
+enum QrDisplay {
+    /// Save the QR code here, open it in an image viewer and print it to the
+    /// terminal
+    Viewer(PathBuf),
+    /// Only print the URL as JSON on stdout, with the QR code PNG saved to a
+    /// file or, for `-`, inline
+    Headless { qr_output: Option<PathBuf> },
+}
+
+/// The provisioning URL, printed by `link --url-only` and `--qr-output` as
+/// soon as it's known
+#[derive(Serialize)]
+struct ProvisioningOutput {
+    url: String,
+    /// Where the QR code PNG was saved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qr_file: Option<String>,
+    /// The QR code PNG, base64-encoded, for `--qr-output -`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qr_png: Option<String>,
+}
+
+fn qr_png(url: &str) -> Result<Vec<u8>> {
+    let image = qrcode::QrCode::new(url)?
+        .render::<image::Luma<u8>>()
+        .build();
+    let mut png = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut png, image::ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+fn show_provisioning_url(url: &str, display: &QrDisplay) -> Result<()> {
+    let qr_file = match display {
+        QrDisplay::Viewer(qr_file) => qr_file,
+        QrDisplay::Headless { qr_output } => {
+            use base64::Engine;
+            let mut output = ProvisioningOutput {
+                url: url.to_string(),
+                qr_file: None,
+                qr_png: None,
+            };
+            match qr_output.as_deref() {
+                Some(path) if path == Path::new("-") => {
+                    let png = qr_png(url)?;
+                    output.qr_png = Some(base64::engine::general_purpose::STANDARD.encode(png));
+                }
+                Some(path) => {
+                    std::fs::write(path, qr_png(url)?)
+                        .with_context(|| format!("Failed to write {}", path.display()))?;
+                    output.qr_file = Some(path.display().to_string());
+                }
+                None => {}
+            }
+            return print_json(&output);
+        }
+    };
+
+    // Save QR code to PNG file and open with system viewer
+    match qr_png(url) {
+        Ok(png) => {
+            if std::fs::write(qr_file, png).is_ok() {
+                eprintln!("QR code saved to: {}", qr_file.display());
+                // Open with system viewer (macOS: open, Linux: xdg-open)
+                #[cfg(target_os = "macos")]
+                let _ = ProcessCommand::new("open").arg(qr_file).spawn();
+                #[cfg(target_os = "linux")]
+                let _ = ProcessCommand::new("xdg-open").arg(qr_file).spawn();
+            }
+        }
+        Err(e) => {
+            warn!("Failed to generate QR code image: {}", e);
+        }
+    }
+
+    // Also print to terminal as fallback
+    eprintln!();
+    eprintln!("Scan this QR code with Signal:");
+    eprintln!("(Signal > Settings > Linked Devices > Link New Device)");
+    eprintln!();
+    qr2term::print_qr(url).expect("Failed to render QR code");
+    eprintln!();
+    eprintln!("Or open this URL: {}", url);
+    Ok(())
+}
+
+/// Link as a secondary device. With `relink`, an existing registration is
+/// replaced rather than kept; presage clears only the registration, so the
+/// store's messages and contacts, and the local databases, carry over.
+async fn cmd_link(
+    args: LinkArgs,
+    default_device_name: Option<String>,
+    default_server: Option<Server>,
+    relink: bool,
+) -> Result<()> {
+    let device_name = args
+        .device_name
+        .or(default_device_name)
+        .unwrap_or_else(|| "jean-claude".to_string());
+    let server = args.server.or(default_server).unwrap_or_default();
+    if daemon::is_running()? {
+        anyhow::bail!("A `serve` daemon is using the message store; stop it before linking");
+    }
+    let db_path = get_db_path()?;
+    debug!("Linking device, store at {}", db_path);
+
+    let mut store = open_store()
+        .await
+        .context("Failed to open Signal database")?;
+
+    match link::recover(&mut store).await? {
+        Recovery::None | Recovery::Completed(_) => {}
+        Recovery::Cleared(pending) => eprintln!(
+            "Cleaned up an unfinished link of '{}'; starting over.",
+            pending.device_name
+        ),
+    }
+
+    // Check if already registered
+    let previous_aci = match Manager::load_registered(store.clone()).await {
+        Ok(_) if !relink => {
+            eprintln!("Already linked to Signal. Use 'signal-cli status' to check.");
+            return Ok(());
+        }
+        Ok(manager) => {
+            eprintln!("Relinking; message history and local state are kept.");
+            Some(manager.registration_data().service_ids.aci)
+        }
+        Err(_) => None,
+    };
+    // Linking replaces the registration, so a copy is kept in case the phone
+    // that scans the code turns out to be another account's
+    let store_path = get_data_dir()?.join("signal.db");
+    let rollback = get_data_dir()?.join("signal.db.relink");
+    let _ = std::fs::remove_file(&rollback);
+    if previous_aci.is_some() {
+        replicate::snapshot(&store_path, &rollback)?;
+    }
+
+    // Cleared once linked; left behind if this run is interrupted
+    link::begin(&device_name)?;
+
+    eprintln!("Linking as secondary device...");
+    eprintln!("Open Signal on your phone: Settings > Linked Devices > Link New Device");
+    eprintln!();
+
+    // Create channel for provisioning URL
+    let (tx, rx) = oneshot::channel();
+
+    // QR code file path, when it's shown in a viewer
+    let qr_file = get_data_dir()?.join("qr.png");
+    let display = if args.url_only || args.qr_output.is_some() {
+        QrDisplay::Headless {
+            qr_output: args.qr_output,
+        }
+    } else {
+        QrDisplay::Viewer(qr_file.clone())
+    };
+
+    // Run linking and QR code display concurrently
+    let linking = future::try_join(
+        async {
+            Manager::link_secondary_device(store, server.into(), device_name.clone(), tx)
+                .await
+                .map_err(anyhow::Error::from)
+        },
+        async move {
+            match rx.await {
+                Ok(url) => show_provisioning_url(url.as_ref(), &display),
+                Err(e) => {
+                    eprintln!("Linking cancelled: {:?}", e);
+                    Ok(())
+                }
+            }
+        },
+    );
+    let result = match args.timeout {
+        Some(secs) => tokio::time::timeout(std::time::Duration::from_secs(secs), linking)
+            .await
+            .unwrap_or_else(|_| {
+                Err(anyhow::anyhow!(
+                    "Not linked within {}s; was the QR code scanned?",
+                    secs
+                ))
+            }),
+        None => linking.await,
+    };
+
+    // Clean up QR file
+    let _ = std::fs::remove_file(&qr_file);
+
+    // Scoped so the store is closed before it could be put back
+    let whoami = {
+        let (manager, ()) = result.inspect_err(|_| {
+            let _ = std::fs::remove_file(&rollback);
+        })?;
+        manager.whoami().await?
+    };
+    if let Some(previous) = previous_aci.filter(|&aci| aci != whoami.aci) {
+        // A stale WAL would be replayed over the restored copy
+        for suffix in ["-wal", "-shm"] {
+            let _ = std::fs::remove_file(get_data_dir()?.join(format!("signal.db{}", suffix)));
+        }
+        std::fs::rename(&rollback, &store_path)
+            .with_context(|| format!("Failed to restore {}", store_path.display()))?;
+        link::cancel()?;
+        anyhow::bail!(
+            "The phone that scanned the code is account {}, but this store belongs to {}; \
+             the store was left as it was. Relink with that account's phone, and remove \
+             '{}' from the other phone's linked devices",
+            whoami.aci,
+            previous,
+            device_name
+        );
+    }
+    let _ = std::fs::remove_file(&rollback);
+    link::finish()?;
+
+    eprintln!("Successfully linked! Device: {}", device_name);
+
+    let output = LinkOutput {
+        success: true,
+        uuid: whoami.aci.to_string(),
+        device_name,
+    };
+    print_json(&output)?;
+
+    Ok(())
+}
+
+/// Decommission this machine: remove the device from the account, then wipe
+/// the data dir (presage store, read sync, usage, events, identities, secrets
+/// file, cached avatars).
+#[instrument(skip_all)]
+async fn cmd_unlink(keep_data: bool) -> Result<()> {
+    if daemon::is_running()? {
+        anyhow::bail!("A `serve` daemon is using the message store; stop it before unlinking");
+    }
+    let unlinked = match open_store().await {
+        Ok(store) => match Manager::load_registered(store).await {
             Ok(manager) => {
-                let whoami = manager.whoami().await.ok();
-                StatusOutput {
-                    linked: true,
-                    uuid: whoami.as_ref().map(|w| w.aci.to_string()),
-                    phone: whoami.as_ref().map(|w| w.number.to_string()),
+                let device_id = u32::from(manager.device_id());
+                outbound::unlink_device(&manager, device_id)
+                    .await
+                    .context("Failed to unlink device from account")?;
+                true
+            }
+            Err(_) => false,
+        },
+        Err(_) => false,
+    };
+
+    if !unlinked {
+        eprintln!("Not linked to Signal; nothing to unregister.");
+    }
+
+    // The manager (and its database handle) is dropped by now
+    let data_deleted = if keep_data {
+        false
+    } else {
+        let data_dir = get_data_dir()?;
+        // Named accounts live inside the default account's dir; leave them be
+        for entry in std::fs::read_dir(&data_dir)? {
+            let path = entry?.path();
+            let result = if path.is_dir() {
+                if is_account_dir(&path) {
+                    continue;
+                }
+                std::fs::remove_dir_all(&path)
+            } else {
+                std::fs::remove_file(&path)
+            };
+            result.with_context(|| format!("Failed to delete {}", path.display()))?;
+        }
+        if std::fs::read_dir(&data_dir)?.next().is_none() {
+            std::fs::remove_dir(&data_dir)?;
+        }
+        true
+    };
+
+    let output = UnlinkOutput {
+        success: true,
+        unlinked,
+        data_deleted,
+    };
+    print_json(&output)?;
+    Ok(())
+}
+
+/// Resolve recipients to UUIDs and add them to a list, or with `remove`
+/// take them off it
+async fn update_list(
+    manager: &SignalManager,
+    name: String,
+    recipients: &[String],
+    remove: bool,
+) -> Result<ListUpdateOutput> {
+    let mut uuids = Vec::new();
+    for recipient in recipients {
+        uuids.push(resolve_recipient(manager, recipient).await?.to_string());
+    }
+    let mut conn = lists::open_lists_db()?;
+    let changed = if remove {
+        lists::remove(&mut conn, &name, &uuids)?
+    } else {
+        lists::add(&mut conn, &name, &uuids)?
+    };
+    Ok(ListUpdateOutput {
+        success: true,
+        name,
+        changed,
+    })
+}
+
+/// A list's members, with their contact names
+async fn show_list(manager: &SignalManager, name: String) -> Result<ListOutput> {
+    let uuids = lists::members(&lists::open_lists_db()?, &name)?;
+    let mut members = Vec::new();
+    for uuid in uuids {
+        let contact_name = match uuid.parse::<Uuid>() {
+            Ok(id) => manager
+                .store()
+                .contact_by_id(&id)
+                .await?
+                .map(|c| c.name)
+                .filter(|n| !n.is_empty()),
+            Err(_) => None,
+        };
+        members.push(ListMemberOutput {
+            uuid,
+            name: contact_name,
+        });
+    }
+    Ok(ListOutput { name, members })
+}
+
+#[instrument(skip_all)]
+async fn cmd_lists(action: ListsCommand) -> Result<()> {
+    let conn = lists::open_lists_db()?;
+
+    match action {
+        ListsCommand::Create { name } => {
+            lists::create(&conn, &name)?;
+            let output = ListUpdateOutput {
+                success: true,
+                name,
+                changed: 0,
+            };
+            print_json(&output)?;
+        }
+        ListsCommand::Delete { name } => {
+            if !lists::delete(&conn, &name)? {
+                anyhow::bail!("No list named '{}'", name);
+            }
+            let output = ListUpdateOutput {
+                success: true,
+                name,
+                changed: 0,
+            };
+            print_json(&output)?;
+        }
+        ListsCommand::Add { name, recipients } => {
+            let request = daemon::Request::ListsUpdate {
+                name: name.clone(),
+                recipients: recipients.clone(),
+                remove: false,
+            };
+            if let Some(output) = daemon::proxy(&request).await? {
+                return print_json(&output);
+            }
+            let manager = load_registered_manager().await?;
+            print_json(&update_list(&manager, name, &recipients, false).await?)?;
+        }
+        ListsCommand::Remove { name, recipients } => {
+            let request = daemon::Request::ListsUpdate {
+                name: name.clone(),
+                recipients: recipients.clone(),
+                remove: true,
+            };
+            if let Some(output) = daemon::proxy(&request).await? {
+                return print_json(&output);
+            }
+            let manager = load_registered_manager().await?;
+            print_json(&update_list(&manager, name, &recipients, true).await?)?;
+        }
+        ListsCommand::Show { name: None } => {
+            let output: Vec<ListSummaryOutput> = lists::all(&conn)?
+                .into_iter()
+                .map(|(name, member_count)| ListSummaryOutput { name, member_count })
+                .collect();
+            print_json(&output)?;
+        }
+        ListsCommand::Show { name: Some(name) } => {
+            let request = daemon::Request::ListsShow { name: name.clone() };
+            if let Some(output) = daemon::proxy(&request).await? {
+                return print_json(&output);
+            }
+            let manager = load_registered_manager().await?;
+            print_json(&show_list(&manager, name).await?)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_draft(conn: &Connection, chat_id: String) -> Result<()> {
+    let draft = drafts::get(conn, &chat_id)?;
+    let output = DraftOutput {
+        chat_id,
+        text: draft.as_ref().map(|(text, _)| text.clone()),
+        updated_at: draft.map(|(_, updated_at)| updated_at),
+    };
+    print_json(&output)
+}
+
+fn cmd_draft(action: DraftCommand) -> Result<()> {
+    let conn = drafts::open_drafts_db()?;
+
+    match action {
+        DraftCommand::Set { chat_id } => {
+            parse_thread(&chat_id)?;
+            let text = {
+                use std::io::Read;
+                let mut buf = String::new();
+                std::io::stdin().read_to_string(&mut buf)?;
+                buf.trim().to_string()
+            };
+            if text.is_empty() {
+                anyhow::bail!("Draft cannot be empty; use 'draft clear' to discard it");
+            }
+            drafts::set(&conn, &chat_id, &text)?;
+            print_draft(&conn, chat_id)?;
+        }
+        DraftCommand::Get { chat_id } => {
+            parse_thread(&chat_id)?;
+            print_draft(&conn, chat_id)?;
+        }
+        DraftCommand::Clear { chat_id } => {
+            let cleared = drafts::clear(&conn, &chat_id)?;
+            let output = DraftClearOutput {
+                success: true,
+                chat_id,
+                cleared,
+            };
+            print_json(&output)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_api_token(action: ApiTokenCommand) -> Result<()> {
+    let conn = api_tokens::open_tokens_db()?;
+
+    match action {
+        ApiTokenCommand::Create {
+            name,
+            operations,
+            chats,
+        } => {
+            // Stored canonical, as the server compares them to parsed chat IDs
+            let chats = chats
+                .iter()
+                .map(|chat_id| Ok(thread_chat_id(&parse_thread(chat_id)?)))
+                .collect::<Result<Vec<_>>>()?;
+            let scope = api_tokens::Scope {
+                chats: (!chats.is_empty()).then_some(chats),
+                operations,
+            };
+            let token = api_tokens::create(&conn, &name, &scope)?;
+            let output = ApiTokenCreateOutput {
+                name,
+                token,
+                chats: scope.chats,
+                operations: scope.operations,
+            };
+            print_json(&output)?;
+        }
+        ApiTokenCommand::List => {
+            print_json(&api_tokens::list(&conn)?)?;
+        }
+        ApiTokenCommand::Revoke { name } => {
+            if !api_tokens::revoke(&conn, &name)? {
+                anyhow::bail!("No token named '{}'", name);
+            }
+            let output = ApiTokenRevokeOutput {
+                success: true,
+                name,
+            };
+            print_json(&output)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Linked devices, this one marked current.
+async fn list_devices(manager: &SignalManager) -> Result<Vec<DeviceOutput>> {
+    let own_id = u32::from(manager.device_id());
+    Ok(manager
+        .devices()
+        .await
+        .context("Failed to fetch linked devices")?
+        .into_iter()
+        .map(|d| {
+            let id = u32::from(d.id);
+            DeviceOutput {
+                id,
+                name: d.name.filter(|n| !n.is_empty()),
+                created: (d.created / 1000) as i64,
+                last_seen: (d.last_seen / 1000) as i64,
+                current: id == own_id,
+            }
+        })
+        .collect())
+}
+
+/// Unlink another device from the account.
+async fn remove_device(manager: &SignalManager, id: u32) -> Result<DeviceRemoveOutput> {
+    if id == u32::from(manager.device_id()) {
+        anyhow::bail!("Device {} is this device; use 'signal-cli unlink' instead", id);
+    }
+    outbound::unlink_device(manager, id)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to remove device {} (only the primary device can remove others)",
+                id
+            )
+        })?;
+    Ok(DeviceRemoveOutput { success: true, id })
+}
+
+#[instrument(skip_all)]
+async fn cmd_devices(action: DevicesCommand) -> Result<()> {
+    let request = match action {
+        DevicesCommand::List => daemon::Request::DevicesList,
+        DevicesCommand::Remove { id } => daemon::Request::DevicesRemove { id },
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let manager = load_registered_manager().await?;
+    match action {
+        DevicesCommand::List => print_json(&list_devices(&manager).await?),
+        DevicesCommand::Remove { id } => print_json(&remove_device(&manager, id).await?),
+    }
+}
+
+async fn whoami(manager: &SignalManager) -> Result<WhoamiOutput> {
+    let whoami = manager.whoami().await?;
+    Ok(WhoamiOutput {
+        uuid: whoami.aci.to_string(),
+        phone: Some(whoami.number.to_string()),
+        device_id: manager.device_id().into(),
+    })
+}
+
+#[instrument(skip_all)]
+async fn cmd_whoami() -> Result<()> {
+    if let Some(output) = daemon::proxy(&daemon::Request::Whoami).await? {
+        return print_json(&output);
+    }
+    let manager = load_registered_manager().await?;
+    print_json(&whoami(&manager).await?)
+}
+
+/// The first request opens the websocket, unless a daemon already has it
+/// open; the second reuses it, so its time is the round trip alone.
+async fn ping(manager: &SignalManager) -> Result<PingOutput> {
+    let start = std::time::Instant::now();
+    manager.whoami().await.map_err(signal_core::Error::from)?;
+    let connect = start.elapsed();
+
+    let start = std::time::Instant::now();
+    manager.whoami().await.map_err(signal_core::Error::from)?;
+    let latency = start.elapsed();
+
+    Ok(PingOutput {
+        connect_ms: connect.as_millis() as u64,
+        latency_ms: latency.as_millis() as u64,
+        tls: true,
+        credentials_valid: true,
+    })
+}
+
+#[instrument(skip_all)]
+async fn cmd_ping() -> Result<()> {
+    if let Some(output) = daemon::proxy(&daemon::Request::Ping).await? {
+        return print_json(&output);
+    }
+    let manager = load_registered_manager().await?;
+    print_json(&ping(&manager).await?)
+}
+
+fn cmd_version(json: bool) -> Result<()> {
+    if json {
+        return print_json(&version::report()?);
+    }
+    println!(
+        "signal-cli {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        env!("JC_GIT_HASH")
+    );
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn cmd_chats(
+    max_results: usize,
+    with_avatars: bool,
+    unread_counts: bool,
+    avatar_dir: &Path,
+) -> Result<()> {
+    // The daemon writes avatars where this process would have
+    let request = daemon::Request::Chats {
+        max_results,
+        unread_counts,
+        avatar_dir: with_avatars
+            .then(|| std::path::absolute(avatar_dir))
+            .transpose()?,
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    let avatar_dir = with_avatars.then_some(avatar_dir);
+    let chats = chats::list_chats(&mut manager, max_results, avatar_dir, unread_counts).await?;
+    print_json(&chats)?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn cmd_conversations(max_results: usize) -> Result<()> {
+    let request = daemon::Request::Conversations { max_results };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    let output = conversations::list_conversations(&mut manager, max_results).await?;
+    print_json(&output)?;
+    Ok(())
+}
+
+/// Set the muted, archived or pinned flag on each chat
+fn cmd_chat_flag(
+    chat_ids: Vec<String>,
+    flag: &'static str,
+    set: fn(&Connection, &str, bool) -> signal_core::Result<()>,
+    value: bool,
+) -> Result<()> {
+    // Check every ID before changing any
+    for chat_id in &chat_ids {
+        parse_thread(chat_id)?;
+    }
+    let conn = conversations::open_conversations_db()?;
+    for chat_id in &chat_ids {
+        set(&conn, chat_id, value)?;
+    }
+
+    let output = ChatFlagOutput {
+        success: true,
+        chat_ids,
+        flag,
+        value,
+    };
+    print_json(&output)?;
+    Ok(())
+}
+
+fn cmd_chat(action: ChatCommand) -> Result<()> {
+    use conversations::{set_archived, set_muted, set_pinned};
+    match action {
+        ChatCommand::Mute(ids) => cmd_chat_flag(ids.chat_ids, "muted", set_muted, true),
+        ChatCommand::Unmute(ids) => cmd_chat_flag(ids.chat_ids, "muted", set_muted, false),
+        ChatCommand::Archive(ids) => cmd_chat_flag(ids.chat_ids, "archived", set_archived, true),
+        ChatCommand::Unarchive(ids) => cmd_chat_flag(ids.chat_ids, "archived", set_archived, false),
+        ChatCommand::Pin(ids) => cmd_chat_flag(ids.chat_ids, "pinned", set_pinned, true),
+        ChatCommand::Unpin(ids) => cmd_chat_flag(ids.chat_ids, "pinned", set_pinned, false),
+    }
+}
+
+async fn avatar(
+    manager: &mut SignalManager,
+    chat_id: String,
+    avatar_dir: &Path,
+) -> Result<AvatarOutput> {
+    let path = avatars::download_avatar(manager, &chat_id, avatar_dir)
+        .await?
+        .with_context(|| format!("No avatar available for {}", chat_id))?;
+    Ok(AvatarOutput {
+        chat_id,
+        path: path.display().to_string(),
+    })
+}
+
+#[instrument(skip_all)]
+async fn cmd_avatar(chat_id: String, avatar_dir: &Path) -> Result<()> {
+    let request = daemon::Request::Avatar {
+        chat_id: chat_id.clone(),
+        avatar_dir: std::path::absolute(avatar_dir)?,
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    print_json(&avatar(&mut manager, chat_id, avatar_dir).await?)
+}
+
+/// The group a send goes to, if `recipients` is one group's chat ID, after
+/// checking the other options suit the recipients.
+fn send_group(
+    recipients: &[String],
+    mentions: bool,
+    queue: bool,
+    dry_run: bool,
+) -> Result<Option<[u8; 32]>> {
+    let group = match recipients {
+        [recipient] => group_key(recipient),
+        _ => None,
+    };
+    if group.is_some() && (queue || dry_run) {
+        return Err(signal_core::Error::Invalid(
+            "--queue and --dry-run only take contacts, not groups".to_string(),
+        )
+        .into());
+    }
+    if group.is_none() && mentions {
+        return Err(signal_core::Error::Invalid(
+            "Mentions only work in group messages".to_string(),
+        )
+        .into());
+    }
+    Ok(group)
+}
+
+#[instrument(skip_all)]
+async fn cmd_send(args: SendArgs, policy: IdentityPolicy) -> Result<()> {
+    // Fail before touching stdin or the network; a dry run sends nothing
+    if !args.dry_run {
+        outbound::ensure_allowed(outbound::Outbound::Message)?;
+    }
+
+    if args.batch {
+        return cmd_send_batch(policy, args.batch_interval, args.dry_run, args.markdown).await;
+    }
+
+    send_group(
+        &args.recipients,
+        !args.mentions.is_empty(),
+        args.queue,
+        args.dry_run,
+    )?;
+    let mentions = args
+        .mentions
+        .iter()
+        .map(|m| m.parse())
+        .collect::<Result<Vec<Mention>, _>>()?;
+    let mut message = SendMessage {
+        recipients: args.recipients,
+        list: args.list,
+        mentions,
+        link_preview: args.link_preview,
+        split: args.split,
+        queue: args.queue,
+        dry_run: args.dry_run,
+        ..Default::default()
+    };
+
+    // Read message (or attachment bytes) from stdin
+    if let Some(path) = &args.voice_note {
+        let data = std::fs::read(path)
+            .with_context(|| format!("Failed to read voice note {}", path.display()))?;
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        let voice_note = voice_notes::attachment(data, file_name)
+            .with_context(|| format!("Can't send {}", path.display()))?;
+        message.attachments = vec![SendAttachment::new(&voice_note)];
+    } else if let Some(card) = args.contact {
+        let path = Path::new(&card);
+        // Anything but a file names a contact, looked up once the store is open
+        message.contact = Some(if path.is_file() {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            shared_contacts::from_vcard(&text)
+                .with_context(|| format!("Can't share {}", path.display()))?;
+            ContactCard::Vcard(text)
+        } else {
+            ContactCard::Contact(card)
+        });
+    } else if args.attachment_from_stdin {
+        let data = {
+            use std::io::Read;
+            let mut buf = Vec::new();
+            std::io::stdin().read_to_end(&mut buf)?;
+            buf
+        };
+        if data.is_empty() {
+            anyhow::bail!("Attachment cannot be empty");
+        }
+        let spec = AttachmentSpec {
+            content_type: args.content_type,
+            length: data.len(),
+            file_name: args.filename,
+            ..Default::default()
+        };
+        let text = args.message.map(|m| m.trim().to_string()).filter(|m| !m.is_empty());
+        if let Some(text) = text {
+            let (text, styles) = styled_text(text, args.markdown);
+            message.text = Some(text);
+            message.styles = styles;
+        }
+        message.attachments = vec![SendAttachment::new(&(spec, data))];
+        message.view_once = args.view_once;
+    } else {
+        let text = {
+            use std::io::Read;
+            let mut buf = String::new();
+            std::io::stdin().read_to_string(&mut buf)?;
+            buf.trim().to_string()
+        };
+        if text.is_empty() {
+            anyhow::bail!("Message cannot be empty");
+        }
+        let (text, styles) = styled_text(text, args.markdown);
+        message.text = Some(text);
+        message.styles = styles;
+    }
+
+    if let Some(output) = daemon::proxy(&daemon::Request::Send(message.clone())).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    print_json(&send_message(&mut manager, message, policy).await?)
+}
+
+/// Send a message as `send` does, or queue it or describe it as asked.
+async fn send_message(
+    manager: &mut SignalManager,
+    message: SendMessage,
+    policy: IdentityPolicy,
+) -> Result<SendMessageOutput> {
+    let group = send_group(
+        &message.recipients,
+        !message.mentions.is_empty(),
+        message.queue,
+        message.dry_run,
+    )?;
+    let contact = match message.contact {
+        None => None,
+        Some(ContactCard::Vcard(text)) => Some(shared_contacts::from_vcard(&text)?),
+        Some(ContactCard::Contact(name)) => {
+            let uuid = resolve_recipient(manager, &name).await?;
+            Some(shared_contacts::from_contact(manager, uuid).await?)
+        }
+    };
+    let mut outgoing = Outgoing {
+        text: message.text,
+        attachments: message
+            .attachments
+            .into_iter()
+            .map(SendAttachment::decode)
+            .collect::<Result<_>>()?,
+        styles: message.styles,
+        preview: None,
+        sticker: None,
+        view_once: message.view_once,
+        contact,
+    };
+
+    // Best effort: a link that can't be previewed is still sent
+    if message.link_preview {
+        if let Some(url) = outgoing.text.as_deref().and_then(link_preview::first_link) {
+            match link_preview::fetch(url).await {
+                Ok(preview) => outgoing.preview = Some(preview),
+                Err(e) => eprintln!("Warning: no link preview for {}: {:#}", url, e),
+            }
+        }
+    }
+
+    // Long text goes as several messages, one after another
+    let outgoing = if message.split {
+        let mut parts = send::split(outgoing);
+        if parts.len() > 1 {
+            return send_parts(
+                manager,
+                &message.recipients,
+                group,
+                parts,
+                message.queue,
+                message.dry_run,
+                policy,
+            )
+            .await;
+        }
+        parts.remove(0)
+    } else {
+        outgoing
+    };
+
+    if let Some(master_key) = group {
+        let report =
+            send::send_to_group(manager, master_key, outgoing, &message.mentions, policy).await?;
+        return Ok(SendMessageOutput::Sent(send_output(report, None)));
+    }
+    if message.recipients.len() > 1 && !message.queue && !message.dry_run {
+        return Ok(SendMessageOutput::EachRecipient(
+            send_to_each(manager, &message.recipients, outgoing, policy).await?,
+        ));
+    }
+
+    // Resolve recipients (UUIDs or contact names, or every member of a list)
+    let recipients = match (message.recipients.as_slice(), &message.list) {
+        ([], None) => anyhow::bail!("No recipients"),
+        ([], Some(list)) => {
+            let members = lists::members(&lists::open_lists_db()?, list)?;
+            if members.is_empty() {
+                anyhow::bail!("List '{}' has no members", list);
+            }
+            members
+                .iter()
+                .map(|m| m.parse::<Uuid>().with_context(|| format!("Invalid UUID in list: {}", m)))
+                .collect::<Result<Vec<_>>>()?
+        }
+        (recipients, _) => {
+            let mut uuids = Vec::new();
+            for recipient in recipients {
+                uuids.push(resolve_recipient(manager, recipient).await?);
+            }
+            uuids
+        }
+    };
+    if message.queue {
+        return Ok(SendMessageOutput::Queued(queue_message(
+            &recipients,
+            &outgoing,
+        )?));
+    }
+    if message.dry_run {
+        let output = preview_send(manager, &recipients, &outgoing).await?;
+        return Ok(SendMessageOutput::DryRun(Box::new(output)));
+    }
+
+    // A list send skips failing members rather than failing everyone
+    let best_effort = message.list.is_some();
+    let report = send::send(manager, recipients, outgoing, policy, best_effort).await?;
+    let delivered = report.delivered;
+    Ok(SendMessageOutput::Sent(send_output(
+        report,
+        message.list.as_ref().map(|_| delivered),
+    )))
+}
+
+/// Send the parts of a split message in order, to a group or to each
+/// recipient. The first part that fails stops the rest, since they'd make
+/// little sense without it.
+async fn send_parts(
+    manager: &mut SignalManager,
+    recipients: &[String],
+    group: Option<[u8; 32]>,
+    parts: Vec<Outgoing>,
+    queue: bool,
+    dry_run: bool,
+    policy: IdentityPolicy,
+) -> Result<SendMessageOutput> {
+    let mut uuids = Vec::new();
+    if group.is_none() {
+        for recipient in recipients {
+            uuids.push(resolve_recipient(manager, recipient).await?);
+        }
+    }
+    if queue {
+        let mut queued = Vec::new();
+        for part in &parts {
+            queued.extend(queue_message(&uuids, part)?.queued);
+        }
+        return Ok(SendMessageOutput::Queued(QueueOutput {
+            success: true,
+            queued,
+        }));
+    }
+    if dry_run {
+        let mut output = Vec::new();
+        for part in &parts {
+            output.push(preview_send(manager, &uuids, part).await?);
+        }
+        return Ok(SendMessageOutput::DryRuns(output));
+    }
+
+    let total = parts.len();
+    let mut timestamps = Vec::new();
+    for (i, part) in parts.into_iter().enumerate() {
+        let report = match group {
+            Some(master_key) => send::send_to_group(manager, master_key, part, &[], policy).await,
+            None => send::send(manager, uuids.clone(), part, policy, false).await,
+        }
+        .with_context(|| format!("Part {} of {}", i + 1, total))?;
+        if i == 0 {
+            for uuid in &report.identity_changed {
+                eprintln!("Warning: the safety number for {} has changed", uuid);
+            }
+        }
+        timestamps.push(Timestamp::from_millis(report.timestamp));
+    }
+    Ok(SendMessageOutput::Sent(SendOutput {
+        success: true,
+        timestamp: timestamps[0],
+        recipients: None,
+        failed: Vec::new(),
+        parts: timestamps,
+    }))
+}
+
+/// Message text and its styles, reading `--markdown` text as Markdown
+fn styled_text(text: String, markdown: bool) -> (String, Vec<StyleRange>) {
+    if markdown {
+        styles::parse_markdown(&text)
+    } else {
+        (text, Vec::new())
+    }
+}
+
+/// The ID of the group a recipient names, if it's a group chat ID
+fn group_key(recipient: &str) -> Option<[u8; 32]> {
+    match parse_thread(recipient) {
+        Ok(Thread::Group(master_key)) => Some(master_key),
+        _ => None,
+    }
+}
+
+/// A send's output, warning of any changed safety numbers
+fn send_output(report: SendReport, recipients: Option<usize>) -> SendOutput {
+    for uuid in &report.identity_changed {
+        eprintln!("Warning: the safety number for {} has changed", uuid);
+    }
+    SendOutput {
+        success: report.failed.is_empty(),
+        timestamp: Timestamp::from_millis(report.timestamp),
+        recipients,
+        failed: report.failed,
+        parts: Vec::new(),
+    }
+}
+
+/// Check a message and describe what sending it would do, without sending.
+async fn preview_send(
+    manager: &SignalManager,
+    recipients: &[Uuid],
+    outgoing: &Outgoing,
+) -> Result<DryRunOutput> {
+    send::validate(outgoing)?;
+    let describe = |(spec, data): &(AttachmentSpec, Vec<u8>)| DryRunAttachment {
+        file_name: spec.file_name.clone(),
+        content_type: spec.content_type.clone(),
+        size: data.len(),
+        voice_note: spec.voice_note == Some(true),
+        duration: (spec.voice_note == Some(true))
+            .then(|| voice_notes::duration(data).ok())
+            .flatten()
+            .map(|duration| duration.as_secs_f64()),
+    };
+    let expiry_db = expiry::open_expiry_db()?;
+    let mut dry_run_recipients = Vec::new();
+    for &uuid in recipients {
+        let name = manager
+            .store()
+            .contact_by_id(&uuid)
+            .await?
+            .map(|contact| contact.name)
+            .filter(|name| !name.is_empty());
+        let expire_timer = expiry::outgoing_timer(manager, &expiry_db, uuid)
+            .await?
+            .map(|(seconds, _)| seconds)
+            .filter(|&seconds| seconds > 0);
+        dry_run_recipients.push(DryRunRecipient {
+            uuid: uuid.to_string(),
+            name,
+            expire_timer,
+        });
+    }
+    Ok(DryRunOutput {
+        dry_run: true,
+        recipients: dry_run_recipients,
+        text: outgoing.text.clone(),
+        styles: outgoing.styles.clone(),
+        attachments: outgoing.attachments.iter().map(describe).collect(),
+        link_preview: outgoing.preview.as_ref().map(|preview| DryRunPreview {
+            url: preview.url.clone(),
+            title: preview.title.clone(),
+            description: preview.description.clone(),
+            image: preview.image.as_ref().map(describe),
+        }),
+        view_once: outgoing.view_once,
+        contact: outgoing.contact.as_ref().map(shared_contacts::render),
+    })
+}
+
+/// Put a message in the outbox for each recipient.
+fn queue_message(recipients: &[Uuid], outgoing: &Outgoing) -> Result<QueueOutput> {
+    let mut conn = outbox::open_outbox_db()?;
+    Ok(QueueOutput {
+        success: true,
+        queued: outbox::enqueue(&mut conn, recipients, outgoing)?,
+    })
+}
+
+/// Send one message to several recipients, with each one's outcome. A
+/// recipient that doesn't resolve or fails doesn't stop the rest.
+async fn send_to_each(
+    manager: &mut SignalManager,
+    recipients: &[String],
+    outgoing: Outgoing,
+    policy: IdentityPolicy,
+) -> Result<Vec<RecipientSendOutput>> {
+    let mut results = Vec::new();
+    let mut uuids = Vec::new();
+    for recipient in recipients {
+        let (uuid, error) = match resolve_recipient(manager, recipient).await {
+            Ok(uuid) => {
+                if !uuids.contains(&uuid) {
+                    uuids.push(uuid);
+                }
+                (Some(uuid), None)
+            }
+            Err(e) => (None, Some(e.to_string())),
+        };
+        results.push((recipient, uuid, error));
+    }
+    if uuids.is_empty() {
+        anyhow::bail!("None of the recipients could be resolved");
+    }
+
+    let report = send::send(manager, uuids, outgoing, policy, true).await?;
+    for uuid in &report.identity_changed {
+        eprintln!("Warning: the safety number for {} has changed", uuid);
+    }
+
+    Ok(results
+        .into_iter()
+        .map(|(recipient, uuid, error)| {
+            let error = error.or_else(|| {
+                let uuid = uuid?;
+                report
+                    .errors
+                    .iter()
+                    .find(|(failed, _)| *failed == uuid)
+                    .map(|(_, e)| e.clone())
+            });
+            RecipientSendOutput {
+                recipient: recipient.clone(),
+                uuid: uuid.map(|uuid| uuid.to_string()),
+                success: error.is_none(),
+                timestamp: error
+                    .is_none()
+                    .then(|| Timestamp::from_millis(report.timestamp)),
+                error,
+            }
+        })
+        .collect())
+}
+
+/// A file to attach, typed by its extension.
+fn read_attachment(path: &Path) -> Result<(AttachmentSpec, Vec<u8>)> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase);
+    let content_type = match extension.as_deref() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("heic") => "image/heic",
+        Some("mp4") => "video/mp4",
+        Some("mov") => "video/quicktime",
+        Some("mp3") => "audio/mpeg",
+        Some("m4a") => "audio/mp4",
+        Some("pdf") => "application/pdf",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    };
+    let spec = AttachmentSpec {
+        content_type: content_type.to_string(),
+        length: data.len(),
+        file_name: path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(str::to_string),
+        ..Default::default()
+    };
+    Ok((spec, data))
+}
+
+/// Resolve a batch message's recipient and read its attachments.
+async fn prepare_batch_message(
+    manager: &SignalManager,
+    message: BatchMessage,
+    markdown: bool,
+) -> Result<(Uuid, Outgoing)> {
+    let text = message
+        .text
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty());
+    if text.is_none() && message.attachments.is_empty() {
+        anyhow::bail!("Message has no text or attachments");
+    }
+    let attachments = message
+        .attachments
+        .iter()
+        .map(|path| read_attachment(path))
+        .collect::<Result<Vec<_>>>()?;
+    let uuid = resolve_recipient(manager, &message.recipient).await?;
+    let (text, styles) = match text {
+        Some(text) => {
+            let (text, styles) = styled_text(text, markdown);
+            (Some(text), styles)
+        }
+        None => (None, Vec::new()),
+    };
+    Ok((
+        uuid,
+        Outgoing {
+            text,
+            attachments,
+            styles,
+            preview: None,
+            sticker: None,
+            view_once: false,
+            contact: None,
+        },
+    ))
+}
+
+/// Send one batch message: resolve, read its attachments and send.
+async fn send_batch_message(
+    manager: &mut SignalManager,
+    message: BatchMessage,
+    policy: IdentityPolicy,
+    markdown: bool,
+) -> Result<(Uuid, u64)> {
+    let (uuid, outgoing) = prepare_batch_message(manager, message, markdown).await?;
+    let report = send::send(manager, vec![uuid], outgoing, policy, false).await?;
+    for uuid in &report.identity_changed {
+        eprintln!("Warning: the safety number for {} has changed", uuid);
+    }
+    Ok((uuid, report.timestamp))
+}
+
+/// Send each message of a JSON array from stdin in turn. A message that
+/// fails is reported and the rest still go. A dry run checks every message
+/// and prints what each would send, failing on the first bad one.
+async fn cmd_send_batch(
+    policy: IdentityPolicy,
+    interval_ms: u64,
+    dry_run: bool,
+    markdown: bool,
+) -> Result<()> {
+    let mut messages: Vec<BatchMessage> = serde_json::from_reader(std::io::stdin().lock())
+        .map_err(|e| signal_core::Error::Invalid(format!("Invalid batch: {}", e)))?;
+    if messages.is_empty() {
+        return Err(signal_core::Error::Invalid("Batch is empty".to_string()).into());
+    }
+
+    // Attachments are read by whichever process sends, whose working
+    // directory may not be this one
+    for message in &mut messages {
+        for path in &mut message.attachments {
+            *path = std::path::absolute(&*path)?;
+        }
+    }
+    let request = daemon::Request::SendBatch {
+        messages: messages.clone(),
+        interval_ms,
+        dry_run,
+        markdown,
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    let output = send_batch(
+        &mut manager,
+        messages,
+        policy,
+        interval_ms,
+        dry_run,
+        markdown,
+    )
+    .await?;
+    print_json(&output)
+}
+
+/// Send a batch's messages, or with `dry_run` describe them.
+async fn send_batch(
+    manager: &mut SignalManager,
+    messages: Vec<BatchMessage>,
+    policy: IdentityPolicy,
+    interval_ms: u64,
+    dry_run: bool,
+    markdown: bool,
+) -> Result<SendMessageOutput> {
+    if dry_run {
+        let mut output = Vec::new();
+        for (i, message) in messages.into_iter().enumerate() {
+            let checked = match prepare_batch_message(manager, message, markdown).await {
+                Ok((uuid, outgoing)) => preview_send(manager, &[uuid], &outgoing).await,
+                Err(e) => Err(e),
+            };
+            output.push(checked.with_context(|| format!("Message {}", i + 1))?);
+        }
+        return Ok(SendMessageOutput::DryRuns(output));
+    }
+    let mut output = Vec::new();
+    for (i, message) in messages.into_iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(interval_ms)).await;
+        }
+        let recipient = message.recipient.clone();
+        output.push(
+            match send_batch_message(manager, message, policy, markdown).await {
+                Ok((uuid, timestamp)) => RecipientSendOutput {
+                    recipient,
+                    uuid: Some(uuid.to_string()),
+                    success: true,
+                    timestamp: Some(Timestamp::from_millis(timestamp)),
+                    error: None,
+                },
+                Err(e) => RecipientSendOutput {
+                    recipient,
+                    uuid: None,
+                    success: false,
+                    timestamp: None,
+                    error: Some(format!("{:#}", e)),
+                },
+            },
+        );
+    }
+    Ok(SendMessageOutput::EachRecipient(output))
+}
+
+/// `receive --store-only` output
+#[derive(Serialize)]
+struct ReceiveSummary {
+    messages: usize,
+    /// Everything else returned: reactions, typing, calls and the like
+    events: usize,
+    /// Messages marked read on other devices; unknown through a daemon
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_syncs: Option<usize>,
+}
+
+impl ReceiveSummary {
+    fn of(items: &[serde_json::Value], read_syncs: Option<usize>) -> Self {
+        // Only events have a `type`
+        let messages = items
+            .iter()
+            .filter(|item| item.get("type").is_none())
+            .count();
+        ReceiveSummary {
+            messages,
+            events: items.len() - messages,
+            read_syncs,
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn cmd_receive(
+    policy: IdentityPolicy,
+    webhook: Option<webhook::Webhook>,
+    hooks: hooks::Hooks,
+    mut policies: policy::Policies,
+    store_only: bool,
+) -> Result<()> {
+    if let Some(output) = daemon::proxy(&daemon::Request::Receive).await? {
+        let items = output.as_array().map(Vec::as_slice).unwrap_or_default();
+        if store_only {
+            print_json(&ReceiveSummary::of(items, None))?;
+        } else {
+            print_json(&output)?;
+        }
+        respond(&hooks, &mut policies, items, None, policy).await;
+        return forward_to_webhook(webhook.as_ref(), items).await;
+    }
+    let mut manager = load_registered_manager().await?;
+
+    eprintln!("Receiving messages...");
+    let result = receive::receive(&mut manager, policy).await?;
+
+    if result.read_sync_count > 0 {
+        eprintln!(
+            "Synced {} read receipts from other devices",
+            result.read_sync_count
+        );
+    }
+    let message_count = result
+        .items
+        .iter()
+        .filter(|item| item.kind() == "message")
+        .count();
+    eprintln!("Received {} messages", message_count);
+
+    let items = result
+        .items
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<serde_json::Result<Vec<_>>>()?;
+    if store_only {
+        print_json(&ReceiveSummary::of(&items, Some(result.read_sync_count)))?;
+    } else {
+        print_json(&result.items)?;
+    }
+    respond(&hooks, &mut policies, &items, Some(&mut manager), policy).await;
+    forward_to_webhook(webhook.as_ref(), &items).await
+}
+
+/// Respond to received messages as each chat's policy says: run its
+/// `--on-message` hook, send an auto-reply, escalate, or nothing. Replies are
+/// sent with `manager` or, when receiving went through the daemon, via the
+/// daemon. Failures are reported and skipped so one bad hook doesn't stop the
+/// rest.
+async fn respond(
+    hooks: &hooks::Hooks,
+    policies: &mut policy::Policies,
+    items: &[serde_json::Value],
+    mut manager: Option<&mut SignalManager>,
+    identity_policy: IdentityPolicy,
+) {
+    if let Err(e) = policies.reload() {
+        eprintln!("Warning: {:#}; keeping the previous policy", e);
+    }
+    let usage_db = usage::open_usage_db()
+        .inspect_err(|e| warn!("Failed to open usage database: {}", e))
+        .ok();
+
+    for (chat_id, message) in hooks::incoming(items) {
+        let rule = policies.rule_for(chat_id);
+        let reply = match rule {
+            policy::Rule::Deny => continue,
+            policy::Rule::AutoReply { template } => {
+                if holds_back_reply(usage_db.as_ref(), chat_id, message) {
+                    continue;
+                }
+                policy::render(template, message)
+            }
+            // An escalation only alerts someone; its output isn't a reply
+            policy::Rule::Escalate { command } => {
+                run_hook(command, chat_id, message).await;
+                continue;
+            }
+            policy::Rule::Allow | policy::Rule::RequireApproval => {
+                let Some(command) = hooks.command_for(chat_id) else {
+                    continue;
+                };
+                // A reply held for approval can't start a loop
+                if *rule == policy::Rule::Allow
+                    && holds_back_reply(usage_db.as_ref(), chat_id, message)
+                {
+                    continue;
+                }
+                match run_hook(command, chat_id, message).await {
+                    Some(reply) => reply,
+                    None => continue,
+                }
+            }
+        };
+
+        if *rule == policy::Rule::RequireApproval {
+            match drafts::open_drafts_db().and_then(|conn| drafts::set(&conn, chat_id, &reply)) {
+                Ok(()) => eprintln!(
+                    "Reply to {} is waiting for approval in its draft (`draft get`)",
+                    chat_id
+                ),
+                Err(e) => eprintln!("Warning: failed to save reply to {}: {}", chat_id, e),
+            }
+            continue;
+        }
+
+        let request = daemon::Request::Send(SendMessage {
+            recipients: vec![chat_id.to_string()],
+            text: Some(reply),
+            ..Default::default()
+        });
+        // In milliseconds, to recognize replies to it
+        let envelope = daemon::Envelope {
+            time_format: TimeFormat::UnixMs,
+            ..daemon::Envelope::new(request)
+        };
+        let sent = match manager.as_deref_mut() {
+            Some(manager) => daemon::execute(manager, envelope, identity_policy).await,
+            None => daemon::proxy_envelope(&envelope)
+                .await
+                .and_then(|output| output.context("Daemon stopped before the reply was sent")),
+        };
+        match sent {
+            Ok(output) => {
+                if let (Some(conn), Some(timestamp)) = (&usage_db, output["timestamp"].as_u64()) {
+                    if let Err(e) = usage::record_auto_reply(conn, chat_id, timestamp) {
+                        warn!("Failed to record automatic reply: {}", e);
+                    }
                 }
             }
-            Err(_) => StatusOutput {
-                linked: false,
-                uuid: None,
-                phone: None,
-            },
-        },
-        Err(_) => StatusOutput {
-            linked: false,
-            uuid: None,
-            phone: None,
+            Err(e) => eprintln!("Warning: failed to send reply to {}: {:#}", chat_id, e),
+        }
+    }
+}
+
+/// How long after an automatic reply to a chat the next one is held back
+const AUTO_REPLY_INTERVAL_MS: u64 = 5 * 60 * 1000;
+
+/// Whether to skip replying to `message` automatically, so two bots can't
+/// answer each other forever: it answers one of our automatic replies, or the
+/// chat had one too recently. A database failure doesn't hold anything back.
+fn holds_back_reply(
+    usage_db: Option<&Connection>,
+    chat_id: &str,
+    message: &serde_json::Value,
+) -> bool {
+    let Some(conn) = usage_db else {
+        return false;
+    };
+    let answers_auto_reply = || -> signal_core::Result<bool> {
+        match message["reply_to"].as_str() {
+            Some(id) => usage::is_auto_reply(conn, chat_id, parse_message_id(id)?.0),
+            None => Ok(false),
+        }
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let reason = match answers_auto_reply() {
+        Ok(true) => "it answers an automatic reply",
+        _ => match usage::last_auto_reply(conn, chat_id) {
+            Ok(Some(last)) if now.saturating_sub(last) < AUTO_REPLY_INTERVAL_MS => {
+                "the chat had one in the last 5 minutes"
+            }
+            Ok(_) => return false,
+            Err(e) => {
+                warn!("Failed to check automatic replies to {}: {}", chat_id, e);
+                return false;
+            }
         },
     };
+    eprintln!("Not replying to {} automatically: {}", chat_id, reason);
+    true
+}
+
+/// Run one hook, recording it in usage. Returns its reply; a failed hook is
+/// reported and has none.
+async fn run_hook(command: &str, chat_id: &str, message: &serde_json::Value) -> Option<String> {
+    if let Err(e) = usage::open_usage_db()
+        .and_then(|conn| usage::record(&conn, chat_id, usage::UsageKind::Hook))
+    {
+        warn!("Failed to record hook usage: {}", e);
+    }
+    hooks::run(command, chat_id, message)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Warning: hook for {} failed: {:#}", chat_id, e);
+            None
+        })
+}
+
+/// The webhook received items are POSTed to, if a URL is set. Its secret is
+/// `secret` (from `--webhook-secret` or the env var), or the stored one.
+fn receive_webhook(
+    url: Option<String>,
+    secret: Option<String>,
+) -> Result<Option<webhook::Webhook>> {
+    let Some(url) = url else {
+        return Ok(None);
+    };
+    let secret = match secret {
+        Some(secret) => secret,
+        None => secrets::get(webhook::WEBHOOK_SECRET)?
+            .map(|(secret, _)| secret)
+            .with_context(|| {
+                format!(
+                    "No webhook secret. Pass --webhook-secret, set JC_SIGNAL_WEBHOOK_SECRET, or run 'signal-cli secret set {}'",
+                    webhook::WEBHOOK_SECRET
+                )
+            })?,
+    };
+    Ok(Some(webhook::Webhook::new(&url, &secret)?))
+}
+
+/// POST received items to the webhook, if there is one. They're already
+/// saved and printed, so a failed delivery only fails the exit status.
+async fn forward_to_webhook<T: Serialize>(
+    webhook: Option<&webhook::Webhook>,
+    items: &[T],
+) -> Result<()> {
+    let Some(webhook) = webhook else {
+        return Ok(());
+    };
+    let failed = webhook.deliver(items).await?;
+    if failed > 0 {
+        anyhow::bail!(
+            "Failed to deliver {} of {} items to the webhook",
+            failed,
+            items.len()
+        );
+    }
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn cmd_messages(
+    chat_id: String,
+    max_results: usize,
+    order_by: MessageOrder,
+    verify_ordering: bool,
+    as_of: Option<u64>,
+    page: history::Page,
+) -> Result<()> {
+    let request = daemon::Request::Messages {
+        chat_id: chat_id.clone(),
+        max_results,
+        order_by,
+        verify_ordering,
+        as_of,
+        page: page.clone(),
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let manager = load_registered_manager().await?;
+    let messages = history::thread_messages(
+        &manager,
+        &chat_id,
+        max_results,
+        order_by,
+        verify_ordering,
+        as_of,
+        &page,
+    )
+    .await?;
+    print_json(&messages)?;
+    Ok(())
+}
+
+async fn export_chat(
+    manager: &SignalManager,
+    chat_id: &str,
+    format: ExportFormat,
+    output: PathBuf,
+    with_attachments: bool,
+    time_format: TimeFormat,
+) -> Result<ExportOutput> {
+    let summary = export::export(
+        manager,
+        chat_id,
+        format,
+        &output,
+        with_attachments,
+        time_format,
+    )
+    .await?;
+    Ok(ExportOutput {
+        success: true,
+        path: output.display().to_string(),
+        messages: summary.messages,
+        attachments_copied: summary.attachments_copied,
+    })
+}
+
+#[instrument(skip_all)]
+async fn cmd_export(
+    chat_id: String,
+    format: ExportFormat,
+    output: PathBuf,
+    with_attachments: bool,
+) -> Result<()> {
+    let request = daemon::Request::Export {
+        chat_id: chat_id.clone(),
+        format,
+        // The daemon's working directory isn't this one
+        output: std::path::absolute(&output)?,
+        with_attachments,
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let manager = load_registered_manager().await?;
+    let time_format = timestamp::time_format();
+    let output = export_chat(
+        &manager,
+        &chat_id,
+        format,
+        output,
+        with_attachments,
+        time_format,
+    )
+    .await?;
+    print_json(&output)
+}
 
-    println!("{}", serde_json::to_string_pretty(&output)?);
+#[instrument(skip_all)]
+async fn cmd_search(
+    query: String,
+    filters: search::SearchFilters,
+    max_results: usize,
+    reindex: bool,
+) -> Result<()> {
+    let request = daemon::Request::Search {
+        query: query.clone(),
+        filters: filters.clone(),
+        max_results,
+        reindex,
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let manager = load_registered_manager().await?;
+    let mut conn = search::open_search_db()?;
+    if reindex {
+        search::reindex(&manager, &mut conn).await?;
+    }
+    let hits = search::search(&manager, &mut conn, &query, &filters, max_results).await?;
+    print_json(&hits)?;
     Ok(())
 }
 
-async fn cmd_mark_read(chat_ids: Vec<String>) -> Result<()> {
+/// The account's status, with `manager` if the store loads as linked.
+async fn status(manager: Option<&SignalManager>, deep: bool) -> Result<StatusOutput> {
+    let (size_bytes, databases) = status::sizes()?;
+    let outbox_pending = outbox::list(&outbox::open_outbox_db()?)?
+        .iter()
+        .filter(|item| item.status == outbox::OutboxStatus::Pending)
+        .count();
+    let mut output = StatusOutput {
+        linked: manager.is_some(),
+        uuid: None,
+        phone: None,
+        reason: None,
+        unlinked_at: None,
+        link_interrupted: false,
+        data_dir: get_data_dir()?.display().to_string(),
+        size_bytes,
+        databases,
+        counts: status::store_counts()?,
+        last_receive: status::last_receive()?,
+        outbox_pending,
+        network: None,
+    };
+    match manager {
+        Some(manager) => {
+            let start = std::time::Instant::now();
+            let whoami = manager.whoami().await.map_err(signal_core::Error::from);
+            let whoami = link::note_unlinked(whoami);
+            let latency = start.elapsed();
+            if let Ok(whoami) = &whoami {
+                output.uuid = Some(whoami.aci.to_string());
+                output.phone = Some(whoami.number.to_string());
+                // Accepted again, so whatever was recorded is stale
+                link::clear_unlinked()?;
+            }
+            if deep {
+                output.network = Some(match whoami {
+                    Ok(_) => NetworkStatus {
+                        reachable: true,
+                        credentials_valid: true,
+                        latency_ms: Some(latency.as_millis() as u64),
+                        error: None,
+                    },
+                    Err(e) => NetworkStatus {
+                        // Only a server that answered can reject the device
+                        reachable: matches!(
+                            e,
+                            signal_core::Error::Unlinked | signal_core::Error::RateLimited
+                        ),
+                        credentials_valid: !matches!(e, signal_core::Error::Unlinked),
+                        latency_ms: None,
+                        error: Some(e.to_string()),
+                    },
+                });
+            }
+        }
+        None => output.link_interrupted = matches!(link::pending(), Ok(Some(_))),
+    }
+    // Recorded by whichever command the server rejected, this one included
+    if output.linked {
+        if let Some(unlinked) = link::unlinked()? {
+            output.linked = false;
+            output.reason = Some("unlinked_by_server");
+            output.unlinked_at = Some(Timestamp::from_millis(unlinked.detected_at));
+        }
+    }
+    Ok(output)
+}
+
+#[instrument(skip_all)]
+async fn cmd_status(deep: bool) -> Result<()> {
+    if let Some(output) = daemon::proxy(&daemon::Request::Status { deep }).await? {
+        return print_json(&output);
+    }
+    let manager = match open_store().await {
+        Ok(store) => Manager::load_registered(store).await.ok(),
+        Err(_) => None,
+    };
+    print_json(&status(manager.as_ref(), deep).await?)
+}
+
+#[instrument(skip_all)]
+/// Mark whole chats and single messages read.
+async fn mark_read(
+    manager: &mut SignalManager,
+    chat_ids: &[String],
+    messages: &[(String, String)],
+    receipts: ReadReceiptPolicy,
+) -> Result<MarkReadOutput> {
+    let mut output = MarkReadOutput {
+        success: true,
+        chats_marked: 0,
+        messages_marked: 0,
+    };
+    if !chat_ids.is_empty() {
+        let result = read_sync::mark_read(manager, chat_ids, receipts).await?;
+        output.chats_marked = result.chats_marked;
+        output.messages_marked += result.messages_marked;
+    }
+    if !messages.is_empty() {
+        let result = read_sync::mark_messages_read(manager, messages, receipts).await?;
+        output.messages_marked += result.messages_marked;
+    }
+    Ok(output)
+}
+
+async fn cmd_mark_read(
+    chat_ids: Vec<String>,
+    messages: Vec<String>,
+    receipts: ReadReceiptPolicy,
+) -> Result<()> {
+    let messages = messages
+        .iter()
+        .map(|message| {
+            let (chat_id, id) = message.split_once(':').ok_or_else(|| {
+                signal_core::Error::Invalid(format!(
+                    "Expected <chat_id>:<message_id>, got '{}'",
+                    message
+                ))
+            })?;
+            Ok((chat_id.to_string(), id.to_string()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let request = daemon::Request::MarkRead {
+        chat_ids: chat_ids.clone(),
+        messages: messages.clone(),
+        read_receipts: receipts,
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    print_json(&mark_read(&mut manager, &chat_ids, &messages, receipts).await?)
+}
+
+async fn cmd_mark_unread(chat_id: String, after: Option<String>) -> Result<()> {
+    let request = daemon::Request::MarkUnread {
+        chat_id: chat_id.clone(),
+        after: after.clone(),
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
     let manager = load_registered_manager().await?;
+    let messages_marked = read_sync::mark_unread(&manager, &chat_id, after.as_deref()).await?;
+    print_json(&MarkUnreadOutput {
+        success: true,
+        chat_id,
+        messages_marked,
+    })
+}
+
+/// Request a contact sync, giving up after `timeout_secs`.
+async fn sync_contacts(
+    manager: &mut SignalManager,
+    policy: IdentityPolicy,
+    timeout_secs: u64,
+) -> Result<SyncContactsOutput> {
+    let timeout = std::time::Duration::from_secs(timeout_secs);
+    let result = tokio::time::timeout(timeout, receive::sync_contacts(manager, policy))
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "No contact sync within {}s; is the phone online?",
+                timeout_secs
+            )
+        })??;
+    let messages_received = result
+        .received
+        .items
+        .iter()
+        .filter(|item| item.kind() == "message")
+        .count();
+    Ok(SyncContactsOutput {
+        success: true,
+        contacts_updated: result.contacts_updated,
+        contacts_total: result.contacts_total,
+        messages_received,
+    })
+}
+
+async fn cmd_sync_contacts(policy: IdentityPolicy, timeout_secs: u64) -> Result<()> {
+    let request = daemon::Request::SyncContacts { timeout_secs };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    eprintln!("Waiting for contacts from the phone...");
+    print_json(&sync_contacts(&mut manager, policy, timeout_secs).await?)
+}
+
+/// How often `await-reply` receives
+const AWAIT_REPLY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Receive until a message from someone else arrives in `chat_id`, and
+/// return it as the event log has it.
+async fn await_reply(
+    chat_id: &str,
+    matching: Option<&regex::Regex>,
+    policy: IdentityPolicy,
+) -> Result<events::EventRecord> {
+    let conn = events::open_events_db()?;
+    // Only what arrives from now on: `receive` logs everything it returns
+    let mut seq = events::last_seq(&conn)?;
+    let mut manager = None;
+    loop {
+        if daemon::proxy(&daemon::Request::Receive).await?.is_none() {
+            let manager = match &mut manager {
+                Some(manager) => manager,
+                None => manager.insert(load_registered_manager().await?),
+            };
+            receive::receive(manager, policy).await?;
+        }
+        for event in events::after(&conn, seq, i64::MAX as usize)? {
+            seq = event.seq;
+            let text = event.data["text"].as_str().unwrap_or_default();
+            let is_reply = event.kind == "message"
+                && event.chat_id.as_deref() == Some(chat_id)
+                && event.data["is_outgoing"] != true
+                && matching.is_none_or(|re| re.is_match(text));
+            if is_reply {
+                return Ok(event);
+            }
+        }
+        tokio::time::sleep(AWAIT_REPLY_INTERVAL).await;
+    }
+}
+
+async fn cmd_await_reply(
+    chat_id: String,
+    timeout: Option<u64>,
+    matching: Option<regex::Regex>,
+    policy: IdentityPolicy,
+) -> Result<()> {
+    parse_thread(&chat_id)?;
+    let waiting = await_reply(&chat_id, matching.as_ref(), policy);
+    let reply = match timeout {
+        Some(secs) => tokio::time::timeout(std::time::Duration::from_secs(secs), waiting)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("No reply within {}s", secs)))?,
+        None => waiting.await?,
+    };
+    print_json(&reply.data)
+}
+
+async fn cmd_flush(policy: IdentityPolicy, all: bool) -> Result<()> {
+    if let Some(output) = daemon::proxy(&daemon::Request::Flush { all }).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    print_json(&outbox::flush(&mut manager, policy, all).await?)
+}
+
+fn cmd_outbox(action: OutboxCommand) -> Result<()> {
+    let conn = outbox::open_outbox_db()?;
+    match action {
+        OutboxCommand::List => print_json(&outbox::list(&conn)?),
+        OutboxCommand::Remove { ids } => print_json(&OutboxRemoveOutput {
+            success: true,
+            removed: outbox::remove(&conn, &ids)?,
+        }),
+    }
+}
+
+async fn cmd_sticker(action: StickerCommand, policy: IdentityPolicy) -> Result<()> {
+    let request = match &action {
+        StickerCommand::List => daemon::Request::StickerList,
+        StickerCommand::Send {
+            chat_id,
+            pack_id,
+            sticker_id,
+        } => {
+            outbound::ensure_allowed(outbound::Outbound::Message)?;
+            daemon::Request::StickerSend {
+                chat_id: chat_id.clone(),
+                pack_id: pack_id.clone(),
+                sticker_id: *sticker_id,
+            }
+        }
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    match action {
+        StickerCommand::List => print_json(&stickers::list(&manager).await?),
+        StickerCommand::Send {
+            chat_id,
+            pack_id,
+            sticker_id,
+        } => {
+            let report = send_sticker(&mut manager, &chat_id, &pack_id, sticker_id, policy).await?;
+            print_json(&send_output(report, None))
+        }
+    }
+}
+
+/// Send a sticker to a contact or group.
+async fn send_sticker(
+    manager: &mut SignalManager,
+    chat_id: &str,
+    pack_id: &str,
+    sticker_id: u32,
+    policy: IdentityPolicy,
+) -> Result<SendReport> {
+    let outgoing = stickers::outgoing(manager, pack_id, sticker_id).await?;
+    Ok(match parse_thread(chat_id)? {
+        Thread::Contact(uuid) => send::send(manager, vec![uuid], outgoing, policy, false).await?,
+        Thread::Group(master_key) => {
+            send::send_to_group(manager, master_key, outgoing, &[], policy).await?
+        }
+    })
+}
+
+async fn cmd_expire(action: ExpireCommand) -> Result<()> {
+    let ExpireCommand::Set { chat_id, seconds } = action;
+    let request = daemon::Request::ExpireSet {
+        chat_id: chat_id.clone(),
+        seconds,
+    };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    print_json(&expire_set(&mut manager, chat_id, seconds).await?)
+}
+
+async fn expire_set(
+    manager: &mut SignalManager,
+    chat_id: String,
+    seconds: u32,
+) -> Result<ExpireSetOutput> {
+    let timestamp = expiry::set(manager, &chat_id, seconds).await?;
+    Ok(ExpireSetOutput {
+        success: true,
+        chat_id,
+        seconds,
+        timestamp: Timestamp::from_millis(timestamp),
+    })
+}
+
+/// Compare each stored group against what its own messages say, locally.
+///
+/// Every group message carries the group revision the sender saw. If that is
+/// ahead of the stored revision, our copy of the membership list is stale and
+/// group sends will fail until presage re-fetches it. presage doesn't expose a
+/// server fetch, so this neither asks the server nor repairs anything;
+/// receiving any newer group message triggers the refresh.
+#[instrument(skip_all)]
+async fn verify_groups(manager: &SignalManager) -> Result<Vec<GroupVerifyOutput>> {
     let store = manager.store();
     let my_uuid = manager.whoami().await?.aci;
 
-    let mut read_db = read_sync::open_read_sync_db()?;
-    let mut total_messages = 0i64;
-    let mut chats_marked = 0usize;
-
-    for chat_id in &chat_ids {
-        // Parse chat_id as UUID (contact) or hex (group)
-        let thread = if let Ok(uuid) = chat_id.parse::<Uuid>() {
-            Thread::Contact(uuid)
-        } else if let Ok(master_key) = hex::decode(chat_id) {
-            let key: [u8; 32] = master_key
-                .try_into()
-                .map_err(|_| anyhow::anyhow!("Group master key must be 32 bytes"))?;
-            Thread::Group(key)
-        } else {
-            warn!("Invalid chat_id: {}", chat_id);
-            continue;
-        };
+    let mut results = Vec::new();
+    for (master_key, group) in store.groups().await?.flatten() {
+        let latest_seen_revision = store
+            .messages(&Thread::Group(master_key), ..)
+            .await?
+            .flatten()
+            .filter_map(|content| match content.body {
+                ContentBody::DataMessage(dm) => dm.group_v2.and_then(|g| g.revision),
+                _ => None,
+            })
+            .max();
+        let is_member = group.members.iter().any(|m| Uuid::from(m.aci) == my_uuid);
 
-        // Get all incoming messages from this chat and mark them read
-        // Collect (sender_aci, timestamp) pairs - groups have multiple senders
-        let messages_iter = store.messages(&thread, ..).await?;
-        let mut to_mark: Vec<(String, u64)> = Vec::new();
-
-        for content in messages_iter.flatten() {
-            if let ContentBody::DataMessage(dm) = &content.body {
-                let sender_uuid = content.metadata.sender.raw_uuid();
-                if sender_uuid != my_uuid {
-                    // Incoming message
-                    if let Some(ts) = dm.timestamp {
-                        let sender_aci = sender_uuid.to_string();
-                        if !read_sync::is_read(&read_db, &sender_aci, ts) {
-                            to_mark.push((sender_aci, ts));
-                        }
-                    }
-                }
+        let mut issues = Vec::new();
+        if let Some(seen) = latest_seen_revision {
+            if seen > group.revision {
+                issues.push(format!(
+                    "stored revision {} is behind revision {} seen in messages",
+                    group.revision, seen
+                ));
             }
         }
+        if !is_member {
+            issues.push("this account is not in the stored member list".to_string());
+        }
+
+        results.push(GroupVerifyOutput {
+            id: hex::encode(master_key),
+            name: group.title,
+            stored_revision: group.revision,
+            latest_seen_revision,
+            is_member,
+            ok: issues.is_empty(),
+            issues,
+        });
+    }
+    Ok(results)
+}
+
+async fn cmd_groups_verify() -> Result<()> {
+    if let Some(output) = daemon::proxy(&daemon::Request::GroupsVerify).await? {
+        return print_json(&output);
+    }
+    let manager = load_registered_manager().await?;
+    print_json(&verify_groups(&manager).await?)
+}
 
-        // Mark each message with its actual sender
-        for (sender_aci, ts) in &to_mark {
-            read_sync::mark_sender_read(&mut read_db, sender_aci, &[*ts])?;
+/// Known identity keys, with their contacts' names
+async fn list_identities(manager: &SignalManager) -> Result<Vec<identity::IdentityRecord>> {
+    let mut records = identity::list(&identity::open_identity_db()?)?;
+    for record in &mut records {
+        if let Ok(uuid) = record.uuid.parse::<Uuid>() {
+            record.name = manager
+                .store()
+                .contact_by_id(&uuid)
+                .await?
+                .map(|c| c.name)
+                .filter(|n| !n.is_empty());
         }
-        total_messages += to_mark.len() as i64;
-        chats_marked += 1;
     }
+    Ok(records)
+}
+
+async fn trust_identity(manager: &SignalManager, uuid: String) -> Result<IdentityTrustOutput> {
+    let uuid: Uuid = uuid
+        .parse()
+        .with_context(|| format!("Invalid UUID: {}", uuid))?;
+    let key = identity::current_identity_key(manager, uuid)
+        .await?
+        .with_context(|| format!("No identity key known for {}", uuid))?;
+
+    // Record the current key first so we trust exactly what's in the store
+    let conn = identity::open_identity_db()?;
+    identity::observe(&conn, &uuid.to_string(), &key, IdentityPolicy::Warn)?;
+    identity::trust(&conn, &uuid.to_string())?;
 
-    let output = MarkReadOutput {
+    Ok(IdentityTrustOutput {
         success: true,
-        chats_marked,
-        messages_marked: total_messages,
+        uuid: uuid.to_string(),
+        fingerprint: key,
+    })
+}
+
+#[instrument(skip_all)]
+async fn cmd_identity(action: IdentityCommand) -> Result<()> {
+    let request = match &action {
+        IdentityCommand::List => daemon::Request::IdentityList,
+        IdentityCommand::Trust { uuid } => daemon::Request::IdentityTrust { uuid: uuid.clone() },
     };
-    println!("{}", serde_json::to_string_pretty(&output)?);
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let manager = load_registered_manager().await?;
+    match action {
+        IdentityCommand::List => print_json(&list_identities(&manager).await?),
+        IdentityCommand::Trust { uuid } => print_json(&trust_identity(&manager, uuid).await?),
+    }
+}
+
+async fn get_profile(manager: &mut SignalManager, uuid: String) -> Result<ProfileOutput> {
+    let uuid: Uuid = uuid
+        .parse()
+        .with_context(|| format!("Invalid UUID: {}", uuid))?;
+
+    // Profiles are encrypted with the owner's profile key, which we
+    // only learn from their messages or a contact sync
+    let contact = manager
+        .store()
+        .contact_by_id(&uuid)
+        .await?
+        .with_context(|| format!("No contact found with UUID {}", uuid))?;
+    let key_bytes: [u8; 32] = contact.profile_key.try_into().map_err(|_| {
+        anyhow::anyhow!(
+            "No profile key known for {}. Run 'signal-cli receive' after they message you.",
+            uuid
+        )
+    })?;
+
+    let profile = manager
+        .retrieve_profile_by_uuid(uuid, ProfileKey::create(key_bytes))
+        .await
+        .context("Failed to retrieve profile")?;
+
+    let name = profile.name.map(|n| match n.family_name {
+        Some(family) if !family.is_empty() => format!("{} {}", n.given_name, family),
+        _ => n.given_name,
+    });
+
+    Ok(ProfileOutput {
+        uuid: uuid.to_string(),
+        name,
+        about: profile.about,
+        about_emoji: profile.about_emoji,
+        avatar_url: profile.avatar,
+        unrestricted_unidentified_access: profile.unrestricted_unidentified_access,
+        capabilities: serde_json::to_value(&profile.capabilities)?,
+    })
+}
+
+#[instrument(skip_all)]
+async fn cmd_profile(action: ProfileCommand) -> Result<()> {
+    let ProfileCommand::Get { uuid } = action;
+    let request = daemon::Request::ProfileGet { uuid: uuid.clone() };
+    if let Some(output) = daemon::proxy(&request).await? {
+        return print_json(&output);
+    }
+    let mut manager = load_registered_manager().await?;
+    print_json(&get_profile(&mut manager, uuid).await?)
+}
+
+#[instrument(skip_all)]
+fn cmd_usage(since: String) -> Result<()> {
+    let since = parse_since(&since)?;
+    let conn = usage::open_usage_db()?;
+    let output = usage::summarize(&conn, since)?;
+
+    print_json(&output)?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+fn cmd_events(consumer: String, max_results: usize, peek: bool) -> Result<()> {
+    let conn = events::open_events_db()?;
+    let pending = events::pending(&conn, &consumer, max_results)?;
+
+    if !peek {
+        if let Some(last) = pending.last() {
+            events::ack(&conn, &consumer, last.seq)?;
+        }
+    }
+
+    print_json(&pending)?;
+    Ok(())
+}
+
+fn cmd_replicate(to: String, interval: u64, once: bool) -> Result<()> {
+    let mut replicator = replicate::Replicator::new(replicate::Target::parse(&to))?;
+    if once {
+        let files = replicator.pass()?;
+        return print_json(&ReplicateOutput {
+            success: true,
+            to,
+            files,
+        });
+    }
+
+    eprintln!("Replicating to {} every {}s...", to, interval);
+    loop {
+        // A failed pass is retried on the next one
+        match replicator.pass() {
+            Ok(files) if files.is_empty() => {}
+            Ok(files) => eprintln!("Copied {}", files.join(", ")),
+            Err(e) => eprintln!("Warning: replication failed: {:#}", e),
+        }
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+    }
+}
+
+#[instrument(skip_all)]
+async fn cmd_serve(options: serve::Options) -> Result<()> {
+    let manager = load_registered_manager().await?;
+    serve::serve(manager, options).await
+}
+
+#[instrument(skip_all)]
+fn cmd_secret(action: SecretCommand) -> Result<()> {
+    match action {
+        SecretCommand::Set { name, store } => {
+            // Read from stdin so the value never lands in shell history
+            let value = {
+                use std::io::Read;
+                let mut buf = String::new();
+                std::io::stdin().read_to_string(&mut buf)?;
+                buf.trim_end_matches(['\r', '\n']).to_string()
+            };
+            if value.is_empty() {
+                anyhow::bail!("Secret value cannot be empty");
+            }
+
+            secrets::set(store, &name, &value)?;
+            let output = SecretSetOutput {
+                success: true,
+                name,
+                store,
+            };
+            print_json(&output)?;
+        }
+        SecretCommand::Get { name } => {
+            let (value, store) = secrets::get(&name)?
+                .with_context(|| format!("Secret '{}' not found", name))?;
+            let output = SecretGetOutput { name, value, store };
+            print_json(&output)?;
+        }
+        SecretCommand::Delete { name } => {
+            let deleted = secrets::delete(&name)?;
+            let output = SecretDeleteOutput {
+                success: true,
+                name,
+                deleted,
+            };
+            print_json(&output)?;
+        }
+    }
     Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
+async fn main() -> ExitCode {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => return errors::report_usage(e),
+    };
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => errors::report(&e),
+    }
+}
 
-    // Initialize logging
-    if cli.verbose {
-        tracing_subscriber::fmt()
-            .with_env_filter("debug")
+async fn run(cli: Cli) -> Result<()> {
+    // Initialize logging and tracing
+    let fmt_layer = cli.verbose.then(|| {
+        tracing_subscriber::fmt::layer()
             .with_writer(std::io::stderr)
-            .init();
+            .with_filter(EnvFilter::new("debug"))
+    });
+    let (chrome_layer, _trace_guard) = match cli.trace_output.clone() {
+        Some(path) => {
+            let (layer, guard) = trace::chrome_layer(path);
+            (Some(layer.with_filter(LevelFilter::INFO)), Some(guard))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(chrome_layer)
+        .init();
+
+    outbound::set_read_only(cli.read_only);
+    lock::set_wait(cli.wait);
+    if let Some(account) = cli.account {
+        set_account(account)?;
     }
+    if let Some(passphrase) = encryption::passphrase(cli.passphrase_file.as_deref())? {
+        set_passphrase(passphrase);
+    }
+
+    // Config lives in the (per-account) data dir; CLI flags take precedence
+    let config = config::load()?;
+    let _ = OUTPUT_FORMAT.set(cli.output.or(config.output).unwrap_or_default());
+    timestamp::set_time_format(cli.time_format.or(config.time_format).unwrap_or_default());
+    let identity_policy = cli
+        .identity_policy
+        .or(config.identity_policy)
+        .unwrap_or_default();
+    receive::set_ignored(
+        cli.ignore_content
+            .or(config.ignore_content)
+            .unwrap_or_default(),
+    );
+    stories::set_included(cli.include_stories || config.include_stories.unwrap_or(false));
+    let limit = |flag: Option<usize>| flag.or(config.max_results).unwrap_or(50);
+    let download_dir = match cli.attachment_dir.or(config.attachment_dir) {
+        Some(dir) => dir,
+        None => get_data_dir()?,
+    };
+    let avatar_dir = download_dir.join("avatars");
+    stickers::set_sticker_dir(download_dir.join("stickers"));
+    view_once::set_quarantine_dir(download_dir.join("view-once"));
+    stories::set_story_dir(download_dir.join("stories"));
 
     match cli.command {
-        Command::Link { device_name } => cmd_link(device_name).await,
+        Command::Link(args) => cmd_link(args, config.device_name, config.server, false).await,
+        Command::Relink(args) => cmd_link(args, config.device_name, config.server, true).await,
+        Command::Register {
+            phone_number,
+            voice,
+            captcha,
+            force,
+            server,
+            hold_session,
+        } => {
+            let request = registration::Request {
+                phone_number,
+                voice,
+                captcha,
+                force,
+                server: server.or(config.server).unwrap_or_default(),
+            };
+            if hold_session {
+                registration::hold(request).await
+            } else {
+                let output = registration::start().await?;
+                eprintln!(
+                    "Verification code sent to {}. Finish with `verify-code <code>`.",
+                    request.phone_number
+                );
+                print_json(&output)
+            }
+        }
+        Command::VerifyCode { code } => {
+            let output = registration::verify(&code).await?;
+            eprintln!("Successfully registered");
+            print_json(&output)
+        }
+        Command::Unlink { keep_data } => cmd_unlink(keep_data).await,
         Command::Whoami => cmd_whoami().await,
-        Command::Chats { max_results } => cmd_chats(max_results).await,
-        Command::Send { recipient } => cmd_send(recipient).await,
-        Command::Receive => cmd_receive().await,
-        Command::Messages { chat_id, max_results } => cmd_messages(chat_id, max_results).await,
-        Command::Status => cmd_status().await,
-        Command::MarkRead { chat_ids } => cmd_mark_read(chat_ids).await,
+        Command::Ping => cmd_ping().await,
+        Command::Version { json } => cmd_version(json),
+        Command::Chats {
+            max_results,
+            with_avatars,
+            with_unread_counts,
+        } => {
+            cmd_chats(
+                limit(max_results),
+                with_avatars,
+                with_unread_counts,
+                &avatar_dir,
+            )
+            .await
+        }
+        Command::Conversations { max_results } => cmd_conversations(limit(max_results)).await,
+        Command::Chat { action } => cmd_chat(action),
+        Command::Avatar { chat_id } => cmd_avatar(chat_id, &avatar_dir).await,
+        Command::Send(args) => cmd_send(args, identity_policy).await,
+        Command::Flush { all } => cmd_flush(identity_policy, all).await,
+        Command::Outbox { action } => cmd_outbox(action),
+        Command::Receive {
+            webhook,
+            webhook_secret,
+            on_message,
+            store_only,
+        } => {
+            // Check the webhook settings before draining the queue
+            let webhook = receive_webhook(webhook.or(config.webhook_url), webhook_secret)?;
+            let hooks = hooks::Hooks::new(on_message, config.on_message);
+            let policies = policy::Policies::load()?;
+            cmd_receive(identity_policy, webhook, hooks, policies, store_only).await
+        }
+        Command::Messages {
+            chat_id,
+            max_results,
+            order_by,
+            verify_ordering,
+            as_of,
+            since,
+            until,
+            before_id,
+            after_id,
+            ascending,
+            unread_only,
+        } => {
+            let page = history::Page {
+                since: parse_millis(since.as_deref())?,
+                until: parse_millis(until.as_deref())?,
+                before_id,
+                after_id,
+                ascending,
+                unread_only,
+            };
+            let as_of = parse_millis(as_of.as_deref())?;
+            let max_results = limit(max_results);
+            cmd_messages(chat_id, max_results, order_by, verify_ordering, as_of, page).await
+        }
+        Command::Export {
+            chat_id,
+            format,
+            output,
+            with_attachments,
+        } => cmd_export(chat_id, format, output, with_attachments).await,
+        Command::Search {
+            query,
+            chat_id,
+            sender,
+            since,
+            until,
+            max_results,
+            reindex,
+        } => {
+            let filters = search::SearchFilters {
+                chat_id,
+                sender,
+                since: parse_millis(since.as_deref())?,
+                until: parse_millis(until.as_deref())?,
+            };
+            cmd_search(query, filters, limit(max_results), reindex).await
+        }
+        Command::Status { deep } => cmd_status(deep).await,
+        Command::SyncContacts { timeout } => cmd_sync_contacts(identity_policy, timeout).await,
+        Command::AwaitReply {
+            chat_id,
+            timeout,
+            matching,
+        } => cmd_await_reply(chat_id, timeout, matching, identity_policy).await,
+        Command::MarkRead {
+            chat_ids,
+            messages,
+            read_receipts,
+        } => {
+            let policy = read_receipts.or(config.read_receipts).unwrap_or_default();
+            cmd_mark_read(chat_ids, messages, policy).await
+        }
+        Command::MarkUnread { chat_id, after } => cmd_mark_unread(chat_id, after).await,
+        Command::Expire { action } => cmd_expire(action).await,
+        Command::Sticker { action } => cmd_sticker(action, identity_policy).await,
+        Command::Usage { since } => cmd_usage(since),
+        Command::Lists { action } => cmd_lists(action).await,
+        Command::Draft { action } => cmd_draft(action),
+        Command::Devices { action } => cmd_devices(action).await,
+        Command::Db { action } => match action {
+            DbCommand::Upgrade => print_json(&db::upgrade().await?),
+        },
+        Command::Doctor { fix } => {
+            if let Some(output) = daemon::proxy(&daemon::Request::Doctor { fix }).await? {
+                return print_json(&output);
+            }
+            let manager = load_registered_manager().await.ok();
+            print_json(&doctor::doctor(manager.as_ref(), fix).await?)
+        }
+        Command::Rekey {
+            new_passphrase_file,
+            generate,
+            store,
+            decrypt: _,
+        } => {
+            let (new_passphrase, store) = if generate {
+                let store = store.unwrap_or(secrets::SecretStore::Keyring);
+                (Some(encryption::generate_passphrase()), Some(store))
+            } else {
+                let new_passphrase = new_passphrase_file
+                    .as_deref()
+                    .map(encryption::read_passphrase_file)
+                    .transpose()?;
+                (new_passphrase, store)
+            };
+            print_json(&encryption::rekey(new_passphrase, store)?)
+        }
+        Command::Prune {
+            retention_days,
+            max_messages_per_chat,
+        } => {
+            let retention_days = retention_days.or(config.retention_days);
+            let max_messages_per_chat = max_messages_per_chat.or(config.max_messages_per_chat);
+            let request = daemon::Request::Prune {
+                retention_days,
+                max_messages_per_chat,
+            };
+            if let Some(output) = daemon::proxy(&request).await? {
+                return print_json(&output);
+            }
+            let manager = load_registered_manager().await?;
+            print_json(&prune::prune(&manager, retention_days, max_messages_per_chat).await?)
+        }
+        Command::Groups { action } => match action {
+            GroupsCommand::Verify => cmd_groups_verify().await,
+        },
+        Command::Identity { action } => cmd_identity(action).await,
+        Command::Profile { action } => cmd_profile(action).await,
+        Command::Events {
+            consumer,
+            max_results,
+            peek,
+        } => cmd_events(consumer, max_results, peek),
+        Command::Replicate { to, interval, once } => cmd_replicate(to, interval, once),
+        Command::Mcp => {
+            mcp::serve(mcp::Options {
+                identity_policy,
+                max_results: limit(None),
+                read_receipts: config.read_receipts.unwrap_or_default(),
+            })
+            .await
+        }
+        Command::Serve {
+            listen,
+            token,
+            receive_interval,
+            webhook,
+            webhook_secret,
+            on_message,
+        } => {
+            let token = match token {
+                Some(token) => token,
+                None => secrets::get(serve::API_TOKEN_SECRET)?
+                    .map(|(token, _)| token)
+                    .with_context(|| {
+                        format!(
+                            "No API token. Pass --token, set JC_SIGNAL_API_TOKEN, or run 'signal-cli secret set {}'",
+                            serve::API_TOKEN_SECRET
+                        )
+                    })?,
+            };
+            if token.is_empty() {
+                anyhow::bail!("API token cannot be empty");
+            }
+            cmd_serve(serve::Options {
+                listen,
+                socket: daemon::socket_path()?,
+                token,
+                identity_policy,
+                max_results: limit(None),
+                receive_interval: std::time::Duration::from_secs(receive_interval.max(1)),
+                webhook: receive_webhook(webhook.or(config.webhook_url), webhook_secret)?,
+                hooks: hooks::Hooks::new(on_message, config.on_message),
+                policies: policy::Policies::load()?,
+            })
+            .await
+        }
+        Command::ApiToken { action } => cmd_api_token(action),
+        Command::Secret { action } => cmd_secret(action),
+        Command::Backup { action } => {
+            let output = match action {
+                BackupCommand::Create { path } => backup::create(&path, &avatar_dir)?,
+                BackupCommand::Restore { path, force } => {
+                    backup::restore(&path, &avatar_dir, force)?
+                }
+            };
+            print_json(&output)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> i64 {
+        std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    #[test]
+    fn parse_since_relative() {
+        let since = parse_since("30m").unwrap();
+        assert!((now() - 30 * 60 - since).abs() <= 1);
+        let since = parse_since("2d").unwrap();
+        assert!((now() - 2 * 24 * 60 * 60 - since).abs() <= 1);
+    }
+
+    #[test]
+    fn parse_since_bad_unit() {
+        assert!(parse_since("5y").is_err());
+        assert!(parse_since("5é").is_err());
+        assert!(parse_since("é").is_err());
+        assert!(parse_since("").is_err());
+    }
+
+    #[test]
+    fn parse_since_out_of_range() {
+        assert!(parse_since("-5d").is_err());
+        assert!(parse_since(&format!("{}w", i64::MAX / 2)).is_err());
+    }
+
+    /// A local database, with an automatic reply sent to chat "a" `ago_ms`
+    /// milliseconds ago. Returns it and the reply's timestamp.
+    fn auto_replied(ago_ms: u64) -> (Connection, u64) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        signal_core::local::migrate(&mut conn).unwrap();
+        let timestamp = now() as u64 * 1000 - ago_ms;
+        usage::record_auto_reply(&conn, "a", timestamp).unwrap();
+        (conn, timestamp)
+    }
+
+    #[test]
+    fn auto_replies_are_held_back_for_a_while() {
+        let message = serde_json::json!({"text": "hi"});
+        let (conn, _) = auto_replied(60 * 1000);
+        assert!(holds_back_reply(Some(&conn), "a", &message));
+        // Per chat
+        assert!(!holds_back_reply(Some(&conn), "b", &message));
+
+        let (conn, _) = auto_replied(AUTO_REPLY_INTERVAL_MS + 1000);
+        assert!(!holds_back_reply(Some(&conn), "a", &message));
+        // Nothing is held back without the database
+        assert!(!holds_back_reply(None, "a", &message));
+    }
+
+    #[test]
+    fn replies_to_auto_replies_are_never_answered() {
+        let (conn, timestamp) = auto_replied(AUTO_REPLY_INTERVAL_MS + 1000);
+        let reply_to = |id: String| serde_json::json!({"text": "hi", "reply_to": id});
+        let sender = "11111111-2222-3333-4444-555555555555";
+        assert!(holds_back_reply(
+            Some(&conn),
+            "a",
+            &reply_to(format!("{}_{}", timestamp, sender))
+        ));
+        assert!(!holds_back_reply(
+            Some(&conn),
+            "a",
+            &reply_to(format!("{}_{}", timestamp + 1, sender))
+        ));
     }
 }
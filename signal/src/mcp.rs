@@ -0,0 +1,332 @@
+//! `mcp`: serve Signal operations as Model Context Protocol tools over stdio.
+//!
+//! Requests and responses are newline-delimited JSON-RPC 2.0 on stdin and
+//! stdout; anything else (logs, warnings) goes to stderr. While a `serve`
+//! daemon runs, tool calls go through it (see [`crate::daemon`]). Otherwise
+//! the manager is loaded on the first tool call and reused, so one server
+//! keeps a single connection to the store for its whole life.
+
+use std::io::{BufRead, Write};
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use signal_core::identity::IdentityPolicy;
+use signal_core::model::{MessageOrder, ReceivedItem};
+use signal_core::read_sync::ReadReceiptPolicy;
+use signal_core::{chats, history, load_registered_manager, receive, SignalManager};
+
+use crate::{daemon, mark_read, send_message, SendMessage};
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Deserialize)]
+struct Request {
+    /// Absent for notifications, which get no response
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct ToolCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Deserialize)]
+struct ListChatsArgs {
+    max_results: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct GetMessagesArgs {
+    chat_id: String,
+    max_results: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct SendMessageArgs {
+    recipient: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct MarkReadArgs {
+    chat_ids: Vec<String>,
+}
+
+/// Settings fixed for the life of the server, from global flags and config
+pub struct Options {
+    pub identity_policy: IdentityPolicy,
+    pub max_results: usize,
+    pub read_receipts: ReadReceiptPolicy,
+}
+
+struct Server {
+    options: Options,
+    manager: Option<SignalManager>,
+}
+
+fn tools() -> Value {
+    json!([
+        {
+            "name": "list_chats",
+            "description": "List Signal chats (contacts, then groups). Chat IDs are UUIDs for contacts and hex master keys for groups.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "max_results": {"type": "integer", "description": "Maximum number of chats to return"}
+                }
+            }
+        },
+        {
+            "name": "receive_messages",
+            "description": "Fetch pending messages from the Signal server and return them. Call this before get_messages to see new messages.",
+            "inputSchema": {"type": "object", "properties": {}}
+        },
+        {
+            "name": "get_messages",
+            "description": "Read stored messages from a chat, newest first.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "chat_id": {"type": "string", "description": "Chat ID from list_chats"},
+                    "max_results": {"type": "integer", "description": "Maximum number of messages to return"}
+                },
+                "required": ["chat_id"]
+            }
+        },
+        {
+            "name": "send_message",
+            "description": "Send a text message to a contact.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "recipient": {"type": "string", "description": "Contact UUID or name"},
+                    "message": {"type": "string", "description": "Message text"}
+                },
+                "required": ["recipient", "message"]
+            }
+        },
+        {
+            "name": "mark_read",
+            "description": "Mark every message in the given chats as read.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "chat_ids": {"type": "array", "items": {"type": "string"}}
+                },
+                "required": ["chat_ids"]
+            }
+        }
+    ])
+}
+
+fn parse_args<T: DeserializeOwned>(args: Value) -> Result<T, (i64, String)> {
+    // Tools without parameters may be called with no arguments at all
+    let args = if args.is_null() { json!({}) } else { args };
+    serde_json::from_value(args).map_err(|e| (INVALID_PARAMS, format!("Invalid arguments: {}", e)))
+}
+
+impl Server {
+    async fn manager(&mut self) -> Result<&mut SignalManager> {
+        if self.manager.is_none() {
+            self.manager = Some(load_registered_manager().await?);
+        }
+        Ok(self.manager.as_mut().expect("manager was just loaded"))
+    }
+
+    /// Run a tool. Bad arguments are protocol errors; failures while running
+    /// the tool are reported in the result, so the model can see them.
+    async fn call_tool(&mut self, call: ToolCall) -> Result<Value, (i64, String)> {
+        let output = match call.name.as_str() {
+            "list_chats" => {
+                let args: ListChatsArgs = parse_args(call.arguments)?;
+                self.list_chats(args).await
+            }
+            "receive_messages" => self.receive_messages().await,
+            "get_messages" => {
+                let args: GetMessagesArgs = parse_args(call.arguments)?;
+                self.get_messages(args).await
+            }
+            "send_message" => {
+                let args: SendMessageArgs = parse_args(call.arguments)?;
+                self.send_message(args).await
+            }
+            "mark_read" => {
+                let args: MarkReadArgs = parse_args(call.arguments)?;
+                self.mark_read(args).await
+            }
+            name => return Err((INVALID_PARAMS, format!("Unknown tool: {}", name))),
+        };
+
+        Ok(match output {
+            Ok(value) => json!({
+                "content": [{"type": "text", "text": value.to_string()}]
+            }),
+            Err(e) => json!({
+                "content": [{"type": "text", "text": format!("{:#}", e)}],
+                "isError": true
+            }),
+        })
+    }
+
+    async fn list_chats(&mut self, args: ListChatsArgs) -> Result<Value> {
+        let max_results = args.max_results.unwrap_or(self.options.max_results);
+        let request = daemon::Request::Chats {
+            max_results,
+            unread_counts: false,
+            avatar_dir: None,
+        };
+        if let Some(output) = daemon::proxy(&request).await? {
+            return Ok(output);
+        }
+        let manager = self.manager().await?;
+        let chats = chats::list_chats(manager, max_results, None, false).await?;
+        Ok(serde_json::to_value(chats)?)
+    }
+
+    async fn receive_messages(&mut self) -> Result<Value> {
+        if let Some(output) = daemon::proxy(&daemon::Request::Receive).await? {
+            return Ok(output);
+        }
+        let policy = self.options.identity_policy;
+        let manager = self.manager().await?;
+        let result = receive::receive(manager, policy).await?;
+        Ok(serde_json::to_value(result.items)?)
+    }
+
+    async fn get_messages(&mut self, args: GetMessagesArgs) -> Result<Value> {
+        let max_results = args.max_results.unwrap_or(self.options.max_results);
+        let request = daemon::Request::Messages {
+            chat_id: args.chat_id.clone(),
+            max_results,
+            order_by: MessageOrder::Sent,
+            verify_ordering: false,
+            as_of: None,
+            page: history::Page::default(),
+        };
+        if let Some(output) = daemon::proxy(&request).await? {
+            return Ok(output);
+        }
+        let manager = self.manager().await?;
+        let messages: Vec<ReceivedItem> = history::thread_messages(
+            manager,
+            &args.chat_id,
+            max_results,
+            MessageOrder::Sent,
+            false,
+            None,
+            &history::Page::default(),
+        )
+        .await?;
+        Ok(serde_json::to_value(messages)?)
+    }
+
+    async fn send_message(&mut self, args: SendMessageArgs) -> Result<Value> {
+        let text = args.message.trim().to_string();
+        if text.is_empty() {
+            anyhow::bail!("Message cannot be empty");
+        }
+        let message = SendMessage {
+            recipients: vec![args.recipient],
+            text: Some(text),
+            ..Default::default()
+        };
+        if let Some(output) = daemon::proxy(&daemon::Request::Send(message.clone())).await? {
+            return Ok(output);
+        }
+        let policy = self.options.identity_policy;
+        let manager = self.manager().await?;
+        Ok(serde_json::to_value(
+            send_message(manager, message, policy).await?,
+        )?)
+    }
+
+    async fn mark_read(&mut self, args: MarkReadArgs) -> Result<Value> {
+        let receipts = self.options.read_receipts;
+        let request = daemon::Request::MarkRead {
+            chat_ids: args.chat_ids.clone(),
+            messages: Vec::new(),
+            read_receipts: receipts,
+        };
+        if let Some(output) = daemon::proxy(&request).await? {
+            return Ok(output);
+        }
+        let manager = self.manager().await?;
+        let output = mark_read(manager, &args.chat_ids, &[], receipts).await?;
+        Ok(serde_json::to_value(output)?)
+    }
+
+    async fn handle(&mut self, request: Request) -> Option<Value> {
+        let result = match request.method.as_str() {
+            "initialize" => Ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": {"tools": {}},
+                "serverInfo": {"name": "signal-cli", "version": env!("CARGO_PKG_VERSION")}
+            })),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tools() })),
+            "tools/call" => match serde_json::from_value::<ToolCall>(request.params) {
+                Ok(call) => self.call_tool(call).await,
+                Err(e) => Err((INVALID_PARAMS, format!("Invalid tool call: {}", e))),
+            },
+            method => Err((METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+        };
+
+        let id = request.id?;
+        Some(match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => error_response(id, code, &message),
+        })
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": code, "message": message}
+    })
+}
+
+/// Serve requests from stdin until it closes.
+pub async fn serve(options: Options) -> Result<()> {
+    let mut server = Server {
+        options,
+        manager: None,
+    };
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => server.handle(request).await,
+            Err(e) => Some(error_response(Value::Null, PARSE_ERROR, &e.to_string())),
+        };
+        if let Some(response) = response {
+            writeln!(stdout, "{}", response)?;
+            stdout.flush()?;
+        }
+    }
+
+    Ok(())
+}
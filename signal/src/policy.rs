@@ -0,0 +1,211 @@
+//! Per-chat response policy: what `receive` does about an incoming message,
+//! decided before any `--on-message` hook runs.
+//!
+//! Rules live in `policy.toml` in the data dir, one per chat plus a default
+//! for every other chat. The file is re-read whenever it changes, so an edit
+//! applies to the next message without restarting anything. With no file,
+//! every chat is `allow`.
+//!
+//! ```toml
+//! [default]
+//! action = "allow"
+//!
+//! [chats."abc123-def456-..."]
+//! action = "require-approval"
+//!
+//! [chats."0123abcd..."]
+//! action = "auto-reply"
+//! template = "Away until Monday; I'll read this then."
+//!
+//! [chats."fedc9876-..."]
+//! action = "escalate"
+//! command = "notify-send 'Signal' \"$(jq -r .text)\""
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::get_data_dir;
+
+/// What to do with a chat's incoming messages.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Rule {
+    /// Run the chat's hook, if it has one, and send its reply
+    #[default]
+    Allow,
+    /// Don't run a hook or reply
+    Deny,
+    /// Run the hook, but save its reply as the chat's draft for a person to
+    /// send instead of sending it
+    RequireApproval,
+    /// Send `template` instead of running a hook. `{chat_id}`, `{sender}` and
+    /// `{text}` are replaced with the incoming message's.
+    AutoReply { template: String },
+    /// Run `command` (with the message on stdin, like a hook) to alert a
+    /// person, and don't reply
+    Escalate { command: String },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    /// For chats without their own rule
+    #[serde(default)]
+    default: Rule,
+    /// By chat ID
+    #[serde(default)]
+    chats: BTreeMap<String, Rule>,
+}
+
+/// The rules from `policy.toml`, as of the last time it changed.
+pub struct Policies {
+    path: PathBuf,
+    /// When the file was last modified as of reading it; `None` if missing
+    modified: Option<SystemTime>,
+    rules: PolicyFile,
+}
+
+/// Modification time, or `None` if the file doesn't exist
+fn modified(path: &Path) -> Result<Option<SystemTime>> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.modified()?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+fn read(path: &Path) -> Result<PolicyFile> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(PolicyFile::default()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    toml::from_str(&text).with_context(|| format!("Invalid policy file {}", path.display()))
+}
+
+impl Policies {
+    pub fn load() -> Result<Self> {
+        let path = get_data_dir()?.join("policy.toml");
+        let modified = modified(&path)?;
+        let rules = read(&path)?;
+        Ok(Policies {
+            path,
+            modified,
+            rules,
+        })
+    }
+
+    /// Re-read the file if it changed since it was last read. If the new
+    /// version is invalid, the previous rules stay in force and the error is
+    /// returned.
+    pub fn reload(&mut self) -> Result<()> {
+        let modified = modified(&self.path)?;
+        if modified == self.modified {
+            return Ok(());
+        }
+        // Don't retry a broken file until it changes again
+        self.modified = modified;
+        self.rules = read(&self.path)?;
+        Ok(())
+    }
+
+    pub fn rule_for(&self, chat_id: &str) -> &Rule {
+        self.rules.chats.get(chat_id).unwrap_or(&self.rules.default)
+    }
+}
+
+/// Fill in an auto-reply template from a `receive` message.
+pub fn render(template: &str, message: &Value) -> String {
+    let field = |name: &str| {
+        message
+            .get(name)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+    };
+    template
+        .replace("{chat_id}", field("chat_id"))
+        .replace("{sender}", field("sender"))
+        .replace("{text}", field("text"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn policies(default: Rule, chats: &[(&str, Rule)]) -> Policies {
+        Policies {
+            path: PathBuf::new(),
+            modified: None,
+            rules: PolicyFile {
+                default,
+                chats: chats
+                    .iter()
+                    .map(|(chat_id, rule)| (chat_id.to_string(), rule.clone()))
+                    .collect(),
+            },
+        }
+    }
+
+    #[test]
+    fn chats_without_a_rule_get_the_default() {
+        let away = Rule::AutoReply {
+            template: "Away".to_string(),
+        };
+        let policies = policies(
+            Rule::Deny,
+            &[("allowed", Rule::Allow), ("away", away.clone())],
+        );
+        assert_eq!(policies.rule_for("allowed"), &Rule::Allow);
+        assert_eq!(policies.rule_for("away"), &away);
+        assert_eq!(policies.rule_for("other"), &Rule::Deny);
+        assert_eq!(PolicyFile::default().default, Rule::Allow);
+    }
+
+    #[test]
+    fn rules_are_tagged_by_action() {
+        let rule = |value| serde_json::from_value::<Rule>(value);
+        assert_eq!(rule(json!({"action": "deny"})).unwrap(), Rule::Deny);
+        assert_eq!(
+            rule(json!({"action": "require-approval"})).unwrap(),
+            Rule::RequireApproval
+        );
+        assert_eq!(
+            rule(json!({"action": "auto-reply", "template": "Away"})).unwrap(),
+            Rule::AutoReply {
+                template: "Away".to_string()
+            }
+        );
+        for invalid in [
+            json!({"action": "ignore"}),
+            json!({"action": "auto-reply"}),
+            json!({"action": "auto-reply", "template": "Away", "delay": 5}),
+        ] {
+            assert!(rule(invalid.clone()).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn render_fills_in_the_message() {
+        let message = json!({
+            "chat_id": "abc",
+            "sender": "Alice",
+            "text": "Are you there?",
+        });
+        assert_eq!(
+            render(
+                "{sender} asked \"{text}\" in {chat_id}; {missing}",
+                &message
+            ),
+            "Alice asked \"Are you there?\" in abc; {missing}"
+        );
+        assert_eq!(render("Hi {sender}", &json!({})), "Hi ");
+    }
+}
@@ -0,0 +1,88 @@
+//! `prune`: delete what the local databases no longer need to keep.
+//!
+//! Messages older than the retention period or beyond a chat's cap go (see
+//! [`retention`]), as do disappearing messages whose time is up, individual
+//! reads of messages that were pruned or never arrived, and view-once media
+//! with no record of its message. The event log loses what
+//! [`events::trim`] drops, and with a retention period everything logged
+//! before it too, since it holds its own copy of each message. Nothing is
+//! pruned by age or count unless asked, by flag or in `config.toml`.
+
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use signal_core::retention::{self, Retention};
+use signal_core::{events, expiry, lock, read_sync, receive, view_once, SignalManager};
+
+#[derive(Serialize)]
+pub struct PruneOutput {
+    /// Messages deleted for their age or their chat's cap
+    pub messages: usize,
+    /// Disappearing messages deleted because their time was up
+    pub expired: usize,
+    /// Individual reads forgotten
+    pub reads: usize,
+    /// View-once media files removed
+    pub attachments: usize,
+    /// Event log entries deleted
+    pub events: usize,
+}
+
+pub async fn prune(
+    manager: &SignalManager,
+    retention_days: Option<u32>,
+    max_messages_per_chat: Option<usize>,
+) -> Result<PruneOutput> {
+    // Deleting from the store races a running receive or `serve` otherwise
+    let _lock = lock::lock_store()?;
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let before = retention_days.map(|days| now.saturating_sub(days as u64 * 24 * 60 * 60 * 1000));
+
+    let messages = retention::prune(
+        manager,
+        Retention {
+            before,
+            max_messages_per_chat,
+        },
+    )
+    .await?;
+    let expired = expiry::prune(manager).await?;
+
+    let mut conn = read_sync::open_read_sync_db()?;
+    let mut reads = match before {
+        Some(before) => read_sync::forget_reads_before(&conn, before)?,
+        None => 0,
+    };
+    let orphaned_before = (now / 1000) as i64 - read_sync::ORPHANED_READ_DAYS * 24 * 60 * 60;
+    let orphaned = read_sync::orphaned_reads(manager, &conn, orphaned_before).await?;
+    read_sync::forget_reads(&mut conn, &orphaned)?;
+    reads += orphaned.len();
+
+    let conn = view_once::open_view_once_db()?;
+    let media = view_once::unreferenced_media(&conn)?;
+    for path in &media {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+
+    // Entries a checkpoint still points at are the next receive's to return
+    let conn = events::open_events_db()?;
+    let events = events::trim(
+        &conn,
+        before.map(|before| (before / 1000) as i64),
+        receive::checkpoint()?,
+    )?;
+
+    Ok(PruneOutput {
+        messages,
+        expired,
+        reads,
+        attachments: media.len(),
+        events,
+    })
+}
@@ -0,0 +1,378 @@
+//! Registering as a primary device in two steps: `register` asks Signal for
+//! a verification code, and `verify-code` finishes with the code it sent.
+//!
+//! presage keeps the registration session (Signal's session ID and the
+//! password made for the new account) inside the `Manager` that requested
+//! the code, with no way to save it or rebuild one from saved parts, so the
+//! session can't outlive that process. `register` therefore starts a copy of
+//! itself that requests the code and holds the session, and returns once the
+//! code is on its way. The holder records the pending registration in
+//! `registration.json` in the data dir and waits for the code on
+//! `run/registration.sock`; `verify-code` reads the one and writes to the
+//! other. The holder gives up after [`SESSION_TIMEOUT`], and presage spends
+//! the session on the first code it's given, right or wrong, so after either
+//! `register` has to be run again. Running it again also ends a session
+//! still waiting.
+
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use presage::libsignal_service::prelude::phonenumber::PhoneNumber;
+use presage::manager::{Confirmation, RegistrationOptions};
+use presage::Manager;
+use presage_store_sqlite::SqliteStore;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use signal_core::timestamp::Timestamp;
+use signal_core::{get_data_dir, open_store};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::config::Server;
+use crate::daemon::{self, Response};
+use crate::errors::{ErrorBody, ErrorCode};
+
+const CAPTCHA_URL: &str = "https://signalcaptchas.org/registration/generate.html";
+
+/// How long the holder waits for the code, about as long as Signal keeps a
+/// verification session open
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// What `register` asked for
+pub struct Request {
+    pub phone_number: String,
+    pub voice: bool,
+    pub captcha: Option<String>,
+    pub force: bool,
+    pub server: Server,
+}
+
+/// A registration waiting for its code, as kept in `registration.json`
+#[derive(Serialize, Deserialize)]
+struct Pending {
+    phone: String,
+    /// Unix seconds
+    expires_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct CodeSentOutput {
+    pub success: bool,
+    pub phone: String,
+    /// "sms" or "voice"
+    pub method: &'static str,
+    /// When the session stops accepting `verify-code`
+    pub expires_at: Timestamp,
+}
+
+#[derive(Serialize)]
+pub struct RegisterOutput {
+    pub success: bool,
+    pub uuid: String,
+    pub phone: String,
+}
+
+/// A message to the holder, one line on its socket
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "kebab-case")]
+enum SessionRequest {
+    Code {
+        code: String,
+    },
+    /// A newer `register` is taking over
+    Cancel,
+}
+
+fn state_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("registration.json"))
+}
+
+fn socket_path() -> Result<PathBuf> {
+    Ok(daemon::socket_dir()?.join("registration.sock"))
+}
+
+/// Forget the pending registration.
+fn clear() -> Result<()> {
+    for path in [state_path()?, socket_path()?] {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Send one request to the holder and read its answer. Returns `None` when
+/// no holder is listening.
+async fn ask(request: &SessionRequest) -> Result<Option<Response>> {
+    let stream = match UnixStream::connect(socket_path()?).await {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e).context("Failed to reach the registration session"),
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    let mut response = String::new();
+    BufReader::new(reader).read_line(&mut response).await?;
+    if response.is_empty() {
+        anyhow::bail!("The registration session closed without responding");
+    }
+    Ok(Some(serde_json::from_str(&response).context(
+        "Invalid response from the registration session",
+    )?))
+}
+
+/// `register`: start the holder and wait until it has requested the code.
+/// Returns its [`CodeSentOutput`], or fails with its error.
+pub async fn start() -> Result<Value> {
+    if daemon::is_running()? {
+        anyhow::bail!("A `serve` daemon is using the message store; stop it before registering");
+    }
+    let mut holder = tokio::process::Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .arg("--hold-session")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        // Its own process group, so closing the terminal doesn't end it
+        .process_group(0)
+        .spawn()
+        .context("Failed to start the registration session")?;
+    let stdout = holder
+        .stdout
+        .take()
+        .context("Failed to read from the registration session")?;
+
+    let mut line = String::new();
+    BufReader::new(stdout).read_line(&mut line).await?;
+    if line.is_empty() {
+        anyhow::bail!("The registration session exited before requesting a code");
+    }
+    match serde_json::from_str(&line).context("Invalid response from the registration session")? {
+        Response::Output(value) => Ok(value),
+        Response::Error(error) => Err(error.into()),
+    }
+}
+
+/// Request the code from Signal.
+async fn request_code(request: &Request) -> Result<Manager<SqliteStore, Confirmation>> {
+    let phone: PhoneNumber = request.phone_number.parse().with_context(|| {
+        format!(
+            "Invalid phone number '{}': use E.164, e.g. +15551234567",
+            request.phone_number
+        )
+    })?;
+
+    let store = open_store().await?;
+    if !request.force && Manager::load_registered(store.clone()).await.is_ok() {
+        anyhow::bail!("Already registered or linked. Use --force to replace this registration.");
+    }
+
+    // The captcha page hands back a signalcaptcha:// link; presage wants the token
+    let captcha = request
+        .captcha
+        .as_deref()
+        .map(|c| c.trim().trim_start_matches("signalcaptcha://"));
+
+    Manager::register(
+        store,
+        RegistrationOptions {
+            signal_servers: request.server.into(),
+            phone_number: phone,
+            use_voice_call: request.voice,
+            captcha,
+            force: request.force,
+        },
+    )
+    .await
+    .map_err(|e| match e {
+        presage::Error::CaptchaRequired => anyhow::anyhow!(
+            "Signal requires a captcha. Solve one at {} and pass the resulting link with --captcha",
+            CAPTCHA_URL
+        ),
+        e => anyhow::Error::from(e).context("Failed to request verification code"),
+    })
+}
+
+/// Request the code, then record the pending registration and listen for
+/// the code.
+async fn begin(
+    request: &Request,
+) -> Result<(
+    Manager<SqliteStore, Confirmation>,
+    UnixListener,
+    CodeSentOutput,
+)> {
+    // A session already waiting removes its files and exits; one that
+    // doesn't answer is replaced all the same
+    let _ = ask(&SessionRequest::Cancel).await;
+    let manager = request_code(request).await?;
+
+    // Left by a holder that was killed
+    clear()?;
+    let path = socket_path()?;
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    let pending = Pending {
+        phone: request.phone_number.clone(),
+        expires_at: now() + SESSION_TIMEOUT.as_secs() as i64,
+    };
+    std::fs::write(state_path()?, serde_json::to_vec(&pending)?)?;
+
+    let output = CodeSentOutput {
+        success: true,
+        phone: pending.phone,
+        method: if request.voice { "voice" } else { "sms" },
+        expires_at: Timestamp::from_millis(pending.expires_at as u64 * 1000),
+    };
+    Ok((manager, listener, output))
+}
+
+/// Spend the session on a code.
+async fn confirm(
+    manager: Manager<SqliteStore, Confirmation>,
+    code: &str,
+) -> Result<RegisterOutput> {
+    let manager = manager
+        .confirm_verification_code(code)
+        .await
+        .context("Failed to verify code")?;
+    let whoami = manager.whoami().await?;
+    Ok(RegisterOutput {
+        success: true,
+        uuid: whoami.aci.to_string(),
+        phone: whoami.number.to_string(),
+    })
+}
+
+/// Answer a client with one line. It may have given up waiting, which
+/// changes nothing here.
+async fn reply(mut writer: OwnedWriteHalf, response: Response) -> Result<()> {
+    let mut line = serde_json::to_string(&response)?;
+    line.push('\n');
+    let _ = writer.write_all(line.as_bytes()).await;
+    Ok(())
+}
+
+/// Answer connections until one brings the code, or a newer `register`
+/// takes over.
+async fn await_code(
+    listener: &UnixListener,
+    manager: Manager<SqliteStore, Confirmation>,
+) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (reader, writer) = stream.into_split();
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await?;
+        match serde_json::from_str(&line) {
+            Ok(SessionRequest::Code { code }) => {
+                let result = confirm(manager, &code).await;
+                clear()?;
+                let response = match result {
+                    Ok(output) => Response::Output(serde_json::to_value(output)?),
+                    Err(e) => Response::Error(ErrorBody::new(&e)),
+                };
+                return reply(writer, response).await;
+            }
+            Ok(SessionRequest::Cancel) => {
+                clear()?;
+                return reply(writer, Response::Output(Value::Null)).await;
+            }
+            Err(e) => {
+                let error = ErrorBody {
+                    code: ErrorCode::InvalidInput,
+                    message: format!("Invalid request: {}", e),
+                };
+                reply(writer, Response::Error(error)).await?;
+            }
+        }
+    }
+}
+
+/// `register --hold-session`, run by [`start`]: request the code, tell
+/// `register` how that went with one line on stdout, then hold the session
+/// until the code arrives or it times out.
+pub async fn hold(request: Request) -> Result<()> {
+    let (manager, listener, output) = match begin(&request).await {
+        Ok(started) => started,
+        Err(e) => {
+            println!(
+                "{}",
+                serde_json::to_string(&Response::Error(ErrorBody::new(&e)))?
+            );
+            return Ok(());
+        }
+    };
+    println!(
+        "{}",
+        serde_json::to_string(&Response::Output(serde_json::to_value(output)?))?
+    );
+
+    let result = tokio::time::timeout(SESSION_TIMEOUT, await_code(&listener, manager)).await;
+    if !matches!(result, Ok(Ok(()))) {
+        clear()?;
+    }
+    Ok(())
+}
+
+/// `verify-code`: hand the code to the waiting session.
+pub async fn verify(code: &str) -> Result<Value> {
+    if daemon::is_running()? {
+        anyhow::bail!("A `serve` daemon is using the message store; stop it before registering");
+    }
+    // Codes are shown as "123-456"
+    let code = code.trim().replace('-', "");
+    if code.is_empty() {
+        anyhow::bail!("Verification code cannot be empty");
+    }
+
+    let pending: Pending = match std::fs::read(state_path()?) {
+        Ok(data) => serde_json::from_slice(&data).context("Invalid registration.json")?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            anyhow::bail!("No registration is waiting for a code; run `register` first")
+        }
+        Err(e) => return Err(e).context("Failed to read registration.json"),
+    };
+    if pending.expires_at < now() {
+        clear()?;
+        anyhow::bail!(
+            "The registration of {} expired; run `register` again",
+            pending.phone
+        );
+    }
+
+    match ask(&SessionRequest::Code { code }).await? {
+        Some(Response::Output(value)) => Ok(value),
+        Some(Response::Error(error)) => Err(error.into()),
+        None => {
+            clear()?;
+            anyhow::bail!(
+                "The registration session for {} has ended; run `register` again",
+                pending.phone
+            )
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! `replicate`: keep a warm standby copy of the data dir.
+//!
+//! Each pass snapshots every database that changed since the last pass (the
+//! message store, which also holds the account keys, and the local databases)
+//! with `VACUUM INTO`, which gives a consistent copy while other processes
+//! keep writing, and copies the config, policy and encrypted secrets files.
+//! Copies are written under a temporary name and renamed into place, so the
+//! standby always has a complete version of every file. A `host:path` target
+//! is reached by rsync over SSH from a local staging dir.
+//!
+//! To fail over, copy the standby's files into the new host's data dir: the
+//! device stays linked and no history is lost.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OpenFlags};
+
+use signal_core::unlock;
+
+use crate::get_data_dir;
+
+/// Files copied as they are, besides the databases
+pub const PLAIN_FILES: &[&str] = &["config.toml", "policy.toml", "secrets.enc"];
+
+/// How long a snapshot waits for a writer to finish
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub enum Target {
+    Dir(PathBuf),
+    /// `host:path`, for rsync
+    Remote(String),
+}
+
+impl Target {
+    /// `host:path` (no `/` before the colon) is remote; anything else is a
+    /// local directory.
+    pub fn parse(value: &str) -> Self {
+        match value.split_once(':') {
+            Some((host, _)) if !host.is_empty() && !host.contains('/') => {
+                Target::Remote(value.to_string())
+            }
+            _ => Target::Dir(PathBuf::from(value)),
+        }
+    }
+}
+
+/// Size and modification time of a file and its WAL, to tell whether it
+/// changed
+type Stamp = Vec<Option<(u64, SystemTime)>>;
+
+fn stamp(paths: &[PathBuf]) -> Stamp {
+    paths
+        .iter()
+        .map(|path| {
+            let metadata = std::fs::metadata(path).ok()?;
+            Some((metadata.len(), metadata.modified().ok()?))
+        })
+        .collect()
+}
+
+/// A consistent copy of a database at `dest`.
+pub fn snapshot(source: &Path, dest: &Path) -> Result<()> {
+    let conn = Connection::open_with_flags(source, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open {}", source.display()))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // An encrypted database's copy is encrypted with the same key
+    unlock(&conn)?;
+    conn.execute("VACUUM INTO ?1", [dest.to_string_lossy()])
+        .with_context(|| format!("Failed to snapshot {}", source.display()))?;
+    Ok(())
+}
+
+pub struct Replicator {
+    data_dir: PathBuf,
+    target: Target,
+    /// Where copies are written: the target itself, or the dir rsync sends
+    staging: PathBuf,
+    /// Each file's stamp when it was last copied
+    copied: HashMap<String, Stamp>,
+    /// Whether the staging dir has changes rsync hasn't sent yet
+    unsent: bool,
+}
+
+impl Replicator {
+    pub fn new(target: Target) -> Result<Self> {
+        let data_dir = get_data_dir()?;
+        let staging = match &target {
+            Target::Dir(dir) => dir.clone(),
+            Target::Remote(_) => data_dir.join("replica"),
+        };
+        if staging == data_dir {
+            anyhow::bail!("Can't replicate the data dir into itself");
+        }
+        std::fs::create_dir_all(&staging)
+            .with_context(|| format!("Failed to create {}", staging.display()))?;
+        Ok(Replicator {
+            data_dir,
+            target,
+            staging,
+            copied: HashMap::new(),
+            unsent: false,
+        })
+    }
+
+    /// Copy every file that changed since the last pass. Returns their names.
+    pub fn pass(&mut self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = std::fs::read_dir(&self.data_dir)?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.ends_with(".db") || PLAIN_FILES.contains(&name.as_str()))
+            .collect();
+        names.sort();
+
+        let mut changed = Vec::new();
+        for name in names {
+            let path = self.data_dir.join(&name);
+            let is_db = name.ends_with(".db");
+            let mut files = vec![path.clone()];
+            if is_db {
+                files.push(self.data_dir.join(format!("{}-wal", name)));
+            }
+            let stamp = stamp(&files);
+            if self.copied.get(&name) == Some(&stamp) {
+                continue;
+            }
+
+            let dest = self.staging.join(&name);
+            let partial = self.staging.join(format!("{}.partial", name));
+            // VACUUM INTO refuses to overwrite
+            let _ = std::fs::remove_file(&partial);
+            if is_db {
+                snapshot(&path, &partial)?;
+            } else {
+                std::fs::copy(&path, &partial)
+                    .with_context(|| format!("Failed to copy {}", path.display()))?;
+            }
+            // These hold the account's keys
+            std::fs::set_permissions(
+                &partial,
+                std::os::unix::fs::PermissionsExt::from_mode(0o600),
+            )?;
+            std::fs::rename(&partial, &dest)
+                .with_context(|| format!("Failed to replace {}", dest.display()))?;
+
+            self.copied.insert(name.clone(), stamp);
+            changed.push(name);
+        }
+
+        if let Target::Remote(dest) = &self.target {
+            self.unsent |= !changed.is_empty();
+            if self.unsent {
+                rsync(&self.staging, dest)?;
+                self.unsent = false;
+            }
+        }
+
+        Ok(changed)
+    }
+}
+
+fn rsync(staging: &Path, dest: &str) -> Result<()> {
+    // The trailing slash sends the dir's contents rather than the dir
+    let source = format!("{}/", staging.display());
+    let status = Command::new("rsync")
+        .args(["-a", "--exclude=*.partial", &source, dest])
+        .status()
+        .context("Failed to run rsync")?;
+    if !status.success() {
+        anyhow::bail!("rsync to {} exited with {}", dest, status);
+    }
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! Named secrets kept out of plaintext files.
+//!
+//! Secrets (webhook tokens, API keys, passwords) live either in the OS keyring
+//! or in an encrypted file in the data dir, managed with `secret set|get|delete`.
+//!
+//! The file store is a JSON map encrypted with AES-256-GCM, keyed by Argon2id
+//! over the passphrase in `SIGNAL_CLI_SECRETS_PASSPHRASE`. Layout on disk:
+//! `salt (16) || nonce (12) || ciphertext`.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::get_data_dir;
+
+/// Keyring service name; secret names are stored as the keyring "user".
+const KEYRING_SERVICE: &str = "jean-claude-signal";
+
+/// Environment variable holding the passphrase for the encrypted file store.
+pub const PASSPHRASE_ENV: &str = "SIGNAL_CLI_SECRETS_PASSPHRASE";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretStore {
+    /// macOS Keychain / Secret Service / Windows Credential Manager
+    Keyring,
+    /// Encrypted file in the data dir (passphrase from SIGNAL_CLI_SECRETS_PASSPHRASE)
+    File,
+}
+
+fn get_secrets_file_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("secrets.enc"))
+}
+
+fn keyring_entry(name: &str) -> Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, name).context("Failed to access OS keyring")
+}
+
+fn file_passphrase() -> Result<String> {
+    std::env::var(PASSPHRASE_ENV)
+        .with_context(|| format!("{} must be set to use the encrypted secrets file", PASSPHRASE_ENV))
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key<Aes256Gcm>> {
+    let mut key = Key::<Aes256Gcm>::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive key: {}", e))?;
+    Ok(key)
+}
+
+/// Encrypt with a key derived from `passphrase`, as
+/// `salt (16) || nonce (12) || ciphertext`.
+pub fn encrypt(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let cipher = Aes256Gcm::new(&derive_key(passphrase, &salt)?);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+
+    let mut data = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+/// Reverse [`encrypt`].
+pub fn decrypt(passphrase: &str, data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < SALT_LEN + NONCE_LEN {
+        anyhow::bail!("Encrypted data is truncated");
+    }
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let cipher = Aes256Gcm::new(&derive_key(passphrase, salt)?);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt: wrong passphrase?"))
+}
+
+fn read_file_store() -> Result<BTreeMap<String, String>> {
+    let path = get_secrets_file_path()?;
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let data = std::fs::read(&path)?;
+    let plaintext = decrypt(&file_passphrase()?, &data)
+        .with_context(|| format!("Failed to read secrets file {}", path.display()))?;
+
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+fn write_file_store(secrets: &BTreeMap<String, String>) -> Result<()> {
+    let data = encrypt(&file_passphrase()?, &serde_json::to_vec(secrets)?)?;
+
+    // Write-then-rename so a crash never leaves a half-written file, and
+    // created owner-only so it's never readable by others; a leftover would
+    // keep its own mode, so it goes first
+    let path = get_secrets_file_path()?;
+    let tmp = path.with_extension("enc.tmp");
+    let _ = std::fs::remove_file(&tmp);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(&data))
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+pub fn set(store: SecretStore, name: &str, value: &str) -> Result<()> {
+    match store {
+        SecretStore::Keyring => keyring_entry(name)?
+            .set_password(value)
+            .context("Failed to save secret to OS keyring"),
+        SecretStore::File => {
+            let mut secrets = read_file_store()?;
+            secrets.insert(name.to_string(), value.to_string());
+            write_file_store(&secrets)
+        }
+    }
+}
+
+/// Look up a secret, checking the keyring first and then the encrypted file.
+/// Returns the value and the store it came from.
+pub fn get(name: &str) -> Result<Option<(String, SecretStore)>> {
+    match keyring_entry(name)?.get_password() {
+        Ok(value) => return Ok(Some((value, SecretStore::Keyring))),
+        Err(keyring::Error::NoEntry) => {}
+        Err(e) => tracing::debug!("Keyring lookup for '{}' failed: {}", name, e),
+    }
+
+    if get_secrets_file_path()?.exists() {
+        if let Some(value) = read_file_store()?.remove(name) {
+            return Ok(Some((value, SecretStore::File)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Remove a secret from one store. Returns whether it was there.
+pub fn remove(store: SecretStore, name: &str) -> Result<bool> {
+    match store {
+        SecretStore::Keyring => match keyring_entry(name)?.delete_credential() {
+            Ok(()) => Ok(true),
+            Err(keyring::Error::NoEntry) => Ok(false),
+            Err(e) => Err(e).context("Failed to delete secret from OS keyring"),
+        },
+        SecretStore::File => {
+            if !get_secrets_file_path()?.exists() {
+                return Ok(false);
+            }
+            let mut secrets = read_file_store()?;
+            if secrets.remove(name).is_none() {
+                return Ok(false);
+            }
+            write_file_store(&secrets)?;
+            Ok(true)
+        }
+    }
+}
+
+/// Remove a secret from every store. Returns whether anything was removed.
+pub fn delete(name: &str) -> Result<bool> {
+    let from_keyring = remove(SecretStore::Keyring, name)?;
+    let from_file = remove(SecretStore::File, name)?;
+    Ok(from_keyring || from_file)
+}
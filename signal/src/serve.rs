@@ -0,0 +1,581 @@
+//! `serve`: a localhost REST API for components that can't embed signal-core.
+//!
+//! Endpoints (all require `Authorization: Bearer <token>`):
+//!
+//! - `GET /chats` — same JSON as `chats`
+//! - `GET /chats/{id}/messages` — same JSON as `messages`
+//! - `POST /chats/{id}/messages` with `{"text": "..."}` — send, same JSON as `send`
+//! - `GET /events` — server-sent events from the event log, one per item
+//!
+//! presage's manager isn't shared between tasks, so a single job loop owns it:
+//! handlers submit jobs and wait for the reply. Between jobs the loop receives
+//! from the server, which appends to the event log that `/events` tails, and
+//! hands what arrived to the webhook, hooks and policies as `receive` would.
+//! A proxied `receive` only returns what its own drain brought, so those
+//! are the only way the rest reaches them.
+//!
+//! The server token can do everything; tokens from `api-token create` are
+//! limited to the chats and operations they were issued for. A token's
+//! [`Scope`] is resolved once per request and checked by each handler.
+//!
+//! The same loop answers the control socket (see [`crate::daemon`]), so CLI
+//! commands run while serving go through this process.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use axum::extract::{Extension, Path, Query, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures::{stream, Stream, StreamExt};
+use presage::store::Thread;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use signal_core::identity::IdentityPolicy;
+use signal_core::model::{ChatOutput, MessageOrder, ReceivedItem};
+use signal_core::send::{self, Outgoing, SendReport};
+use signal_core::timestamp::Timestamp;
+use signal_core::{
+    chats, events, history, outbox, parse_thread, receive, thread_chat_id, Error, SignalManager,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use crate::api_tokens::{self, Operation, Scope};
+use crate::hooks::Hooks;
+use crate::policy::Policies;
+use crate::webhook::Webhook;
+use crate::{daemon, SendOutput};
+
+/// Secret holding the API token when neither `--token` nor the env var is set
+pub const API_TOKEN_SECRET: &str = "signal-api-token";
+
+/// How often `/events` checks the event log for new entries
+const EVENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type Reply<T> = oneshot::Sender<signal_core::Result<T>>;
+
+/// Work for the job loop, which owns the manager
+enum Job {
+    Chats {
+        max_results: usize,
+        reply: Reply<Vec<ChatOutput>>,
+    },
+    Messages {
+        chat_id: String,
+        max_results: usize,
+        reply: Reply<Vec<ReceivedItem>>,
+    },
+    Send {
+        thread: Thread,
+        text: String,
+        reply: Reply<SendReport>,
+    },
+    /// A CLI command proxied through the control socket
+    Command {
+        envelope: daemon::Envelope,
+        reply: oneshot::Sender<Result<Value>>,
+    },
+}
+
+pub struct Options {
+    pub listen: SocketAddr,
+    /// Control socket for proxied CLI commands
+    pub socket: PathBuf,
+    pub token: String,
+    pub identity_policy: IdentityPolicy,
+    pub max_results: usize,
+    /// How often to receive from the server while idle
+    pub receive_interval: Duration,
+    /// What to do with what's received while idle, as for `receive`
+    pub webhook: Option<Webhook>,
+    pub hooks: Hooks,
+    pub policies: Policies,
+}
+
+/// What the job loop does with items it receives between jobs
+struct Responders {
+    webhook: Option<Webhook>,
+    hooks: Hooks,
+    policies: Policies,
+}
+
+#[derive(Clone)]
+struct AppState {
+    token: Arc<str>,
+    jobs: mpsc::Sender<Job>,
+    max_results: usize,
+}
+
+#[derive(Deserialize)]
+struct LimitQuery {
+    max_results: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// Start after this sequence number instead of at the newest event
+    after: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct SendBody {
+    text: String,
+}
+
+/// An error response: `{"error": "..."}` with a matching status
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        let status = match &e {
+            Error::NotLinked | Error::Unlinked | Error::LinkInterrupted => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Error::Network(_) => StatusCode::BAD_GATEWAY,
+            Error::Busy => StatusCode::CONFLICT,
+            Error::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Error::UntrustedIdentity(_) => StatusCode::CONFLICT,
+            Error::InvalidRecipient(_) | Error::Invalid(_) => StatusCode::BAD_REQUEST,
+            Error::ReadOnly(_) => StatusCode::FORBIDDEN,
+            Error::Storage(_) | Error::Encrypted => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError(status, e.to_string())
+    }
+}
+
+/// Compare without exiting early, so response timing doesn't leak the token
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// The scope a bearer token grants: everything for the server token,
+/// otherwise whatever it was issued with.
+fn token_scope(state: &AppState, token: &str) -> Result<Option<Scope>, ApiError> {
+    if token_matches(token, &state.token) {
+        return Ok(Some(Scope::full()));
+    }
+    let internal =
+        |e: anyhow::Error| ApiError(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e));
+    let conn = api_tokens::open_tokens_db().map_err(internal)?;
+    api_tokens::lookup(&conn, token).map_err(internal)
+}
+
+async fn require_token(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let scope = match token.map(|token| token_scope(&state, token)) {
+        Some(Ok(Some(scope))) => scope,
+        Some(Err(e)) => return e.into_response(),
+        Some(Ok(None)) | None => {
+            return ApiError(
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid bearer token".to_string(),
+            )
+            .into_response();
+        }
+    };
+    request.extensions_mut().insert(scope);
+    next.run(request).await
+}
+
+/// Fail with 403 unless the token may do `operation` (in `chat_id`, if given).
+fn authorize(scope: &Scope, operation: Operation, chat_id: Option<&str>) -> Result<(), ApiError> {
+    let forbidden = |message: String| Err(ApiError(StatusCode::FORBIDDEN, message));
+    if !scope.allows(operation) {
+        let action = match operation {
+            Operation::Read => "reading",
+            Operation::Send => "sending",
+        };
+        return forbidden(format!("Token doesn't allow {}", action));
+    }
+    match chat_id {
+        Some(chat_id) if !scope.allows_chat(chat_id) => {
+            forbidden(format!("Token doesn't allow chat {}", chat_id))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl AppState {
+    async fn submit<T>(&self, job: impl FnOnce(Reply<T>) -> Job) -> Result<T, ApiError> {
+        let (reply, response) = oneshot::channel();
+        let stopped = || {
+            ApiError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Server is shutting down".to_string(),
+            )
+        };
+        self.jobs.send(job(reply)).await.map_err(|_| stopped())?;
+        Ok(response.await.map_err(|_| stopped())??)
+    }
+}
+
+async fn get_chats(
+    State(state): State<AppState>,
+    Extension(scope): Extension<Scope>,
+    Query(query): Query<LimitQuery>,
+) -> Result<Json<Vec<ChatOutput>>, ApiError> {
+    authorize(&scope, Operation::Read, None)?;
+    let max_results = query.max_results.unwrap_or(state.max_results);
+    // A chat-scoped token only sees its chats, so filter before truncating
+    let limit = if scope.chats.is_some() {
+        usize::MAX
+    } else {
+        max_results
+    };
+    let mut chats = state
+        .submit(|reply| Job::Chats {
+            max_results: limit,
+            reply,
+        })
+        .await?;
+    chats.retain(|chat| scope.allows_chat(&chat.id));
+    chats.truncate(max_results);
+    Ok(Json(chats))
+}
+
+async fn get_messages(
+    State(state): State<AppState>,
+    Extension(scope): Extension<Scope>,
+    Path(chat_id): Path<String>,
+    Query(query): Query<LimitQuery>,
+) -> Result<Json<Vec<ReceivedItem>>, ApiError> {
+    // Scoped tokens store canonical chat IDs, so compare against one
+    let chat_id = thread_chat_id(&parse_thread(&chat_id)?);
+    authorize(&scope, Operation::Read, Some(&chat_id))?;
+    let max_results = query.max_results.unwrap_or(state.max_results);
+    let messages = state
+        .submit(|reply| Job::Messages {
+            chat_id,
+            max_results,
+            reply,
+        })
+        .await?;
+    Ok(Json(messages))
+}
+
+async fn post_message(
+    State(state): State<AppState>,
+    Extension(scope): Extension<Scope>,
+    Path(chat_id): Path<String>,
+    Json(body): Json<SendBody>,
+) -> Result<Json<SendOutput>, ApiError> {
+    // Scoped tokens store canonical chat IDs, so compare against one
+    let thread = parse_thread(&chat_id)?;
+    authorize(&scope, Operation::Send, Some(&thread_chat_id(&thread)))?;
+    let text = body.text.trim().to_string();
+    if text.is_empty() {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            "Message cannot be empty".to_string(),
+        ));
+    }
+
+    let report = state
+        .submit(|reply| Job::Send {
+            thread,
+            text,
+            reply,
+        })
+        .await?;
+    Ok(Json(SendOutput {
+        success: report.failed.is_empty(),
+        timestamp: Timestamp::from_millis(report.timestamp),
+        recipients: None,
+        failed: report.failed,
+        parts: Vec::new(),
+    }))
+}
+
+/// Stream the event log. Resumes after `Last-Event-ID` (or `?after=`) when
+/// given, otherwise starts with events logged from now on. A chat-scoped
+/// token only gets its chats' events.
+async fn get_events(
+    Extension(scope): Extension<Scope>,
+    headers: HeaderMap,
+    Query(query): Query<EventsQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    authorize(&scope, Operation::Read, None)?;
+    let conn = events::open_events_db()?;
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let start = match last_event_id.or(query.after) {
+        Some(seq) => seq,
+        None => events::last_seq(&conn)?,
+    };
+
+    let batches = stream::unfold((conn, start), |(conn, mut after)| async move {
+        loop {
+            match events::after(&conn, after, 100) {
+                Ok(batch) if !batch.is_empty() => {
+                    after = batch.last().map_or(after, |record| record.seq);
+                    return Some((batch, (conn, after)));
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to read event log: {}", e),
+            }
+            tokio::time::sleep(EVENT_POLL_INTERVAL).await;
+        }
+    });
+    let sse_events = batches
+        .flat_map(stream::iter)
+        .filter(move |record| {
+            let allowed = match &record.chat_id {
+                Some(chat_id) => scope.allows_chat(chat_id),
+                None => scope.chats.is_none(),
+            };
+            futures::future::ready(allowed)
+        })
+        .filter_map(|record| async move {
+            match Event::default()
+                .id(record.seq.to_string())
+                .event(record.kind.clone())
+                .json_data(&record)
+            {
+                Ok(event) => Some(Ok(event)),
+                Err(e) => {
+                    warn!("Failed to encode event {}: {}", record.seq, e);
+                    None
+                }
+            }
+        });
+
+    Ok(Sse::new(sse_events).keep_alive(KeepAlive::default()))
+}
+
+/// Run jobs one at a time, receiving from the server whenever idle for
+/// `receive_interval`.
+async fn run_jobs(
+    mut manager: SignalManager,
+    mut jobs: mpsc::Receiver<Job>,
+    policy: IdentityPolicy,
+    receive_interval: Duration,
+    mut responders: Responders,
+) {
+    let mut receive_tick = tokio::time::interval(receive_interval);
+    loop {
+        tokio::select! {
+            job = jobs.recv() => {
+                let Some(job) = job else { break };
+                // A caller that gave up waiting doesn't need the reply
+                match job {
+                    Job::Chats { max_results, reply } => {
+                        let result = chats::list_chats(&mut manager, max_results, None, false).await;
+                        let _ = reply.send(result);
+                    }
+                    Job::Messages {
+                        chat_id,
+                        max_results,
+                        reply,
+                    } => {
+                        let result = history::thread_messages(
+                            &manager,
+                            &chat_id,
+                            max_results,
+                            MessageOrder::Sent,
+                            false,
+                            None,
+                            &history::Page::default(),
+                        )
+                        .await;
+                        let _ = reply.send(result);
+                    }
+                    Job::Send {
+                        thread,
+                        text,
+                        reply,
+                    } => {
+                        let outgoing = Outgoing {
+                            text: Some(text),
+                            attachments: Vec::new(),
+                            styles: Vec::new(),
+                            preview: None,
+                            sticker: None,
+                            view_once: false,
+                            contact: None,
+                        };
+                        let result = match thread {
+                            Thread::Contact(recipient) => {
+                                send::send(&mut manager, vec![recipient], outgoing, policy, false)
+                                    .await
+                            }
+                            Thread::Group(master_key) => {
+                                send::send_to_group(&mut manager, master_key, outgoing, &[], policy)
+                                    .await
+                            }
+                        };
+                        let _ = reply.send(result);
+                    }
+                    Job::Command { envelope, reply } => {
+                        let result = daemon::execute(&mut manager, envelope, policy).await;
+                        let _ = reply.send(result);
+                    }
+                }
+            }
+            _ = receive_tick.tick() => {
+                match receive::receive(&mut manager, policy).await {
+                    Ok(result) if !result.items.is_empty() => {
+                        info!("Received {} items", result.items.len());
+                        respond(&mut manager, &mut responders, &result.items, policy).await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to receive messages: {}", e),
+                }
+                match outbox::flush(&mut manager, policy, false).await {
+                    Ok(report) if !report.results.is_empty() => {
+                        info!("Flushed outbox: {} sent, {} pending", report.sent, report.pending);
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to flush outbox: {}", e),
+                }
+            }
+        }
+    }
+}
+
+/// Hand items received between jobs to the webhook, hooks and policies.
+/// Failures are logged; the items are already saved and in the event log.
+async fn respond(
+    manager: &mut SignalManager,
+    responders: &mut Responders,
+    items: &[ReceivedItem],
+    policy: IdentityPolicy,
+) {
+    let items = match items
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<serde_json::Result<Vec<_>>>()
+    {
+        Ok(items) => items,
+        Err(e) => {
+            warn!("Failed to serialize received items: {}", e);
+            return;
+        }
+    };
+    crate::respond(
+        &responders.hooks,
+        &mut responders.policies,
+        &items,
+        Some(manager),
+        policy,
+    )
+    .await;
+    if let Err(e) = crate::forward_to_webhook(responders.webhook.as_ref(), &items).await {
+        warn!("{:#}", e);
+    }
+}
+
+/// Bind the control socket, replacing one left by a daemon that's gone.
+async fn bind_socket(path: &std::path::Path) -> Result<tokio::net::UnixListener> {
+    if path.exists() {
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            anyhow::bail!("Another daemon is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    // Anyone who can connect can send as this account; the socket dir keeps
+    // other users out from the moment it's bound (see `daemon::socket_dir`)
+    tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))
+}
+
+/// Accept control socket connections, handing each request to the job loop.
+async fn accept_commands(
+    listener: tokio::net::UnixListener,
+    jobs: mpsc::Sender<Job>,
+) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await.context("Control socket failed")?;
+        let jobs = jobs.clone();
+        tokio::spawn(async move {
+            let run = |envelope| {
+                let jobs = jobs.clone();
+                async move {
+                    let (reply, response) = oneshot::channel();
+                    jobs.send(Job::Command { envelope, reply })
+                        .await
+                        .map_err(|_| anyhow::anyhow!("Daemon is shutting down"))?;
+                    response
+                        .await
+                        .map_err(|_| anyhow::anyhow!("Daemon is shutting down"))?
+                }
+            };
+            if let Err(e) = daemon::handle_connection(stream, run).await {
+                warn!("Control socket connection failed: {:#}", e);
+            }
+        });
+    }
+}
+
+/// Serve until the listener fails or the process is stopped.
+pub async fn serve(manager: SignalManager, options: Options) -> Result<()> {
+    let (jobs_tx, jobs_rx) = mpsc::channel(16);
+    let responders = Responders {
+        webhook: options.webhook,
+        hooks: options.hooks,
+        policies: options.policies,
+    };
+    let socket = bind_socket(&options.socket).await?;
+    let state = AppState {
+        token: options.token.into(),
+        jobs: jobs_tx.clone(),
+        max_results: options.max_results,
+    };
+
+    let app = Router::new()
+        .route("/chats", get(get_chats))
+        .route("/chats/{id}/messages", get(get_messages).post(post_message))
+        .route("/events", get(get_events))
+        .layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(options.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", options.listen))?;
+    eprintln!("Serving on http://{}", options.listen);
+    eprintln!("Accepting CLI commands on {}", options.socket.display());
+
+    let result = tokio::select! {
+        result = axum::serve(listener, app) => result.context("Server failed"),
+        result = accept_commands(socket, jobs_tx) => result,
+        _ = run_jobs(
+            manager,
+            jobs_rx,
+            options.identity_policy,
+            options.receive_interval,
+            responders,
+        ) => Ok(()),
+    };
+    let _ = std::fs::remove_file(&options.socket);
+    result
+}
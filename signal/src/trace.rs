@@ -0,0 +1,149 @@
+//! `--trace-output`: write span timings as a Chrome trace.
+//!
+//! Each closed span becomes a complete ("X") event with its fields as args.
+//! Open the file in `chrome://tracing` or https://ui.perfetto.dev to see where
+//! a command spent its time (store open, network, decrypt, persist).
+
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde_json::{json, Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Start time and recorded fields, kept in the span's extensions
+struct SpanTiming {
+    start: Instant,
+    tid: u64,
+    args: Map<String, Value>,
+}
+
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), json!(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+}
+
+/// Small stable per-thread IDs, since `ThreadId::as_u64` isn't stable
+fn thread_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static ID: Cell<u64> = const { Cell::new(0) };
+    }
+    ID.with(|id| {
+        if id.get() == 0 {
+            id.set(NEXT.fetch_add(1, Ordering::Relaxed));
+        }
+        id.get()
+    })
+}
+
+pub struct ChromeLayer {
+    origin: Instant,
+    events: Arc<Mutex<Vec<Value>>>,
+}
+
+/// Writes the trace file when dropped, so keep it alive for the whole run.
+pub struct FlushGuard {
+    path: PathBuf,
+    events: Arc<Mutex<Vec<Value>>>,
+}
+
+pub fn chrome_layer(path: PathBuf) -> (ChromeLayer, FlushGuard) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let layer = ChromeLayer {
+        origin: Instant::now(),
+        events: events.clone(),
+    };
+    (layer, FlushGuard { path, events })
+}
+
+impl<S> Layer<S> for ChromeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut args = Map::new();
+        attrs.record(&mut FieldVisitor(&mut args));
+        span.extensions_mut().insert(SpanTiming {
+            start: Instant::now(),
+            tid: thread_id(),
+            args,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<SpanTiming>() {
+            values.record(&mut FieldVisitor(&mut timing.args));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let event = {
+            let extensions = span.extensions();
+            let Some(timing) = extensions.get::<SpanTiming>() else {
+                return;
+            };
+            json!({
+                "name": span.name(),
+                "cat": span.metadata().target(),
+                "ph": "X",
+                "ts": timing.start.duration_since(self.origin).as_micros() as u64,
+                "dur": timing.start.elapsed().as_micros() as u64,
+                "pid": std::process::id(),
+                "tid": timing.tid,
+                "args": timing.args,
+            })
+        };
+        if let Ok(mut events) = self.events.lock() {
+            events.push(event);
+        }
+    }
+}
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        let events = match self.events.lock() {
+            Ok(mut events) => std::mem::take(&mut *events),
+            Err(_) => return,
+        };
+        let trace = json!({ "traceEvents": events, "displayTimeUnit": "ms" });
+        let result = serde_json::to_vec(&trace)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(std::fs::write(&self.path, data)?));
+        match result {
+            Ok(()) => eprintln!("Trace written to {}", self.path.display()),
+            Err(e) => eprintln!("Failed to write trace to {}: {}", self.path.display(), e),
+        }
+    }
+}
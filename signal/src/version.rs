@@ -0,0 +1,96 @@
+//! `version`: what this build is and what it can do, for bug reports and for
+//! checking a remote deployment.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{db, get_data_dir};
+
+/// Protocol features, and whether this build supports them
+const FEATURES: &[(&str, bool)] = &[
+    ("attachments", true),
+    ("editing", false),
+    ("group_sends", true),
+    ("read_receipts", true),
+    ("search", true),
+    ("stickers", true),
+    ("stories", true),
+    ("usernames", false),
+    ("view_once", true),
+    ("voice_notes", true),
+];
+
+#[derive(Serialize)]
+pub struct VersionOutput {
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    /// Resolved versions of the Signal libraries, by crate
+    pub libraries: BTreeMap<&'static str, &'static str>,
+    pub features: BTreeMap<&'static str, bool>,
+    pub schemas: Schemas,
+}
+
+/// Schema versions of the databases in the data dir. Databases that haven't
+/// been created yet are left out.
+#[derive(Serialize)]
+pub struct Schemas {
+    /// The message store's latest applied migration
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store: Option<i64>,
+    /// Each local database's `user_version`, by file name
+    pub local: BTreeMap<String, i64>,
+}
+
+fn schemas() -> Result<Schemas> {
+    let data_dir = get_data_dir()?;
+
+    let store_path = data_dir.join("signal.db");
+    let store = if store_path.exists() {
+        // sqlx records migrations here; without the table there's no version
+        db::open_read_only(&store_path)?
+            .query_row(
+                "SELECT MAX(version) FROM _sqlx_migrations WHERE success",
+                [],
+                |row| row.get(0),
+            )
+            .ok()
+            .flatten()
+    } else {
+        None
+    };
+
+    let mut local = BTreeMap::new();
+    if data_dir.exists() {
+        for entry in std::fs::read_dir(&data_dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !name.ends_with(".db") || name == "signal.db" {
+                continue;
+            }
+            let version =
+                db::open_read_only(&path)?
+                    .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+            local.insert(name.to_string(), version);
+        }
+    }
+
+    Ok(Schemas { store, local })
+}
+
+pub fn report() -> Result<VersionOutput> {
+    Ok(VersionOutput {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("JC_GIT_HASH"),
+        libraries: BTreeMap::from([
+            ("presage", env!("JC_PRESAGE_VERSION")),
+            ("libsignal-service", env!("JC_LIBSIGNAL_SERVICE_VERSION")),
+            ("libsignal", env!("JC_LIBSIGNAL_VERSION")),
+        ]),
+        features: FEATURES.iter().copied().collect(),
+        schemas: schemas()?,
+    })
+}